@@ -0,0 +1,266 @@
+use compiler::backend::BackendKind;
+use compiler::codegen::emit::EmitKind;
+use compiler::codegen::passes::{parse_opt_level, OptimizationLevel};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: kaleidoscope build <file.ks> [-o <output>] [-O<level>] [--emit=llvm-ir|bitcode|asm|obj] [--link] [--no-bounds-checks]
+       kaleidoscope run <file.ks> [-O<level>] [--backend=jit|vm|interp]
+
+Options:
+    -o <output>         Write the output to <output>
+    -O<level>           Optimization level from 0 to 3, 0 by default
+    --emit=<kind>       What to emit, obj by default
+    --link              Link the object file with the runtime into an executable
+    --no-bounds-checks  Do not check the indices of arrays
+    --backend=<kind>    What to run the file with, jit by default";
+
+/// A subcommand along with its options
+#[derive(PartialEq, Debug)]
+pub enum Command {
+    Build(BuildOptions),
+    Run(RunOptions),
+}
+
+/// Options of `kaleidoscope build`
+#[derive(PartialEq, Debug)]
+pub struct BuildOptions {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    pub emit: EmitKind,
+    pub link: bool,
+    pub opt_level: OptimizationLevel,
+    /// Whether indexing an array checks the index, unless --no-bounds-checks
+    pub bounds_checks: bool,
+}
+
+/// Options of `kaleidoscope run`
+#[derive(PartialEq, Debug)]
+pub struct RunOptions {
+    pub input: PathBuf,
+    pub backend: BackendKind,
+    pub opt_level: OptimizationLevel,
+}
+
+impl BuildOptions {
+    /// The output path, derived from the input if not given
+    pub fn output_path(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => output.clone(),
+            None if self.link => self.input.with_extension(""),
+            None => self.input.with_extension(self.emit.extension()),
+        }
+    }
+}
+
+/// Parse the command line arguments, without the program name
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.into_iter();
+
+    let build = match args.next().as_deref() {
+        Some("build") => true,
+        Some("run") => false,
+        Some(command) => return Err(format!("Unknown command {}.", command)),
+        None => return Err("Expect a command.".into()),
+    };
+
+    let mut input = None;
+    let mut output = None;
+    let mut emit = None;
+    let mut link = false;
+    let mut bounds_checks = true;
+    let mut backend = None;
+    let mut opt_level = OptimizationLevel::None;
+
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => return Err("Expect an output path after -o.".into()),
+            }
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            opt_level = parse_opt_level(level)?;
+        } else if let Some(kind) = arg.strip_prefix("--emit=") {
+            emit = Some(kind.parse()?);
+        } else if arg == "--link" {
+            link = true;
+        } else if arg == "--no-bounds-checks" {
+            bounds_checks = false;
+        } else if let Some(kind) = arg.strip_prefix("--backend=") {
+            backend = Some(kind.parse()?);
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option {}.", arg));
+        } else if input.is_none() {
+            input = Some(PathBuf::from(arg));
+        } else {
+            return Err(format!("Unexpected argument {}.", arg));
+        }
+    }
+
+    let input = input.ok_or_else(|| "Expect an input file.".to_string())?;
+
+    if !build {
+        if output.is_some() || emit.is_some() || link || !bounds_checks {
+            return Err(
+                "-o, --emit, --link and --no-bounds-checks can only be used with build.".into(),
+            );
+        }
+
+        return Ok(Command::Run(RunOptions {
+            input,
+            backend: backend.unwrap_or(BackendKind::Jit),
+            opt_level,
+        }));
+    }
+
+    if backend.is_some() {
+        return Err("--backend can only be used with run.".into());
+    }
+
+    // only an object file can be linked
+    if link && emit.map_or(false, |emit| emit != EmitKind::Object) {
+        return Err("--link can only be used with --emit=obj.".into());
+    }
+
+    Ok(Command::Build(BuildOptions {
+        input,
+        output,
+        emit: emit.unwrap_or(EmitKind::Object),
+        link,
+        opt_level,
+        bounds_checks,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        parse_args(args.split_whitespace().map(String::from))
+    }
+
+    fn parse_build(args: &str) -> BuildOptions {
+        match parse(args) {
+            Ok(Command::Build(options)) => options,
+            command => panic!("not a build command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn build_options() {
+        assert_eq!(
+            parse("build a.ks"),
+            Ok(Command::Build(BuildOptions {
+                input: "a.ks".into(),
+                output: None,
+                emit: EmitKind::Object,
+                link: false,
+                opt_level: OptimizationLevel::None,
+                bounds_checks: true,
+            }))
+        );
+
+        assert_eq!(
+            parse("build --emit=llvm-ir a.ks -o out.ll"),
+            Ok(Command::Build(BuildOptions {
+                input: "a.ks".into(),
+                output: Some("out.ll".into()),
+                emit: EmitKind::LlvmIr,
+                link: false,
+                opt_level: OptimizationLevel::None,
+                bounds_checks: true,
+            }))
+        );
+
+        assert_eq!(
+            parse("build a.ks --link"),
+            Ok(Command::Build(BuildOptions {
+                input: "a.ks".into(),
+                output: None,
+                emit: EmitKind::Object,
+                link: true,
+                opt_level: OptimizationLevel::None,
+                bounds_checks: true,
+            }))
+        );
+    }
+
+    #[test]
+    fn run_options() {
+        assert_eq!(
+            parse("run a.ks"),
+            Ok(Command::Run(RunOptions {
+                input: "a.ks".into(),
+                backend: BackendKind::Jit,
+                opt_level: OptimizationLevel::None,
+            }))
+        );
+
+        assert_eq!(
+            parse("run --backend=vm a.ks -O1"),
+            Ok(Command::Run(RunOptions {
+                input: "a.ks".into(),
+                backend: BackendKind::Vm,
+                opt_level: OptimizationLevel::Less,
+            }))
+        );
+
+        assert!(parse("run a.ks --backend=llvm").is_err());
+        assert!(parse("run a.ks --emit=asm").is_err());
+        assert!(parse("run a.ks -o a").is_err());
+        assert!(parse("build a.ks --backend=vm").is_err());
+    }
+
+    #[test]
+    fn opt_levels() {
+        assert_eq!(
+            parse_build("build a.ks -O2").opt_level,
+            OptimizationLevel::Default
+        );
+        assert_eq!(
+            parse_build("build -O3 a.ks -O1").opt_level,
+            OptimizationLevel::Less
+        );
+        assert!(parse("build a.ks -O").is_err());
+        assert!(parse("build a.ks -O4").is_err());
+    }
+
+    #[test]
+    fn bounds_checks() {
+        assert!(!parse_build("build a.ks -O3 --no-bounds-checks").bounds_checks);
+        assert!(parse("run a.ks --no-bounds-checks").is_err());
+    }
+
+    #[test]
+    fn invalid_args() {
+        assert!(parse("").is_err());
+        assert!(parse("check a.ks").is_err());
+        assert!(parse("build").is_err());
+        assert!(parse("build a.ks b.ks").is_err());
+        assert!(parse("build a.ks -o").is_err());
+        assert!(parse("build a.ks --emit=exe").is_err());
+        assert!(parse("build a.ks --emit=asm --link").is_err());
+        assert!(parse("build a.ks --verbose").is_err());
+    }
+
+    #[test]
+    fn output_path() {
+        assert_eq!(
+            parse_build("build a.ks").output_path(),
+            PathBuf::from("a.o")
+        );
+        assert_eq!(
+            parse_build("build dir/a.ks --emit=bitcode").output_path(),
+            PathBuf::from("dir/a.bc")
+        );
+        assert_eq!(
+            parse_build("build a.ks --link").output_path(),
+            PathBuf::from("a")
+        );
+        assert_eq!(
+            parse_build("build a.ks --link -o b").output_path(),
+            PathBuf::from("b")
+        );
+    }
+}
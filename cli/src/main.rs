@@ -0,0 +1,341 @@
+mod args;
+
+use args::{parse_args, BuildOptions, Command, RunOptions, USAGE};
+use compiler::backend::{create_backend, Backend};
+use compiler::codegen::codegen_context::{create_inkwell_context, CodegenContext};
+use compiler::codegen::emit::{emit, EmitKind};
+use compiler::diagnostics::Diagnostic;
+use compiler::lexer::Lexer;
+use compiler::parser::nodes::{ASTNode, Prototype};
+use compiler::parser::parser::Parser;
+use compiler::sema::Analyzer;
+use std::path::Path;
+use std::process::exit;
+
+/// Defines the functions available to compiled programs, e.g. `extern printd(x);`
+const RUNTIME: &str = include_str!("runtime.c");
+
+/// Parse and analyze the file, rendering every error to stderr
+fn check_file(source_name: &str, source: &str) -> Option<Vec<ASTNode>> {
+    let program = Parser::new(Lexer::new(source.chars())).parse_program();
+    let sema_errors = Analyzer::new().check_program(&program.nodes);
+
+    let diagnostics: Vec<Diagnostic> = program
+        .errors
+        .into_iter()
+        .map(Diagnostic::from)
+        .chain(sema_errors.into_iter().map(Diagnostic::from))
+        .collect();
+    for diagnostic in &diagnostics {
+        eprint!("{}", diagnostic.render(source_name, source));
+    }
+
+    if diagnostics.is_empty() {
+        Some(program.nodes)
+    } else {
+        None
+    }
+}
+
+/// Whether an item defines a type, which the prototypes of the functions may use
+fn is_type_def(node: &ASTNode) -> bool {
+    matches!(node, ASTNode::StructNode(_) | ASTNode::UnionNode(_))
+}
+
+/// The prototypes of the functions defined by the file,
+/// declared before any function is defined so that the functions can call the ones after them
+fn prototypes(nodes: &[ASTNode]) -> impl Iterator<Item = &Prototype> {
+    nodes.iter().filter_map(|node| match node {
+        ASTNode::FunctionNode(func) if !func.prototype.is_anonymous() => Some(&func.prototype),
+        _ => None,
+    })
+}
+
+/// Compile the file into the module of `cc`, returning the names of the top level expressions.
+/// The types are defined and the functions declared first, then the items are compiled in order.
+/// Every error is rendered to stderr.
+fn compile_file(cc: &mut CodegenContext, source_name: &str, source: &str) -> Option<Vec<String>> {
+    let nodes = check_file(source_name, source)?;
+
+    let mut has_error = false;
+    let mut report = |diagnostic: Diagnostic| {
+        has_error = true;
+        eprint!("{}", diagnostic.render(source_name, source));
+    };
+
+    for node in nodes.iter().filter(|node| is_type_def(node)) {
+        let result = match node {
+            ASTNode::StructNode(def) => cc
+                .compile_struct(def)
+                .map(|_| ())
+                .map_err(|err| err.into_diagnostic(def.span)),
+            ASTNode::UnionNode(def) => cc
+                .compile_union(def)
+                .map(|_| ())
+                .map_err(|err| err.into_diagnostic(def.span)),
+            _ => continue,
+        };
+        if let Err(diagnostic) = result {
+            report(diagnostic);
+        }
+    }
+
+    for proto in prototypes(&nodes) {
+        if let Err(err) = cc.declare_func(proto) {
+            report(err.into_diagnostic(proto.span));
+        }
+    }
+
+    let mut anonymous = vec![];
+    for node in &nodes {
+        let result = match node {
+            ASTNode::ExternNode(proto) => cc
+                .compile_extern(proto)
+                .map(|_| ())
+                .map_err(|err| err.into_diagnostic(proto.span)),
+            ASTNode::FunctionNode(func) => cc
+                .compile_func(func)
+                .map(|_| ())
+                .map_err(|err| err.into_diagnostic(func.span)),
+            ASTNode::DerivativeNode(def) => cc
+                .compile_derivative(def)
+                .map(|_| ())
+                .map_err(|err| err.into_diagnostic(def.span)),
+            _ => continue,
+        };
+
+        match result {
+            Ok(_) => {
+                if let ASTNode::FunctionNode(func) = node {
+                    if func.prototype.is_anonymous() {
+                        anonymous.push(func.prototype.name.clone());
+                    }
+                }
+            }
+            Err(diagnostic) => report(diagnostic),
+        }
+    }
+
+    if has_error {
+        None
+    } else {
+        Some(anonymous)
+    }
+}
+
+/// Link the object file with the runtime into an executable using the system C compiler
+fn link(object: &Path, output: &Path) -> Result<(), String> {
+    let runtime =
+        std::env::temp_dir().join(format!("kaleidoscope_runtime_{}.c", std::process::id()));
+    std::fs::write(&runtime, RUNTIME).map_err(|err| err.to_string())?;
+
+    let status = std::process::Command::new("cc")
+        .arg(object)
+        .arg(&runtime)
+        .arg("-o")
+        .arg(output)
+        .arg("-lm")
+        .status();
+    let _ = std::fs::remove_file(&runtime);
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("cc failed with {}.", status)),
+        Err(err) => Err(format!("Failed to run cc: {}", err)),
+    }
+}
+
+/// Read the input file, returning its name shown in diagnostics and its content
+fn read_source(input: &Path) -> Result<(String, String), String> {
+    let source_name = input.display().to_string();
+    let source = std::fs::read_to_string(input)
+        .map_err(|err| format!("Failed to read {}: {}", source_name, err))?;
+    Ok((source_name, source))
+}
+
+fn build(options: &BuildOptions) -> Result<(), String> {
+    let (source_name, source) = read_source(&options.input)?;
+
+    let context = create_inkwell_context();
+    let module_name = options
+        .input
+        .file_stem()
+        .map_or("main".into(), |stem| stem.to_string_lossy());
+    let mut cc = CodegenContext::new_without_jit(&context, &module_name);
+    cc.set_opt_level(options.opt_level);
+    cc.set_bounds_checks(options.bounds_checks);
+
+    let anonymous = match compile_file(&mut cc, &source_name, &source) {
+        Some(anonymous) => anonymous,
+        None => return Err(format!("Failed to compile {}.", source_name)),
+    };
+
+    let output = options.output_path();
+    if !options.link {
+        cc.optimize_module();
+        return emit(cc.module(), options.emit, &output);
+    }
+
+    // run the top level expressions in main
+    cc.compile_main(&anonymous).map_err(|err| err.to_string())?;
+    cc.optimize_module();
+
+    let object = std::env::temp_dir().join(format!(
+        "kaleidoscope_{}_{}.o",
+        module_name,
+        std::process::id()
+    ));
+    emit(cc.module(), EmitKind::Object, &object)?;
+    let result = link(&object, &output);
+    let _ = std::fs::remove_file(&object);
+    result
+}
+
+/// Run the items of a file on a backend, stopping at the first error.
+/// As for a file compiled ahead of time, the types are defined and the functions declared first.
+fn run_items(backend: &mut dyn Backend, nodes: &[ASTNode]) -> Result<(), Box<Diagnostic>> {
+    for node in nodes.iter().filter(|node| is_type_def(node)) {
+        backend.run_item(node)?;
+    }
+    for proto in prototypes(nodes) {
+        backend.declare_function(proto)?;
+    }
+    for node in nodes.iter().filter(|node| !is_type_def(node)) {
+        backend.run_item(node)?;
+    }
+    Ok(())
+}
+
+/// Run the items of the file one by one on a backend, stopping at the first error
+fn run(options: &RunOptions) -> Result<(), String> {
+    let (source_name, source) = read_source(&options.input)?;
+
+    let nodes = match check_file(&source_name, &source) {
+        Some(nodes) => nodes,
+        None => return Err(format!("Failed to compile {}.", source_name)),
+    };
+
+    let context = create_inkwell_context();
+    let mut backend = create_backend(options.backend, &context, options.opt_level)?;
+
+    if let Err(diagnostic) = run_items(backend.as_mut(), &nodes) {
+        eprint!("{}", diagnostic.render(&source_name, &source));
+        return Err(format!("Failed to run {}.", source_name));
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let command = match parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            exit(2);
+        }
+    };
+
+    let result = match &command {
+        Command::Build(options) => build(options),
+        Command::Run(options) => run(options),
+    };
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::backend::BackendKind;
+    use compiler::codegen::passes::OptimizationLevel;
+
+    const MUTUAL_RECURSION: &str = "
+        def even(n) if n < 1 then 1 else odd(n - 1);
+        def odd(n) if n < 1 then 0 else even(n - 1);
+        even(4); odd(4); even(7);
+        ";
+    const PROGRAM: &str = "
+        def fib(x) if x < 3 then 1.0 else fib(x - 1) + fib(x - 2);
+        def twice(f: (double) -> double) \\x -> f(f(x));
+        var g = twice(\\x -> fib(x)) in g(5);
+        ";
+
+    /// Run a program written to a file of its own on a backend
+    fn run_source(name: &str, source: &str, backend: BackendKind) -> Result<(), String> {
+        let input = std::env::temp_dir().join(format!(
+            "kaleidoscope_{}_{:?}_{}.ks",
+            name,
+            backend,
+            std::process::id()
+        ));
+        std::fs::write(&input, source).unwrap();
+        let result = run(&RunOptions {
+            input: input.clone(),
+            backend,
+            opt_level: OptimizationLevel::None,
+        });
+        let _ = std::fs::remove_file(&input);
+        result
+    }
+
+    #[test]
+    fn mutual_recursion() {
+        let nodes = check_file("even.ks", MUTUAL_RECURSION).unwrap();
+
+        let context = create_inkwell_context();
+        let mut cc = CodegenContext::new_without_jit(&context, "even");
+        let anonymous = compile_file(&mut cc, "even.ks", MUTUAL_RECURSION).unwrap();
+        assert!(cc.compile_main(&anonymous).is_ok());
+
+        for kind in &[BackendKind::Jit, BackendKind::Vm, BackendKind::Interp] {
+            let mut backend = create_backend(*kind, &context, OptimizationLevel::None).unwrap();
+            for proto in prototypes(&nodes) {
+                backend.declare_function(proto).unwrap();
+            }
+            let results: Vec<_> = nodes
+                .iter()
+                .filter_map(|node| backend.run_item(node).unwrap())
+                .collect();
+            assert_eq!(results, vec![1.0, 0.0, 0.0], "{:?}", kind);
+
+            assert_eq!(run_source("even", MUTUAL_RECURSION, *kind), Ok(()));
+        }
+    }
+
+    #[test]
+    fn calls_before_definitions() {
+        // odd is declared but not defined yet when even(4) runs
+        let program = "
+            def even(n) if n < 1 then 1 else odd(n - 1);
+            even(4);
+            def odd(n) if n < 1 then 0 else even(n - 1);
+            ";
+        assert!(check_file("even.ks", program).is_some());
+
+        for kind in &[BackendKind::Jit, BackendKind::Vm, BackendKind::Interp] {
+            assert!(run_source("early", program, *kind)
+                .unwrap_err()
+                .starts_with("Failed to run"));
+        }
+    }
+
+    #[test]
+    fn compile_and_run_files() {
+        let context = create_inkwell_context();
+        let mut cc = CodegenContext::new_without_jit(&context, "fib");
+        let anonymous = compile_file(&mut cc, "fib.ks", PROGRAM).unwrap();
+        assert_eq!(anonymous.len(), 1);
+        assert!(cc.compile_main(&anonymous).is_ok());
+
+        for backend in &[BackendKind::Jit, BackendKind::Vm, BackendKind::Interp] {
+            assert_eq!(
+                run_source("fib", PROGRAM, *backend),
+                Ok(()),
+                "{:?}",
+                backend
+            );
+        }
+    }
+}
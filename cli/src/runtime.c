@@ -0,0 +1,45 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* putchard - putchar that takes a double and returns 0. */
+double putchard(double x) {
+  fputc((char)x, stderr);
+  return 0;
+}
+
+/* printd - printf that takes a double prints it as "%f\n", returning 0. */
+double printd(double x) {
+  fprintf(stderr, "%f\n", x);
+  return 0;
+}
+
+/* print - prints a string as it is, returning 0. */
+double print(const char *s) {
+  fputs(s, stderr);
+  return 0;
+}
+
+/* strlen comes from the C library, returning the length in bytes. */
+
+/* concat - a new string made of two strings, which is never freed. */
+char *concat(const char *a, const char *b) {
+  size_t a_len = strlen(a);
+  size_t b_len = strlen(b);
+  char *result = malloc(a_len + b_len + 1);
+  memcpy(result, a, a_len);
+  memcpy(result + a_len, b, b_len + 1);
+  return result;
+}
+
+/* kaleidoscope_out_of_bounds - reports an index out of bounds of an array
+   indexed at line:column and aborts. */
+void kaleidoscope_out_of_bounds(int64_t index, int64_t len, int64_t line,
+                                int64_t column) {
+  fprintf(stderr,
+          "Index %lld is out of bounds for an array of length %lld at "
+          "%lld:%lld.\n",
+          (long long)index, (long long)len, (long long)line, (long long)column);
+  abort();
+}
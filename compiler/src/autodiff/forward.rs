@@ -0,0 +1,793 @@
+use crate::parser::nodes::{DerivativeDef, Expression, ExpressionKind, Function, Prototype};
+use crate::types::{Type, TypeError};
+use crate::util::Span;
+use std::collections::{HashMap, HashSet};
+
+/// The externs of one double whose derivatives are known
+const INTRINSICS: [&str; 5] = ["sin", "cos", "exp", "log", "sqrt"];
+
+/// Generates the derivatives of functions of doubles by forward-mode differentiation,
+/// as functions of their own defined by the backends like any other.
+///
+/// The derivative of `f(x y)` is `f.tangent(x y x.tangent y.tangent)`, the derivative of `f` at `(x, y)`
+/// along `(x.tangent, y.tangent)`, and the gradient of `f` is `f.grad(x y)`, the array of the derivatives
+/// along every axis. Names with `.` cannot be written in the source, so they never clash with the user's.
+/// `def' f` defines `f'` calling the gradient of `f`, which the user can call.
+#[derive(Default)]
+pub struct Differentiator {
+    /// Every function defined so far, as elaborated by the type checker
+    functions: HashMap<String, Function>,
+    /// The derivatives and gradients generated so far
+    generated: HashSet<String>,
+}
+
+impl Differentiator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a type checked function, so that it can be differentiated.
+    /// Redefining a function discards every derivative generated so far, which may call its old one.
+    pub fn define(&mut self, func: &Function) {
+        if func.prototype.is_anonymous() {
+            return;
+        }
+        let name = func.prototype.name.clone();
+        if self.functions.insert(name, func.clone()).is_some() {
+            self.generated.clear();
+        }
+    }
+
+    /// Replace the gradients taken by a function before type checking with the functions computing them,
+    /// returning the function along with the derivatives and gradients to define first, the callees first.
+    ///
+    /// `prototypes` are the typed prototypes of the functions and externs defined so far.
+    pub fn expand(
+        &mut self,
+        func: &Function,
+        prototypes: &HashMap<String, Prototype>,
+    ) -> Result<(Function, Vec<Function>), Box<TypeError>> {
+        let mut expansion = Expansion {
+            functions: &self.functions,
+            generated: &mut self.generated,
+            prototypes,
+            derivatives: vec![],
+            span: func.span,
+        };
+        let mut body = func.body.clone();
+        if let Err(err) = expansion.expand_expr(&mut body) {
+            expansion.forget();
+            return Err(err);
+        }
+
+        let derivatives = expansion.derivatives;
+        Ok((
+            Function {
+                body,
+                ..func.clone()
+            },
+            derivatives,
+        ))
+    }
+
+    /// Generate `f'` for `def' f`, a function of the same args as `f` returning its gradient,
+    /// returning it unchecked along with the derivatives and gradients to define first, the callees first.
+    pub fn derive(
+        &mut self,
+        def: &DerivativeDef,
+        prototypes: &HashMap<String, Prototype>,
+    ) -> Result<(Function, Vec<Function>), Box<TypeError>> {
+        let mut expansion = Expansion {
+            functions: &self.functions,
+            generated: &mut self.generated,
+            prototypes,
+            derivatives: vec![],
+            span: def.span,
+        };
+        let gradient = match expansion.gradient(&def.name) {
+            Ok(gradient) => gradient,
+            Err(err) => {
+                expansion.forget();
+                return Err(err);
+            }
+        };
+
+        let span = def.span;
+        let args = gradient
+            .args
+            .iter()
+            .map(|arg| variable(arg, span))
+            .collect();
+        let func = Function {
+            body: call(&gradient.name, args, span),
+            prototype: Prototype {
+                name: def.derivative_name(),
+                span,
+                ..gradient
+            },
+            span,
+        };
+        Ok((func, expansion.derivatives))
+    }
+}
+
+/// The state of the expansion of the gradients of a function
+struct Expansion<'a> {
+    functions: &'a HashMap<String, Function>,
+    generated: &'a mut HashSet<String>,
+    prototypes: &'a HashMap<String, Prototype>,
+    /// The derivatives and gradients generated by the expansion, the callees first
+    derivatives: Vec<Function>,
+    /// Where the gradient being expanded is taken, where errors are reported
+    span: Span,
+}
+
+impl<'a> Expansion<'a> {
+    /// Forget the derivatives generated when the expansion fails,
+    /// which are not defined, so that they are generated again next time
+    fn forget(&mut self) {
+        for derivative in &self.derivatives {
+            self.generated.remove(&derivative.prototype.name);
+        }
+    }
+
+    fn expand_expr(&mut self, expr: &mut Expression) -> Result<(), Box<TypeError>> {
+        match &mut expr.kind {
+            ExpressionKind::NumberExpr(_)
+            | ExpressionKind::IntExpr(_)
+            | ExpressionKind::BoolExpr(_)
+            | ExpressionKind::StringExpr(_)
+            | ExpressionKind::VariableExpr(_)
+            | ExpressionKind::ClosureExpr { .. } => {}
+            ExpressionKind::UnaryExpr(_, operand)
+            | ExpressionKind::CastExpr(_, operand)
+            | ExpressionKind::FieldExpr {
+                object: operand, ..
+            }
+            | ExpressionKind::LambdaExpr { body: operand, .. } => self.expand_expr(operand)?,
+            ExpressionKind::BinaryExpr(_, left, right) | ExpressionKind::IndexExpr(left, right) => {
+                self.expand_expr(left)?;
+                self.expand_expr(right)?;
+            }
+            ExpressionKind::CallExpr(_, args)
+            | ExpressionKind::ArrayExpr(args)
+            | ExpressionKind::VariantExpr(_, args) => {
+                for arg in args {
+                    self.expand_expr(arg)?;
+                }
+            }
+            ExpressionKind::StructExpr(_, fields) => {
+                for (_, value) in fields {
+                    self.expand_expr(value)?;
+                }
+            }
+            ExpressionKind::MatchExpr { value, arms, .. } => {
+                self.expand_expr(value)?;
+                for (_, body) in arms {
+                    self.expand_expr(body)?;
+                }
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                self.expand_expr(cond)?;
+                self.expand_expr(then_expr)?;
+                self.expand_expr(else_expr)?;
+            }
+            ExpressionKind::ForExpr {
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.expand_expr(start)?;
+                self.expand_expr(end)?;
+                if let Some(step) = step {
+                    self.expand_expr(step)?;
+                }
+                self.expand_expr(body)?;
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                for (_, init) in vars {
+                    if let Some(init) = init {
+                        self.expand_expr(init)?;
+                    }
+                }
+                self.expand_expr(body)?;
+            }
+            ExpressionKind::GradExpr(name) => {
+                self.span = expr.span;
+                let gradient = self.gradient(&name.clone())?;
+                expr.kind = ExpressionKind::VariableExpr(gradient.name);
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, name: &str, reason: impl Into<String>) -> Box<TypeError> {
+        Box::new(TypeError::NotDifferentiable {
+            name: name.into(),
+            reason: reason.into(),
+            span: self.span,
+        })
+    }
+
+    /// Generate the gradient of a function if it is not yet, returning its prototype.
+    /// The gradient of a function of one arg returns its derivative,
+    /// the one of a function of several args the array of its partial derivatives.
+    fn gradient(&mut self, name: &str) -> Result<Prototype, Box<TypeError>> {
+        let proto = self.derivative(name)?;
+        let n = proto.args.len();
+        let gradient = Prototype {
+            name: format!("{}.grad", name),
+            ret_type: Some(if n == 1 {
+                Type::Double
+            } else {
+                Type::array(Type::Double)
+            }),
+            ..proto
+        };
+        if self.generated.contains(&gradient.name) {
+            return Ok(gradient);
+        }
+
+        let span = gradient.span;
+        // the derivative along the i-th axis
+        let partial = |i: usize| {
+            let args = gradient.args.iter().map(|arg| variable(arg, span));
+            let axis = (0..n).map(|j| number(if i == j { 1.0 } else { 0.0 }, span));
+            call(&derivative_name(name), args.chain(axis).collect(), span)
+        };
+        let body = if n == 1 {
+            partial(0)
+        } else {
+            let partials = (0..n).map(partial).collect();
+            Expression::new(ExpressionKind::ArrayExpr(partials), span)
+        };
+
+        self.generated.insert(gradient.name.clone());
+        self.derivatives.push(Function {
+            prototype: gradient.clone(),
+            body,
+            span,
+        });
+        Ok(gradient)
+    }
+
+    /// Generate the derivative of a function if it is not yet, returning the prototype of the function
+    fn derivative(&mut self, name: &str) -> Result<Prototype, Box<TypeError>> {
+        let func = match (self.functions.get(name), self.prototypes.get(name)) {
+            (Some(func), _) => func.clone(),
+            // an intrinsic is differentiated as a function calling it
+            (None, Some(proto)) if INTRINSICS.contains(&name) && proto.args.len() == 1 => {
+                let arg = variable(&proto.args[0], proto.span);
+                Function {
+                    prototype: proto.clone(),
+                    body: call(name, vec![arg], proto.span),
+                    span: proto.span,
+                }
+            }
+            (None, _) => return Err(self.error(name, "it is not defined")),
+        };
+
+        let proto = func.prototype.annotated();
+        let n = proto.args.len();
+        if (0..n).any(|i| proto.arg_type(i) != Type::Double) || proto.return_type() != Type::Double
+        {
+            return Err(self.error(name, "it takes or returns values other than doubles"));
+        }
+        if n == 0 {
+            return Err(self.error(name, "it takes no args"));
+        }
+
+        let derivative = derivative_name(name);
+        if self.generated.contains(&derivative) {
+            return Ok(proto);
+        }
+
+        // generated before its body, which may call it
+        self.generated.insert(derivative.clone());
+        let mut scope = proto.args.clone();
+        let body = match self.tangent(&func.body, &mut scope, name) {
+            Ok(body) => body,
+            Err(err) => {
+                self.generated.remove(&derivative);
+                return Err(err);
+            }
+        };
+
+        let args: Vec<String> = proto
+            .args
+            .iter()
+            .cloned()
+            .chain(proto.args.iter().map(|arg| tangent_name(arg)))
+            .collect();
+        self.derivatives.push(Function {
+            prototype: Prototype {
+                name: derivative,
+                arg_types: vec![Some(Type::Double); args.len()],
+                args,
+                ..proto.clone()
+            },
+            body,
+            span: func.span,
+        });
+        Ok(proto)
+    }
+
+    /// The derivative of an expression of `func` along the derivatives of the variables in `scope`,
+    /// named after them with `.tangent`
+    fn tangent(
+        &mut self,
+        expr: &Expression,
+        scope: &mut Vec<String>,
+        func: &str,
+    ) -> Result<Expression, Box<TypeError>> {
+        let span = expr.span;
+        let kind = match &expr.kind {
+            ExpressionKind::NumberExpr(_) => return Ok(number(0.0, span)),
+            ExpressionKind::VariableExpr(var) if scope.contains(var) => {
+                return Ok(variable(&tangent_name(var), span))
+            }
+            ExpressionKind::BinaryExpr(op @ '+', left, right)
+            | ExpressionKind::BinaryExpr(op @ '-', left, right) => ExpressionKind::BinaryExpr(
+                *op,
+                Box::new(self.tangent(left, scope, func)?),
+                Box::new(self.tangent(right, scope, func)?),
+            ),
+            ExpressionKind::BinaryExpr('*', left, right) => {
+                let (left_tangent, right_tangent) = (
+                    self.tangent(left, scope, func)?,
+                    self.tangent(right, scope, func)?,
+                );
+                // (a * b)' = a' * b + a * b'
+                ExpressionKind::BinaryExpr(
+                    '+',
+                    Box::new(binary('*', left_tangent, right.as_ref().clone(), span)),
+                    Box::new(binary('*', left.as_ref().clone(), right_tangent, span)),
+                )
+            }
+            ExpressionKind::BinaryExpr('/', left, right) => {
+                let (left_tangent, right_tangent) = (
+                    self.tangent(left, scope, func)?,
+                    self.tangent(right, scope, func)?,
+                );
+                // (a / b)' = (a' * b - a * b') / (b * b)
+                let numerator = binary(
+                    '-',
+                    binary('*', left_tangent, right.as_ref().clone(), span),
+                    binary('*', left.as_ref().clone(), right_tangent, span),
+                    span,
+                );
+                let square = binary('*', right.as_ref().clone(), right.as_ref().clone(), span);
+                ExpressionKind::BinaryExpr('/', Box::new(numerator), Box::new(square))
+            }
+            ExpressionKind::BinaryExpr('<', ..) | ExpressionKind::BinaryExpr('>', ..) => {
+                return Err(self.error(func, "comparisons are not differentiable"))
+            }
+            ExpressionKind::BinaryExpr('=', ..) => {
+                return Err(self.error(func, "assignments are not supported"))
+            }
+            ExpressionKind::BinaryExpr(op, left, right) => {
+                let args = [left.as_ref().clone(), right.as_ref().clone()];
+                return self.call_tangent(&format!("binary{}", op), &args, span, scope, func);
+            }
+            ExpressionKind::UnaryExpr(op, operand) => {
+                let args = [operand.as_ref().clone()];
+                return self.call_tangent(&format!("unary{}", op), &args, span, scope, func);
+            }
+            ExpressionKind::CallExpr(name, args) => {
+                return self.call_tangent(name, args, span, scope, func)
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => ExpressionKind::IfExpr(
+                cond.clone(),
+                Box::new(self.tangent(then_expr, scope, func)?),
+                Box::new(self.tangent(else_expr, scope, func)?),
+            ),
+            ExpressionKind::VarExpr { vars, body } => {
+                let depth = scope.len();
+                let mut tangent_vars = Vec::with_capacity(vars.len() * 2);
+                for (var, init) in vars {
+                    // the derivative first, as the value may shadow a variable it uses
+                    let init_tangent = match init {
+                        Some(init) => Some(self.tangent(init, scope, func)?),
+                        None => None,
+                    };
+                    tangent_vars.push((tangent_name(var), init_tangent));
+                    tangent_vars.push((var.clone(), init.clone()));
+                    scope.push(var.clone());
+                }
+                let body = self.tangent(body, scope, func);
+                scope.truncate(depth);
+                ExpressionKind::VarExpr {
+                    vars: tangent_vars,
+                    body: Box::new(body?),
+                }
+            }
+            // a double converted from an int or a bool is piecewise constant
+            ExpressionKind::CastExpr(Type::Double, operand) => {
+                return match self.known_type(operand, scope) {
+                    Some(Type::Double) | None => self.tangent(operand, scope, func),
+                    Some(_) => Ok(number(0.0, span)),
+                };
+            }
+            ExpressionKind::CastExpr(ty, _) => {
+                return Err(self.error(func, format!("casts to {} are not differentiable", ty)))
+            }
+            ExpressionKind::IntExpr(_) => {
+                return Err(self.error(func, "ints are not differentiable"))
+            }
+            ExpressionKind::BoolExpr(_) => {
+                return Err(self.error(func, "bools are not differentiable"))
+            }
+            ExpressionKind::StringExpr(_) => {
+                return Err(self.error(func, "strings are not supported"))
+            }
+            ExpressionKind::ArrayExpr(_) | ExpressionKind::IndexExpr(..) => {
+                return Err(self.error(func, "arrays are not supported"))
+            }
+            ExpressionKind::StructExpr(..) | ExpressionKind::FieldExpr { .. } => {
+                return Err(self.error(func, "structs are not supported"))
+            }
+            ExpressionKind::VariantExpr(..) | ExpressionKind::MatchExpr { .. } => {
+                return Err(self.error(func, "unions are not supported"))
+            }
+            ExpressionKind::ForExpr { .. } => {
+                return Err(self.error(func, "for loops are not supported"))
+            }
+            ExpressionKind::VariableExpr(_)
+            | ExpressionKind::LambdaExpr { .. }
+            | ExpressionKind::GradExpr(_)
+            | ExpressionKind::ClosureExpr { .. } => {
+                return Err(self.error(func, "functions used as values are not supported"))
+            }
+        };
+        Ok(Expression::new(kind, span))
+    }
+
+    /// The type of an expression of a function of doubles, if it is known without type checking it again.
+    /// The variables in `scope` are doubles.
+    fn known_type(&self, expr: &Expression, scope: &[String]) -> Option<Type> {
+        match &expr.kind {
+            ExpressionKind::NumberExpr(_) => Some(Type::Double),
+            ExpressionKind::IntExpr(_) => Some(Type::Int),
+            ExpressionKind::BoolExpr(_)
+            | ExpressionKind::BinaryExpr('<', ..)
+            | ExpressionKind::BinaryExpr('>', ..) => Some(Type::Bool),
+            ExpressionKind::VariableExpr(var) if scope.contains(var) => Some(Type::Double),
+            ExpressionKind::BinaryExpr('+', left, _)
+            | ExpressionKind::BinaryExpr('-', left, _)
+            | ExpressionKind::BinaryExpr('*', left, _)
+            | ExpressionKind::BinaryExpr('/', left, _) => self.known_type(left, scope),
+            ExpressionKind::BinaryExpr(op, ..) => self.return_type(&format!("binary{}", op)),
+            ExpressionKind::UnaryExpr(op, _) => self.return_type(&format!("unary{}", op)),
+            ExpressionKind::CallExpr(name, _) => self.return_type(name),
+            ExpressionKind::CastExpr(ty, _) => Some(ty.clone()),
+            ExpressionKind::IfExpr(_, then_expr, _) => self.known_type(then_expr, scope),
+            _ => None,
+        }
+    }
+
+    fn return_type(&self, name: &str) -> Option<Type> {
+        self.prototypes
+            .get(name)
+            .or_else(|| self.functions.get(name).map(|func| &func.prototype))
+            .map(|proto| proto.return_type())
+    }
+
+    /// The derivative of a call to a function or an operator by the chain rule,
+    /// the derivative of a function defined by the user being generated
+    fn call_tangent(
+        &mut self,
+        name: &str,
+        args: &[Expression],
+        span: Span,
+        scope: &mut Vec<String>,
+        func: &str,
+    ) -> Result<Expression, Box<TypeError>> {
+        let mut tangents = Vec::with_capacity(args.len());
+        for arg in args {
+            tangents.push(self.tangent(arg, scope, func)?);
+        }
+
+        if self.functions.contains_key(name) {
+            self.derivative(name)?;
+            let args = args.iter().cloned().chain(tangents).collect();
+            return Ok(call(&derivative_name(name), args, span));
+        }
+
+        if !INTRINSICS.contains(&name) || args.len() != 1 {
+            return Err(self.error(func, format!("{} is not differentiable", name)));
+        }
+        // the derivatives of sin and cos call each other
+        let other = match name {
+            "sin" => Some("cos"),
+            "cos" => Some("sin"),
+            _ => None,
+        };
+        if let Some(other) = other.filter(|other| !self.prototypes.contains_key(*other)) {
+            let reason = format!("{} must be declared to differentiate {}", other, name);
+            return Err(self.error(func, reason));
+        }
+
+        let arg = args[0].clone();
+        let derivative = match name {
+            "sin" => call("cos", vec![arg], span),
+            "cos" => binary('-', number(0.0, span), call("sin", vec![arg], span), span),
+            "exp" => call("exp", vec![arg], span),
+            "log" => binary('/', number(1.0, span), arg, span),
+            _ => binary('/', number(0.5, span), call("sqrt", vec![arg], span), span),
+        };
+        let tangent = tangents.pop().expect("intrinsics take one arg");
+        Ok(binary('*', derivative, tangent, span))
+    }
+}
+
+/// The name of the derivative of a function
+fn derivative_name(func: &str) -> String {
+    format!("{}.tangent", func)
+}
+
+/// The name of the derivative of a variable
+fn tangent_name(var: &str) -> String {
+    format!("{}.tangent", var)
+}
+
+fn number(value: f64, span: Span) -> Expression {
+    Expression::new(ExpressionKind::NumberExpr(value), span)
+}
+
+fn variable(name: &str, span: Span) -> Expression {
+    Expression::new(ExpressionKind::VariableExpr(name.into()), span)
+}
+
+fn binary(op: char, left: Expression, right: Expression, span: Span) -> Expression {
+    Expression::new(
+        ExpressionKind::BinaryExpr(op, Box::new(left), Box::new(right)),
+        span,
+    )
+}
+
+fn call(name: &str, args: Vec<Expression>, span: Span) -> Expression {
+    Expression::new(ExpressionKind::CallExpr(name.into(), args), span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+    use crate::types::{check_function, TypeDefs};
+
+    /// Check and define the externs and functions of a program but the last function,
+    /// returning it unchecked
+    fn define_all(
+        program: &str,
+        differentiator: &mut Differentiator,
+        prototypes: &mut HashMap<String, Prototype>,
+    ) -> Function {
+        let mut nodes = Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes;
+        let last = nodes.pop();
+        for node in nodes {
+            match node {
+                ASTNode::ExternNode(proto) => {
+                    prototypes.insert(proto.name.clone(), proto.annotated());
+                }
+                ASTNode::FunctionNode(func) => {
+                    let func = check_function(&func, prototypes, &TypeDefs::new()).unwrap();
+                    prototypes.insert(func.prototype.name.clone(), func.prototype.clone());
+                    differentiator.define(&func);
+                }
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+        match last {
+            Some(ASTNode::FunctionNode(func)) => func,
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    /// Expand the gradients of the last function of a program,
+    /// returning the signatures of the functions generated
+    fn expand(program: &str) -> Result<Vec<String>, String> {
+        let mut differentiator = Differentiator::new();
+        let mut prototypes = HashMap::new();
+        let func = define_all(program, &mut differentiator, &mut prototypes);
+        let (_, derivatives) = differentiator
+            .expand(&func, &prototypes)
+            .map_err(|err| err.to_string())?;
+        Ok(signatures(&derivatives))
+    }
+
+    fn signatures(funcs: &[Function]) -> Vec<String> {
+        funcs
+            .iter()
+            .map(|func| func.prototype.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn generate_derivatives() {
+        assert_eq!(
+            expand("def f(x y) x * y; grad(f)"),
+            Ok(vec![
+                "f.tangent(x: double y: double x.tangent: double y.tangent: double): double".into(),
+                "f.grad(x: double y: double): [double]".into(),
+            ])
+        );
+
+        // the callees first, a recursive function once, and intrinsics inline
+        assert_eq!(
+            expand(
+                "extern sin(x); extern cos(x); def g(x) sin(x); \
+                 def f(x n) if n < 1 then g(x) else x * f(x, n - 1); grad(f)"
+            ),
+            Ok(vec![
+                "g.tangent(x: double x.tangent: double): double".into(),
+                "f.tangent(x: double n: double x.tangent: double n.tangent: double): double".into(),
+                "f.grad(x: double n: double): [double]".into(),
+            ])
+        );
+        assert_eq!(
+            expand("extern exp(x); grad(exp)"),
+            Ok(vec![
+                "exp.tangent(x: double x.tangent: double): double".into(),
+                "exp.grad(x: double): double".into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn replace_gradients() {
+        let mut differentiator = Differentiator::new();
+        let mut prototypes = HashMap::new();
+        let func = define_all(
+            "def f(x) x * x; apply(grad(f))",
+            &mut differentiator,
+            &mut prototypes,
+        );
+
+        let (expanded, derivatives) = differentiator.expand(&func, &prototypes).unwrap();
+        assert_eq!(derivatives.len(), 2);
+        match &expanded.body.kind {
+            ExpressionKind::CallExpr(_, args) => {
+                assert_eq!(args[0].kind, ExpressionKind::VariableExpr("f.grad".into()))
+            }
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+
+        // generated once, until the function is redefined
+        let (_, derivatives) = differentiator.expand(&func, &prototypes).unwrap();
+        assert!(derivatives.is_empty());
+        let func = define_all(
+            "def f(x) x; apply(grad(f))",
+            &mut differentiator,
+            &mut prototypes,
+        );
+        let (_, derivatives) = differentiator.expand(&func, &prototypes).unwrap();
+        assert_eq!(derivatives.len(), 2);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |program: &str| expand(program).unwrap_err();
+
+        assert_eq!(
+            error("grad(g)"),
+            "Cannot differentiate g: it is not defined."
+        );
+        assert_eq!(
+            error("def f(n: int) n; grad(f)"),
+            "Cannot differentiate f: it takes or returns values other than doubles."
+        );
+        assert_eq!(
+            error("def f() 1.5; grad(f)"),
+            "Cannot differentiate f: it takes no args."
+        );
+        assert_eq!(
+            error("def f(x) for i = 0, i < x in x; grad(f)"),
+            "Cannot differentiate f: for loops are not supported."
+        );
+        // a double converted from a bool is constant, unlike a bool in a variable
+        assert_eq!(
+            expand("def f(x) double(x < 1); grad(f)"),
+            Ok(vec![
+                "f.tangent(x: double x.tangent: double): double".into(),
+                "f.grad(x: double): double".into(),
+            ])
+        );
+        assert_eq!(
+            error("def f(x) var b = x < 1 in x; grad(f)"),
+            "Cannot differentiate f: comparisons are not differentiable."
+        );
+        assert_eq!(
+            error("extern sin(x); def f(x) sin(x); grad(f)"),
+            "Cannot differentiate f: cos must be declared to differentiate sin."
+        );
+        assert_eq!(
+            error("extern tan(x); def f(x) tan(x); grad(f)"),
+            "Cannot differentiate f: tan is not differentiable."
+        );
+        // the callee is reported
+        assert_eq!(
+            error("def g(x) x = 1; def f(x) g(x); grad(f)"),
+            "Cannot differentiate g: assignments are not supported."
+        );
+    }
+
+    #[test]
+    fn failed_expansions_are_forgotten() {
+        let mut differentiator = Differentiator::new();
+        let mut prototypes = HashMap::new();
+        let func = define_all(
+            "extern tan(x); def g(x) x * x; def f(x) g(x) + tan(x); grad(f)",
+            &mut differentiator,
+            &mut prototypes,
+        );
+        assert!(differentiator.expand(&func, &prototypes).is_err());
+
+        // the derivative of g was generated before the error, but never defined
+        let func = Function {
+            body: ExpressionKind::GradExpr("g".into()).into(),
+            ..func
+        };
+        let (_, derivatives) = differentiator.expand(&func, &prototypes).unwrap();
+        assert_eq!(
+            signatures(&derivatives),
+            vec![
+                "g.tangent(x: double x.tangent: double): double",
+                "g.grad(x: double): double"
+            ]
+        );
+    }
+
+    #[test]
+    fn derive_functions() {
+        let mut differentiator = Differentiator::new();
+        let mut prototypes = HashMap::new();
+        define_all(
+            "def f(x y) x * y; def g(n: int) n; 0",
+            &mut differentiator,
+            &mut prototypes,
+        );
+        let def = |name: &str| DerivativeDef {
+            name: name.into(),
+            span: Span::default(),
+        };
+
+        let (func, derivatives) = differentiator.derive(&def("f"), &prototypes).unwrap();
+        assert_eq!(
+            func.prototype.to_string(),
+            "f'(x: double y: double): [double]"
+        );
+        assert_eq!(
+            func.body,
+            call(
+                "f.grad",
+                vec![variable("x", func.span), variable("y", func.span)],
+                func.span
+            )
+        );
+        assert_eq!(
+            signatures(&derivatives),
+            vec![
+                "f.tangent(x: double y: double x.tangent: double y.tangent: double): double",
+                "f.grad(x: double y: double): [double]"
+            ]
+        );
+
+        // the gradient is generated once, unlike f' which is defined again
+        let (_, derivatives) = differentiator.derive(&def("f"), &prototypes).unwrap();
+        assert_eq!(derivatives, vec![]);
+
+        assert_eq!(
+            differentiator
+                .derive(&def("g"), &prototypes)
+                .unwrap_err()
+                .to_string(),
+            "Cannot differentiate g: it takes or returns values other than doubles."
+        );
+    }
+}
@@ -0,0 +1,3 @@
+mod forward;
+
+pub use forward::*;
@@ -0,0 +1,168 @@
+use crate::autodiff::Differentiator;
+use crate::closure::ClosureConverter;
+use crate::parser::nodes::{DerivativeDef, Function, Prototype};
+use crate::types::{check_function, TypeDefs, TypeError};
+use std::collections::HashMap;
+
+/// The front end shared by the backends, which lowers a function into type checked functions
+/// without gradients or lambdas: the gradients the function takes are expanded into the functions
+/// computing them, and its lambdas are lifted into functions of their own, all defined before it.
+#[derive(Default)]
+pub struct Lowering {
+    /// Lifts the lambdas of every function into functions of their own
+    closures: ClosureConverter,
+    /// Generates the functions computing the gradients taken by every function
+    derivatives: Differentiator,
+}
+
+impl Lowering {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// A backend defining the functions lowered by the front end
+pub trait Lower {
+    /// What a function is defined as, e.g. its bytecode
+    type Output;
+    /// The errors of the backend, which type errors are reported as
+    type Error: From<Box<TypeError>>;
+
+    /// The front end, along with the typed prototypes of the functions and externs declared so far
+    /// and the structs and unions defined so far, which functions are type checked against
+    fn front_end(&mut self) -> (&mut Lowering, &HashMap<String, Prototype>, &TypeDefs);
+
+    /// Declare a new function before the functions its lambdas are lifted into, which may call it
+    fn declare_lowered(&mut self, proto: &Prototype) -> Result<(), Self::Error>;
+
+    /// Define a type checked function without gradients or lambdas,
+    /// replacing the previous definition if any, which is kept if the function cannot be defined
+    fn define_lowered(&mut self, func: &Function) -> Result<Self::Output, Self::Error>;
+
+    /// Forget a function that was new when it was defined,
+    /// e.g. a lambda of a function that could not be defined or of an evaluated anonymous function
+    fn forget_lowered(&mut self, name: &str);
+
+    /// Called once the functions computing the gradients taken by a function are defined,
+    /// before the function itself
+    fn derivatives_defined(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Lower a function and define it after the functions it is lowered into,
+    /// so that the gradients of the function can be taken by later functions
+    fn lower(&mut self, func: &Function) -> Result<Self::Output, Self::Error> {
+        let func = expand(self, func)?;
+        let checked = check(self, &func)?;
+        let output = define_checked(self, &checked)?;
+        self.front_end().0.derivatives.define(&checked);
+        Ok(output)
+    }
+
+    /// Generate `f'` for `def' f`, calling the gradient of `f`,
+    /// and define it after the functions computing the gradient
+    fn lower_derivative(&mut self, def: &DerivativeDef) -> Result<Self::Output, Self::Error> {
+        let (lowering, prototypes, _) = self.front_end();
+        let (func, derivatives) = lowering.derivatives.derive(def, prototypes)?;
+        define_derivatives(self, &derivatives)?;
+        self.lower(&func)
+    }
+
+    /// Lower an anonymous function wrapping a top level expression and run it,
+    /// forgetting the function and its lambdas afterwards, as they cannot be called by later code
+    fn lower_and_run<T>(
+        &mut self,
+        func: &Function,
+        run: impl FnOnce(&mut Self, &Function) -> Result<T, Self::Error>,
+    ) -> Result<T, Self::Error> {
+        let func = expand(self, func)?;
+        let checked = check(self, &func)?;
+        let (func, lambdas) = self.front_end().0.closures.convert(&checked);
+        define_lambdas(self, &lambdas)?;
+
+        let result = run(self, &func);
+        self.forget_lowered(&func.prototype.name);
+        forget_lambdas(self, &lambdas);
+        result
+    }
+}
+
+/// Expand the gradients taken by a function before type checking,
+/// defining the functions computing them
+fn expand<B: Lower + ?Sized>(backend: &mut B, func: &Function) -> Result<Function, B::Error> {
+    let (lowering, prototypes, _) = backend.front_end();
+    let (func, derivatives) = lowering.derivatives.expand(func, prototypes)?;
+    define_derivatives(backend, &derivatives)?;
+    Ok(func)
+}
+
+/// Type check and define the derivatives and gradients generated by the differentiator
+fn define_derivatives<B: Lower + ?Sized>(
+    backend: &mut B,
+    derivatives: &[Function],
+) -> Result<(), B::Error> {
+    if derivatives.is_empty() {
+        return Ok(());
+    }
+    for derivative in derivatives {
+        let derivative = check(backend, derivative)?;
+        define_checked(backend, &derivative)?;
+    }
+    backend.derivatives_defined()
+}
+
+/// Type check a function against the functions and types the backend knows so far
+fn check<B: Lower + ?Sized>(backend: &mut B, func: &Function) -> Result<Function, B::Error> {
+    let (_, prototypes, type_defs) = backend.front_end();
+    Ok(check_function(func, prototypes, type_defs)?)
+}
+
+/// Convert the lambdas of a type checked function into closures,
+/// and define the function after the functions they are lifted into.
+/// A new function is declared before them, and forgotten if it cannot be defined.
+fn define_checked<B: Lower + ?Sized>(
+    backend: &mut B,
+    func: &Function,
+) -> Result<B::Output, B::Error> {
+    let name = &func.prototype.name;
+    let is_new = !backend.front_end().1.contains_key(name);
+    if is_new {
+        backend.declare_lowered(&func.prototype)?;
+    }
+
+    let (func, lambdas) = backend.front_end().0.closures.convert(func);
+    let result =
+        define_lambdas(backend, &lambdas).and_then(|_| match backend.define_lowered(&func) {
+            Ok(output) => Ok(output),
+            Err(err) => {
+                forget_lambdas(backend, &lambdas);
+                Err(err)
+            }
+        });
+    if result.is_err() && is_new {
+        backend.forget_lowered(name);
+    }
+    result
+}
+
+/// Define the functions lambdas are lifted into, the inner lambdas first,
+/// forgetting them all if one cannot be defined
+fn define_lambdas<B: Lower + ?Sized>(
+    backend: &mut B,
+    lambdas: &[Function],
+) -> Result<(), B::Error> {
+    for (i, lambda) in lambdas.iter().enumerate() {
+        if let Err(err) = backend.define_lowered(lambda) {
+            forget_lambdas(backend, &lambdas[..i]);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Forget the functions lambdas are lifted into, the outer lambdas first as they use the inner ones
+fn forget_lambdas<B: Lower + ?Sized>(backend: &mut B, lambdas: &[Function]) {
+    for lambda in lambdas.iter().rev() {
+        backend.forget_lowered(&lambda.prototype.name);
+    }
+}
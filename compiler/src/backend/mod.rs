@@ -0,0 +1,143 @@
+mod lowering;
+
+pub use lowering::*;
+
+use crate::codegen::codegen_context::CodegenContext;
+use crate::codegen::passes::OptimizationLevel;
+use crate::diagnostics::Diagnostic;
+use crate::interp::Interpreter;
+use crate::parser::nodes::{ASTNode, DerivativeDef, Function, Prototype, StructDef, UnionDef};
+use crate::vm::VirtualMachine;
+use inkwell::context::Context;
+use std::str::FromStr;
+
+/// Runs top level items one by one, e.g. by JIT compiling or interpreting them.
+/// Errors are reported as diagnostics, at the item if there is no better place.
+pub trait Backend {
+    /// Declare an extern, so that it can be called from later items
+    fn declare_extern(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>>;
+
+    /// Declare a function defined by a later item, so that the items before its definition can call it,
+    /// e.g. for the functions of a file to call each other
+    fn declare_function(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>>;
+
+    /// Define a function, so that it can be called from later items
+    fn define_function(&mut self, func: &Function) -> Result<(), Box<Diagnostic>>;
+
+    /// Define `f'` for `def' f`, so that the derivative of `f` can be called from later items
+    fn define_derivative(&mut self, def: &DerivativeDef) -> Result<(), Box<Diagnostic>>;
+
+    /// Define a struct, so that it can be used by later items
+    fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<Diagnostic>>;
+
+    /// Define a tagged union, so that its variants can be used by later items
+    fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<Diagnostic>>;
+
+    /// Evaluate an anonymous function wrapping a top level expression
+    fn evaluate(&mut self, func: &Function) -> Result<f64, Box<Diagnostic>>;
+
+    /// What a declared or defined function was compiled to, e.g. LLVM IR or bytecode
+    fn dump(&self, name: &str) -> Option<String>;
+
+    /// Run a top level item, returning the value of an expression
+    fn run_item(&mut self, node: &ASTNode) -> Result<Option<f64>, Box<Diagnostic>> {
+        match node {
+            ASTNode::ExternNode(proto) => self.declare_extern(proto).map(|_| None),
+            ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                self.evaluate(func).map(Some)
+            }
+            ASTNode::FunctionNode(func) => self.define_function(func).map(|_| None),
+            ASTNode::DerivativeNode(def) => self.define_derivative(def).map(|_| None),
+            ASTNode::StructNode(def) => self.define_struct(def).map(|_| None),
+            ASTNode::UnionNode(def) => self.define_union(def).map(|_| None),
+            ASTNode::EOF | ASTNode::Delimiter => Ok(None),
+        }
+    }
+}
+
+/// The backends to choose from
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum BackendKind {
+    Jit,
+    Vm,
+    Interp,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jit" => Ok(BackendKind::Jit),
+            "vm" => Ok(BackendKind::Vm),
+            "interp" => Ok(BackendKind::Interp),
+            _ => Err(format!(
+                "Unknown backend {}, expect one of jit, vm or interp.",
+                s
+            )),
+        }
+    }
+}
+
+/// Create a backend of `kind`. The optimization level only applies to the JIT.
+pub fn create_backend<'ctx>(
+    kind: BackendKind,
+    context: &'ctx Context,
+    opt_level: OptimizationLevel,
+) -> Result<Box<dyn Backend + 'ctx>, String> {
+    Ok(match kind {
+        BackendKind::Jit => {
+            let mut cc = CodegenContext::new(context, "main").map_err(|err| err.to_string())?;
+            cc.set_opt_level(opt_level);
+            Box::new(cc)
+        }
+        BackendKind::Vm => Box::new(VirtualMachine::new()),
+        BackendKind::Interp => Box::new(Interpreter::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::parser::Parser;
+
+    const PROGRAM: &str = "
+        extern sqrt(x);
+        def norm(x y) sqrt(x * x + y * y);
+        norm(3, 4);
+        def f(x) y;
+        ";
+
+    #[test]
+    fn parse_backend_kind() {
+        assert_eq!("jit".parse(), Ok(BackendKind::Jit));
+        assert_eq!("vm".parse(), Ok(BackendKind::Vm));
+        assert_eq!("interp".parse(), Ok(BackendKind::Interp));
+        assert!("llvm".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn run_items_on_every_backend() {
+        let context = Context::create();
+        let nodes = Parser::new(Lexer::new(PROGRAM.chars()))
+            .parse_program()
+            .nodes;
+
+        for kind in &[BackendKind::Jit, BackendKind::Vm, BackendKind::Interp] {
+            let mut backend = create_backend(*kind, &context, OptimizationLevel::None).unwrap();
+            let results: Vec<_> = nodes
+                .iter()
+                .map(|node| backend.run_item(node).is_ok())
+                .collect();
+
+            // the interpreter only finds unknown variables when evaluating
+            let defined = *kind == BackendKind::Interp;
+            assert_eq!(results, vec![true, true, true, defined], "{:?}", kind);
+            assert_eq!(backend.run_item(&nodes[2]), Ok(Some(5.0)), "{:?}", kind);
+
+            assert!(backend.dump("norm").is_some(), "{:?}", kind);
+            assert!(backend.dump("g").is_none(), "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::parser::nodes::{Expression, ExpressionKind, Function, Prototype};
+use crate::types::Type;
+
+/// Lifts lambdas into functions named `lambda.0`, `lambda.1`... in the order they are converted,
+/// so that the lambdas converted by the same converter never share a name
+#[derive(Default)]
+pub struct ClosureConverter {
+    /// How many lambdas have been converted so far
+    count: usize,
+}
+
+impl ClosureConverter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Convert the lambdas of a type checked function into closures,
+    /// returning the function along with the function of every lambda, the inner lambdas first.
+    ///
+    /// The function of a lambda takes the variables it captures before its params,
+    /// and a closure is the function along with the values of the variables.
+    pub fn convert(&mut self, func: &Function) -> (Function, Vec<Function>) {
+        let mut lambdas = vec![];
+        let mut body = func.body.clone();
+        self.convert_expr(&mut body, &mut lambdas);
+        (
+            Function {
+                body,
+                ..func.clone()
+            },
+            lambdas,
+        )
+    }
+
+    fn convert_expr(&mut self, expr: &mut Expression, lambdas: &mut Vec<Function>) {
+        match &mut expr.kind {
+            ExpressionKind::NumberExpr(_)
+            | ExpressionKind::IntExpr(_)
+            | ExpressionKind::BoolExpr(_)
+            | ExpressionKind::StringExpr(_)
+            | ExpressionKind::VariableExpr(_)
+            | ExpressionKind::GradExpr(_)
+            | ExpressionKind::ClosureExpr { .. } => {}
+            ExpressionKind::UnaryExpr(_, operand) | ExpressionKind::CastExpr(_, operand) => {
+                self.convert_expr(operand, lambdas)
+            }
+            ExpressionKind::BinaryExpr(_, left, right) | ExpressionKind::IndexExpr(left, right) => {
+                self.convert_expr(left, lambdas);
+                self.convert_expr(right, lambdas);
+            }
+            ExpressionKind::CallExpr(_, args)
+            | ExpressionKind::ArrayExpr(args)
+            | ExpressionKind::VariantExpr(_, args) => {
+                for arg in args {
+                    self.convert_expr(arg, lambdas);
+                }
+            }
+            ExpressionKind::StructExpr(_, fields) => {
+                for (_, value) in fields {
+                    self.convert_expr(value, lambdas);
+                }
+            }
+            ExpressionKind::FieldExpr { object, .. } => self.convert_expr(object, lambdas),
+            ExpressionKind::MatchExpr { value, arms, .. } => {
+                self.convert_expr(value, lambdas);
+                for (_, body) in arms {
+                    self.convert_expr(body, lambdas);
+                }
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                self.convert_expr(cond, lambdas);
+                self.convert_expr(then_expr, lambdas);
+                self.convert_expr(else_expr, lambdas);
+            }
+            ExpressionKind::ForExpr {
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.convert_expr(start, lambdas);
+                self.convert_expr(end, lambdas);
+                if let Some(step) = step {
+                    self.convert_expr(step, lambdas);
+                }
+                self.convert_expr(body, lambdas);
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                for (_, init) in vars {
+                    if let Some(init) = init {
+                        self.convert_expr(init, lambdas);
+                    }
+                }
+                self.convert_expr(body, lambdas);
+            }
+            ExpressionKind::LambdaExpr {
+                params,
+                param_types,
+                ret_type,
+                captures,
+                body,
+            } => {
+                self.convert_expr(body, lambdas);
+
+                let name = format!("lambda.{}", self.count);
+                self.count += 1;
+
+                let param_types: Vec<Type> = param_types
+                    .iter()
+                    .map(|ty| ty.clone().expect("type checked"))
+                    .collect();
+                let ret_type = ret_type.clone().expect("type checked");
+                lambdas.push(Function {
+                    prototype: Prototype {
+                        name: name.clone(),
+                        args: captures
+                            .iter()
+                            .map(|(var, _)| var.clone())
+                            .chain(params.iter().cloned())
+                            .collect(),
+                        arg_types: captures
+                            .iter()
+                            .map(|(_, ty)| ty.clone())
+                            .chain(param_types.iter().cloned())
+                            .map(Some)
+                            .collect(),
+                        ret_type: Some(ret_type.clone()),
+                        span: expr.span,
+                    },
+                    body: body.as_ref().clone(),
+                    span: expr.span,
+                });
+
+                expr.kind = ExpressionKind::ClosureExpr {
+                    function: name,
+                    captures: captures.iter().map(|(var, _)| var.clone()).collect(),
+                    ty: Type::function(param_types, ret_type),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+    use crate::types::{check_function, TypeDefs};
+    use std::collections::HashMap;
+
+    /// Check and convert the function of a program
+    fn convert(program: &str) -> (Function, Vec<Function>) {
+        match Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+            .remove(0)
+        {
+            ASTNode::FunctionNode(func) => {
+                let func = check_function(&func, &HashMap::new(), &TypeDefs::new()).unwrap();
+                ClosureConverter::new().convert(&func)
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    fn signatures(lambdas: &[Function]) -> Vec<String> {
+        lambdas
+            .iter()
+            .map(|lambda| lambda.prototype.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn lift_lambdas() {
+        let unary = Type::function(vec![Type::Double], Type::Double);
+
+        let (func, lambdas) = convert("def adder(k) \\x -> x + k");
+        assert_eq!(
+            signatures(&lambdas),
+            vec!["lambda.0(k: double x: double): double"]
+        );
+        assert_eq!(
+            func.body.kind,
+            ExpressionKind::ClosureExpr {
+                function: "lambda.0".into(),
+                captures: vec!["k".into()],
+                ty: unary.clone(),
+            }
+        );
+
+        // an inner lambda captures the params of the outer one, which captures what it needs
+        let (func, lambdas) = convert("def f(a) \\x -> \\y -> x + y + a");
+        assert_eq!(
+            signatures(&lambdas),
+            vec![
+                "lambda.0(x: double a: double y: double): double",
+                "lambda.1(a: double x: double): (double) -> double"
+            ]
+        );
+        assert_eq!(
+            lambdas[1].body.kind,
+            ExpressionKind::ClosureExpr {
+                function: "lambda.0".into(),
+                captures: vec!["x".into(), "a".into()],
+                ty: unary,
+            }
+        );
+        assert!(matches!(func.body.kind, ExpressionKind::ClosureExpr { .. }));
+    }
+}
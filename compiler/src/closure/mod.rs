@@ -0,0 +1,3 @@
+mod conversion;
+
+pub use conversion::*;
@@ -1,216 +1,3137 @@
-use crate::parser::nodes::Expression;
-use crate::parser::nodes::{Function, Prototype};
+use super::passes;
+use super::runtime::{catch_traps, runtime_function, OUT_OF_BOUNDS, UNDEFINED};
+use super::{CodegenError, PrototypeMismatch};
+use crate::backend::{Backend, Lower, Lowering};
+use crate::diagnostics::Diagnostic;
+use crate::matching::{Decision, Occurrence};
+use crate::parser::nodes::{DerivativeDef, Function, Prototype, StructDef, UnionDef};
+use crate::parser::nodes::{Expression, ExpressionKind, Pattern};
+use crate::types::{Type, TypeDefs};
+use crate::util::Span;
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module;
-use inkwell::types::BasicTypeEnum;
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::module::{Linkage, Module};
+use inkwell::passes::PassManager;
+use inkwell::types::{BasicType, BasicTypeEnum, FunctionType, StructType};
+use inkwell::values::AggregateValueEnum;
 use inkwell::values::AnyValueEnum;
 use inkwell::values::BasicValueEnum;
-use inkwell::values::FloatValue;
+use inkwell::values::IntValue;
 use inkwell::{
     values::{BasicValue, FunctionValue, PointerValue},
-    FloatPredicate,
+    AddressSpace, FloatPredicate, IntPredicate, OptimizationLevel,
 };
+use std::cell::Cell;
 use std::collections::HashMap;
 
+/// Signature of the JIT-compiled anonymous functions wrapping top level expressions,
+/// which the functions of the runtime may unwind out of
+type AnonymousFunction = unsafe extern "C-unwind" fn() -> f64;
+
 pub struct CodegenContext<'ctx> {
     context: &'ctx Context,
+    /// The module new functions are compiled into.
+    /// With a JIT, it is handed over to the JIT once a top level definition or expression is completed.
     module: Module<'ctx>,
+    module_name: String,
+    module_count: usize,
     builder: Builder<'ctx>,
+    /// None if everything is compiled ahead of time into a single module
+    execution_engine: Option<ExecutionEngine<'ctx>>,
     named_values: HashMap<String, PointerValue<'ctx>>,
+    /// Every prototype seen so far, used to redeclare functions in later modules
+    function_protos: HashMap<String, Prototype>,
+    /// Every function defined so far, kept alive by the JIT or the module
+    defined_functions: HashMap<String, FunctionValue<'ctx>>,
+    /// How many bodies each function defined with the JIT has had
+    function_versions: HashMap<String, usize>,
+    /// The address of the latest body of every function defined with the JIT, called by its trampoline
+    function_slots: HashMap<String, Box<Cell<usize>>>,
+    /// The functions defined with the JIT whose slots do not point at their latest bodies yet,
+    /// along with the symbols of the bodies
+    unlinked_bodies: Vec<(String, String)>,
+    opt_level: OptimizationLevel,
+    /// Optimizes every function compiled into the current module, None at -O0
+    function_pass_manager: Option<PassManager<FunctionValue<'ctx>>>,
+    /// Whether indexing an array checks the index against its length
+    bounds_checks: bool,
+    /// Whether the arrays created by the function being compiled may outlive its call
+    heap_arrays: bool,
+    /// How many loops the expression being compiled is in
+    loop_depth: usize,
+    /// Every struct and union defined so far
+    type_defs: TypeDefs,
+    /// The named LLVM types of the structs and unions
+    named_types: HashMap<String, StructType<'ctx>>,
+    /// The LLVM types of the fields of the variants of every union, in the order of their tags
+    variant_types: HashMap<String, Vec<StructType<'ctx>>>,
+    /// Lowers every function into functions without gradients or lambdas
+    lowering: Lowering,
 }
 
 impl<'ctx> CodegenContext<'ctx> {
-    pub fn new(context: &'ctx Context, module_name: &str) -> Self {
+    pub fn new(context: &'ctx Context, module_name: &str) -> Result<Self, CodegenError> {
+        let execution_engine = context
+            .create_module(module_name)
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|err| CodegenError::Llvm(err.to_string()))?;
+
+        let mut cc = CodegenContext::new_without_jit(context, module_name);
+        cc.execution_engine = Some(execution_engine);
+        Ok(cc)
+    }
+
+    /// Create a context compiling everything into a single module, e.g. to be emitted as an object file
+    pub fn new_without_jit(context: &'ctx Context, module_name: &str) -> Self {
         CodegenContext {
             context,
             module: context.create_module(module_name),
+            module_name: module_name.into(),
+            module_count: 0,
             builder: context.create_builder(),
+            execution_engine: None,
             named_values: HashMap::new(),
+            function_protos: HashMap::new(),
+            defined_functions: HashMap::new(),
+            function_versions: HashMap::new(),
+            function_slots: HashMap::new(),
+            unlinked_bodies: vec![],
+            opt_level: OptimizationLevel::None,
+            function_pass_manager: None,
+            bounds_checks: true,
+            heap_arrays: false,
+            loop_depth: 0,
+            type_defs: TypeDefs::new(),
+            named_types: HashMap::new(),
+            variant_types: HashMap::new(),
+            lowering: Lowering::new(),
         }
     }
 
-    /// Generate code of an expression
-    /// All expressions have return value of float
-    pub fn compile_expr(&self, expr: &Expression) -> Result<FloatValue<'ctx>, String> {
-        match expr {
-            Expression::NumberExpr(num) => Ok(self.context.f64_type().const_float(*num)),
-            Expression::VariableExpr(ref var) => self
-                .named_values
-                .get(var)
-                .map(|x| self.builder.build_load(*x, var).into_float_value())
-                .ok_or(format!("Unknown variable name: {}", var)),
-            Expression::BinaryExpr(op, left, right) => {
+    /// Optimize functions compiled from now on, and modules handed over to the JIT, at `level`
+    pub fn set_opt_level(&mut self, level: OptimizationLevel) {
+        self.opt_level = level;
+        self.function_pass_manager = passes::create_function_pass_manager(&self.module, level);
+    }
+
+    /// Check the indices of arrays in the functions compiled from now on, which is the default.
+    /// Without checks, an index out of bounds is undefined behavior.
+    pub fn set_bounds_checks(&mut self, enabled: bool) {
+        self.bounds_checks = enabled;
+    }
+
+    /// Run the module level pipeline on the current module,
+    /// e.g. before it is emitted as an object file
+    pub fn optimize_module(&self) {
+        passes::optimize_module(&self.module, self.opt_level);
+    }
+
+    /// The module new functions are compiled into
+    pub fn module(&self) -> &Module<'ctx> {
+        &self.module
+    }
+
+    /// Get a function by name in the current module.
+    /// If it was declared or defined in a previous module, its declaration is added to the current one.
+    pub fn get_function(&self, name: &str) -> Option<FunctionValue<'ctx>> {
+        self.module.get_function(name).or_else(|| {
+            self.function_protos
+                .get(name)
+                .and_then(|proto| self.compile_proto(proto).ok())
+        })
+    }
+
+    /// Generate code of an expression.
+    /// The expression must be type checked, ints being i64, bools i1, doubles f64 and strings i8*.
+    /// An array is a struct of its length as an i64 and a pointer to its elements,
+    /// a struct is a named LLVM struct of its fields,
+    /// a union is a named LLVM struct of its tag as an i64 and space for the fields of any variant,
+    /// and a function is a closure, a struct of a pointer to its code and a pointer to its environment.
+    pub fn compile_expr(
+        &mut self,
+        expr: &Expression,
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        match &expr.kind {
+            ExpressionKind::NumberExpr(num) => Ok(self.context.f64_type().const_float(*num).into()),
+            ExpressionKind::IntExpr(num) => {
+                Ok(self.context.i64_type().const_int(*num as u64, true).into())
+            }
+            ExpressionKind::BoolExpr(value) => Ok(self
+                .context
+                .bool_type()
+                .const_int(*value as u64, false)
+                .into()),
+            // a private constant of the current module, which is merged with identical ones
+            ExpressionKind::StringExpr(value) => Ok(self
+                .builder
+                .build_global_string_ptr(value, "str")
+                .as_pointer_value()
+                .into()),
+            ExpressionKind::VariableExpr(ref var) => match self.named_values.get(var) {
+                Some(&alloca) => Ok(self.builder.build_load(alloca, var)),
+                // a function used as a value is a closure capturing nothing
+                None => match (self.get_function(var), self.function_protos.get(var)) {
+                    (Some(func), Some(proto)) => {
+                        let code = self.build_code(func, &proto.ty(), &[])?;
+                        self.build_closure(code, &[])
+                    }
+                    _ => Err(CodegenError::UnknownVariable {
+                        name: var.clone(),
+                        span: expr.span,
+                    }),
+                },
+            },
+            ExpressionKind::BinaryExpr('=', left, right) => {
+                // the destination must be a variable, which should not be evaluated, or an array element
+                let var = match &left.kind {
+                    ExpressionKind::VariableExpr(var) => var,
+                    ExpressionKind::IndexExpr(array, index) => {
+                        // the value is evaluated first, as for a variable
+                        let value = self.compile_expr(right)?;
+                        let array = self.compile_expr(array)?;
+                        let index = self.compile_expr(index)?;
+                        let element = self.build_element_pointer(array, index, left.span)?;
+                        self.builder.build_store(element, value);
+                        return Ok(value);
+                    }
+                    _ => return Err(CodegenError::InvalidAssignment { span: left.span }),
+                };
+
+                let value = self.compile_expr(right)?;
+                let alloca = self.get_variable(var, left.span)?;
+                self.builder.build_store(alloca, value);
+
+                Ok(value)
+            }
+            ExpressionKind::BinaryExpr(op, left, right) => {
                 let lhs = self.compile_expr(left)?;
                 let rhs = self.compile_expr(right)?;
                 match op {
-                    '+' => Ok(self.builder.build_float_add(lhs, rhs, "tmpadd")),
-                    '-' => Ok(self.builder.build_float_sub(lhs, rhs, "tmpsub")),
-                    '*' => Ok(self.builder.build_float_mul(lhs, rhs, "tmpmul")),
-                    '/' => Ok(self.builder.build_float_div(lhs, rhs, "tmpdiv")),
-                    '<' => Ok({
-                        let cmp = self.builder.build_float_compare(
-                            FloatPredicate::ULT,
-                            lhs,
-                            rhs,
-                            "tmpcmp",
-                        );
-
-                        self.builder.build_unsigned_int_to_float(
-                            cmp,
-                            self.context.f64_type(),
-                            "tmpbool",
-                        )
-                    }),
-                    '>' => Ok({
-                        let cmp = self.builder.build_float_compare(
-                            FloatPredicate::ULT,
-                            rhs,
-                            lhs,
-                            "tmpcmp",
-                        );
-
-                        self.builder.build_unsigned_int_to_float(
-                            cmp,
-                            self.context.f64_type(),
-                            "tmpbool",
-                        )
-                    }),
-                    _ => Err(format!("Unknown binary op {}", op)),
+                    '+' | '-' | '*' | '/' | '<' | '>' => self.build_binary(*op, lhs, rhs),
+                    // fall back to user defined operators
+                    _ => {
+                        let func = self.get_function(&format!("binary{}", op)).ok_or(
+                            CodegenError::UnknownOperator {
+                                op: *op,
+                                unary: false,
+                                span: expr.span,
+                            },
+                        )?;
+                        self.build_call(func, &[lhs, rhs], "binop")
+                    }
+                }
+            }
+            ExpressionKind::UnaryExpr(op, operand) => {
+                let operand = self.compile_expr(operand)?;
+                let func = self.get_function(&format!("unary{}", op)).ok_or(
+                    CodegenError::UnknownOperator {
+                        op: *op,
+                        unary: true,
+                        span: expr.span,
+                    },
+                )?;
+                self.build_call(func, &[operand], "unop")
+            }
+            ExpressionKind::CastExpr(ty, operand) => {
+                let operand = self.compile_expr(operand)?;
+                self.build_cast(operand, ty)
+            }
+            ExpressionKind::ArrayExpr(elements) => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements {
+                    values.push(self.compile_expr(element)?);
+                }
+                self.build_array(&values)
+            }
+            ExpressionKind::IndexExpr(array, index) => {
+                let array = self.compile_expr(array)?;
+                let index = self.compile_expr(index)?;
+                let element = self.build_element_pointer(array, index, expr.span)?;
+                Ok(self.builder.build_load(element, "element"))
+            }
+            ExpressionKind::StructExpr(name, fields) => {
+                let struct_type = self.named_types.get(name).copied().ok_or_else(|| {
+                    CodegenError::Llvm(format!("Struct {} is not defined.", name))
+                })?;
+
+                // the fields are in the order they are declared
+                let mut value: AggregateValueEnum = struct_type.get_undef().into();
+                for (i, (field, init)) in fields.iter().enumerate() {
+                    let init = self.compile_expr(init)?;
+                    value = self
+                        .builder
+                        .build_insert_value(value, init, i as u32, field)
+                        .ok_or_else(|| CodegenError::Llvm("Invalid struct.".into()))?;
+                }
+                Ok(value.into_struct_value().into())
+            }
+            ExpressionKind::VariantExpr(name, args) => {
+                let (union, tag, field_types) = self
+                    .type_defs
+                    .variant(name)
+                    .map(|(def, tag)| {
+                        (def.name.clone(), tag, def.variants[tag].field_types.clone())
+                    })
+                    .ok_or_else(|| {
+                        CodegenError::Llvm(format!("Variant {} is not defined.", name))
+                    })?;
+                let union_type = self.named_types[&union];
+                let variant_type = self.variant_types[&union][tag];
+
+                let mut fields: AggregateValueEnum = variant_type.get_undef().into();
+                for (i, (arg, field_type)) in args.iter().zip(&field_types).enumerate() {
+                    let mut arg = self.compile_expr(arg)?;
+                    if holds_itself(&union, field_type) {
+                        arg = self.build_heap_copy(arg)?.into();
+                    }
+                    fields = self
+                        .builder
+                        .build_insert_value(fields, arg, i as u32, "field")
+                        .ok_or_else(|| CodegenError::Llvm("Invalid variant.".into()))?;
                 }
+
+                // the fields are written after the tag, through a pointer to a struct of them
+                let parent = self.current_function()?;
+                let alloca = self.create_entry_block_alloca(&parent, name, union_type.into());
+                let tag_ptr = self.build_union_gep(alloca, 0, "tagptr")?;
+                let tag = self.context.i64_type().const_int(tag as u64, false);
+                self.builder.build_store(tag_ptr, tag);
+                if !args.is_empty() {
+                    let payload = self.build_union_gep(alloca, 1, "payload")?;
+                    let fields_ptr = self.builder.build_pointer_cast(
+                        payload,
+                        variant_type.ptr_type(AddressSpace::Generic),
+                        "fields",
+                    );
+                    self.builder
+                        .build_store(fields_ptr, fields.into_struct_value());
+                }
+                Ok(self.builder.build_load(alloca, name))
+            }
+            ExpressionKind::MatchExpr { value, arms, tree } => {
+                let tree = tree
+                    .as_deref()
+                    .ok_or_else(|| CodegenError::Llvm("Match is not type checked.".into()))?;
+
+                // the parts of the value are tested and bound in a copy of it
+                let value = self.compile_expr(value)?;
+                let parent = self.current_function()?;
+                let alloca = self.create_entry_block_alloca(&parent, "matched", value.get_type());
+                self.builder.build_store(alloca, value);
+
+                let merge_bb = self.context.append_basic_block(parent, "matchcont");
+                let mut parts = HashMap::new();
+                parts.insert(vec![], alloca);
+                let mut incoming = vec![];
+                self.compile_decision(tree, &mut parts, arms, merge_bb, &mut incoming)?;
+
+                // merge, every arm has the same type
+                self.builder.position_at_end(merge_bb);
+                let phi = self.builder.build_phi(incoming[0].0.get_type(), "matchtmp");
+                let incoming: Vec<(&dyn BasicValue, BasicBlock)> = incoming
+                    .iter()
+                    .map(|(value, block)| (value as &dyn BasicValue, *block))
+                    .collect();
+                phi.add_incoming(&incoming);
+
+                Ok(phi.as_basic_value())
+            }
+            ExpressionKind::FieldExpr {
+                object,
+                field,
+                index,
+            } => {
+                let object = self.compile_expr(object)?;
+                match (object, index) {
+                    (BasicValueEnum::StructValue(object), Some(index)) => self
+                        .builder
+                        .build_extract_value(object, *index as u32, field)
+                        .ok_or_else(|| CodegenError::Llvm("Invalid struct.".into())),
+                    _ => Err(CodegenError::Llvm(format!(
+                        "Invalid field {} of {:?}.",
+                        field,
+                        object.get_type()
+                    ))),
+                }
+            }
+            ExpressionKind::LambdaExpr { .. } => {
+                unreachable!("lambdas are converted into closures")
+            }
+            ExpressionKind::GradExpr(_) => {
+                unreachable!("gradients are expanded before type checking")
+            }
+            ExpressionKind::ClosureExpr {
+                function,
+                captures,
+                ty,
+            } => {
+                let func =
+                    self.get_function(function)
+                        .ok_or_else(|| CodegenError::UnknownFunction {
+                            name: function.clone(),
+                            span: expr.span,
+                        })?;
+                let mut values = Vec::with_capacity(captures.len());
+                for var in captures {
+                    let alloca = self.get_variable(var, expr.span)?;
+                    values.push(self.builder.build_load(alloca, var));
+                }
+                let code = self.build_code(func, ty, &values)?;
+                self.build_closure(code, &values)
+            }
+            ExpressionKind::CallExpr(name, args) if self.named_values.contains_key(name) => {
+                // an indirect call to the code of the closure held by a variable,
+                // which takes its environment before the args
+                let closure = self
+                    .builder
+                    .build_load(self.named_values[name], name)
+                    .into_struct_value();
+                let field = |index, name| {
+                    self.builder
+                        .build_extract_value(closure, index, name)
+                        .ok_or_else(|| CodegenError::Llvm("Invalid closure.".into()))
+                };
+                let callee = field(0, "code")?.into_pointer_value();
+                let mut parsed_args: Vec<BasicValueEnum> = Vec::with_capacity(args.len() + 1);
+                parsed_args.push(field(1, "env")?);
+                for arg in args {
+                    parsed_args.push(self.compile_expr(arg)?);
+                }
+
+                let expected = arity(pointee_function_type(callee)) - 1;
+                if args.len() != expected {
+                    return Err(CodegenError::ArityMismatch {
+                        name: name.clone(),
+                        expected,
+                        found: args.len(),
+                        span: expr.span,
+                    });
+                }
+
+                self.build_indirect_call(callee, parsed_args.as_slice(), "tmpcall")
             }
-            Expression::CallExpr(name, args) => {
+            ExpressionKind::CallExpr(name, args) => {
                 // Get function
-                let func = self
-                    .module
-                    .get_function(name)
-                    .ok_or(format!("Unknown function: {}", name))?;
+                let func =
+                    self.get_function(name)
+                        .ok_or_else(|| CodegenError::UnknownFunction {
+                            name: name.clone(),
+                            span: expr.span,
+                        })?;
 
                 // validate args len
-                if args.len() != func.count_params() as usize {
-                    return Err(format!(
-                        "Unmatched arg number. Function expects {} but the input has {}.",
-                        args.len(),
-                        func.count_params()
-                    ));
+                if args.len() != arity(func.get_type()) {
+                    return Err(CodegenError::ArityMismatch {
+                        name: name.clone(),
+                        expected: arity(func.get_type()),
+                        found: args.len(),
+                        span: expr.span,
+                    });
                 }
 
                 // Parse args
                 let mut parsed_args: Vec<BasicValueEnum> = Vec::with_capacity(args.len());
 
                 for arg in args {
-                    parsed_args.push(self.compile_expr(arg)?.into());
+                    parsed_args.push(self.compile_expr(arg)?);
                 }
 
+                self.build_call(func, parsed_args.as_slice(), "tmpcall")
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                let cond = self.compile_expr(cond)?;
+                let cond = self.build_is_true(cond, "ifcond")?;
+
+                let parent = self.current_function()?;
+                let then_bb = self.context.append_basic_block(parent, "then");
+                let else_bb = self.context.append_basic_block(parent, "else");
+                let merge_bb = self.context.append_basic_block(parent, "ifcont");
+
+                self.builder
+                    .build_conditional_branch(cond, then_bb, else_bb);
+
+                // then branch
+                // the branch may contain other blocks, so the incoming block of phi is the current one
+                self.builder.position_at_end(then_bb);
+                let then_val = self.compile_expr(then_expr)?;
+                self.builder.build_unconditional_branch(merge_bb);
+                let then_bb = self.builder.get_insert_block().unwrap();
+
+                // else branch
+                self.builder.position_at_end(else_bb);
+                let else_val = self.compile_expr(else_expr)?;
+                self.builder.build_unconditional_branch(merge_bb);
+                let else_bb = self.builder.get_insert_block().unwrap();
+
+                // merge, both branches have the same type
+                self.builder.position_at_end(merge_bb);
+                let phi = self.builder.build_phi(then_val.get_type(), "iftmp");
+                phi.add_incoming(&[(&then_val, then_bb), (&else_val, else_bb)]);
+
+                Ok(phi.as_basic_value())
+            }
+            ExpressionKind::ForExpr {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let parent = self.current_function()?;
+
+                // emit the start value without the variable in scope,
+                // the variable has the type of its start value
+                let start = self.compile_expr(start)?;
+                let alloca = self.create_entry_block_alloca(&parent, var, start.get_type());
+                self.builder.build_store(alloca, start);
+
+                let loop_bb = self.context.append_basic_block(parent, "loop");
+                self.builder.build_unconditional_branch(loop_bb);
+                self.builder.position_at_end(loop_bb);
+
+                // shadow any outer binding of the same name within the loop,
+                // and restore it whether or not the loop is compiled successfully
+                let old_val = self.named_values.insert(var.clone(), alloca);
+                self.loop_depth += 1;
+                let end_cond = self.compile_for_loop(var, alloca, end, step.as_deref(), body);
+                self.loop_depth -= 1;
+                self.restore_named_values(vec![(var.clone(), old_val)]);
+                let end_cond = end_cond?;
+
+                let after_bb = self.context.append_basic_block(parent, "afterloop");
                 self.builder
-                    .build_call(func, parsed_args.as_slice(), "tmpcall")
-                    .try_as_basic_value()
-                    .left()
-                    .map(|x| x.into_float_value())
-                    .ok_or("Invalid call.".into())
+                    .build_conditional_branch(end_cond, loop_bb, after_bb);
+                self.builder.position_at_end(after_bb);
+
+                // for expression always evaluates to 0.0
+                Ok(self.context.f64_type().const_float(0.0).into())
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                let parent = self.current_function()?;
+
+                let mut old_vals = Vec::with_capacity(vars.len());
+                let mut result = Ok(());
+                for (var, init) in vars {
+                    // the initializer is evaluated before the variable is in scope,
+                    // so `var a = a in ...` refers to the outer a
+                    let init = match init {
+                        Some(init) => match self.compile_expr(init) {
+                            Ok(init) => init,
+                            Err(err) => {
+                                result = Err(err);
+                                break;
+                            }
+                        },
+                        None => self.context.f64_type().const_float(0.0).into(),
+                    };
+
+                    let alloca = self.create_entry_block_alloca(&parent, var, init.get_type());
+                    self.builder.build_store(alloca, init);
+
+                    old_vals.push((var.clone(), self.named_values.insert(var.clone(), alloca)));
+                }
+
+                let body = result.and_then(|_| self.compile_expr(body));
+                self.restore_named_values(old_vals);
+                body
             }
         }
     }
 
-    /// Generate code of proto, convert a function prototype to a FunctionValue
-    pub fn compile_proto(&self, proto: &Prototype) -> Result<FunctionValue<'ctx>, String> {
-        let ret_type = self.context.f64_type();
-        let arg_types: Vec<BasicTypeEnum> = vec![ret_type.into(); proto.args.len()];
-        let arg_types_slice = arg_types.as_slice();
+    /// Generate code of the decision tree of a match, testing the parts of the value at `parts`.
+    /// Every arm reached branches to `merge_bb`, and its value is added to `incoming`.
+    /// An arm reached by several paths is generated for each of them.
+    fn compile_decision(
+        &mut self,
+        decision: &Decision,
+        parts: &mut HashMap<Occurrence, PointerValue<'ctx>>,
+        arms: &[(Pattern, Expression)],
+        merge_bb: BasicBlock<'ctx>,
+        incoming: &mut Vec<(BasicValueEnum<'ctx>, BasicBlock<'ctx>)>,
+    ) -> Result<(), CodegenError> {
+        let (occurrence, ty, cases, default) = match decision {
+            Decision::Arm { index, bindings } => {
+                // bind the variables to the parts of the copy, as a var expression does
+                let old_vals = bindings
+                    .iter()
+                    .map(|(var, occurrence)| {
+                        let part = parts[occurrence];
+                        (var.clone(), self.named_values.insert(var.clone(), part))
+                    })
+                    .collect();
+                let value = self.compile_expr(&arms[*index].1);
+                self.restore_named_values(old_vals);
+
+                incoming.push((value?, self.builder.get_insert_block().unwrap()));
+                self.builder.build_unconditional_branch(merge_bb);
+                return Ok(());
+            }
+            Decision::Switch {
+                occurrence,
+                ty,
+                cases,
+                default,
+            } => (occurrence, ty, cases, default),
+        };
+        let union = match ty {
+            Type::Named(name) => &**name,
+            _ => return Err(CodegenError::Llvm(format!("Invalid union {}.", ty))),
+        };
+
+        let part = parts[occurrence];
+        let tag_ptr = self.build_union_gep(part, 0, "tagptr")?;
+        let tag = self.builder.build_load(tag_ptr, "tag").into_int_value();
+        let payload = self.build_union_gep(part, 1, "payload")?;
 
-        let fn_type = self.context.f64_type().fn_type(arg_types_slice, false);
-        let fn_val = self.module.add_function(&proto.name, fn_type, None);
+        // without a default, the last case is taken for the tags not tested before
+        let parent = self.current_function()?;
+        let blocks: Vec<BasicBlock> = cases
+            .iter()
+            .map(|_| self.context.append_basic_block(parent, "case"))
+            .collect();
+        let (tested, else_bb) = match default {
+            Some(_) => (
+                &blocks[..],
+                self.context.append_basic_block(parent, "default"),
+            ),
+            None => (&blocks[..blocks.len() - 1], blocks[blocks.len() - 1]),
+        };
+        let i64_type = self.context.i64_type();
+        let switch_cases: Vec<(IntValue, BasicBlock)> = cases
+            .iter()
+            .zip(tested)
+            .map(|((tag, _), block)| (i64_type.const_int(*tag as u64, false), *block))
+            .collect();
+        self.builder.build_switch(tag, else_bb, &switch_cases);
 
-        // set argument names
-        for (i, arg) in fn_val.get_param_iter().enumerate() {
-            arg.into_float_value().set_name(&proto.args[i]);
+        for ((tag, decision), block) in cases.iter().zip(&blocks) {
+            self.builder.position_at_end(*block);
+            let variant_type = self.variant_types[union][*tag];
+            let field_types = self
+                .type_defs
+                .get_union(union)
+                .map_or(vec![], |def| def.variants[*tag].field_types.clone());
+            let fields_ptr = self.builder.build_pointer_cast(
+                payload,
+                variant_type.ptr_type(AddressSpace::Generic),
+                "fields",
+            );
+            for (i, field_type) in field_types.iter().enumerate() {
+                let mut field = self
+                    .builder
+                    .build_struct_gep(fields_ptr, i as u32, "field")
+                    .map_err(|_| CodegenError::Llvm("Invalid variant.".into()))?;
+                // the copy on the heap is copied again,
+                // so that assigning a variable bound to it does not change the other values holding it
+                if holds_itself(union, field_type) {
+                    let copy = self.builder.build_load(field, "copy").into_pointer_value();
+                    let value = self.builder.build_load(copy, "field");
+                    field = self.create_entry_block_alloca(&parent, "field", value.get_type());
+                    self.builder.build_store(field, value);
+                }
+                parts.insert([occurrence.as_slice(), &[i]].concat(), field);
+            }
+            self.compile_decision(decision, parts, arms, merge_bb, incoming)?;
         }
 
-        Ok(fn_val)
+        if let Some(default) = default {
+            self.builder.position_at_end(else_bb);
+            self.compile_decision(default, parts, arms, merge_bb, incoming)?;
+        }
+        Ok(())
     }
 
-    /// Creates a new stack allocation instruction
-    pub fn create_entry_block_alloca(
+    /// Build a pointer to the tag of a union in memory at index 0, or to the space for its fields at 1
+    fn build_union_gep(
         &self,
-        fun_val: &FunctionValue,
+        union: PointerValue<'ctx>,
+        index: u32,
         name: &str,
-    ) -> PointerValue<'ctx> {
-        let builder = self.context.create_builder();
+    ) -> Result<PointerValue<'ctx>, CodegenError> {
+        self.builder
+            .build_struct_gep(union, index, name)
+            .map_err(|_| CodegenError::Llvm("Invalid union.".into()))
+    }
 
-        let entry = fun_val.get_first_basic_block().unwrap();
+    fn get_variable(&self, var: &str, span: Span) -> Result<PointerValue<'ctx>, CodegenError> {
+        self.named_values
+            .get(var)
+            .copied()
+            .ok_or_else(|| CodegenError::UnknownVariable {
+                name: var.into(),
+                span,
+            })
+    }
 
-        match entry.get_first_instruction() {
-            Some(first_instr) => builder.position_before(&first_instr),
-            None => builder.position_at_end(entry),
+    /// Restore bindings shadowed by a scope, in reverse order of shadowing
+    fn restore_named_values(&mut self, old_vals: Vec<(String, Option<PointerValue<'ctx>>)>) {
+        for (var, old_val) in old_vals.into_iter().rev() {
+            match old_val {
+                Some(old_val) => self.named_values.insert(var, old_val),
+                None => self.named_values.remove(&var),
+            };
         }
-
-        builder.build_alloca(self.context.f64_type(), name)
     }
 
-    pub fn compile_func(&mut self, func: &Function) -> Result<FunctionValue, String> {
-        // if the FunctionValue does not exist, compile it.
-        let fun_val = match self.module.get_function(&func.prototype.name) {
-            Some(func) => func,
-            None => self.compile_proto(&func.prototype)?,
+    /// Generate the body, step and end condition of a for loop,
+    /// with the induction variable already bound to `alloca`.
+    /// Returns whether the loop should continue.
+    fn compile_for_loop(
+        &mut self,
+        var: &str,
+        alloca: PointerValue<'ctx>,
+        end: &Expression,
+        step: Option<&Expression>,
+        body: &Expression,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        // the value of body is ignored
+        self.compile_expr(body)?;
+
+        let step = step.map(|step| self.compile_expr(step)).transpose()?;
+
+        // compute the end condition before incrementing the variable
+        let end_cond = self.compile_expr(end)?;
+
+        let curr_var = self.builder.build_load(alloca, var);
+        // the type checker writes out a missing step with the type of the variable
+        let step = match (step, curr_var) {
+            (Some(step), _) => step,
+            (None, BasicValueEnum::IntValue(curr_var)) => {
+                curr_var.get_type().const_int(1, false).into()
+            }
+            (None, _) => self.context.f64_type().const_float(1.0).into(),
         };
+        let next_var = self.build_binary('+', curr_var, step)?;
+        self.builder.build_store(alloca, next_var);
 
-        let basic_block = self.context.append_basic_block(fun_val, "entry");
-        self.builder.position_at_end(basic_block);
+        self.build_is_true(end_cond, "loopcond")
+    }
 
-        // record the functioin arguments in the named_values
-        self.named_values.clear();
-        for (i, arg) in fun_val.get_param_iter().enumerate() {
-            let arg_name = &func.prototype.args[i];
-            let alloca = self.create_entry_block_alloca(&fun_val, arg_name);
+    /// Build one of the builtin binary operators `+ - * / < >`, with operands of the same type.
+    /// Int arithmetic wraps around, and the comparisons of doubles are true if either is NaN.
+    fn build_binary(
+        &self,
+        op: char,
+        lhs: BasicValueEnum<'ctx>,
+        rhs: BasicValueEnum<'ctx>,
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        match (lhs, rhs) {
+            (BasicValueEnum::IntValue(lhs), BasicValueEnum::IntValue(rhs)) => Ok(match op {
+                '+' => self.builder.build_int_add(lhs, rhs, "tmpadd").into(),
+                '-' => self.builder.build_int_sub(lhs, rhs, "tmpsub").into(),
+                '*' => self.builder.build_int_mul(lhs, rhs, "tmpmul").into(),
+                '/' => self.build_int_div(lhs, rhs).into(),
+                '<' => self
+                    .builder
+                    .build_int_compare(IntPredicate::SLT, lhs, rhs, "tmpcmp")
+                    .into(),
+                _ => self
+                    .builder
+                    .build_int_compare(IntPredicate::SGT, lhs, rhs, "tmpcmp")
+                    .into(),
+            }),
+            (BasicValueEnum::FloatValue(lhs), BasicValueEnum::FloatValue(rhs)) => Ok(match op {
+                '+' => self.builder.build_float_add(lhs, rhs, "tmpadd").into(),
+                '-' => self.builder.build_float_sub(lhs, rhs, "tmpsub").into(),
+                '*' => self.builder.build_float_mul(lhs, rhs, "tmpmul").into(),
+                '/' => self.builder.build_float_div(lhs, rhs, "tmpdiv").into(),
+                '<' => self
+                    .builder
+                    .build_float_compare(FloatPredicate::ULT, lhs, rhs, "tmpcmp")
+                    .into(),
+                _ => self
+                    .builder
+                    .build_float_compare(FloatPredicate::ULT, rhs, lhs, "tmpcmp")
+                    .into(),
+            }),
+            _ => Err(CodegenError::Llvm(format!(
+                "Invalid operands of {}: {:?} and {:?}.",
+                op,
+                lhs.get_type(),
+                rhs.get_type()
+            ))),
+        }
+    }
 
-            self.builder.build_store(alloca, arg);
+    /// Build an int division defined for every input, unlike `sdiv`:
+    /// dividing by 0 gives 0, and dividing the minimum by -1 wraps around
+    fn build_int_div(&self, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let zero = i64_type.const_zero();
+        let minus_one = i64_type.const_all_ones();
 
-            self.named_values.insert(arg_name.into(), alloca);
+        let is_zero = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, rhs, zero, "divzero");
+        let is_minus_one =
+            self.builder
+                .build_int_compare(IntPredicate::EQ, rhs, minus_one, "divminusone");
+
+        // divide by 1 instead, then pick the result of those cases
+        let special = self.builder.build_or(is_zero, is_minus_one, "divspecial");
+        let divisor = self
+            .builder
+            .build_select(special, i64_type.const_int(1, false), rhs, "divisor")
+            .into_int_value();
+        let quotient = self.builder.build_int_signed_div(lhs, divisor, "tmpdiv");
+        let negated = self.builder.build_int_sub(zero, lhs, "tmpneg");
+
+        let quotient = self
+            .builder
+            .build_select(is_minus_one, negated, quotient, "tmpdiv")
+            .into_int_value();
+        self.builder
+            .build_select(is_zero, zero, quotient, "tmpdiv")
+            .into_int_value()
+    }
+
+    /// Whether a value is true, zero and NaN being false
+    fn build_is_true(
+        &self,
+        value: BasicValueEnum<'ctx>,
+        name: &str,
+    ) -> Result<IntValue<'ctx>, CodegenError> {
+        match value {
+            BasicValueEnum::IntValue(value) if value.get_type().get_bit_width() == 1 => Ok(value),
+            BasicValueEnum::IntValue(value) => Ok(self.builder.build_int_compare(
+                IntPredicate::NE,
+                value,
+                value.get_type().const_zero(),
+                name,
+            )),
+            BasicValueEnum::FloatValue(value) => Ok(self.builder.build_float_compare(
+                FloatPredicate::ONE,
+                value,
+                value.get_type().const_float(0.0),
+                name,
+            )),
+            value => Err(CodegenError::Llvm(format!(
+                "Invalid condition of type {:?}.",
+                value.get_type()
+            ))),
         }
+    }
 
-        let body = self.compile_expr(&func.body)?;
-        self.builder.build_return(Some(&body));
+    /// Build a cast expression.
+    /// A double is truncated into an int, saturating at the bounds and NaN becoming 0.
+    fn build_cast(
+        &self,
+        value: BasicValueEnum<'ctx>,
+        ty: &Type,
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let i64_type = self.context.i64_type();
+        let f64_type = self.context.f64_type();
 
-        if fun_val.verify(true) {
-            Ok(fun_val)
+        Ok(match (value, ty) {
+            (value, Type::Bool) => self.build_is_true(value, "tobool")?.into(),
+            (BasicValueEnum::IntValue(value), Type::Int)
+                if value.get_type().get_bit_width() == 1 =>
+            {
+                self.builder
+                    .build_int_z_extend(value, i64_type, "booltoint")
+                    .into()
+            }
+            (BasicValueEnum::IntValue(value), Type::Double)
+                if value.get_type().get_bit_width() == 1 =>
+            {
+                self.builder
+                    .build_unsigned_int_to_float(value, f64_type, "booltodouble")
+                    .into()
+            }
+            (BasicValueEnum::IntValue(value), Type::Double) => self
+                .builder
+                .build_signed_int_to_float(value, f64_type, "inttodouble")
+                .into(),
+            (BasicValueEnum::FloatValue(value), Type::Int) => {
+                // fptosi is poison out of the range of i64, so the bounds and NaN are picked instead
+                let converted =
+                    self.builder
+                        .build_float_to_signed_int(value, i64_type, "doubletoint");
+                let too_small = self.builder.build_float_compare(
+                    FloatPredicate::OLT,
+                    value,
+                    f64_type.const_float(i64::MIN as f64),
+                    "toosmall",
+                );
+                let too_large = self.builder.build_float_compare(
+                    FloatPredicate::OGE,
+                    value,
+                    f64_type.const_float(i64::MAX as f64),
+                    "toolarge",
+                );
+                let is_nan =
+                    self.builder
+                        .build_float_compare(FloatPredicate::UNO, value, value, "isnan");
+
+                let converted = self
+                    .builder
+                    .build_select(
+                        too_small,
+                        i64_type.const_int(i64::MIN as u64, true),
+                        converted,
+                        "doubletoint",
+                    )
+                    .into_int_value();
+                let converted = self
+                    .builder
+                    .build_select(
+                        too_large,
+                        i64_type.const_int(i64::MAX as u64, true),
+                        converted,
+                        "doubletoint",
+                    )
+                    .into_int_value();
+                self.builder
+                    .build_select(is_nan, i64_type.const_zero(), converted, "doubletoint")
+            }
+            // already of the type
+            (value, _) => value,
+        })
+    }
+
+    /// Copy a value to the heap, where it is never freed, returning a pointer to the copy
+    fn build_heap_copy(
+        &self,
+        value: BasicValueEnum<'ctx>,
+    ) -> Result<PointerValue<'ctx>, CodegenError> {
+        let copy = self
+            .builder
+            .build_malloc(value.get_type(), "copy")
+            .map_err(|err| CodegenError::Llvm(err.into()))?;
+        self.builder.build_store(copy, value);
+        Ok(copy)
+    }
+
+    /// Build an array of values of the same type, of which there is at least one.
+    /// The elements are on the stack, unless the array may outlive the call
+    /// or the same literal may be evaluated again by a loop while the array is still used,
+    /// in which case they are on the heap and never freed.
+    fn build_array(
+        &self,
+        values: &[BasicValueEnum<'ctx>],
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let i64_type = self.context.i64_type();
+        let element_type = values[0].get_type();
+        let len = i64_type.const_int(values.len() as u64, false);
+
+        let data = if self.heap_arrays || self.loop_depth > 0 {
+            self.builder
+                .build_array_malloc(element_type, len, "data")
+                .map_err(|err| CodegenError::Llvm(err.into()))?
         } else {
-            unsafe {
-                fun_val.delete();
+            let parent = self.current_function()?;
+            let array_type = element_type.array_type(values.len() as u32);
+            let alloca = self.create_entry_block_alloca(&parent, "array", array_type.into());
+            self.builder.build_pointer_cast(
+                alloca,
+                element_type.ptr_type(AddressSpace::Generic),
+                "data",
+            )
+        };
+
+        for (i, value) in values.iter().enumerate() {
+            let index = i64_type.const_int(i as u64, false);
+            let element = unsafe { self.builder.build_in_bounds_gep(data, &[index], "element") };
+            self.builder.build_store(element, *value);
+        }
+
+        let array = self.llvm_array_type(element_type).get_undef();
+        let array = self
+            .builder
+            .build_insert_value(array, len, 0, "array")
+            .and_then(|array| self.builder.build_insert_value(array, data, 1, "array"))
+            .ok_or_else(|| CodegenError::Llvm("Invalid array.".into()))?;
+        Ok(array.into_struct_value().into())
+    }
+
+    /// Build a pointer to the element of an array, the array being indexed at `span`.
+    /// With bounds checks, an index out of bounds calls the trap of the runtime,
+    /// which reports the location and never returns: the JIT unwinds out of the generated code,
+    /// while an executable aborts.
+    fn build_element_pointer(
+        &self,
+        array: BasicValueEnum<'ctx>,
+        index: BasicValueEnum<'ctx>,
+        span: Span,
+    ) -> Result<PointerValue<'ctx>, CodegenError> {
+        let (array, index) = match (array, index) {
+            (BasicValueEnum::StructValue(array), BasicValueEnum::IntValue(index)) => (array, index),
+            _ => {
+                return Err(CodegenError::Llvm(format!(
+                    "Invalid index {:?} of {:?}.",
+                    index.get_type(),
+                    array.get_type()
+                )))
             }
-            Err(format!(
-                "Generated function {} verification failed.",
-                func.prototype.name
-            ))
+        };
+        let field = |index, name| {
+            self.builder
+                .build_extract_value(array, index, name)
+                .ok_or_else(|| CodegenError::Llvm("Invalid array.".into()))
+        };
+        let data = field(1, "data")?.into_pointer_value();
+
+        if self.bounds_checks {
+            let len = field(0, "len")?.into_int_value();
+            // a negative index is a large unsigned one
+            let in_bounds =
+                self.builder
+                    .build_int_compare(IntPredicate::ULT, index, len, "inbounds");
+
+            let parent = self.current_function()?;
+            let out_of_bounds_bb = self.context.append_basic_block(parent, "outofbounds");
+            let in_bounds_bb = self.context.append_basic_block(parent, "inbounds");
+            self.builder
+                .build_conditional_branch(in_bounds, in_bounds_bb, out_of_bounds_bb);
+
+            self.builder.position_at_end(out_of_bounds_bb);
+            let i64_type = self.context.i64_type();
+            let line = i64_type.const_int(span.start.line as u64, false);
+            let column = i64_type.const_int(span.start.column as u64, false);
+            self.builder.build_call(
+                self.out_of_bounds_trap(),
+                &[index.into(), len.into(), line.into(), column.into()],
+                "",
+            );
+            self.builder.build_unreachable();
+
+            self.builder.position_at_end(in_bounds_bb);
         }
+
+        Ok(unsafe { self.builder.build_in_bounds_gep(data, &[index], "element") })
     }
-}
 
-pub fn create_inkwell_context() -> Context {
-    return Context::create();
-}
+    /// The function of the runtime called with an index out of bounds, its length and location,
+    /// declared in the current module
+    fn out_of_bounds_trap(&self) -> FunctionValue<'ctx> {
+        let i64_type: BasicTypeEnum = self.context.i64_type().into();
+        self.runtime_trap(OUT_OF_BOUNDS, &[i64_type; 4])
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The function of the runtime reporting an error found by the generated code,
+    /// declared in the current module
+    fn runtime_trap(&self, name: &str, params: &[BasicTypeEnum<'ctx>]) -> FunctionValue<'ctx> {
+        if let Some(trap) = self.module.get_function(name) {
+            return trap;
+        }
 
-    #[test]
-    fn compile_proto() {
-        let context = Context::create();
-        let cc = CodegenContext::new(&context, "test");
+        let fn_type = self.context.void_type().fn_type(params, false);
+        let trap = self.module.add_function(name, fn_type, None);
+        // the trap unwinds instead of returning
+        let noreturn = Attribute::get_named_enum_kind_id("noreturn");
+        trap.add_attribute(
+            AttributeLoc::Function,
+            self.context.create_enum_attribute(noreturn, 0),
+        );
 
-        let test_name = "test_func";
+        if let (Some(execution_engine), Some(address)) =
+            (&self.execution_engine, runtime_function(name))
+        {
+            execution_engine.add_global_mapping(&trap, address);
+        }
+        trap
+    }
+
+    /// Build a call, returning the value of the function.
+    /// The structs passed in memory are copied onto the stack of the caller,
+    /// which also allocates the struct returned in memory if any.
+    fn build_call(
+        &self,
+        func: FunctionValue<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+        name: &str,
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        self.build_indirect_call(func.as_global_value().as_pointer_value(), args, name)
+    }
+
+    /// Build a call through a pointer to a function, as `build_call`.
+    /// The call is marked with the `sret` and `byval` parameters,
+    /// which the function called may not be known to have.
+    fn build_indirect_call(
+        &self,
+        callee: PointerValue<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+        name: &str,
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let parent = self.current_function()?;
+        let fn_type = pointee_function_type(callee);
+        let param_types = fn_type.get_param_types();
+        let mut call_args = Vec::with_capacity(args.len() + 1);
 
-        let proto = Prototype {
-            name: test_name.into(),
-            args: vec!["arg1".into(), "arg2".into()],
+        let result = if returns_in_memory(fn_type) {
+            let result_type = param_types
+                .first()
+                .ok_or_else(|| CodegenError::Llvm("Invalid call.".into()))?
+                .into_pointer_type()
+                .get_element_type()
+                .into_struct_type();
+            let result = self.create_entry_block_alloca(&parent, name, result_type.into());
+            call_args.push(result.into());
+            Some(result)
+        } else {
+            None
         };
 
-        let compiled_proto = cc.compile_proto(&proto).unwrap();
+        let mut in_memory = vec![];
+        for (arg, param_type) in args.iter().zip(&param_types[call_args.len()..]) {
+            match (arg, param_type) {
+                (BasicValueEnum::StructValue(_), BasicTypeEnum::PointerType(_)) => {
+                    let copy = self.create_entry_block_alloca(&parent, "arg", arg.get_type());
+                    self.builder.build_store(copy, *arg);
+                    in_memory.push(call_args.len() as u32);
+                    call_args.push(copy.into());
+                }
+                _ => call_args.push(*arg),
+            }
+        }
 
-        println!("{:?}", compiled_proto);
-        assert_eq!(
-            test_name.to_string(),
-            compiled_proto.get_name().to_str().unwrap()
+        let call = self.builder.build_call(callee, &call_args, name);
+        if result.is_some() {
+            let sret = Attribute::get_named_enum_kind_id("sret");
+            call.add_attribute(
+                AttributeLoc::Param(0),
+                self.context.create_enum_attribute(sret, 0),
+            );
+        }
+        let byval = Attribute::get_named_enum_kind_id("byval");
+        for index in in_memory {
+            call.add_attribute(
+                AttributeLoc::Param(index),
+                self.context.create_enum_attribute(byval, 0),
+            );
+        }
+
+        match result {
+            Some(result) => Ok(self.builder.build_load(result, name)),
+            None => call
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| CodegenError::Llvm("Invalid call.".into())),
+        }
+    }
+
+    /// Build a closure of some code, copying the values it captures
+    /// into an environment on the heap, which is never freed, or null if it captures nothing
+    fn build_closure(
+        &self,
+        code: FunctionValue<'ctx>,
+        captured: &[BasicValueEnum<'ctx>],
+    ) -> Result<BasicValueEnum<'ctx>, CodegenError> {
+        let env_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let env = if captured.is_empty() {
+            env_ptr_type.const_null()
+        } else {
+            let env_type = self.llvm_env_type(captured);
+            let env = self
+                .builder
+                .build_malloc(env_type, "env")
+                .map_err(|err| CodegenError::Llvm(err.into()))?;
+            for (i, value) in captured.iter().enumerate() {
+                let field = self
+                    .builder
+                    .build_struct_gep(env, i as u32, "capture")
+                    .map_err(|_| CodegenError::Llvm("Invalid environment.".into()))?;
+                self.builder.build_store(field, *value);
+            }
+            self.builder.build_pointer_cast(env, env_ptr_type, "env")
+        };
+
+        let code = code.as_global_value().as_pointer_value();
+        let closure_type = self
+            .context
+            .struct_type(&[code.get_type().into(), env_ptr_type.into()], false);
+        let closure = self
+            .builder
+            .build_insert_value(closure_type.get_undef(), code, 0, "closure")
+            .and_then(|closure| self.builder.build_insert_value(closure, env, 1, "closure"))
+            .ok_or_else(|| CodegenError::Llvm("Invalid closure.".into()))?;
+        Ok(closure.into_struct_value().into())
+    }
+
+    /// The code of the closures of a function of type `ty` capturing values like `captured`,
+    /// e.g. `lambda.0.code` for the function `lambda.0`, with internal linkage in the current module.
+    /// It takes the environment before the args of the closure,
+    /// and calls the function with the captured values loaded from it before the args.
+    fn build_code(
+        &self,
+        func: FunctionValue<'ctx>,
+        ty: &Type,
+        captured: &[BasicValueEnum<'ctx>],
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let symbol = format!("{}.code", func.get_name().to_string_lossy());
+        if let Some(code) = self.module.get_function(&symbol) {
+            return Ok(code);
+        }
+        let function = match ty {
+            Type::Function(function) => function,
+            _ => return Err(CodegenError::Llvm(format!("Invalid closure of {}.", ty))),
+        };
+
+        let fn_type = self.llvm_function_type(&function.params, &function.ret, true);
+        let code = self
+            .module
+            .add_function(&symbol, fn_type, Some(Linkage::Internal));
+        self.add_memory_attributes(code, &function.params, true);
+        self.add_unwind_table(code);
+
+        // the code is built in the middle of the function creating the closure
+        let block = self.builder.get_insert_block();
+        let entry = self.context.append_basic_block(code, "entry");
+        self.builder.position_at_end(entry);
+
+        let sret = returns_in_memory(fn_type) as usize;
+        let params = code.get_params();
+        let env = self.builder.build_pointer_cast(
+            params[sret].into_pointer_value(),
+            self.llvm_env_type(captured).ptr_type(AddressSpace::Generic),
+            "env",
         );
+        let mut args = Vec::with_capacity(captured.len() + function.params.len());
+        for i in 0..captured.len() {
+            let capture = self
+                .builder
+                .build_struct_gep(env, i as u32, "capture")
+                .map_err(|_| CodegenError::Llvm("Invalid environment.".into()))?;
+            args.push(self.builder.build_load(capture, "capture"));
+        }
+        for (param, &arg) in function.params.iter().zip(&params[sret + 1..]) {
+            // a struct passed in memory is copied again for the call
+            args.push(if self.passed_in_memory(param) {
+                self.builder.build_load(arg.into_pointer_value(), "arg")
+            } else {
+                arg
+            });
+        }
+
+        let result = self.build_call(func, &args, "result")?;
+        match code.get_first_param().filter(|_| sret == 1) {
+            Some(pointer) => {
+                self.builder
+                    .build_store(pointer.into_pointer_value(), result);
+                self.builder.build_return(None);
+            }
+            None => {
+                self.builder.build_return(Some(&result));
+            }
+        }
+        if let Some(block) = block {
+            self.builder.position_at_end(block);
+        }
+
+        self.verify(code, &symbol, Default::default())?;
+        if let Some(fpm) = &self.function_pass_manager {
+            fpm.run_on(&code);
+        }
+        Ok(code)
+    }
+
+    /// The LLVM type of the environment of closures capturing values like `captured`
+    fn llvm_env_type(&self, captured: &[BasicValueEnum<'ctx>]) -> StructType<'ctx> {
+        let field_types: Vec<BasicTypeEnum> =
+            captured.iter().map(|value| value.get_type()).collect();
+        self.context.struct_type(&field_types, false)
+    }
+
+    /// The function the builder is currently inserting into
+    fn current_function(&self) -> Result<FunctionValue<'ctx>, CodegenError> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or_else(|| CodegenError::Llvm("Builder is not positioned in a function.".into()))
+    }
+
+    /// The LLVM type of values of a type
+    fn llvm_type(&self, ty: &Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::Int => self.context.i64_type().into(),
+            Type::Bool => self.context.bool_type().into(),
+            Type::Double => self.context.f64_type().into(),
+            Type::Str => self
+                .context
+                .i8_type()
+                .ptr_type(AddressSpace::Generic)
+                .into(),
+            Type::Array(element) => self.llvm_array_type(self.llvm_type(element)).into(),
+            Type::Named(name) => self.named_types[&**name].into(),
+            Type::Function(function) => {
+                let code_type = self
+                    .llvm_function_type(&function.params, &function.ret, true)
+                    .ptr_type(AddressSpace::Generic);
+                let env_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+                self.context
+                    .struct_type(&[code_type.into(), env_type.into()], false)
+                    .into()
+            }
+        }
+    }
+
+    /// The LLVM type of functions taking and returning values of some types.
+    /// A struct passed in memory is a pointer to a copy,
+    /// and a struct returned in memory is written through a pointer passed first.
+    /// The code of a closure takes its environment as an `i8*` before the args.
+    fn llvm_function_type(&self, params: &[Type], ret: &Type, env: bool) -> FunctionType<'ctx> {
+        let return_type = self.llvm_type(ret);
+        let sret = self.passed_in_memory(ret);
+
+        let mut arg_types: Vec<BasicTypeEnum> = Vec::with_capacity(params.len() + 2);
+        if sret {
+            arg_types.push(return_type.ptr_type(AddressSpace::Generic).into());
+        }
+        if env {
+            arg_types.push(
+                self.context
+                    .i8_type()
+                    .ptr_type(AddressSpace::Generic)
+                    .into(),
+            );
+        }
+        for param in params {
+            let arg_type = self.llvm_type(param);
+            arg_types.push(if self.passed_in_memory(param) {
+                arg_type.ptr_type(AddressSpace::Generic).into()
+            } else {
+                arg_type
+            });
+        }
+
+        if sret {
+            self.context.void_type().fn_type(&arg_types, false)
+        } else {
+            return_type.fn_type(&arg_types, false)
+        }
+    }
+
+    /// The LLVM type of arrays of elements of a type, `{ i64, T* }`
+    fn llvm_array_type(&self, element_type: BasicTypeEnum<'ctx>) -> StructType<'ctx> {
+        let len_type = self.context.i64_type().into();
+        let data_type = element_type.ptr_type(AddressSpace::Generic).into();
+        self.context.struct_type(&[len_type, data_type], false)
+    }
+
+    /// The size and alignment in bytes of values of a type in C,
+    /// a struct being laid out as a C struct of its fields,
+    /// and a union as a C struct of its tag and of the fields of its variants rounded up to 8 bytes
+    fn c_layout(&self, ty: &Type) -> (u64, u64) {
+        match ty {
+            Type::Bool => (1, 1),
+            Type::Array(_) | Type::Function(_) => (16, 8),
+            Type::Named(name) => match self.type_defs.get_struct(name) {
+                Some(def) => c_struct_layout(def.field_types.iter().map(|ty| self.c_layout(ty))),
+                None => (8 + 8 * self.union_payload_len(name), 8),
+            },
+            _ => (8, 8),
+        }
+    }
+
+    /// How many i64 the fields of the largest variant of a union take,
+    /// a field holding the union itself being a pointer
+    fn union_payload_len(&self, name: &str) -> u64 {
+        self.type_defs.get_union(name).map_or(0, |def| {
+            def.variants
+                .iter()
+                .map(|variant| {
+                    let fields = variant.field_types.iter().map(|ty| {
+                        if holds_itself(name, ty) {
+                            (8, 8)
+                        } else {
+                            self.c_layout(ty)
+                        }
+                    });
+                    c_struct_layout(fields).0.div_ceil(8)
+                })
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    /// Whether a value of a type is passed and returned in memory, which is the case of structs
+    /// larger than 16 bytes as in the x86-64 System V ABI.
+    /// Smaller structs are passed as LLVM aggregates,
+    /// which matches C when every field takes 8 bytes, e.g. ints, doubles, strings and arrays,
+    /// so `TypeDefs::check_extern` rejects the structs with bools and the other targets.
+    fn passed_in_memory(&self, ty: &Type) -> bool {
+        matches!(ty, Type::Named(_)) && self.c_layout(ty).0 > 16
+    }
+
+    /// How deep arrays are nested in values of a type, e.g. 2 for arrays of structs holding arrays
+    fn array_depth(&self, ty: &Type) -> usize {
+        match ty {
+            Type::Array(element) => 1 + self.array_depth(element),
+            Type::Named(name) => {
+                let field_types: Vec<Type> = match self.type_defs.get_struct(name) {
+                    Some(def) => def.field_types.clone(),
+                    None => self.type_defs.get_union(name).map_or(vec![], |def| {
+                        def.variants
+                            .iter()
+                            .flat_map(|variant| variant.field_types.iter().cloned())
+                            .collect()
+                    }),
+                };
+                // a union holding itself is as deep as its other fields
+                field_types
+                    .into_iter()
+                    .filter(|field| field != ty)
+                    .map(|field| self.array_depth(&field))
+                    .max()
+                    .unwrap_or(0)
+            }
+            // a closure may capture arrays
+            Type::Function(_) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Generate code of proto, convert a function prototype to a FunctionValue.
+    /// A struct passed in memory is a `byval` pointer to a copy,
+    /// and a struct returned in memory is written through an `sret` pointer passed first.
+    pub fn compile_proto(&self, proto: &Prototype) -> Result<FunctionValue<'ctx>, CodegenError> {
+        self.compile_proto_as(proto, &proto.name)
+    }
+
+    /// Generate code of proto under another symbol, e.g. for a body of a function defined with the JIT
+    fn compile_proto_as(
+        &self,
+        proto: &Prototype,
+        symbol: &str,
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let arg_types: Vec<Type> = (0..proto.args.len()).map(|i| proto.arg_type(i)).collect();
+        let fn_type = self.llvm_function_type(&arg_types, &proto.return_type(), false);
+        let sret = returns_in_memory(fn_type);
+        let fn_val = self.module.add_function(symbol, fn_type, None);
+
+        self.add_memory_attributes(fn_val, &arg_types, false);
+        let offset = sret as usize;
+        if sret {
+            fn_val
+                .get_first_param()
+                .unwrap()
+                .into_pointer_value()
+                .set_name("result");
+        }
+
+        // set argument names, which are all ints, bools, doubles, strings, arrays, structs
+        // or pointers to structs
+        for (i, arg) in fn_val.get_param_iter().skip(offset).enumerate() {
+            match arg {
+                BasicValueEnum::IntValue(arg) => arg.set_name(&proto.args[i]),
+                BasicValueEnum::FloatValue(arg) => arg.set_name(&proto.args[i]),
+                BasicValueEnum::PointerValue(arg) => arg.set_name(&proto.args[i]),
+                BasicValueEnum::StructValue(arg) => arg.set_name(&proto.args[i]),
+                _ => {}
+            }
+        }
+
+        Ok(fn_val)
+    }
+
+    /// Mark the pointer to the struct a function returns in memory as `sret`,
+    /// and the pointers to the structs passed in memory among its args of types `params` as `byval`.
+    /// With `env`, the args come after an environment as for the code of a closure.
+    fn add_memory_attributes(&self, fn_val: FunctionValue<'ctx>, params: &[Type], env: bool) {
+        let sret = returns_in_memory(fn_val.get_type());
+        if sret {
+            let sret = Attribute::get_named_enum_kind_id("sret");
+            fn_val.add_attribute(
+                AttributeLoc::Param(0),
+                self.context.create_enum_attribute(sret, 0),
+            );
+        }
+
+        let offset = sret as usize + env as usize;
+        let byval = Attribute::get_named_enum_kind_id("byval");
+        for (i, param) in params.iter().enumerate() {
+            if self.passed_in_memory(param) {
+                fn_val.add_attribute(
+                    AttributeLoc::Param((i + offset) as u32),
+                    self.context.create_enum_attribute(byval, 0),
+                );
+            }
+        }
+    }
+
+    /// Give a function the unwind table needed to unwind its frames when the runtime reports an error,
+    /// e.g. an index out of bounds, unwinding out of the generated code
+    fn add_unwind_table(&self, fn_val: FunctionValue<'ctx>) {
+        let uwtable = Attribute::get_named_enum_kind_id("uwtable");
+        fn_val.add_attribute(
+            AttributeLoc::Function,
+            self.context.create_enum_attribute(uwtable, 0),
+        );
+    }
+
+    /// Creates a new stack allocation instruction
+    pub fn create_entry_block_alloca(
+        &self,
+        fun_val: &FunctionValue,
+        name: &str,
+        ty: BasicTypeEnum<'ctx>,
+    ) -> PointerValue<'ctx> {
+        let builder = self.context.create_builder();
+
+        let entry = fun_val.get_first_basic_block().unwrap();
+
+        match entry.get_first_instruction() {
+            Some(first_instr) => builder.position_before(&first_instr),
+            None => builder.position_at_end(entry),
+        }
+
+        builder.build_alloca(ty, name)
+    }
+
+    /// The symbol the next body of a function is compiled under.
+    /// The JIT keeps resolving a symbol to its first definition,
+    /// so with a JIT, every definition of a named function gets a symbol of its own,
+    /// and the function itself is a trampoline calling its latest body.
+    fn body_symbol(&self, proto: &Prototype) -> String {
+        if self.execution_engine.is_none() || proto.is_anonymous() {
+            return proto.name.clone();
+        }
+        let version = self.function_versions.get(&proto.name).unwrap_or(&0);
+        format!("{}.{}", proto.name, version)
+    }
+
+    /// Check a prototype against the previous declaration or definition of the same function,
+    /// both with every type annotated.
+    /// The parameters and types of an extern must be repeated exactly,
+    /// while a redefinition may rename the parameters but must keep the types,
+    /// which the functions calling it were compiled against.
+    fn check_prototype(&self, proto: &Prototype) -> Result<(), CodegenError> {
+        let previous = match self.function_protos.get(&proto.name) {
+            Some(previous) => previous,
+            None => return Ok(()),
+        };
+
+        let matches = if self.defined_functions.contains_key(&proto.name) {
+            previous.arg_types == proto.arg_types && previous.ret_type == proto.ret_type
+        } else {
+            previous.args == proto.args
+                && previous.arg_types == proto.arg_types
+                && previous.ret_type == proto.ret_type
+        };
+        if matches {
+            return Ok(());
+        }
+
+        Err(CodegenError::PrototypeMismatch(Box::new(
+            PrototypeMismatch {
+                name: proto.name.clone(),
+                expected: previous.to_string(),
+                found: proto.to_string(),
+                span: proto.span,
+                previous: previous.span,
+            },
+        )))
+    }
+
+    /// Generate code of an extern, and remember its prototype for later modules.
+    /// The args and return value without a type annotation are doubles.
+    /// With a JIT, the functions of the runtime are resolved to the ones linked into the compiler.
+    pub fn compile_extern(
+        &mut self,
+        proto: &Prototype,
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let proto = &proto.annotated();
+        self.type_defs
+            .check_extern(proto)
+            .map_err(CodegenError::Type)?;
+        self.check_prototype(proto)?;
+
+        // redeclaring a function keeps the prototype it was defined with
+        if !self.defined_functions.contains_key(&proto.name) {
+            self.function_protos
+                .insert(proto.name.clone(), proto.clone());
+        }
+        let fun_val = match self.get_function(&proto.name) {
+            Some(fun_val) => fun_val,
+            None => self.compile_proto(proto)?,
+        };
+
+        // a function defined by the program keeps its definition
+        let address = runtime_function(&proto.name)
+            .filter(|_| !self.defined_functions.contains_key(&proto.name));
+        if let (Some(execution_engine), Some(address)) = (&self.execution_engine, address) {
+            execution_engine.add_global_mapping(&fun_val, address);
+        }
+        Ok(fun_val)
+    }
+
+    /// Declare a function defined later, so that the functions before its definition can call it.
+    /// With a JIT, the function is a trampoline calling a body reporting that it is not defined,
+    /// until the function is defined.
+    pub fn declare_func(&mut self, proto: &Prototype) -> Result<(), CodegenError> {
+        let proto = &proto.annotated();
+        self.type_defs
+            .check_annotations(proto)
+            .map_err(CodegenError::Type)?;
+        self.check_prototype(proto)?;
+        if self.function_protos.contains_key(&proto.name) {
+            return Ok(());
+        }
+
+        self.function_protos
+            .insert(proto.name.clone(), proto.clone());
+        if self.execution_engine.is_some() {
+            self.build_undefined_body(proto)?;
+        }
+        Ok(())
+    }
+
+    /// Compile the body of a function declared but not defined yet, which calls the runtime to report it
+    fn build_undefined_body(&mut self, proto: &Prototype) -> Result<(), CodegenError> {
+        let symbol = self.body_symbol(proto);
+        let fun_val = self.compile_proto_as(proto, &symbol)?;
+        self.add_unwind_table(fun_val);
+
+        let entry = self.context.append_basic_block(fun_val, "entry");
+        self.builder.position_at_end(entry);
+        let name = self
+            .builder
+            .build_global_string_ptr(&proto.name, "name")
+            .as_pointer_value();
+        let trap = self.runtime_trap(UNDEFINED, &[name.get_type().into()]);
+        self.builder.build_call(trap, &[name.into()], "");
+        self.builder.build_unreachable();
+        self.verify(fun_val, &proto.name, proto.span)?;
+
+        self.build_trampoline(proto)?;
+        *self
+            .function_versions
+            .entry(proto.name.clone())
+            .or_default() += 1;
+        self.unlinked_bodies.push((proto.name.clone(), symbol));
+        Ok(())
+    }
+
+    /// Type check and generate code of a function.
+    /// Without a JIT, a function can only be defined once.
+    /// With a JIT, a redefinition is compiled under a new symbol, which the trampoline of the function
+    /// calls from the next evaluation on, so that every caller calls the latest definition.
+    pub fn compile_func(&mut self, func: &Function) -> Result<FunctionValue<'ctx>, CodegenError> {
+        self.lower(func)
+    }
+
+    /// Compile `f'` for `def' f`, calling the gradient of `f`, along with the functions computing it
+    pub fn compile_derivative(
+        &mut self,
+        def: &DerivativeDef,
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        self.lower_derivative(def)
+    }
+
+    fn compile_checked(&mut self, func: &Function) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let name = &func.prototype.name;
+        self.check_prototype(&func.prototype)?;
+
+        if self.defined_functions.contains_key(name) && self.execution_engine.is_none() {
+            return Err(CodegenError::Redefinition {
+                name: name.clone(),
+                span: func.prototype.span,
+                previous: self.function_protos[name].span,
+            });
+        }
+        let previous_proto = self
+            .function_protos
+            .insert(name.clone(), func.prototype.clone());
+
+        // forget the new prototype if the function cannot be compiled,
+        // so that later code keeps calling the previous definition
+        let result = self.compile_func_body(func);
+        if result.is_err() {
+            match previous_proto {
+                Some(proto) => self.function_protos.insert(name.clone(), proto),
+                None => self.function_protos.remove(name),
+            };
+        }
+        result
+    }
+
+    fn compile_func_body(&mut self, func: &Function) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let symbol = self.body_symbol(&func.prototype);
+
+        // an extern declared in this module may already be called by other functions
+        let declared = self.module.get_function(&symbol);
+
+        // if the FunctionValue does not exist, compile it.
+        let fun_val = match declared {
+            Some(func) => func,
+            None => self.compile_proto_as(&func.prototype, &symbol)?,
+        };
+        let declared = declared.is_some();
+        self.add_unwind_table(fun_val);
+
+        let basic_block = self.context.append_basic_block(fun_val, "entry");
+        self.builder.position_at_end(basic_block);
+
+        // arrays returned or stored into arrays of arrays of the caller outlive the call
+        let proto = &func.prototype;
+        self.heap_arrays = self.array_depth(&proto.return_type()) > 0
+            || (0..proto.args.len()).any(|i| self.array_depth(&proto.arg_type(i)) > 1);
+        self.loop_depth = 0;
+
+        let sret = self.passed_in_memory(&proto.return_type());
+        let result = fun_val.get_first_param().filter(|_| sret);
+
+        // record the functioin arguments in the named_values
+        self.named_values.clear();
+        for (i, arg) in fun_val.get_param_iter().skip(sret as usize).enumerate() {
+            let arg_name = &func.prototype.args[i];
+
+            // a struct passed in memory is already a copy owned by the function
+            if self.passed_in_memory(&proto.arg_type(i)) {
+                self.named_values
+                    .insert(arg_name.into(), arg.into_pointer_value());
+                continue;
+            }
+
+            let alloca = self.create_entry_block_alloca(&fun_val, arg_name, arg.get_type());
+
+            self.builder.build_store(alloca, arg);
+
+            self.named_values.insert(arg_name.into(), alloca);
+        }
+
+        // do not leave a half built function in the module
+        let body = match self.compile_expr(&func.body) {
+            Ok(body) => body,
+            Err(err) => {
+                unsafe {
+                    if declared {
+                        for block in fun_val.get_basic_blocks() {
+                            let _ = block.delete();
+                        }
+                    } else {
+                        fun_val.delete();
+                    }
+                }
+                return Err(err);
+            }
+        };
+        match result {
+            Some(result) => {
+                self.builder.build_store(result.into_pointer_value(), body);
+                self.builder.build_return(None);
+            }
+            None => {
+                self.builder.build_return(Some(&body));
+            }
+        }
+
+        self.verify(fun_val, &func.prototype.name, func.span)?;
+
+        if let Some(fpm) = &self.function_pass_manager {
+            fpm.run_on(&fun_val);
+        }
+
+        // anonymous functions are removed right after being evaluated
+        if func.prototype.is_anonymous() {
+            return Ok(fun_val);
+        }
+        if symbol != func.prototype.name {
+            if !self.function_slots.contains_key(&func.prototype.name) {
+                self.build_trampoline(&func.prototype)?;
+            }
+            *self
+                .function_versions
+                .entry(func.prototype.name.clone())
+                .or_default() += 1;
+            self.unlinked_bodies
+                .push((func.prototype.name.clone(), symbol));
+        }
+        self.defined_functions
+            .insert(func.prototype.name.clone(), fun_val);
+        Ok(fun_val)
+    }
+
+    /// Define the function called under the name of a function defined with the JIT,
+    /// which calls the latest body of the function through a slot holding its address
+    fn build_trampoline(&mut self, proto: &Prototype) -> Result<(), CodegenError> {
+        // the body may call itself, and an extern of the same name may be declared in this module
+        let trampoline = match self.module.get_function(&proto.name) {
+            Some(trampoline) => trampoline,
+            None => self.compile_proto(proto)?,
+        };
+        self.add_unwind_table(trampoline);
+
+        let slot_type = trampoline.get_type().ptr_type(AddressSpace::Generic);
+        let slot = self
+            .module
+            .add_global(slot_type, None, &format!("{}.slot", proto.name));
+        let address = Box::new(Cell::new(0));
+        self.jit()?
+            .add_global_mapping(&slot, address.as_ptr() as usize);
+        self.function_slots.insert(proto.name.clone(), address);
+
+        let entry = self.context.append_basic_block(trampoline, "entry");
+        self.builder.position_at_end(entry);
+        let body = self
+            .builder
+            .build_load(slot.as_pointer_value(), "body")
+            .into_pointer_value();
+        let args: Vec<BasicValueEnum> = trampoline.get_param_iter().collect();
+        let call = self.builder.build_call(body, &args, "call");
+
+        // the struct returned in memory and the structs passed in memory are passed along as they are
+        let sret = returns_in_memory(trampoline.get_type());
+        if sret {
+            let sret = Attribute::get_named_enum_kind_id("sret");
+            call.add_attribute(
+                AttributeLoc::Param(0),
+                self.context.create_enum_attribute(sret, 0),
+            );
+        }
+        let byval = Attribute::get_named_enum_kind_id("byval");
+        for i in 0..proto.args.len() {
+            if self.passed_in_memory(&proto.arg_type(i)) {
+                call.add_attribute(
+                    AttributeLoc::Param((i + sret as usize) as u32),
+                    self.context.create_enum_attribute(byval, 0),
+                );
+            }
+        }
+
+        match call.try_as_basic_value().left() {
+            Some(result) => self.builder.build_return(Some(&result)),
+            None => self.builder.build_return(None),
+        };
+        self.verify(trampoline, &proto.name, proto.span)
+    }
+
+    /// Type check a struct and generate its named LLVM type, e.g. `%Point = type { double, double }`
+    pub fn compile_struct(&mut self, def: &StructDef) -> Result<StructType<'ctx>, CodegenError> {
+        self.type_defs
+            .define_struct(def)
+            .map_err(CodegenError::Type)?;
+
+        let struct_type = self.build_named_type(&def.name, &def.field_types);
+        self.named_types.insert(def.name.clone(), struct_type);
+        Ok(struct_type)
+    }
+
+    /// Type check a union and generate its named LLVM type, e.g. `%Shape = type { i64, [2 x i64] }`,
+    /// along with a named type for the fields of each variant, e.g. `%Shape.Rect = type { double, double }`,
+    /// which the space after the tag is cast to.
+    /// A field holding the union itself is a pointer to a copy on the heap,
+    /// e.g. `%Tree.Node = type { %Tree*, double, %Tree* }`.
+    pub fn compile_union(&mut self, def: &UnionDef) -> Result<StructType<'ctx>, CodegenError> {
+        self.type_defs
+            .define_union(def)
+            .map_err(CodegenError::Type)?;
+
+        // the union is named before its variants, which may hold it
+        let union_type = self.context.opaque_struct_type(&def.name);
+        self.named_types.insert(def.name.clone(), union_type);
+
+        let variant_types = def
+            .variants
+            .iter()
+            .map(|variant| {
+                let field_types: Vec<BasicTypeEnum> = variant
+                    .field_types
+                    .iter()
+                    .map(|ty| {
+                        let field_type = self.llvm_type(ty);
+                        if holds_itself(&def.name, ty) {
+                            field_type.ptr_type(AddressSpace::Generic).into()
+                        } else {
+                            field_type
+                        }
+                    })
+                    .collect();
+                let name = format!("{}.{}", def.name, variant.name);
+                let variant_type = self.context.opaque_struct_type(&name);
+                variant_type.set_body(&field_types, false);
+                variant_type
+            })
+            .collect();
+
+        let i64_type = self.context.i64_type();
+        let payload_type = i64_type.array_type(self.union_payload_len(&def.name) as u32);
+        union_type.set_body(&[i64_type.into(), payload_type.into()], false);
+
+        self.variant_types.insert(def.name.clone(), variant_types);
+        Ok(union_type)
+    }
+
+    /// Generate a named LLVM struct of fields of some types
+    fn build_named_type(&self, name: &str, field_types: &[Type]) -> StructType<'ctx> {
+        let field_types: Vec<BasicTypeEnum> =
+            field_types.iter().map(|ty| self.llvm_type(ty)).collect();
+        let struct_type = self.context.opaque_struct_type(name);
+        struct_type.set_body(&field_types, false);
+        struct_type
+    }
+
+    /// Compile a top level definition and hand it over to the JIT,
+    /// so that it can be called from later expressions.
+    pub fn define_func(&mut self, func: &Function) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let fun_val = self.compile_func(func)?;
+        self.flush_module()?;
+        Ok(fun_val)
+    }
+
+    /// Compile `f'` for `def' f` and hand it over to the JIT,
+    /// so that it can be called from later expressions.
+    pub fn define_derivative(
+        &mut self,
+        def: &DerivativeDef,
+    ) -> Result<FunctionValue<'ctx>, CodegenError> {
+        let fun_val = self.compile_derivative(def)?;
+        self.flush_module()?;
+        Ok(fun_val)
+    }
+
+    /// JIT-compile an anonymous function wrapping a top level expression and run it.
+    /// The module containing it is removed from the JIT afterwards,
+    /// even if the expression fails, e.g. with an index out of bounds.
+    pub fn evaluate(&mut self, func: &Function) -> Result<f64, CodegenError> {
+        self.lower_and_run(func, |cc, func| {
+            cc.compile_checked(func)?;
+            let module = cc.flush_module()?;
+
+            let result = cc.link_bodies().and_then(|_| {
+                let address = cc
+                    .jit()?
+                    .get_function_address(&func.prototype.name)
+                    .map_err(|err| {
+                        CodegenError::Llvm(format!(
+                            "Failed to JIT {}: {:?}",
+                            func.prototype.name, err
+                        ))
+                    })?;
+                let fun = unsafe { std::mem::transmute::<usize, AnonymousFunction>(address) };
+                catch_traps(|| unsafe { fun() }).map_err(CodegenError::Runtime)
+            });
+
+            cc.jit()?.remove_module(&module).map_err(|err| {
+                CodegenError::Llvm(format!("Failed to remove module from JIT: {:?}", err))
+            })?;
+
+            result
+        })
+    }
+
+    /// Generate a `main` calling the given functions in order and returning 0,
+    /// e.g. to run the top level expressions of a file compiled ahead of time
+    pub fn compile_main(&mut self, funcs: &[String]) -> Result<FunctionValue<'ctx>, CodegenError> {
+        if self.get_function("main").is_some() {
+            return Err(CodegenError::Redefinition {
+                name: "main".into(),
+                span: Default::default(),
+                previous: self
+                    .function_protos
+                    .get("main")
+                    .map(|proto| proto.span)
+                    .unwrap_or_default(),
+            });
+        }
+
+        let i32_type = self.context.i32_type();
+        let main = self
+            .module
+            .add_function("main", i32_type.fn_type(&[], false), None);
+        let entry = self.context.append_basic_block(main, "entry");
+        self.builder.position_at_end(entry);
+
+        for name in funcs {
+            let func = match self.get_function(name) {
+                Some(func) => func,
+                None => {
+                    unsafe {
+                        main.delete();
+                    }
+                    return Err(CodegenError::UnknownFunction {
+                        name: name.clone(),
+                        span: Default::default(),
+                    });
+                }
+            };
+            self.builder.build_call(func, &[], "");
+        }
+
+        self.builder
+            .build_return(Some(&i32_type.const_int(0, false)));
+
+        self.verify(main, "main", Default::default())?;
+        Ok(main)
+    }
+
+    /// Verify a generated function, deleting it if it is invalid
+    fn verify(
+        &self,
+        fun_val: FunctionValue<'ctx>,
+        name: &str,
+        span: Span,
+    ) -> Result<(), CodegenError> {
+        if fun_val.verify(true) {
+            return Ok(());
+        }
+
+        let ir = fun_val.print_to_string().to_string();
+        unsafe {
+            fun_val.delete();
+        }
+        Err(CodegenError::VerificationFailed {
+            name: name.into(),
+            ir,
+            span,
+        })
+    }
+
+    /// Point the trampolines of the functions defined since the last evaluation at their latest bodies,
+    /// which are compiled by the JIT only once something is evaluated,
+    /// so that the functions they call can be defined after them
+    fn link_bodies(&mut self) -> Result<(), CodegenError> {
+        let execution_engine = self
+            .execution_engine
+            .as_ref()
+            .ok_or(CodegenError::JitNotEnabled)?;
+        for (name, symbol) in self.unlinked_bodies.drain(..) {
+            let address = execution_engine
+                .get_function_address(&symbol)
+                .map_err(|err| CodegenError::Llvm(format!("Failed to JIT {}: {:?}", name, err)))?;
+            self.function_slots[&name].set(address);
+        }
+        Ok(())
+    }
+
+    fn jit(&self) -> Result<&ExecutionEngine<'ctx>, CodegenError> {
+        self.execution_engine
+            .as_ref()
+            .ok_or(CodegenError::JitNotEnabled)
+    }
+
+    /// Hand the current module over to the JIT and start a new one
+    fn flush_module(&mut self) -> Result<Module<'ctx>, CodegenError> {
+        self.jit()?;
+        self.module_count += 1;
+        let new_module = self
+            .context
+            .create_module(&format!("{}_{}", self.module_name, self.module_count));
+        let module = std::mem::replace(&mut self.module, new_module);
+        self.function_pass_manager =
+            passes::create_function_pass_manager(&self.module, self.opt_level);
+
+        passes::optimize_module(&module, self.opt_level);
+        self.jit()?
+            .add_module(&module)
+            .map_err(|_| CodegenError::Llvm("Failed to add module to JIT.".into()))?;
+
+        Ok(module)
+    }
+}
+
+impl<'ctx> Lower for CodegenContext<'ctx> {
+    type Output = FunctionValue<'ctx>;
+    type Error = CodegenError;
+
+    fn front_end(&mut self) -> (&mut Lowering, &HashMap<String, Prototype>, &TypeDefs) {
+        (&mut self.lowering, &self.function_protos, &self.type_defs)
+    }
+
+    fn declare_lowered(&mut self, proto: &Prototype) -> Result<(), CodegenError> {
+        self.function_protos
+            .insert(proto.name.clone(), proto.clone());
+        Ok(())
+    }
+
+    fn define_lowered(&mut self, func: &Function) -> Result<FunctionValue<'ctx>, CodegenError> {
+        self.compile_checked(func)
+    }
+
+    /// Forget a function, deleting what was compiled of it into the current module,
+    /// as the module it was handed over to the JIT in is removed from the JIT with it
+    fn forget_lowered(&mut self, name: &str) {
+        self.function_protos.remove(name);
+        self.defined_functions.remove(name);
+        self.function_versions.remove(name);
+        self.function_slots.remove(name);
+
+        let mut symbols = vec![];
+        self.unlinked_bodies.retain(|(function, symbol)| {
+            if function != name {
+                return true;
+            }
+            symbols.push(symbol.clone());
+            false
+        });
+        // the bodies first, as they may call the trampoline, which loads the slot
+        symbols.push(name.into());
+        for symbol in &symbols {
+            if let Some(fun_val) = self.module.get_function(symbol) {
+                unsafe {
+                    fun_val.delete();
+                }
+            }
+        }
+        if let Some(slot) = self.module.get_global(&format!("{}.slot", name)) {
+            unsafe {
+                slot.delete();
+            }
+        }
+    }
+
+    /// The derivatives outlive the anonymous function whose module is removed from the JIT,
+    /// unlike its lambdas
+    fn derivatives_defined(&mut self) -> Result<(), CodegenError> {
+        if self.execution_engine.is_some() {
+            self.flush_module()?;
+        }
+        Ok(())
+    }
+}
+
+impl<'ctx> Backend for CodegenContext<'ctx> {
+    fn declare_extern(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        self.compile_extern(proto)
+            .map(|_| ())
+            .map_err(|err| Box::new(err.into_diagnostic(proto.span)))
+    }
+
+    fn declare_function(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        self.declare_func(proto)
+            .map_err(|err| Box::new(err.into_diagnostic(proto.span)))
+    }
+
+    fn define_function(&mut self, func: &Function) -> Result<(), Box<Diagnostic>> {
+        self.define_func(func)
+            .map(|_| ())
+            .map_err(|err| Box::new(err.into_diagnostic(func.span)))
+    }
+
+    fn define_derivative(&mut self, def: &DerivativeDef) -> Result<(), Box<Diagnostic>> {
+        CodegenContext::define_derivative(self, def)
+            .map(|_| ())
+            .map_err(|err| Box::new(err.into_diagnostic(def.span)))
+    }
+
+    fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<Diagnostic>> {
+        self.compile_struct(def)
+            .map(|_| ())
+            .map_err(|err| Box::new(err.into_diagnostic(def.span)))
+    }
+
+    fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<Diagnostic>> {
+        self.compile_union(def)
+            .map(|_| ())
+            .map_err(|err| Box::new(err.into_diagnostic(def.span)))
+    }
+
+    fn evaluate(&mut self, func: &Function) -> Result<f64, Box<Diagnostic>> {
+        CodegenContext::evaluate(self, func).map_err(|err| Box::new(err.into_diagnostic(func.span)))
+    }
+
+    /// The LLVM IR of the function
+    fn dump(&self, name: &str) -> Option<String> {
+        self.defined_functions
+            .get(name)
+            .copied()
+            .or_else(|| self.module.get_function(name))
+            .map(|func| func.print_to_string().to_string())
+    }
+}
+
+/// The size and alignment in bytes of a C struct of fields of some sizes and alignments
+fn c_struct_layout(fields: impl Iterator<Item = (u64, u64)>) -> (u64, u64) {
+    let (mut size, mut align): (u64, u64) = (0, 1);
+    for (field_size, field_align) in fields {
+        size = size.div_ceil(field_align) * field_align + field_size;
+        align = align.max(field_align);
+    }
+    (size.div_ceil(align) * align, align)
+}
+
+/// Whether a field of a variant of the union `union` holds the union itself
+fn holds_itself(union: &str, ty: &Type) -> bool {
+    matches!(ty, Type::Named(name) if &**name == union)
+}
+
+/// Whether a function returns a struct in memory, through a pointer passed first.
+/// Every other function returns a value.
+fn returns_in_memory(fn_type: FunctionType) -> bool {
+    fn_type.get_return_type().is_none()
+}
+
+/// The number of args of a function, without the pointer to the struct it returns in memory
+fn arity(fn_type: FunctionType) -> usize {
+    fn_type.count_param_types() as usize - returns_in_memory(fn_type) as usize
+}
+
+/// The type of the function a pointer points to
+fn pointee_function_type(callee: PointerValue) -> FunctionType {
+    callee.get_type().get_element_type().into_function_type()
+}
+
+pub fn create_inkwell_context() -> Context {
+    return Context::create();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+    use crate::types::TypeError;
+
+    #[test]
+    fn compile_proto() {
+        let context = Context::create();
+        let cc = CodegenContext::new(&context, "test").unwrap();
+
+        let test_name = "test_func";
+
+        let proto = Prototype::new(
+            test_name,
+            vec!["arg1".into(), "arg2".into()],
+            Default::default(),
+        );
+
+        let compiled_proto = cc.compile_proto(&proto).unwrap();
+
+        println!("{:?}", compiled_proto);
+        assert_eq!(
+            test_name.to_string(),
+            compiled_proto.get_name().to_str().unwrap()
+        );
+    }
+
+    fn evaluate_all(program: &str) -> Vec<f64> {
+        evaluate_all_at(program, OptimizationLevel::None)
+    }
+
+    fn evaluate_all_at(program: &str, level: OptimizationLevel) -> Vec<f64> {
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+        cc.set_opt_level(level);
+
+        let mut parser = Parser::new(Lexer::new(program.chars()));
+
+        let mut results = vec![];
+        loop {
+            match parser.parse().unwrap() {
+                ASTNode::ExternNode(proto) => {
+                    cc.compile_extern(&proto).unwrap();
+                }
+                ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                    results.push(cc.evaluate(&func).unwrap());
+                }
+                ASTNode::FunctionNode(func) => {
+                    cc.define_func(&func).unwrap();
+                }
+                ASTNode::DerivativeNode(def) => {
+                    cc.define_derivative(&def).unwrap();
+                }
+                ASTNode::StructNode(def) => {
+                    cc.compile_struct(&def).unwrap();
+                }
+                ASTNode::UnionNode(def) => {
+                    cc.compile_union(&def).unwrap();
+                }
+                ASTNode::Delimiter => continue,
+                ASTNode::EOF => return results,
+            }
+        }
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        assert_eq!(evaluate_all("1+2*3; 4-2;"), vec![7.0, 2.0]);
+    }
+
+    #[test]
+    fn call_defined_functions() {
+        assert_eq!(
+            evaluate_all(
+                "
+                def square(x) x*x;
+                def add(a b) a+b;
+                add(square(2), 1);
+                square(add(1, 2));
+                "
+            ),
+            vec![5.0, 9.0]
+        );
+    }
+
+    #[test]
+    fn if_expr() {
+        assert_eq!(
+            evaluate_all(
+                "
+                def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2);
+                fib(10);
+                if 0 then 1 else 2;
+                if 3 then 1 else 2;
+                "
+            ),
+            vec![55.0, 2.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn for_expr() {
+        assert_eq!(
+            evaluate_all(
+                "
+                def count(n) for i = 0, i < n in 1;
+                def shadow(i) (for i = 0, i < 3, 0.5 in i) + i;
+                count(5);
+                shadow(7);
+                "
+            ),
+            vec![0.0, 7.0]
+        );
+    }
+
+    #[test]
+    fn user_defined_operators() {
+        assert_eq!(
+            evaluate_all(
+                "
+                def unary!(v) if v then 0 else 1;
+                def unary-(v) 0-v;
+                def binary| 5 (lhs rhs) if lhs then 1 else if rhs then 1 else 0;
+                def binary : 1 (x y) y;
+                !0; !3;
+                0 | 1; 0 | 0;
+                -3 + 1;
+                1 : 2 : 3;
+                "
+            ),
+            vec![1.0, 0.0, 1.0, 0.0, -2.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn mutable_variables() {
+        assert_eq!(
+            evaluate_all(
+                "
+                def binary : 1 (x y) y;
+                def sum(n) var acc = 0 in (for i = 0, i < n in acc = acc + i) : acc;
+                def shadow(x) (var x = 2, y in x + y) + x;
+                def swap(a b) var tmp = a in a = b : b = tmp : a - b;
+                sum(5);
+                shadow(5);
+                swap(1, 3);
+                "
+            ),
+            vec![15.0, 7.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn invalid_assignments() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+
+        for program in &["def unknown(x) y = 1", "def not_var(x) x + 1 = 1"] {
+            match Parser::new(Lexer::new(program.chars())).parse().unwrap() {
+                ASTNode::FunctionNode(func) => assert!(cc.define_func(&func).is_err()),
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+    }
+
+    /// Compile the items of a program into one module, returning the first error
+    fn first_error(program: &str) -> CodegenError {
+        let context = Context::create();
+        let mut cc = CodegenContext::new_without_jit(&context, "test");
+
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            let result = match node {
+                ASTNode::ExternNode(proto) => cc.compile_extern(&proto).map(|_| ()),
+                ASTNode::FunctionNode(func) => cc.compile_func(&func).map(|_| ()),
+                ASTNode::DerivativeNode(def) => cc.compile_derivative(&def).map(|_| ()),
+                ASTNode::StructNode(def) => cc.compile_struct(&def).map(|_| ()),
+                ASTNode::UnionNode(def) => cc.compile_union(&def).map(|_| ()),
+                _ => continue,
+            };
+            if let Err(err) = result {
+                return err;
+            }
+        }
+        panic!("{} should not compile", program);
+    }
+
+    /// The span from `start` to `end` on the first line, both 1-based columns
+    fn columns(start: usize, end: usize) -> Span {
+        let mut start_pos = crate::util::Position::default();
+        for _ in 1..start {
+            start_pos = start_pos.after(' ');
+        }
+        let mut end_pos = start_pos;
+        for _ in start..end {
+            end_pos = end_pos.after(' ');
+        }
+        Span::new(start_pos, end_pos)
+    }
+
+    #[test]
+    fn codegen_errors() {
+        assert_eq!(
+            first_error("def f(x) y"),
+            CodegenError::UnknownVariable {
+                name: "y".into(),
+                span: columns(10, 11)
+            }
+        );
+        assert_eq!(
+            first_error("def f(x) y = 1"),
+            CodegenError::UnknownVariable {
+                name: "y".into(),
+                span: columns(10, 11)
+            }
+        );
+        assert_eq!(
+            first_error("def f(x) g(x)"),
+            CodegenError::UnknownFunction {
+                name: "g".into(),
+                span: columns(10, 14)
+            }
+        );
+        assert_eq!(
+            first_error("def g(x) x; def f(x) g(x, x)"),
+            CodegenError::ArityMismatch {
+                name: "g".into(),
+                expected: 1,
+                found: 2,
+                span: columns(22, 29)
+            }
+        );
+        assert_eq!(
+            first_error("def f(x) !x"),
+            CodegenError::UnknownOperator {
+                op: '!',
+                unary: true,
+                span: columns(10, 12)
+            }
+        );
+        assert_eq!(
+            first_error("def f(x) x; def f(y) y"),
+            CodegenError::Redefinition {
+                name: "f".into(),
+                span: columns(17, 21),
+                previous: columns(5, 9)
+            }
+        );
+        assert_eq!(
+            first_error("extern f(x y); def f(x) x"),
+            CodegenError::PrototypeMismatch(Box::new(PrototypeMismatch {
+                name: "f".into(),
+                expected: "f(x: double y: double): double".into(),
+                found: "f(x: double): double".into(),
+                span: columns(20, 24),
+                previous: columns(8, 14)
+            }))
+        );
+        assert_eq!(
+            first_error("extern f(x); def f(y) y"),
+            CodegenError::PrototypeMismatch(Box::new(PrototypeMismatch {
+                name: "f".into(),
+                expected: "f(x: double): double".into(),
+                found: "f(y: double): double".into(),
+                span: columns(18, 22),
+                previous: columns(8, 12)
+            }))
+        );
+        assert_eq!(
+            first_error("extern f(n: int); def f(n) n"),
+            CodegenError::PrototypeMismatch(Box::new(PrototypeMismatch {
+                name: "f".into(),
+                expected: "f(n: int): double".into(),
+                found: "f(n: double): double".into(),
+                span: columns(23, 27),
+                previous: columns(8, 17)
+            }))
+        );
+        assert_eq!(
+            first_error("def f(n: int) n + 1.5"),
+            CodegenError::Type(Box::new(TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Double,
+                span: columns(19, 22),
+                expected_origin: columns(5, 14),
+                found_origin: columns(19, 22)
+            }))
+        );
+        assert_eq!(
+            first_error("def f(x) x + 1 = 1"),
+            CodegenError::InvalidAssignment {
+                span: columns(10, 15)
+            }
+        );
+        assert_eq!(
+            first_error("def f(n: int) n; def' f"),
+            CodegenError::Type(Box::new(TypeError::NotDifferentiable {
+                name: "f".into(),
+                reason: "it takes or returns values other than doubles".into(),
+                span: columns(18, 24)
+            }))
+        );
+    }
+
+    #[test]
+    fn define_externs() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new_without_jit(&context, "test");
+
+        // the body of a failed definition is removed, but the declaration called by g is kept
+        let results: Vec<_> = Parser::new(Lexer::new(
+            "extern f(x); def g(x) f(x); def f(x) y; def f(x) x + 1; extern f(x);".chars(),
+        ))
+        .parse_program()
+        .nodes
+        .iter()
+        .map(|node| match node {
+            ASTNode::ExternNode(proto) => cc.compile_extern(proto).is_ok(),
+            ASTNode::FunctionNode(func) => cc.compile_func(func).is_ok(),
+            node => panic!("unexpected node {:?}", node),
+        })
+        .collect();
+
+        assert_eq!(results, vec![true, true, false, true, true]);
+        assert_eq!(
+            cc.module().get_function("f").unwrap().count_basic_blocks(),
+            1
+        );
+        assert!(cc.module().verify().is_ok());
+    }
+
+    #[test]
+    fn redefine_functions() {
+        // g calls the latest definition of f, as it does with the other backends
+        assert_eq!(
+            evaluate_all(
+                "
+                def f(x) x + 1;
+                def g(x) f(x);
+                f(1);
+                def f(x) x + 2;
+                f(1);
+                g(1);
+                def f(y) y + 3;
+                f(1);
+                var h = g in h(1);
+                "
+            ),
+            vec![2.0, 3.0, 3.0, 4.0, 4.0]
+        );
+
+        // a redefinition keeps the types g was compiled against
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+        let results: Vec<_> = Parser::new(Lexer::new(
+            "def f(x) x; def g(x) f(x); def f(n: int) n; g(1);".chars(),
+        ))
+        .parse_program()
+        .nodes
+        .iter()
+        .map(|node| cc.run_item(node).map_err(|err| err.message))
+        .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(None),
+                Ok(None),
+                Err("Prototype f(n: int): int \
+                     does not match the previous declaration f(x: double): double."
+                    .into()),
+                Ok(Some(1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_redefinitions_are_discarded() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+
+        let results: Vec<_> = Parser::new(Lexer::new(
+            "def f(x) x + 1; def f(x) y; def f(x y) x; f(1);".chars(),
+        ))
+        .parse_program()
+        .nodes
+        .iter()
+        .map(|node| cc.run_item(node).map_err(|err| err.message))
+        .collect();
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(None),
+                Err("Unknown variable name: y".into()),
+                Err("Prototype f(x: double y: double): double \
+                     does not match the previous declaration f(x: double): double."
+                    .into()),
+                Ok(Some(2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn verification_failed() {
+        let context = Context::create();
+        let cc = CodegenContext::new_without_jit(&context, "test");
+
+        // a block without terminator is invalid
+        let proto = Prototype::new("broken", vec![], Default::default());
+        let fun_val = cc.compile_proto(&proto).unwrap();
+        context.append_basic_block(fun_val, "entry");
+
+        match cc.verify(fun_val, "broken", columns(1, 2)) {
+            Err(CodegenError::VerificationFailed { name, ir, span }) => {
+                assert_eq!(name, "broken");
+                assert!(ir.contains("define double @broken()"), "{}", ir);
+                assert_eq!(span, columns(1, 2));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(cc.module().get_function("broken").is_none());
+    }
+
+    #[test]
+    fn jit_not_enabled() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new_without_jit(&context, "test");
+
+        match Parser::new(Lexer::new("1 + 1".chars())).parse().unwrap() {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(cc.evaluate(&func), Err(CodegenError::JitNotEnabled));
+                assert!(!cc.function_protos.contains_key(&func.prototype.name));
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    fn failed_evaluations_are_forgotten() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+
+        for program in &["1 + y", "1 + true"] {
+            match Parser::new(Lexer::new(program.chars())).parse().unwrap() {
+                ASTNode::FunctionNode(func) => {
+                    assert!(cc.evaluate(&func).is_err(), "{}", program);
+                    assert!(!cc.function_protos.contains_key(&func.prototype.name));
+                }
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+    }
+
+    #[test]
+    fn compile_main() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new_without_jit(&context, "test");
+
+        let mut parser = Parser::new(Lexer::new("def f(x) x; f(1); f(2);".chars()));
+        let mut anonymous = vec![];
+        loop {
+            match parser.parse().unwrap() {
+                ASTNode::FunctionNode(func) => {
+                    cc.compile_func(&func).unwrap();
+                    if func.prototype.is_anonymous() {
+                        anonymous.push(func.prototype.name);
+                    }
+                }
+                ASTNode::EOF => break,
+                _ => continue,
+            }
+        }
+
+        let main = cc.compile_main(&anonymous).unwrap();
+        assert_eq!(main.count_basic_blocks(), 1);
+        assert!(cc.module().verify().is_ok());
+        assert!(matches!(
+            cc.compile_main(&anonymous),
+            Err(CodegenError::Redefinition { .. })
+        ));
+
+        // nothing can be evaluated without a JIT
+        assert_eq!(cc.flush_module().err(), Some(CodegenError::JitNotEnabled));
+    }
+
+    #[test]
+    fn typed_expressions() {
+        let program = "
+            def half(n: int) n / 2;
+            def pick(b: bool, x) if b then x else 0 - x;
+            def min(): int 0 - 9223372036854775807 - 1;
+            half(7); half(0 - 7); 7 / 0; min() / (0 - 1); min() - 1;
+            pick(1 < 2, 3); pick(false, 3);
+            int(2.9); int(0.0 - 2.9); int(0.0 / 0); int(1 / 0.0);
+            double(true); bool(0.5); bool(0);
+        ";
+        let min = i64::MIN as f64;
+
+        for level in &[OptimizationLevel::None, OptimizationLevel::Aggressive] {
+            assert_eq!(
+                evaluate_all_at(program, *level),
+                vec![
+                    3.0,
+                    -3.0,
+                    0.0,
+                    min,
+                    i64::MAX as f64,
+                    3.0,
+                    -3.0,
+                    2.0,
+                    -2.0,
+                    0.0,
+                    i64::MAX as f64,
+                    1.0,
+                    1.0,
+                    0.0
+                ]
+            );
+        }
+
+        let ir = compile_to_ir(
+            "def f(n: int, b: bool) if b then n else 0",
+            OptimizationLevel::None,
+        );
+        assert!(ir.contains("define i64 @f(i64 %n, i1 %b)"), "{}", ir);
+    }
+
+    #[test]
+    fn inferred_signatures() {
+        assert_eq!(
+            evaluate_all("def twice(n: int) n * 2; def f(x) twice(x) + 1; f(3);"),
+            vec![7.0]
+        );
+
+        let ir = compile_to_ir(
+            "def f(n: int, b) if b then n else 0; def g(x, y) f(x, y) < x",
+            OptimizationLevel::None,
+        );
+        assert!(ir.contains("define i64 @f(i64 %n, double %b)"), "{}", ir);
+        assert!(ir.contains("define i1 @g(i64 %x, double %y)"), "{}", ir);
+    }
+
+    #[test]
+    fn call_externs() {
+        assert_eq!(evaluate_all("extern sqrt(x); sqrt(16);"), vec![4.0]);
+    }
+
+    #[test]
+    fn strings() {
+        let program = r#"
+            extern strlen(s: string): int;
+            extern concat(a: string b: string): string;
+            extern print(s: string);
+            def greet(name) concat("hello, ", name);
+            strlen(greet("world")); strlen("\u{e9}\n"); print("");
+        "#;
+        assert_eq!(evaluate_all(program), vec![12.0, 3.0, 0.0]);
+
+        let ir = compile_to_ir(
+            r#"extern print(s: string); def f() print("hi\n")"#,
+            OptimizationLevel::None,
+        );
+        assert!(ir.contains(r#"c"hi\0A\00""#), "{}", ir);
+        assert!(ir.contains("declare double @print(i8*)"), "{}", ir);
+    }
+
+    #[test]
+    fn arrays() {
+        let program = "
+            def dot(a: [double], b: [double], n: int)
+                var sum = 0.0 in (for i = 0, i < n - 1 in sum = sum + a[i] * b[i]) + sum;
+            def squares(n: int)
+                var sum = 0.0 in (for i = 0, i < n in sum = sum + double([i, i * i][1])) + sum;
+            def matrix() [[1, 2], [3, 4]];
+            dot([1, 2, 3], [4, 5, 6], 3);
+            squares(3);
+            var m = matrix() in m[1][0] = m[0][1] + m[1][1];
+        ";
+        assert_eq!(evaluate_all(program), vec![32.0, 14.0, 6.0]);
+
+        let ir = compile_to_ir("def f(i: int) [1, 2][i]", OptimizationLevel::None);
+        assert!(ir.contains("alloca [2 x i64]"), "{}", ir);
+        assert!(
+            ir.contains("call void @kaleidoscope_out_of_bounds"),
+            "{}",
+            ir
+        );
+        assert!(!ir.contains("@malloc"), "{}", ir);
+
+        let ir = compile_to_ir_with("def f(i: int) [1, 2][i]", OptimizationLevel::None, false);
+        assert!(!ir.contains("@kaleidoscope_out_of_bounds"), "{}", ir);
+
+        // arrays returned or created in a loop are on the heap
+        let ir = compile_to_ir("def f(x) [x]", OptimizationLevel::None);
+        assert!(ir.contains("@malloc"), "{}", ir);
+        let ir = compile_to_ir(
+            "def f() for i = 0, [i][0] < 2 in 0",
+            OptimizationLevel::None,
+        );
+        assert!(ir.contains("@malloc"), "{}", ir);
+    }
+
+    #[test]
+    fn indices_out_of_bounds() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+        let program = "def get(a: [int] i: int) a[i];\nget([1, 2], 2);\nget([1, 2], 1);";
+
+        let mut results = vec![];
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                    results.push(cc.evaluate(&func))
+                }
+                ASTNode::FunctionNode(func) => {
+                    cc.define_func(&func).unwrap();
+                }
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+        // the JIT goes on after the generated code reports the error
+        assert_eq!(
+            results,
+            vec![
+                Err(CodegenError::Runtime(
+                    "Index 2 is out of bounds for an array of length 2 at 1:26.".into()
+                )),
+                Ok(2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn traps_unwind_through_closures_and_loops() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new(&context, "test").unwrap();
+        let program = "
+            def sum(a: [double] n: int) var s = 0.0 in (for i = 0, i < n - 1 in s = s + a[i]) + s;
+            def apply(f: (int) -> double i: int) f(i);
+            var a = [1.0, 2.0] in apply(\\n: int -> sum(a, n), 3);
+            var a = [1.0, 2.0] in apply(\\n: int -> sum(a, n), 2);
+        ";
+
+        let mut results = vec![];
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                    results.push(cc.evaluate(&func))
+                }
+                ASTNode::FunctionNode(func) => {
+                    cc.define_func(&func).unwrap();
+                }
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+        // the frames of the lambda, the trampolines and the loop are unwound
+        assert_eq!(
+            results,
+            vec![
+                Err(CodegenError::Runtime(
+                    "Index 2 is out of bounds for an array of length 2 at 2:89.".into()
+                )),
+                Ok(3.0)
+            ]
+        );
+
+        let ir = compile_to_ir("def get(a: [int] i: int) a[i];", OptimizationLevel::None);
+        assert!(ir.contains("uwtable"), "{}", ir);
+        assert!(ir.contains("noreturn"), "{}", ir);
+    }
+
+    #[test]
+    fn structs() {
+        let program = "
+            struct Point { x, y: int }
+            struct Box { min: Point, max: Point }
+            def area(b: Box) (b.max.x - b.min.x) * double(b.max.y - b.min.y);
+            def grow(b: Box d: int)
+                Box { min = b.min, max = Point { x = b.max.x + double(d), y = b.max.y + d } };
+            def origin() Point { x = 0, y = 0 };
+            area(grow(Box { min = origin(), max = origin() }, 2));
+            var p = origin() in (p = Point { x = 1.5, y = 2 }).x + double(p.y);
+        ";
+        assert_eq!(evaluate_all(program), vec![4.0, 3.5]);
+    }
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", unix))]
+    fn structs_in_c_abi() {
+        // small structs are passed in registers, large ones in memory as in C
+        let ir = compile_to_ir(
+            "
+            struct Point { x, y }
+            struct Box { min: Point, max: Point }
+            extern width(b: Box): double;
+            extern corner(b: Box): Point;
+            def f(p: Point) Box { min = p, max = corner(Box { min = p, max = p }) };
+            ",
+            OptimizationLevel::None,
+        );
+        assert!(ir.contains("%Point = type { double, double }"), "{}", ir);
+        assert!(ir.contains("%Box = type { %Point, %Point }"), "{}", ir);
+        assert!(ir.contains("declare double @width(%Box* byval"), "{}", ir);
+        assert!(ir.contains("declare %Point @corner(%Box* byval"), "{}", ir);
+        assert!(ir.contains("define void @f(%Box* sret"), "{}", ir);
+
+        // C passes the bools of a small struct in the same register
+        let err = first_error("struct Flags { a: bool, b: bool } extern set(f: Flags);");
+        assert_eq!(
+            err.to_string(),
+            "Cannot pass Flags to or from an extern: a bool field is not passed as in C."
+        );
+        let err =
+            first_error("struct Flags { a: bool } struct Pair { x, f: Flags } extern get(): Pair;");
+        assert!(matches!(err, CodegenError::Type(_)), "{:?}", err);
+        assert!(compile_to_ir(
+            "struct Flags { a: bool } extern set(f: [Flags]);",
+            OptimizationLevel::None
+        )
+        .contains("@set"));
+    }
+
+    #[test]
+    fn unions() {
+        let program = "
+            type Shape = Circle(r) | Rect(w, h) | Empty
+            type Pair = Pair(a: Shape, b: Shape)
+            def area(s: Shape) match s { Circle(r) -> 3 * r * r, Rect(w, h) -> w * h, Empty -> 0 };
+            def first(p) match p { Pair(Empty, s) -> area(s), Pair(s, _) -> area(s) };
+            def scale(s k) match s { Circle(r) -> Circle(r * k), other -> other };
+            area(Rect(2, 3)) + area(Empty);
+            first(Pair(Empty, Circle(1))) + first(Pair(scale(Circle(1), 2), Empty));
+            area(scale(Rect(1, 1), 2));
+        ";
+        assert_eq!(evaluate_all(program), vec![6.0, 15.0, 1.0]);
+
+        let ir = compile_to_ir(
+            "
+            type Shape = Circle(r) | Rect(w, h) | Empty
+            def area(s: Shape) match s { Circle(r) -> 3 * r * r, Rect(w, h) -> w * h, Empty -> 0 };
+            ",
+            OptimizationLevel::None,
+        );
+        assert!(ir.contains("%Shape = type { i64, [2 x i64] }"), "{}", ir);
+        assert!(
+            ir.contains("%Shape.Rect = type { double, double }"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("switch i64"), "{}", ir);
+
+        // a union holding itself holds pointers to copies on the heap
+        let program = "
+            type List = Nil | Cons(x, rest: List)
+            def range(n) if n < 1 then Nil else Cons(n, range(n - 1));
+            def sum(l) match l { Nil -> 0, Cons(x, rest) -> x + sum(rest) };
+            sum(range(4));
+        ";
+        assert_eq!(evaluate_all(program), vec![10.0]);
+        let ir = compile_to_ir(program, OptimizationLevel::None);
+        assert!(ir.contains("%List = type { i64, [2 x i64] }"), "{}", ir);
+        assert!(
+            ir.contains("%List.Cons = type { double, %List* }"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("@malloc"), "{}", ir);
+    }
+
+    #[test]
+    fn first_class_functions() {
+        let program = "
+            extern sin(x);
+            struct Point { x, y }
+            struct Box { min: Point, max: Point }
+            def square(x) x * x;
+            def apply(f x) f(x);
+            def integrate(f a b n: int)
+              var sum = 0.0, h = (b - a) / double(n) in
+                (for i = 0, i < n - 1 in sum = sum + f(a + (double(i) + 0.5) * h) * h) + sum;
+            def flip(b: Box) Box { min = b.max, max = b.min };
+            def twice(f: (Box) -> Box, b) f(f(b));
+            apply(square, 3);
+            integrate(square, 0, 3, 3);
+            var g = square in var a = g(3), b = (g = sin) in a + g(0);
+            twice(flip, Box { min = Point { x = 1, y = 2 }, max = Point { x = 3, y = 4 } }).max.y;
+        ";
+        assert_eq!(evaluate_all(program), vec![9.0, 8.75, 9.0, 4.0]);
+
+        // functions are passed as closures, and structs passed in memory are marked at the call
+        let ir = compile_to_ir(
+            "
+            struct Point { x, y }
+            struct Box { min: Point, max: Point }
+            def apply(f x) f(x);
+            def twice(f: (Box) -> Box, b) f(f(b));
+            ",
+            OptimizationLevel::None,
+        );
+        assert!(
+            ir.contains("define double @apply({ double (i8*, double)*, i8* } %f, double %x)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("call double %"), "{}", ir);
+        assert!(ir.contains("void (%Box*, i8*, %Box*)*"), "{}", ir);
+        assert!(ir.contains("(%Box* sret "), "{}", ir);
+    }
+
+    #[test]
+    fn closures() {
+        let program = "
+            struct Point { x, y }
+            def adder(k) \\x -> x + k;
+            def compose(f: (double) -> double g: (double) -> double) \\x -> f(g(x));
+            def fold(f: (double, double) -> double acc a: [double] n: int)
+              (for i = 0, i < n - 1 in acc = f(acc, a[i])) + acc;
+            def shift(d: Point) \\p: Point -> Point { x = p.x + d.x, y = p.y + d.y };
+            var add2 = adder(2) in add2(1);
+            var k = 10 in fold(\\acc x -> acc + x * k, 0, [1, 2, 3], 3);
+            var f = compose(adder(1), \\x -> x * 2) in f(5);
+            var k = 1 in var f = \\x -> x + k in (k = 100) + f(0);
+            var f = shift(Point { x = 1, y = 2 }) in f(Point { x = 3, y = 4 }).y;
+        ";
+        assert_eq!(evaluate_all(program), vec![3.0, 60.0, 11.0, 101.0, 6.0]);
+
+        // the lambda takes what it captures first, and the code of the closure loads it from the heap
+        let ir = compile_to_ir("def adder(k) \\x -> x + k;", OptimizationLevel::None);
+        assert!(
+            ir.contains("define double @lambda.0(double %k, double %x)"),
+            "{}",
+            ir
+        );
+        assert!(
+            ir.contains("define internal double @lambda.0.code(i8* "),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("@malloc("), "{}", ir);
+    }
+
+    #[test]
+    fn gradients() {
+        let program = "
+            extern sin(x);
+            extern cos(x);
+            def cube(x) x * x * x;
+            def f(x y) sin(x) * y + cube(y) / x;
+            var d = grad(cube) in d(2);
+            var d = grad(f) in var g = d(1, 2) in g[0] + g[1];
+            var d = grad(cube) in d(3);
+            def' cube;
+            def' f;
+            cube'(3) + f'(1, 2)[1];
+        ";
+        assert_eq!(
+            evaluate_all(program),
+            vec![
+                12.0,
+                2.0 * 1f64.cos() - 8.0 + 1f64.sin() + 12.0,
+                27.0,
+                27.0 + 1f64.sin() + 12.0
+            ]
+        );
+
+        // the derivative takes the derivatives of the args after them
+        let ir = compile_to_ir(
+            "def cube(x) x * x * x; def f() grad(cube);",
+            OptimizationLevel::None,
+        );
+        assert!(
+            ir.contains("@cube.tangent(double %x, double %x.tangent)"),
+            "{}",
+            ir
+        );
+        assert!(ir.contains("define double @cube.grad(double %x)"), "{}", ir);
+
+        // def' defines a function calling the gradient, named after the function with a prime
+        let ir = compile_to_ir("def cube(x) x * x * x; def' cube", OptimizationLevel::None);
+        assert!(ir.contains("define double @\"cube'\"(double %x)"), "{}", ir);
+        assert!(ir.contains("define double @cube.grad(double %x)"), "{}", ir);
+    }
+
+    fn compile_to_ir(program: &str, level: OptimizationLevel) -> String {
+        compile_to_ir_with(program, level, true)
+    }
+
+    fn compile_to_ir_with(program: &str, level: OptimizationLevel, bounds_checks: bool) -> String {
+        let context = Context::create();
+        let mut cc = CodegenContext::new_without_jit(&context, "test");
+        cc.set_opt_level(level);
+        cc.set_bounds_checks(bounds_checks);
+
+        let mut parser = Parser::new(Lexer::new(program.chars()));
+        loop {
+            match parser.parse().unwrap() {
+                ASTNode::ExternNode(proto) => {
+                    cc.compile_extern(&proto).unwrap();
+                }
+                ASTNode::FunctionNode(func) => {
+                    cc.compile_func(&func).unwrap();
+                }
+                ASTNode::DerivativeNode(def) => {
+                    cc.compile_derivative(&def).unwrap();
+                }
+                ASTNode::StructNode(def) => {
+                    cc.compile_struct(&def).unwrap();
+                }
+                ASTNode::UnionNode(def) => {
+                    cc.compile_union(&def).unwrap();
+                }
+                ASTNode::EOF => break,
+                _ => continue,
+            }
+        }
+
+        cc.optimize_module();
+        assert!(cc.module().verify().is_ok());
+        cc.module().print_to_string().to_string()
+    }
+
+    #[test]
+    fn promote_allocas() {
+        let program = "def f(x y) var z = x in z = z + y";
+
+        assert!(compile_to_ir(program, OptimizationLevel::None).contains("alloca"));
+        for level in &[
+            OptimizationLevel::Less,
+            OptimizationLevel::Default,
+            OptimizationLevel::Aggressive,
+        ] {
+            let ir = compile_to_ir(program, *level);
+            assert!(!ir.contains("alloca"), "{}", ir);
+            assert!(!ir.contains("load"), "{}", ir);
+        }
+    }
+
+    #[test]
+    fn evaluate_optimized() {
+        let program = "
+            def fib(n) if n < 3 then 1 else fib(n - 1) + fib(n - 2);
+            def sum(n) var acc = 0 in (for i = 1, i < n in acc = acc + i) + acc;
+            fib(10);
+            sum(10);
+        ";
+
+        for level in &[
+            OptimizationLevel::None,
+            OptimizationLevel::Less,
+            OptimizationLevel::Default,
+            OptimizationLevel::Aggressive,
+        ] {
+            assert_eq!(evaluate_all_at(program, *level), vec![55.0, 55.0]);
+        }
     }
 }
@@ -0,0 +1,147 @@
+use inkwell::module::Module;
+use inkwell::targets::{
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
+};
+use inkwell::OptimizationLevel;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The kinds of output a module can be emitted as
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EmitKind {
+    LlvmIr,
+    Bitcode,
+    Asm,
+    Object,
+}
+
+impl EmitKind {
+    /// The conventional file extension of the output
+    pub fn extension(self) -> &'static str {
+        match self {
+            EmitKind::LlvmIr => "ll",
+            EmitKind::Bitcode => "bc",
+            EmitKind::Asm => "s",
+            EmitKind::Object => "o",
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "llvm-ir" => Ok(EmitKind::LlvmIr),
+            "bitcode" => Ok(EmitKind::Bitcode),
+            "asm" => Ok(EmitKind::Asm),
+            "obj" => Ok(EmitKind::Object),
+            _ => Err(format!(
+                "Unknown emit kind {}, expect one of llvm-ir, bitcode, asm or obj.",
+                s
+            )),
+        }
+    }
+}
+
+/// Create a machine targeting the host
+pub fn create_native_target_machine() -> Result<TargetMachine, String> {
+    Target::initialize_native(&InitializationConfig::default())?;
+
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|err| err.to_string())?;
+
+    // PIC so that objects can be linked into position independent executables
+    target
+        .create_target_machine(
+            &triple,
+            "generic",
+            "",
+            OptimizationLevel::Default,
+            RelocMode::PIC,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| format!("Failed to create target machine for {:?}.", triple))
+}
+
+/// Write `module` to `path` as `kind`, targeting the host
+pub fn emit(module: &Module, kind: EmitKind, path: &Path) -> Result<(), String> {
+    let machine = create_native_target_machine()?;
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+
+    match kind {
+        EmitKind::LlvmIr => module.print_to_file(path).map_err(|err| err.to_string()),
+        EmitKind::Bitcode => {
+            if module.write_bitcode_to_path(path) {
+                Ok(())
+            } else {
+                Err(format!("Failed to write bitcode to {}.", path.display()))
+            }
+        }
+        EmitKind::Asm => machine
+            .write_to_file(module, FileType::Assembly, path)
+            .map_err(|err| err.to_string()),
+        EmitKind::Object => machine
+            .write_to_file(module, FileType::Object, path)
+            .map_err(|err| err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::codegen_context::CodegenContext;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+    use inkwell::context::Context;
+
+    #[test]
+    fn parse_emit_kind() {
+        assert_eq!("llvm-ir".parse(), Ok(EmitKind::LlvmIr));
+        assert_eq!("bitcode".parse(), Ok(EmitKind::Bitcode));
+        assert_eq!("asm".parse(), Ok(EmitKind::Asm));
+        assert_eq!("obj".parse(), Ok(EmitKind::Object));
+        assert!("exe".parse::<EmitKind>().is_err());
+    }
+
+    #[test]
+    fn emit_all_kinds() {
+        let context = Context::create();
+        let mut cc = CodegenContext::new_without_jit(&context, "test");
+
+        match Parser::new(Lexer::new("def double(x) x*2".chars()))
+            .parse()
+            .unwrap()
+        {
+            ASTNode::FunctionNode(func) => cc.compile_func(&func).unwrap(),
+            node => panic!("unexpected node {:?}", node),
+        };
+
+        let dir = std::env::temp_dir();
+        for kind in &[
+            EmitKind::LlvmIr,
+            EmitKind::Bitcode,
+            EmitKind::Asm,
+            EmitKind::Object,
+        ] {
+            let path = dir.join(format!(
+                "kaleidoscope_emit_test_{}.{}",
+                std::process::id(),
+                kind.extension()
+            ));
+            emit(cc.module(), *kind, &path).unwrap();
+
+            let output = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(!output.is_empty());
+
+            if *kind == EmitKind::LlvmIr {
+                assert!(String::from_utf8(output)
+                    .unwrap()
+                    .contains("define double @double(double %x)"));
+            }
+        }
+    }
+}
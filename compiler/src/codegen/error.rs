@@ -0,0 +1,288 @@
+use crate::diagnostics::Diagnostic;
+use crate::types::TypeError;
+use crate::util::Span;
+use std::fmt;
+
+/// Why code could not be generated or run
+#[derive(PartialEq, Clone, Debug)]
+pub enum CodegenError {
+    UnknownVariable {
+        name: String,
+        span: Span,
+    },
+    UnknownFunction {
+        name: String,
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    UnknownOperator {
+        op: char,
+        unary: bool,
+        span: Span,
+    },
+    Redefinition {
+        name: String,
+        span: Span,
+        /// The prototype of the previous definition
+        previous: Span,
+    },
+    /// A prototype does not match a previous declaration of the same function
+    PrototypeMismatch(Box<PrototypeMismatch>),
+    Type(Box<TypeError>),
+    /// The destination of `=` is neither a variable nor an array element
+    InvalidAssignment {
+        span: Span,
+    },
+    /// LLVM rejected the generated function, which is a bug of codegen
+    VerificationFailed {
+        name: String,
+        /// The rejected function
+        ir: String,
+        span: Span,
+    },
+    /// The context was created without a JIT, so nothing can be evaluated
+    JitNotEnabled,
+    /// An error reported by LLVM or the JIT
+    Llvm(String),
+    /// An error found by the generated code while it runs, e.g. an index out of bounds
+    Runtime(String),
+}
+
+/// A prototype and the previous declaration of the same function it does not match
+#[derive(PartialEq, Clone, Debug)]
+pub struct PrototypeMismatch {
+    pub name: String,
+    /// The previous prototype, with every type written out
+    pub expected: String,
+    pub found: String,
+    pub span: Span,
+    pub previous: Span,
+}
+
+impl CodegenError {
+    /// Where the error is in the source, if it is caused by the source
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CodegenError::UnknownVariable { span, .. }
+            | CodegenError::UnknownFunction { span, .. }
+            | CodegenError::ArityMismatch { span, .. }
+            | CodegenError::UnknownOperator { span, .. }
+            | CodegenError::Redefinition { span, .. }
+            | CodegenError::InvalidAssignment { span }
+            | CodegenError::VerificationFailed { span, .. } => Some(*span),
+            CodegenError::PrototypeMismatch(mismatch) => Some(mismatch.span),
+            CodegenError::Type(err) => Some(err.span()),
+            CodegenError::JitNotEnabled | CodegenError::Llvm(_) | CodegenError::Runtime(_) => None,
+        }
+    }
+
+    /// Report the error, at `item_span` if the error has no span
+    pub fn into_diagnostic(self, item_span: Span) -> Diagnostic {
+        let span = self.span().unwrap_or(item_span);
+        let mut diagnostic = Diagnostic::from(self);
+        diagnostic.span = span;
+        diagnostic
+    }
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::UnknownVariable { name, .. } => {
+                write!(f, "Unknown variable name: {}", name)
+            }
+            CodegenError::UnknownFunction { name, .. } => write!(f, "Unknown function: {}", name),
+            CodegenError::ArityMismatch {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "Unmatched arg number. Function {} expects {} but the input has {}.",
+                name, expected, found
+            ),
+            CodegenError::UnknownOperator { op, unary, .. } => write!(
+                f,
+                "Unknown {} op {}",
+                if *unary { "unary" } else { "binary" },
+                op
+            ),
+            CodegenError::Redefinition { name, .. } => {
+                write!(f, "Function {} is already defined.", name)
+            }
+            CodegenError::PrototypeMismatch(mismatch) => write!(
+                f,
+                "Prototype {} does not match the previous declaration {}.",
+                mismatch.found, mismatch.expected
+            ),
+            CodegenError::Type(err) => write!(f, "{}", err),
+            CodegenError::InvalidAssignment { .. } => {
+                write!(
+                    f,
+                    "Destination of '=' must be a variable or an array element."
+                )
+            }
+            CodegenError::VerificationFailed { name, .. } => {
+                write!(f, "Generated function {} verification failed.", name)
+            }
+            CodegenError::JitNotEnabled => write!(f, "JIT is not enabled."),
+            CodegenError::Llvm(message) | CodegenError::Runtime(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+impl From<Box<TypeError>> for CodegenError {
+    fn from(err: Box<TypeError>) -> Self {
+        CodegenError::Type(err)
+    }
+}
+
+impl From<CodegenError> for Diagnostic {
+    fn from(err: CodegenError) -> Self {
+        let diagnostic = Diagnostic::error(err.to_string(), err.span().unwrap_or_default());
+        match err {
+            CodegenError::VerificationFailed { ir, .. } => {
+                diagnostic.with_note(format!("the generated IR is\n{}", ir.trim_end()))
+            }
+            // the previous prototype is unknown if it was not written in the source, e.g. main
+            CodegenError::Redefinition { previous, .. } if previous != Span::default() => {
+                diagnostic.with_label(previous, "previously defined here")
+            }
+            CodegenError::PrototypeMismatch(mismatch) => {
+                diagnostic.with_label(mismatch.previous, "previously declared here")
+            }
+            CodegenError::Type(err) => Diagnostic::from(err),
+            _ => diagnostic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Type;
+    use crate::util::Position;
+
+    fn span() -> Span {
+        let start = Position::default();
+        Span::new(start, start.after('f'))
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            CodegenError::ArityMismatch {
+                name: "f".into(),
+                expected: 1,
+                found: 2,
+                span: span()
+            }
+            .to_string(),
+            "Unmatched arg number. Function f expects 1 but the input has 2."
+        );
+        assert_eq!(
+            CodegenError::UnknownOperator {
+                op: '|',
+                unary: false,
+                span: span()
+            }
+            .to_string(),
+            "Unknown binary op |"
+        );
+        assert_eq!(
+            CodegenError::UnknownOperator {
+                op: '!',
+                unary: true,
+                span: span()
+            }
+            .to_string(),
+            "Unknown unary op !"
+        );
+        assert_eq!(
+            CodegenError::PrototypeMismatch(Box::new(PrototypeMismatch {
+                name: "f".into(),
+                expected: "f(x: double y: double): double".into(),
+                found: "f(a: int): double".into(),
+                span: span(),
+                previous: span()
+            }))
+            .to_string(),
+            "Prototype f(a: int): double does not match the previous declaration f(x: double y: double): double."
+        );
+    }
+
+    #[test]
+    fn into_diagnostic() {
+        let diagnostic: Diagnostic = CodegenError::VerificationFailed {
+            name: "f".into(),
+            ir: "define double @f() {\n}\n".into(),
+            span: span(),
+        }
+        .into();
+        assert_eq!(
+            diagnostic.message,
+            "Generated function f verification failed."
+        );
+        assert_eq!(diagnostic.span, span());
+        assert_eq!(
+            diagnostic.notes,
+            vec!["the generated IR is\ndefine double @f() {\n}".to_string()]
+        );
+
+        let diagnostic: Diagnostic = CodegenError::Redefinition {
+            name: "f".into(),
+            span: span(),
+            previous: Span::at(span().end),
+        }
+        .into();
+        assert_eq!(diagnostic.labels.len(), 1);
+        assert_eq!(diagnostic.labels[0].span, Span::at(span().end));
+        assert_eq!(diagnostic.labels[0].message, "previously defined here");
+
+        let diagnostic: Diagnostic = CodegenError::Type(Box::new(TypeError::Mismatch {
+            expected: Type::Int,
+            found: Type::Bool,
+            span: span(),
+            expected_origin: Span::at(span().end),
+            found_origin: span(),
+        }))
+        .into();
+        assert_eq!(diagnostic.message, "Expected int but found bool.");
+        assert_eq!(diagnostic.span, span());
+        assert_eq!(diagnostic.labels.len(), 1);
+        assert_eq!(diagnostic.labels[0].span, Span::at(span().end));
+        assert_eq!(diagnostic.labels[0].message, "this is int");
+        assert_eq!(
+            diagnostic.notes,
+            vec!["use `int(...)` to convert it".to_string()]
+        );
+
+        let diagnostic: Diagnostic = CodegenError::JitNotEnabled.into();
+        assert_eq!(diagnostic.span, Span::default());
+        assert!(diagnostic.notes.is_empty());
+
+        assert_eq!(
+            CodegenError::JitNotEnabled.into_diagnostic(span()).span,
+            span()
+        );
+        assert_eq!(
+            CodegenError::UnknownVariable {
+                name: "x".into(),
+                span: span()
+            }
+            .into_diagnostic(Span::default())
+            .span,
+            span()
+        );
+    }
+}
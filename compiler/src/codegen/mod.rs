@@ -1 +1,7 @@
 pub mod codegen_context;
+pub mod emit;
+mod error;
+pub mod passes;
+mod runtime;
+
+pub use error::{CodegenError, PrototypeMismatch};
@@ -0,0 +1,81 @@
+use inkwell::module::Module;
+use inkwell::passes::{PassManager, PassManagerBuilder};
+use inkwell::values::FunctionValue;
+
+/// Re-exported so that users do not depend on inkwell to select a level
+pub use inkwell::OptimizationLevel;
+
+/// Parse an optimization level from `0` to `3`, as in `-O2`
+pub fn parse_opt_level(level: &str) -> Result<OptimizationLevel, String> {
+    match level {
+        "0" => Ok(OptimizationLevel::None),
+        "1" => Ok(OptimizationLevel::Less),
+        "2" => Ok(OptimizationLevel::Default),
+        "3" => Ok(OptimizationLevel::Aggressive),
+        _ => Err(format!(
+            "Unknown optimization level {}, expect one of 0, 1, 2 or 3.",
+            level
+        )),
+    }
+}
+
+/// Create the pass manager run on every function compiled into `module`,
+/// or None if nothing should be optimized.
+///
+/// The passes are those of the tutorial:
+/// promote allocas to registers, then do simple peephole and bit-twiddling optimizations,
+/// reassociate expressions, eliminate common subexpressions and simplify the control flow graph.
+pub fn create_function_pass_manager<'ctx>(
+    module: &Module<'ctx>,
+    level: OptimizationLevel,
+) -> Option<PassManager<FunctionValue<'ctx>>> {
+    if level == OptimizationLevel::None {
+        return None;
+    }
+
+    let fpm = PassManager::create(module);
+    fpm.add_promote_memory_to_register_pass();
+    fpm.add_instruction_combining_pass();
+    fpm.add_reassociate_pass();
+    fpm.add_gvn_pass();
+    fpm.add_cfg_simplification_pass();
+    fpm.initialize();
+
+    Some(fpm)
+}
+
+/// Run the standard module level pipeline of `level` on `module`
+pub fn optimize_module(module: &Module, level: OptimizationLevel) {
+    if level == OptimizationLevel::None {
+        return;
+    }
+
+    let builder = PassManagerBuilder::create();
+    builder.set_optimization_level(level);
+
+    // the same inlining thresholds as clang
+    match level {
+        OptimizationLevel::Default => builder.set_inliner_with_threshold(225),
+        OptimizationLevel::Aggressive => builder.set_inliner_with_threshold(275),
+        _ => {}
+    }
+
+    let mpm = PassManager::create(());
+    builder.populate_module_pass_manager(&mpm);
+    mpm.run_on(module);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_opt_levels() {
+        assert_eq!(parse_opt_level("0"), Ok(OptimizationLevel::None));
+        assert_eq!(parse_opt_level("1"), Ok(OptimizationLevel::Less));
+        assert_eq!(parse_opt_level("2"), Ok(OptimizationLevel::Default));
+        assert_eq!(parse_opt_level("3"), Ok(OptimizationLevel::Aggressive));
+        assert!(parse_opt_level("4").is_err());
+        assert!(parse_opt_level("s").is_err());
+    }
+}
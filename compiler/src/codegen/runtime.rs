@@ -0,0 +1,127 @@
+//! The functions compiled programs can declare with `extern` besides libm, linked into the JIT.
+//! Programs compiled ahead of time are linked with the same functions written in C, `cli/src/runtime.c`.
+
+use std::ffi::{CStr, CString};
+use std::io::Write;
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+/// The function the generated code calls with an index out of bounds
+pub const OUT_OF_BOUNDS: &str = "kaleidoscope_out_of_bounds";
+
+/// The function the JIT calls in place of a function declared but not defined yet
+pub const UNDEFINED: &str = "kaleidoscope_undefined";
+
+/// putchard - putchar that takes a double and returns 0
+extern "C" fn putchard(x: f64) -> f64 {
+    let _ = std::io::stderr().write_all(&[x as u8]);
+    0.0
+}
+
+/// printd - printf that takes a double prints it as "%f\n", returning 0
+extern "C" fn printd(x: f64) -> f64 {
+    eprintln!("{:.6}", x);
+    0.0
+}
+
+/// print - prints a string as it is, returning 0
+extern "C" fn print(s: *const c_char) -> f64 {
+    let s = unsafe { CStr::from_ptr(s) };
+    let _ = std::io::stderr().write_all(s.to_bytes());
+    0.0
+}
+
+/// strlen - the length of a string in bytes
+extern "C" fn strlen(s: *const c_char) -> i64 {
+    unsafe { CStr::from_ptr(s) }.to_bytes().len() as i64
+}
+
+/// concat - a new string made of two strings, which is never freed
+extern "C" fn concat(a: *const c_char, b: *const c_char) -> *mut c_char {
+    let (a, b) = unsafe { (CStr::from_ptr(a), CStr::from_ptr(b)) };
+    let bytes = [a.to_bytes(), b.to_bytes()].concat();
+    // neither string contains a NUL
+    CString::new(bytes).unwrap().into_raw()
+}
+
+/// An error found by the generated code, unwinding out of it up to `catch_traps`
+struct Trap(String);
+
+/// kaleidoscope_out_of_bounds - reports an index out of bounds of an array indexed at line:column,
+/// unwinding out of the generated code, which goes on with the next top level expression
+extern "C-unwind" fn kaleidoscope_out_of_bounds(index: i64, len: i64, line: i64, column: i64) {
+    resume_unwind(Box::new(Trap(format!(
+        "Index {} is out of bounds for an array of length {} at {}:{}.",
+        index, len, line, column
+    ))));
+}
+
+/// kaleidoscope_undefined - reports a call of a function declared by a file but not defined yet,
+/// unwinding out of the generated code as for an index out of bounds.
+/// Only the JIT calls it, as a file compiled ahead of time defines every function it declares.
+extern "C-unwind" fn kaleidoscope_undefined(name: *const c_char) {
+    let name = unsafe { CStr::from_ptr(name) };
+    resume_unwind(Box::new(Trap(format!(
+        "Function {} is not defined.",
+        name.to_string_lossy()
+    ))));
+}
+
+/// Run generated code, returning the message of the error it reports if any
+pub fn catch_traps<T>(run: impl FnOnce() -> T) -> Result<T, String> {
+    catch_unwind(AssertUnwindSafe(run)).map_err(|payload| match payload.downcast::<Trap>() {
+        Ok(trap) => trap.0,
+        Err(payload) => resume_unwind(payload),
+    })
+}
+
+/// The address of the function of the runtime named `name`
+pub fn runtime_function(name: &str) -> Option<usize> {
+    type Double = extern "C" fn(f64) -> f64;
+    let address = match name {
+        "putchard" => putchard as Double as usize,
+        "printd" => printd as Double as usize,
+        "print" => print as extern "C" fn(_) -> _ as usize,
+        "strlen" => strlen as extern "C" fn(_) -> _ as usize,
+        "concat" => concat as extern "C" fn(_, _) -> _ as usize,
+        OUT_OF_BOUNDS => kaleidoscope_out_of_bounds as extern "C-unwind" fn(_, _, _, _) as usize,
+        UNDEFINED => kaleidoscope_undefined as extern "C-unwind" fn(_) as usize,
+        _ => return None,
+    };
+    Some(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings() {
+        let hello = CString::new("hello, ").unwrap();
+        let world = CString::new("wörld").unwrap();
+
+        let joined = concat(hello.as_ptr(), world.as_ptr());
+        assert_eq!(strlen(joined), 13);
+        let joined = unsafe { CString::from_raw(joined) };
+        assert_eq!(joined.to_str(), Ok("hello, wörld"));
+
+        assert!(runtime_function("concat").is_some());
+        assert!(runtime_function("sin").is_none());
+        assert!(runtime_function(OUT_OF_BOUNDS).is_some());
+        assert!(runtime_function(UNDEFINED).is_some());
+    }
+
+    #[test]
+    fn traps() {
+        assert_eq!(
+            catch_traps(|| kaleidoscope_out_of_bounds(2, 2, 3, 4)),
+            Err("Index 2 is out of bounds for an array of length 2 at 3:4.".into())
+        );
+        let name = CString::new("odd").unwrap();
+        assert_eq!(
+            catch_traps(|| kaleidoscope_undefined(name.as_ptr())),
+            Err("Function odd is not defined.".into())
+        );
+        assert_eq!(catch_traps(|| 1), Ok(1));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::lexer::LexerError;
+use crate::parser::parser::ParseError;
+use crate::util::Span;
+use std::fmt;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A secondary source range related to a diagnostic
+#[derive(PartialEq, Clone, Debug)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A problem found in the source, reported to the user by `Diagnostic::render`
+#[derive(PartialEq, Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where the problem is
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            span,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn error(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic::new(Severity::Error, message, span)
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic::new(Severity::Warning, message, span)
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+/// An error of the VM or of the interpreter, at the expression causing it if it is caused by one,
+/// e.g. an unknown variable, and otherwise at the item being run, e.g. an index out of bounds
+#[derive(PartialEq, Clone, Debug)]
+pub struct SpannedError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl SpannedError {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        SpannedError {
+            message: message.into(),
+            span: Some(span),
+        }
+    }
+
+    /// Put the error at `span` unless it already has a span
+    pub fn or_at(self, span: Span) -> Self {
+        SpannedError {
+            span: self.span.or(Some(span)),
+            ..self
+        }
+    }
+
+    /// Report the error, at `item_span` if the error has no span
+    pub fn into_diagnostic(self, item_span: Span) -> Diagnostic {
+        Diagnostic::error(self.message, self.span.unwrap_or(item_span))
+    }
+}
+
+impl From<String> for SpannedError {
+    fn from(message: String) -> Self {
+        SpannedError {
+            message,
+            span: None,
+        }
+    }
+}
+
+impl From<&str> for SpannedError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+impl From<LexerError> for Diagnostic {
+    fn from(err: LexerError) -> Self {
+        match err {
+            LexerError::NumberNotValid(val, span) => {
+                Diagnostic::error(format!("invalid number `{}`", val), span)
+                    .with_note("a number can contain at most one `.`")
+            }
+            LexerError::IntegerOutOfRange(val, span) => {
+                Diagnostic::error(format!("integer `{}` is out of range", val), span)
+                    .with_note("an int is 64-bit, add a `.` to write a double instead")
+            }
+            LexerError::NotRecognized(c, span) => {
+                Diagnostic::error(format!("unrecognized character `{}`", c), span)
+            }
+            LexerError::UnterminatedString(span) => {
+                Diagnostic::error("unterminated string", span).with_note("add a `\"` to close it")
+            }
+            LexerError::InvalidEscape(sequence, span) => {
+                Diagnostic::error(format!("invalid escape sequence `{}`", sequence), span)
+                    .with_note(
+                        "use `\\n`, `\\t`, `\\\"`, `\\\\` or `\\u{..}` with a nonzero code point",
+                    )
+            }
+        }
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::UnexpectedToken {
+                token,
+                message,
+                span,
+            } => {
+                let found = match token {
+                    Some(token) => format!("found `{}`", token),
+                    None => "found end of input".into(),
+                };
+                Diagnostic::error(message, span).with_note(found)
+            }
+            ParseError::Lexer(err) => err.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::parser::Parser;
+
+    #[test]
+    fn from_lexer_error() {
+        let err = Lexer::new("1.2.3".chars()).next().unwrap().unwrap_err();
+        let span = err.span();
+
+        assert_eq!(
+            Diagnostic::from(err),
+            Diagnostic::error("invalid number `1.2.3`", span)
+                .with_note("a number can contain at most one `.`")
+        );
+    }
+
+    #[test]
+    fn from_parse_error() {
+        let err = Parser::new(Lexer::new("def f(x) if x 1".chars()))
+            .parse()
+            .unwrap_err();
+        let span = err.span();
+
+        assert!(matches!(
+            err,
+            ParseError::UnexpectedToken {
+                token: Some(Token::Integer(1)),
+                ..
+            }
+        ));
+        assert_eq!(
+            Diagnostic::from(err),
+            Diagnostic::error("expect then", span).with_note("found `1`")
+        );
+    }
+}
@@ -0,0 +1,4 @@
+mod diagnostic;
+mod render;
+
+pub use diagnostic::*;
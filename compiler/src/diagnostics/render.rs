@@ -0,0 +1,173 @@
+use super::diagnostic::Diagnostic;
+use crate::util::Span;
+use std::fmt::Write;
+
+impl Diagnostic {
+    /// Render the diagnostic along with the offending source lines, e.g.
+    ///
+    /// ```text
+    /// error: expect then
+    ///  --> <stdin>:1:15
+    ///   |
+    /// 1 | def f(x) if x 1 else 2
+    ///   |               ^
+    ///   = note: found `1`
+    /// ```
+    ///
+    /// The primary span is underlined with `^`, and secondary labels with `-`.
+    pub fn render(&self, source_name: &str, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+
+        // the gutter is as wide as the largest line number shown
+        let gutter = std::iter::once(self.span)
+            .chain(self.labels.iter().map(|label| label.span))
+            .map(|span| span.start.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(gutter);
+
+        let mut out = String::new();
+        writeln!(out, "{}: {}", self.severity, self.message).unwrap();
+        writeln!(
+            out,
+            "{}--> {}:{}:{}",
+            pad, source_name, self.span.start.line, self.span.start.column
+        )
+        .unwrap();
+        writeln!(out, "{} |", pad).unwrap();
+
+        render_snippet(&mut out, &lines, gutter, self.span, '^', "");
+        for label in &self.labels {
+            render_snippet(&mut out, &lines, gutter, label.span, '-', &label.message);
+        }
+
+        for note in &self.notes {
+            writeln!(out, "{} = note: {}", pad, note).unwrap();
+        }
+
+        out
+    }
+}
+
+/// Render the first line of `span` and underline the spanned part with `marker`
+fn render_snippet(
+    out: &mut String,
+    lines: &[&str],
+    gutter: usize,
+    span: Span,
+    marker: char,
+    message: &str,
+) {
+    let line = match span
+        .start
+        .line
+        .checked_sub(1)
+        .and_then(|index| lines.get(index))
+    {
+        Some(line) => line,
+        None => return,
+    };
+    let pad = " ".repeat(gutter);
+
+    writeln!(
+        out,
+        "{:>width$} | {}",
+        span.start.line,
+        line,
+        width = gutter
+    )
+    .unwrap();
+
+    // keep tabs so that the underline is aligned with the source
+    let indent: String = line
+        .chars()
+        .take(span.start.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    // a span across lines is underlined until the end of its first line,
+    // and an empty span (e.g. end of input) still gets one marker
+    let end_column = if span.end.line == span.start.line {
+        span.end.column
+    } else {
+        line.chars().count() + 1
+    };
+    let underline = marker
+        .to_string()
+        .repeat(end_column.saturating_sub(span.start.column).max(1));
+
+    if message.is_empty() {
+        writeln!(out, "{} | {}{}", pad, indent, underline).unwrap();
+    } else {
+        writeln!(out, "{} | {}{} {}", pad, indent, underline, message).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Diagnostic;
+    use crate::util::{Position, Span};
+
+    fn pos(line: usize, column: usize) -> Position {
+        Position {
+            // offsets are not used when rendering
+            offset: 0,
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn primary_span() {
+        let diagnostic = Diagnostic::error("expect then", Span::new(pos(1, 15), pos(1, 16)))
+            .with_note("found `1`");
+
+        assert_eq!(
+            diagnostic.render("<stdin>", "def f(x) if x 1 else 2"),
+            "\
+error: expect then
+ --> <stdin>:1:15
+  |
+1 | def f(x) if x 1 else 2
+  |               ^
+  = note: found `1`
+"
+        );
+    }
+
+    #[test]
+    fn labels_and_wide_gutter() {
+        let source = "def f(x)\n\tx + y\n\n\n\n\n\n\n\ndef g(y) f(y, y)";
+        let diagnostic = Diagnostic::error("wrong call", Span::new(pos(10, 10), pos(10, 17)))
+            .with_label(Span::new(pos(1, 5), pos(2, 7)), "defined here");
+
+        assert_eq!(
+            diagnostic.render("test.ks", source),
+            "\
+error: wrong call
+  --> test.ks:10:10
+   |
+10 | def g(y) f(y, y)
+   |          ^^^^^^^
+ 1 | def f(x)
+   |     ---- defined here
+"
+        );
+    }
+
+    #[test]
+    fn empty_span_and_tabs() {
+        let diagnostic = Diagnostic::warning("here", Span::at(pos(1, 3)));
+
+        assert_eq!(
+            diagnostic.render("t", "\ta"),
+            "\
+warning: here
+ --> t:1:3
+  |
+1 | \ta
+  | \t ^
+"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use super::Value;
+use crate::parser::nodes::Prototype;
+use crate::types::Type;
+use std::io::Write;
+
+/// A function provided by the host, callable after being declared with `extern`
+#[derive(Clone)]
+pub enum Builtin {
+    Unary(fn(f64) -> f64),
+    Binary(fn(f64, f64) -> f64),
+    /// A function of the string runtime, taking values of the given types
+    Runtime {
+        params: &'static [Type],
+        ret: Type,
+        func: fn(&[Value]) -> Value,
+    },
+}
+
+impl Builtin {
+    pub fn arity(&self) -> usize {
+        match self {
+            Builtin::Unary(_) => 1,
+            Builtin::Binary(_) => 2,
+            Builtin::Runtime { params, .. } => params.len(),
+        }
+    }
+
+    /// The types of the args, doubles unless it is a function of the string runtime
+    pub fn param_types(&self) -> Vec<Type> {
+        match self {
+            Builtin::Runtime { params, .. } => params.to_vec(),
+            _ => vec![Type::Double; self.arity()],
+        }
+    }
+
+    pub fn return_type(&self) -> Type {
+        match self {
+            Builtin::Runtime { ret, .. } => ret.clone(),
+            _ => Type::Double,
+        }
+    }
+
+    /// Call the builtin, `args` must have as many values of the right types as its arity
+    pub fn call(&self, args: &[Value]) -> Value {
+        match self {
+            Builtin::Unary(func) => Value::Double(func(args[0].to_f64())),
+            Builtin::Binary(func) => Value::Double(func(args[0].to_f64(), args[1].to_f64())),
+            Builtin::Runtime { func, .. } => func(args),
+        }
+    }
+}
+
+/// putchard - putchar that takes a double and returns 0
+fn putchard(x: f64) -> f64 {
+    let _ = std::io::stderr().write_all(&[x as u8]);
+    0.0
+}
+
+/// printd - printf that takes a double prints it as "%f\n", returning 0
+fn printd(x: f64) -> f64 {
+    eprintln!("{:.6}", x);
+    0.0
+}
+
+/// The string of a value of a function of the string runtime
+fn as_str(value: &Value) -> &str {
+    match value {
+        Value::Str(value) => value,
+        value => unreachable!("{:?} is not a string", value),
+    }
+}
+
+/// print - prints a string as it is, returning 0
+fn print(args: &[Value]) -> Value {
+    eprint!("{}", as_str(&args[0]));
+    Value::Double(0.0)
+}
+
+/// strlen - the length of a string in bytes
+fn strlen(args: &[Value]) -> Value {
+    Value::Int(as_str(&args[0]).len() as i64)
+}
+
+/// concat - a new string made of two strings
+fn concat(args: &[Value]) -> Value {
+    Value::Str([as_str(&args[0]), as_str(&args[1])].concat().into())
+}
+
+/// Get the builtin named `name`, mirroring the functions of libm and the runtime
+pub fn get_builtin(name: &str) -> Option<Builtin> {
+    let builtin = match name {
+        "sin" => Builtin::Unary(f64::sin),
+        "cos" => Builtin::Unary(f64::cos),
+        "tan" => Builtin::Unary(f64::tan),
+        "atan" => Builtin::Unary(f64::atan),
+        "sqrt" => Builtin::Unary(f64::sqrt),
+        "exp" => Builtin::Unary(f64::exp),
+        "log" => Builtin::Unary(f64::ln),
+        "fabs" => Builtin::Unary(f64::abs),
+        "floor" => Builtin::Unary(f64::floor),
+        "ceil" => Builtin::Unary(f64::ceil),
+        "pow" => Builtin::Binary(f64::powf),
+        "atan2" => Builtin::Binary(f64::atan2),
+        "fmod" => Builtin::Binary(|x, y| x % y),
+        "putchard" => Builtin::Unary(putchard),
+        "printd" => Builtin::Unary(printd),
+        "print" => Builtin::Runtime {
+            params: &[Type::Str],
+            ret: Type::Double,
+            func: print,
+        },
+        "strlen" => Builtin::Runtime {
+            params: &[Type::Str],
+            ret: Type::Int,
+            func: strlen,
+        },
+        "concat" => Builtin::Runtime {
+            params: &[Type::Str, Type::Str],
+            ret: Type::Str,
+            func: concat,
+        },
+        _ => return None,
+    };
+    Some(builtin)
+}
+
+/// Get the builtin an extern refers to, which must be declared with the types it takes and returns
+pub fn resolve_extern(proto: &Prototype) -> Result<Builtin, String> {
+    let builtin = get_builtin(&proto.name)
+        .ok_or_else(|| format!("Unknown extern function: {}", proto.name))?;
+
+    if builtin.arity() != proto.args.len() {
+        return Err(format!(
+            "Extern function {} expects {} args but is declared with {}.",
+            proto.name,
+            builtin.arity(),
+            proto.args.len()
+        ));
+    }
+
+    let params = builtin.param_types();
+    let matches = (0..proto.args.len()).all(|i| proto.arg_type(i) == params[i])
+        && proto.return_type() == builtin.return_type();
+    if matches {
+        return Ok(builtin);
+    }
+
+    if let Builtin::Runtime { .. } = builtin {
+        let params: Vec<_> = params.iter().map(Type::to_string).collect();
+        Err(format!(
+            "Extern function {} takes {} and returns {} but is declared as {}.",
+            proto.name,
+            params.join(", "),
+            builtin.return_type(),
+            proto
+        ))
+    } else {
+        Err(format!(
+            "Extern function {} takes and returns doubles but is declared as {}.",
+            proto.name, proto
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+
+    #[test]
+    fn builtins() {
+        let call = |name, args: &[f64]| {
+            let args: Vec<_> = args.iter().map(|&arg| Value::Double(arg)).collect();
+            get_builtin(name).unwrap().call(&args)
+        };
+        let call_str = |name, args: &[Value]| get_builtin(name).unwrap().call(args);
+        assert_eq!(call("sqrt", &[16.0]), Value::Double(4.0));
+        assert_eq!(call("pow", &[2.0, 10.0]), Value::Double(1024.0));
+        assert_eq!(call("fmod", &[7.5, 2.0]), Value::Double(1.5));
+        assert_eq!(get_builtin("atan2").unwrap().arity(), 2);
+        assert!(get_builtin("printf").is_none());
+
+        let string = |value: &str| Value::Str(value.into());
+        assert_eq!(call_str("strlen", &[string("héllo")]), Value::Int(6));
+        assert_eq!(
+            call_str("concat", &[string("ab"), string("c")]),
+            string("abc")
+        );
+    }
+
+    #[test]
+    fn resolve_externs() {
+        let proto = |source: &str| match Parser::new(Lexer::new(source.chars())).parse() {
+            Ok(ASTNode::ExternNode(proto)) => proto,
+            node => panic!("unexpected node {:?}", node),
+        };
+
+        assert!(resolve_extern(&proto("extern sqrt(x)")).is_ok());
+        assert!(resolve_extern(&proto("extern sqrt(x: double): double")).is_ok());
+        assert_eq!(
+            resolve_extern(&proto("extern printf(x)")).err(),
+            Some("Unknown extern function: printf".into())
+        );
+        assert_eq!(
+            resolve_extern(&proto("extern sqrt(x y)")).err(),
+            Some("Extern function sqrt expects 1 args but is declared with 2.".into())
+        );
+        assert_eq!(
+            resolve_extern(&proto("extern sqrt(n: int)")).err(),
+            Some(
+                "Extern function sqrt takes and returns doubles but is declared as sqrt(n: int)."
+                    .into()
+            )
+        );
+        assert!(resolve_extern(&proto("extern concat(a: string b: string): string")).is_ok());
+        assert_eq!(
+            resolve_extern(&proto("extern strlen(s: string)")).err(),
+            Some(
+                "Extern function strlen takes string and returns int but is declared as strlen(s: string)."
+                    .into()
+            )
+        );
+    }
+}
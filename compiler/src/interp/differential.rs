@@ -0,0 +1,323 @@
+//! Runs the same programs through the interpreter, the bytecode VM and the JIT
+//! and compares the results, so that a failure points at either the frontend or a backend.
+
+use crate::backend::{create_backend, Backend, BackendKind};
+use crate::codegen::passes::OptimizationLevel;
+use crate::lexer::Lexer;
+use crate::parser::nodes::ASTNode;
+use crate::parser::parser::Parser;
+use crate::util::Span;
+use inkwell::context::Context;
+
+/// Results of the top level expressions, or where they failed
+fn run_on(backend: &mut dyn Backend, nodes: &[ASTNode]) -> Vec<Result<f64, Span>> {
+    let mut results = vec![];
+    for node in nodes {
+        match node {
+            ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                results.push(backend.evaluate(func).map_err(|err| err.span))
+            }
+            node => {
+                backend.run_item(node).unwrap();
+            }
+        }
+    }
+    results
+}
+
+/// Compare bit by bit, so that 0.0 does not equal -0.0.
+/// Any NaN equals any NaN, as constant folding may not produce the same NaN as the hardware.
+/// Errors are compared by where they are reported.
+fn same(expected: &[Result<f64, Span>], actual: &[Result<f64, Span>]) -> bool {
+    expected.len() == actual.len()
+        && expected.iter().zip(actual).all(|(a, b)| match (a, b) {
+            (Ok(a), Ok(b)) => (a.is_nan() && b.is_nan()) || a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        })
+}
+
+/// Check that the VM and the JIT at every optimization level agree with the interpreter
+fn assert_same(program: &str) {
+    let parsed = Parser::new(Lexer::new(program.chars())).parse_program();
+    assert_eq!(parsed.errors, vec![], "program should parse");
+
+    let context = Context::create();
+    let interp = create_backend(BackendKind::Interp, &context, OptimizationLevel::None);
+    let expected = run_on(interp.unwrap().as_mut(), &parsed.nodes);
+
+    for (kind, level) in &[
+        (BackendKind::Vm, OptimizationLevel::None),
+        (BackendKind::Jit, OptimizationLevel::None),
+        (BackendKind::Jit, OptimizationLevel::Aggressive),
+    ] {
+        let mut backend = create_backend(*kind, &context, *level).unwrap();
+        let actual = run_on(backend.as_mut(), &parsed.nodes);
+        assert!(
+            same(&expected, &actual),
+            "{:?} at {:?} disagrees with the interpreter on\n{}\ninterpreter: {:?}\n{:?}: {:?}",
+            kind,
+            level,
+            program,
+            expected,
+            kind,
+            actual
+        );
+    }
+}
+
+#[test]
+fn arithmetic() {
+    assert_same("1+2*3; 4-2-1; 1/3.0; 2*(3+4)/5.0; 0.0-0; 1/0.0; 0-1/0.0;");
+    assert_same("1 < 2; 2 < 1; 1 < 1; 2 > 1; 1 > 2; 0.0/0 < 1; 1 > 0.0/0; 0.0/0;");
+}
+
+#[test]
+fn types() {
+    assert_same("7 / 2; 0 - 7 / 2; 1 / 0; 2 * (3 + 4) / 5; 9223372036854775807 + 1;");
+    assert_same(
+        "int(2.9); int(0.0 - 2.9); int(0.0 / 0); int(1 / 0.0); int(0 - 1 / 0.0); \
+         double(true); bool(0 - 0.0); bool(0.0 / 0); int(1 < 2) + 1;",
+    );
+    assert_same(
+        "
+        def half(n: int) n / 2;
+        def pick(b: bool, x) if b then x else 0 - x;
+        def sum(n: int) var acc = 0 in (for i = 0, i < n in acc = acc + i) * 0.0 + double(acc);
+        def min(): int 0 - 9223372036854775807 - 1;
+        half(9); pick(false, 2); pick(half(1) < 1, 2); sum(10); min() / (0 - 1);
+        ",
+    );
+}
+
+#[test]
+fn control_flow() {
+    assert_same(
+        "
+        def fib(n) if n < 3 then 1 else fib(n - 1) + fib(n - 2);
+        def cond(x) if x then 1 else 2;
+        fib(1); fib(10); fib(20);
+        cond(0); cond(0-0); cond(0/0); cond(0.5);
+        ",
+    );
+    assert_same(
+        "
+        def count(n) var c = 0 in (for i = 0, i < n in c = c + 1) + c;
+        def shadow(i) (for i = 0, i < 3, 0.5 in i) + i;
+        def down(n) var acc = 0 in (for i = n, i > 0, 0 - 1 in acc = acc * 2 + i) + acc;
+        def nested(n) var acc = 0 in (for i = 0, i < n in for j = 0, j < i in acc = acc + j) + acc;
+        count(0); count(5); shadow(7); down(6); nested(6);
+        ",
+    );
+}
+
+#[test]
+fn variables() {
+    assert_same(
+        "
+        def binary : 1 (x y) y;
+        def swap(a b) var tmp = a in a = b : b = tmp : a - b;
+        def outer(x) (var x = x + 1, y = x * 2 in x + y) + x;
+        def default(x) var y in y + x;
+        swap(1, 3); outer(5); default(4);
+        ",
+    );
+}
+
+#[test]
+fn user_defined_operators() {
+    assert_same(
+        "
+        def unary ! (v) if v then 0 else 1;
+        def unary - (v) 0 - v;
+        def binary | 5 (l r) if l then 1 else if r then 1 else 0;
+        def binary & 6 (l r) if !l then 0 else !!r;
+        def binary ~ 9 (l r) !(l < r | l > r);
+        !0; !1; -(2 + 3); 0 | 1; 1 & 0; 1 < 2 & 2 < 3; 2 ~ 2; 1 ~ 2;
+        ",
+    );
+}
+
+#[test]
+fn externs() {
+    assert_same(
+        "
+        extern sin(x); extern cos(x); extern sqrt(x); extern exp(x); extern pow(x y);
+        sin(1); cos(2); sqrt(2); exp(1.5); pow(2, 0.5);
+        def norm(x y) sqrt(x * x + y * y);
+        def identity(x) sin(x) * sin(x) + cos(x) * cos(x);
+        norm(3, 4); identity(0.3); sqrt(0 - 1);
+        ",
+    );
+}
+
+#[test]
+fn strings() {
+    assert_same(
+        r#"
+        extern strlen(s: string): int; extern concat(a: string b: string): string;
+        def repeat(s n: int) var acc = "" in (for i = 0, i < n in acc = concat(acc, s)) + double(strlen(acc));
+        def longer(a b) if strlen(b) < strlen(a) then a else b;
+        strlen("\u{1F600}\t\"\\"); repeat("ab", 5); strlen(longer("abc", "de")); strlen("");
+        "#,
+    );
+}
+
+#[test]
+fn arrays() {
+    assert_same(
+        "
+        def sum(a: [double] n: int) var acc = 0 in (for i = 0, i < n - 1 in acc = acc + a[i]) + acc;
+        def swap(a: [int] i j) var t = a[i] in (a[i] = a[j]) * 0 + (a[j] = t);
+        def reverse(a: [int] n: int) for i = 0, i < n / 2 - 1 in swap(a, i, n - 1 - i);
+        def grid(n: int) [[n, n + 1], [n * 2]];
+        def squares(n: int) var acc = 0 in (for i = 1, i < n in acc = acc + [i * i][0]) * 0.0 + double(acc);
+        sum([1.5, 2, 3], 3); var a = [1, 2, 3, 4, 5] in reverse(a, 5) + double(a[0] * 10 + a[4]);
+        var g = grid(3) in g[1][0] = g[0][1] + g[1][0]; grid(2)[0][1]; squares(4);
+        [true, false][1]; [[1.5]][0][0] = 2;
+        sum([1], 2); var a = [1] in a[0 - 1] = 2; grid(1)[1][1];
+        ",
+    );
+}
+
+#[test]
+fn structs() {
+    assert_same(
+        "
+        struct Vec2 { x, y }
+        struct Body { pos: Vec2, vel: Vec2, mass: int, tags: [int] }
+        def step(b: Body dt) Body {
+            pos = Vec2 { x = b.pos.x + b.vel.x * dt, y = b.pos.y + b.vel.y * dt },
+            vel = b.vel, mass = b.mass, tags = b.tags
+        };
+        def energy(b: Body) 0.5 * double(b.mass) * (b.vel.x * b.vel.x + b.vel.y * b.vel.y);
+        var b = Body { pos = Vec2 { x = 0, y = 0 }, vel = Vec2 { x = 1, y = 2 }, mass = 3, tags = [7] } in
+          (for i = 0, i < 2 in b = step(b, 0.5)) + b.pos.x * 10 + b.pos.y + double(b.tags[0]);
+        energy(Body { pos = Vec2 { x = 0, y = 0 }, vel = Vec2 { x = 3, y = 4 }, mass = 2, tags = [0] });
+        ",
+    );
+}
+
+#[test]
+fn unions() {
+    assert_same(
+        "
+        type Shape = Circle(r) | Rect(w, h) | Empty
+        type Op = Add(a, b) | Neg(a) | Area(s: Shape, scale: int)
+        def area(s: Shape) match s { Circle(r) -> 3 * r * r, Rect(w, h) -> w * h, Empty -> 0 };
+        def eval(op) match op {
+          Add(a, b) -> a + b,
+          Neg(a) -> 0 - a,
+          Area(Empty, _) -> 0 - 1,
+          Area(s, k) -> area(s) * double(k),
+        };
+        eval(Add(1, 2)) + eval(Neg(4)) + eval(Area(Rect(2, 3), 2)) + eval(Area(Empty, 5));
+        var s = Empty in (for i = 1, i < 3 in s = match s { Empty -> Circle(i), Circle(r) -> Rect(r, i), _ -> s })
+          + area(s);
+        ",
+    );
+}
+
+#[test]
+fn recursive_unions() {
+    assert_same(
+        "
+        type Tree = Leaf | Node(l: Tree, v, r: Tree)
+        def insert(t x) match t {
+          Leaf -> Node(Leaf, x, Leaf),
+          Node(l, v, r) -> if x < v then Node(insert(l, x), v, r) else Node(l, v, insert(r, x)),
+        };
+        def sum(t) match t { Leaf -> 0, Node(l, v, r) -> sum(l) + v + sum(r) };
+        def leaves(t) match t { Node(Leaf, _, Leaf) -> 1, Node(l, _, r) -> leaves(l) + leaves(r), Leaf -> 0 };
+        var t = insert(insert(insert(insert(Leaf, 2), 1), 3), 4) in sum(t) * 10 + double(leaves(t));
+        var t = insert(Node(Leaf, 2, Leaf), 1) in match t { Node(l, _, _) -> sum(l = Leaf), _ -> 1 } + sum(t);
+        ",
+    );
+}
+
+#[test]
+fn first_class_functions() {
+    assert_same(
+        "
+        extern sin(x);
+        def square(x) x * x;
+        def apply(f x) f(x);
+        def integrate(f a b n: int)
+          var sum = 0.0, h = (b - a) / double(n) in
+            (for i = 0, i < n - 1 in sum = sum + f(a + (double(i) + 0.5) * h) * h) + sum;
+        def pick(b: bool) if b then square else sin;
+        apply(square, 3) + integrate(sin, 0, 3.14159, 100);
+        var f = pick(false), g = pick(true) in f(1) + g(2) + apply(f, 0);
+        ",
+    );
+}
+
+#[test]
+fn closures() {
+    assert_same(
+        "
+        def map(f: (double) -> double a: [double] b: [double] n: int)
+          for i = 0, i < n - 1 in b[i] = f(a[i]);
+        def fold(f: (double, double) -> double acc a: [double] n: int)
+          (for i = 0, i < n - 1 in acc = f(acc, a[i])) + acc;
+        def integrate(f: (double) -> double a b n: int)
+          var h = (b - a) / double(n) in
+            fold(\\sum i -> sum + f(a + (i + 0.5) * h) * h, 0, [0, 1, 2, 3], n);
+        def adder(k) \\x -> x + k;
+        def compose(f: (double) -> double g: (double) -> double) \\x -> f(g(x));
+        var k = 3, a = [1, 2, 3, 4], b = [0, 0, 0, 0] in
+          map(\\x -> x * k, a, b, 4) + fold(\\acc x -> acc + x, 0, b, 4);
+        var c = 2 in integrate(\\x -> c * x, 0, 1, 4);
+        var f = compose(adder(1), adder(10)) in var g = compose(f, f) in f(0.5) + g(0);
+        var k = 1 in var f = \\x -> x + k in (k = 100) + f(0);
+        def count(n) if n < 1 then 0 else var g = \\x -> count(x) in 1 + g(n - 1);
+        count(3);
+        ",
+    );
+}
+
+#[test]
+fn gradients() {
+    assert_same(
+        "
+        extern sin(x);
+        extern cos(x);
+        extern exp(x);
+        extern log(x);
+        extern sqrt(x);
+        def tanh(x) var e = exp(2 * x) in (e - 1) / (e + 1);
+        def norm(x y) sqrt(x * x + y * y);
+        def pow(x n) if n < 1 then 1 else x * pow(x, n - 1);
+        def f(x y z) log(norm(x, y)) * cos(z) + pow(tanh(x), 3) * sin(y * z);
+        var d = grad(tanh) in d(0.5);
+        var d = grad(norm) in d(3, 4)[0] + d(3, 4)[1];
+        var d = grad(f) in var g = d(0.5, 1, 2) in g[0] + g[1] * 10 + g[2] * 100;
+        grad(pow);
+        def' tanh;
+        def' norm;
+        tanh'(0.5) + norm'(3, 4)[1];
+        ",
+    );
+}
+
+#[test]
+fn redefinitions() {
+    // the functions defined before a redefinition call the latest definition
+    assert_same(
+        "
+        def f(x) x + 1;
+        def g(x) f(x) * 2;
+        def h(x) if x < 1 then f(x) else h(x - 1);
+        g(1); h(2);
+        def f(y) y * 10;
+        g(1); h(2); var k = f in k(3);
+        def h(x) 0 - x;
+        h(2);
+        ",
+    );
+}
+
+#[test]
+fn runtime_errors() {
+    // codegen and the VM reject these when compiling, the interpreter when evaluating
+    assert_same("x; foo(1); def f(x) x; f(1, 2); 1 = 2; x + 1; f(y);");
+}
@@ -0,0 +1,922 @@
+use super::builtins::{resolve_extern, Builtin};
+use super::Value;
+use crate::backend::{Backend, Lower, Lowering};
+use crate::diagnostics::{Diagnostic, SpannedError};
+use crate::matching::{Decision, Occurrence};
+use crate::parser::nodes::{
+    DerivativeDef, Expression, ExpressionKind, Function, Prototype, StructDef, UnionDef,
+};
+use crate::types::{check_function, Type, TypeDefs, TypeError};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Values of the variables in scope
+type Environment = HashMap<String, Value>;
+
+/// Evaluates the AST directly, with the same semantics as the generated code.
+///
+/// Unlike codegen, errors such as unknown variables are only reported
+/// when the offending expression is evaluated.
+#[derive(Default)]
+pub struct Interpreter {
+    /// Every function defined so far, as elaborated by the type checker
+    functions: HashMap<String, Function>,
+    /// Every extern declared so far, all of them are builtins
+    externs: HashMap<String, Builtin>,
+    /// The typed prototype of every function and extern, to type check the functions calling them
+    prototypes: HashMap<String, Prototype>,
+    /// Every struct and union defined so far
+    type_defs: TypeDefs,
+    /// Lowers every function into functions without gradients or lambdas
+    lowering: Lowering,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Declare an extern, which must be one of the builtins
+    pub fn declare_extern(&mut self, proto: &Prototype) -> Result<(), String> {
+        let builtin = resolve_extern(proto)?;
+        self.externs.insert(proto.name.clone(), builtin);
+        self.prototypes
+            .insert(proto.name.clone(), proto.annotated());
+        Ok(())
+    }
+
+    /// Define a struct, so that it can be used by later functions
+    pub fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<TypeError>> {
+        self.type_defs.define_struct(def)
+    }
+
+    /// Define a tagged union, so that its variants can be used by later functions
+    pub fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<TypeError>> {
+        self.type_defs.define_union(def)
+    }
+
+    /// Type check a function against the functions and types defined and declared so far
+    pub fn check(&self, func: &Function) -> Result<Function, Box<TypeError>> {
+        check_function(func, &self.prototypes, &self.type_defs)
+    }
+
+    /// Declare a function defined later, so that the functions before its definition can call it
+    pub fn declare_func(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        let proto = proto.annotated();
+        self.type_defs.check_annotations(&proto)?;
+        self.check_redefinition(&proto)
+            .map_err(|err| Diagnostic::error(err, proto.span))?;
+        self.prototypes.entry(proto.name.clone()).or_insert(proto);
+        Ok(())
+    }
+
+    /// Type check and define a function, so that it can be called from later expressions.
+    /// A redefinition replaces the previous definition, which the functions calling it call from then on.
+    pub fn define_func(&mut self, func: &Function) -> Result<(), Box<Diagnostic>> {
+        self.lower(func)
+    }
+
+    /// Type check and define `f'` for `def' f`, so that it can be called from later expressions
+    pub fn define_derivative(&mut self, def: &DerivativeDef) -> Result<(), Box<Diagnostic>> {
+        self.lower_derivative(def)
+    }
+
+    /// Type check and evaluate an anonymous function wrapping a top level expression
+    pub fn evaluate(&mut self, func: &Function) -> Result<f64, Box<Diagnostic>> {
+        self.lower_and_run(func, |interp, func| {
+            let value = interp
+                .eval_expr(&func.body, &mut Environment::new())
+                .map_err(|err| err.into_diagnostic(func.span))?;
+            Ok(value.to_f64())
+        })
+    }
+
+    /// Check a function against the previous declaration or definition of the same function,
+    /// whose types the functions calling it were checked against
+    fn check_redefinition(&self, proto: &Prototype) -> Result<(), String> {
+        match self.prototypes.get(&proto.name) {
+            Some(previous)
+                if previous.arg_types != proto.arg_types || previous.ret_type != proto.ret_type =>
+            {
+                Err(format!(
+                    "Function {} is already declared as {}.",
+                    proto.name, previous
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `name` is a defined function or a declared extern
+    pub fn is_callable(&self, name: &str) -> bool {
+        self.functions.contains_key(name) || self.externs.contains_key(name)
+    }
+
+    /// Check that `name` is a defined function or a declared extern taking `arg_count` arguments
+    fn check_call(&self, name: &str, arg_count: usize) -> Result<(), String> {
+        let arity = match (self.functions.get(name), self.externs.get(name)) {
+            (Some(func), _) => func.prototype.args.len(),
+            (None, Some(builtin)) => builtin.arity(),
+            // declared to be defined later
+            (None, None) if self.prototypes.contains_key(name) => {
+                return Err(format!("Function {} is not defined.", name))
+            }
+            (None, None) => return Err(format!("Unknown function: {}", name)),
+        };
+
+        if arg_count != arity {
+            return Err(format!(
+                "Unmatched arg number. Function {} expects {} but the input has {}.",
+                name, arity, arg_count
+            ));
+        }
+        Ok(())
+    }
+
+    /// Call a defined function or a declared extern by name
+    pub fn call(&self, name: &str, args: &[Value]) -> Result<Value, SpannedError> {
+        self.check_call(name, args.len())?;
+
+        match self.functions.get(name) {
+            Some(func) => {
+                let mut env = func
+                    .prototype
+                    .args
+                    .iter()
+                    .cloned()
+                    .zip(args.iter().cloned())
+                    .collect();
+                self.eval_expr(&func.body, &mut env)
+            }
+            None => Ok(self.externs[name].call(args)),
+        }
+    }
+
+    /// Evaluate an expression, with the variables in scope in `env`.
+    /// Errors caused by an expression, e.g. an unknown variable, are at the span of the expression,
+    /// and runtime errors, e.g. an index out of bounds, have no span.
+    pub fn eval_expr(
+        &self,
+        expr: &Expression,
+        env: &mut Environment,
+    ) -> Result<Value, SpannedError> {
+        // locates an error at the expression
+        let at_expr = |message: String| SpannedError::new(message, expr.span);
+        match &expr.kind {
+            ExpressionKind::NumberExpr(num) => Ok(Value::Double(*num)),
+            ExpressionKind::IntExpr(num) => Ok(Value::Int(*num)),
+            ExpressionKind::BoolExpr(value) => Ok(Value::Bool(*value)),
+            ExpressionKind::StringExpr(value) => Ok(Value::Str(value.as_str().into())),
+            ExpressionKind::VariableExpr(var) => match env.get(var) {
+                Some(value) => Ok(value.clone()),
+                // a function used as a value
+                None if self.is_callable(var) => Ok(Value::Function(
+                    self.prototypes[var].ty(),
+                    var.as_str().into(),
+                    Rc::new([]),
+                )),
+                None => Err(at_expr(format!("Unknown variable name: {}", var))),
+            },
+            ExpressionKind::BinaryExpr('=', left, right) => {
+                // the destination must be a variable, which should not be evaluated, or an array element
+                let var = match &left.kind {
+                    ExpressionKind::VariableExpr(var) => var,
+                    ExpressionKind::IndexExpr(array, index) => {
+                        // the value is evaluated first, as for a variable
+                        let value = self.eval_expr(right, env)?;
+                        let array = self.eval_expr(array, env)?;
+                        let index = self.eval_expr(index, env)?;
+                        array.store(&index, value.clone(), left.span.start)?;
+                        return Ok(value);
+                    }
+                    _ => {
+                        return Err(SpannedError::new(
+                            "Destination of '=' must be a variable or an array element.",
+                            left.span,
+                        ))
+                    }
+                };
+
+                let value = self.eval_expr(right, env)?;
+                match env.get_mut(var) {
+                    Some(slot) => *slot = value.clone(),
+                    None => {
+                        return Err(SpannedError::new(
+                            format!("Unknown variable name: {}", var),
+                            left.span,
+                        ))
+                    }
+                }
+
+                Ok(value)
+            }
+            ExpressionKind::BinaryExpr(op, left, right) => {
+                let lhs = self.eval_expr(left, env)?;
+                let rhs = self.eval_expr(right, env)?;
+                match op {
+                    '+' | '-' | '*' | '/' | '<' | '>' => Ok(Value::binary(*op, lhs, rhs)?),
+                    // fall back to user defined operators
+                    _ => {
+                        let name = format!("binary{}", op);
+                        if !self.is_callable(&name) {
+                            return Err(at_expr(format!("Unknown binary op {}", op)));
+                        }
+                        self.call(&name, &[lhs, rhs])
+                    }
+                }
+            }
+            ExpressionKind::UnaryExpr(op, operand) => {
+                let operand = self.eval_expr(operand, env)?;
+                let name = format!("unary{}", op);
+                if !self.is_callable(&name) {
+                    return Err(at_expr(format!("Unknown unary op {}", op)));
+                }
+                self.call(&name, &[operand])
+            }
+            ExpressionKind::CastExpr(ty, operand) => {
+                Ok(self.eval_expr(operand, env)?.cast(ty.clone()))
+            }
+            ExpressionKind::LambdaExpr { .. } => {
+                unreachable!("lambdas are converted into closures")
+            }
+            ExpressionKind::GradExpr(_) => {
+                unreachable!("gradients are expanded before type checking")
+            }
+            ExpressionKind::ClosureExpr {
+                function,
+                captures,
+                ty,
+            } => Ok(Value::Function(
+                ty.clone(),
+                function.as_str().into(),
+                captures
+                    .iter()
+                    .map(|var| {
+                        env.get(var)
+                            .cloned()
+                            .ok_or_else(|| at_expr(format!("Unknown variable name: {}", var)))
+                    })
+                    .collect::<Result<_, _>>()?,
+            )),
+            ExpressionKind::ArrayExpr(elements) => Ok(Value::array(
+                elements
+                    .iter()
+                    .map(|element| self.eval_expr(element, env))
+                    .collect::<Result<_, _>>()?,
+            )),
+            ExpressionKind::IndexExpr(array, index) => {
+                let array = self.eval_expr(array, env)?;
+                let index = self.eval_expr(index, env)?;
+                Ok(array.index(&index, expr.span.start)?)
+            }
+            ExpressionKind::StructExpr(name, fields) => Ok(Value::Struct(
+                Type::named(name),
+                fields
+                    .iter()
+                    .map(|(_, value)| self.eval_expr(value, env))
+                    .collect::<Result<_, _>>()?,
+            )),
+            ExpressionKind::FieldExpr {
+                object,
+                field,
+                index,
+            } => {
+                let index = index.ok_or_else(|| at_expr(format!("Unknown field: {}", field)))?;
+                Ok(self.eval_expr(object, env)?.field(index)?)
+            }
+            ExpressionKind::VariantExpr(name, args) => {
+                let (def, tag) = self
+                    .type_defs
+                    .variant(name)
+                    .ok_or_else(|| at_expr(format!("Unknown variant: {}", name)))?;
+                Ok(Value::Variant(
+                    Type::named(&def.name),
+                    tag,
+                    args.iter()
+                        .map(|arg| self.eval_expr(arg, env))
+                        .collect::<Result<_, _>>()?,
+                ))
+            }
+            ExpressionKind::MatchExpr { value, arms, tree } => {
+                let value = self.eval_expr(value, env)?;
+                let tree = tree.as_ref().ok_or("Match is not type checked.")?;
+                let (index, bindings) = decide(tree, &value)?;
+
+                // bind the variables of the pattern, as a var expression does
+                let mut old_vals = Vec::with_capacity(bindings.len());
+                for (var, occurrence) in bindings {
+                    let part = occurrence
+                        .iter()
+                        .try_fold(value.clone(), |part, &field| part.field(field))?;
+                    old_vals.push((var.clone(), env.insert(var.clone(), part)));
+                }
+
+                let body = self.eval_expr(&arms[index].1, env);
+                restore_env(env, old_vals);
+                body
+            }
+            ExpressionKind::CallExpr(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval_expr(arg, env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // a variable holding a function shadows the function with its name
+                let (function, args) = match env.get(name) {
+                    Some(callee) => {
+                        let (function, captured) = callee.function()?;
+                        (function, [captured, &args].concat())
+                    }
+                    None => (name.as_str(), args),
+                };
+                // the errors of the callee are located in its body, only the call itself is at the call
+                self.check_call(function, args.len()).map_err(at_expr)?;
+                self.call(function, &args)
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                if self.eval_expr(cond, env)?.is_true() {
+                    self.eval_expr(then_expr, env)
+                } else {
+                    self.eval_expr(else_expr, env)
+                }
+            }
+            ExpressionKind::ForExpr {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                // evaluate the start value without the variable in scope
+                let start = self.eval_expr(start, env)?;
+
+                let old_val = env.insert(var.clone(), start);
+                let result = self.eval_for_loop(var, end, step.as_deref(), body, env);
+                restore_env(env, vec![(var.clone(), old_val)]);
+                result?;
+
+                // for expression always evaluates to 0.0
+                Ok(Value::Double(0.0))
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                let mut old_vals = Vec::with_capacity(vars.len());
+                let mut result = Ok(());
+                for (var, init) in vars {
+                    // the initializer is evaluated before the variable is in scope
+                    let init = match init {
+                        Some(init) => match self.eval_expr(init, env) {
+                            Ok(init) => init,
+                            Err(err) => {
+                                result = Err(err);
+                                break;
+                            }
+                        },
+                        None => Value::Double(0.0),
+                    };
+
+                    old_vals.push((var.clone(), env.insert(var.clone(), init)));
+                }
+
+                let body = result.and_then(|_| self.eval_expr(body, env));
+                restore_env(env, old_vals);
+                body
+            }
+        }
+    }
+
+    /// Run a for loop with the induction variable already in `env`.
+    /// As the generated code, the body runs at least once,
+    /// and the end condition is evaluated before the variable is incremented.
+    fn eval_for_loop(
+        &self,
+        var: &str,
+        end: &Expression,
+        step: Option<&Expression>,
+        body: &Expression,
+        env: &mut Environment,
+    ) -> Result<(), SpannedError> {
+        loop {
+            // the value of body is ignored
+            self.eval_expr(body, env)?;
+
+            let step = step.map(|step| self.eval_expr(step, env)).transpose()?;
+
+            let end_cond = self.eval_expr(end, env)?;
+
+            // scopes in the body restore what they shadow, so the variable is still bound
+            let slot = env.get_mut(var).unwrap();
+            let step = step.unwrap_or_else(|| Value::Int(1).cast(slot.ty()));
+            *slot = Value::binary('+', slot.clone(), step)?;
+
+            if !end_cond.is_true() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Lower for Interpreter {
+    type Output = ();
+    type Error = Box<Diagnostic>;
+
+    fn front_end(&mut self) -> (&mut Lowering, &HashMap<String, Prototype>, &TypeDefs) {
+        (&mut self.lowering, &self.prototypes, &self.type_defs)
+    }
+
+    fn declare_lowered(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        self.prototypes.insert(proto.name.clone(), proto.clone());
+        Ok(())
+    }
+
+    /// Define a function converted into closures, replacing the previous definition if any
+    fn define_lowered(&mut self, func: &Function) -> Result<(), Box<Diagnostic>> {
+        self.check_redefinition(&func.prototype)
+            .map_err(|err| Diagnostic::error(err, func.prototype.span))?;
+        self.prototypes
+            .insert(func.prototype.name.clone(), func.prototype.clone());
+        self.functions
+            .insert(func.prototype.name.clone(), func.clone());
+        Ok(())
+    }
+
+    fn forget_lowered(&mut self, name: &str) {
+        self.functions.remove(name);
+        self.prototypes.remove(name);
+    }
+}
+
+impl Backend for Interpreter {
+    fn declare_extern(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        Interpreter::declare_extern(self, proto)
+            .map_err(|err| Box::new(Diagnostic::error(err, proto.span)))
+    }
+
+    fn declare_function(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        self.declare_func(proto)
+    }
+
+    fn define_function(&mut self, func: &Function) -> Result<(), Box<Diagnostic>> {
+        self.define_func(func)
+    }
+
+    fn define_derivative(&mut self, def: &DerivativeDef) -> Result<(), Box<Diagnostic>> {
+        Interpreter::define_derivative(self, def)
+    }
+
+    fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<Diagnostic>> {
+        Interpreter::define_struct(self, def).map_err(|err| Box::new(err.into()))
+    }
+
+    fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<Diagnostic>> {
+        Interpreter::define_union(self, def).map_err(|err| Box::new(err.into()))
+    }
+
+    fn evaluate(&mut self, func: &Function) -> Result<f64, Box<Diagnostic>> {
+        Interpreter::evaluate(self, func)
+    }
+
+    /// The AST of the function, which is what gets interpreted
+    fn dump(&self, name: &str) -> Option<String> {
+        match (self.functions.get(name), self.externs.get(name)) {
+            (Some(func), _) => Some(format!("{:#?}", func)),
+            (None, Some(_)) => Some(format!("builtin {}", name)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The variables of an arm and the parts of the matched value they are bound to
+type Bindings = [(String, Occurrence)];
+
+/// Follow a decision tree, returning the arm a value goes to and the parts of it to bind
+fn decide<'a>(tree: &'a Decision, value: &Value) -> Result<(usize, &'a Bindings), String> {
+    match tree {
+        Decision::Arm { index, bindings } => Ok((*index, bindings)),
+        Decision::Switch {
+            occurrence,
+            cases,
+            default,
+            ..
+        } => {
+            let tag = occurrence
+                .iter()
+                .try_fold(value.clone(), |part, &field| part.field(field))?
+                .tag()?;
+            match cases.iter().find(|(case, _)| *case == tag) {
+                Some((_, decision)) => decide(decision, value),
+                None => decide(default.as_ref().expect("exhaustive"), value),
+            }
+        }
+    }
+}
+
+/// Restore bindings shadowed by a scope, in reverse order of shadowing
+fn restore_env(env: &mut Environment, old_vals: Vec<(String, Option<Value>)>) {
+    for (var, old_val) in old_vals.into_iter().rev() {
+        match old_val {
+            Some(old_val) => env.insert(var, old_val),
+            None => env.remove(&var),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+
+    /// Interpret a program, returning the results of its top level expressions
+    fn interpret_all(program: &str) -> Vec<Result<f64, String>> {
+        let mut interp = Interpreter::new();
+        let mut results = vec![];
+
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::ExternNode(proto) => interp.declare_extern(&proto).unwrap(),
+                ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                    results.push(interp.evaluate(&func).map_err(|err| err.message))
+                }
+                ASTNode::FunctionNode(func) => interp.define_func(&func).unwrap(),
+                ASTNode::DerivativeNode(def) => interp.define_derivative(&def).unwrap(),
+                ASTNode::StructNode(def) => interp.define_struct(&def).unwrap(),
+                ASTNode::UnionNode(def) => interp.define_union(&def).unwrap(),
+                _ => continue,
+            }
+        }
+
+        results
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            interpret_all("1+2*3; 4-2; 1/4.0; 1 < 2; 2 < 1; 2 > 1;"),
+            vec![Ok(7.0), Ok(2.0), Ok(0.25), Ok(1.0), Ok(0.0), Ok(1.0)]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            interpret_all(
+                r#"
+                extern strlen(s: string): int;
+                extern concat(a: string b: string): string;
+                def greet(name) concat("hello, ", name);
+                strlen(greet("world")); strlen("\u{e9}\n"); strlen(concat("", ""));
+                "hi";
+                "#
+            ),
+            vec![
+                Ok(12.0),
+                Ok(3.0),
+                Ok(0.0),
+                Err("Cannot convert string to double.".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn structs() {
+        assert_eq!(
+            interpret_all(
+                "
+                struct Point { x, y }
+                struct Particle { pos: Point, mass: int }
+                def add(a: Point b: Point) Point { x = a.x + b.x, y = a.y + b.y };
+                def norm2(p) p.x * p.x + p.y * p.y;
+                def heaviest(ps: [Particle] n: int)
+                  var best = ps[0] in
+                    (for i = 1, i < n - 1 in if best.mass < ps[i].mass then best = ps[i] else best)
+                    + double(best.mass);
+                norm2(add(Point { x = 1, y = 2 }, Point { x = 2, y = 2 }));
+                var o = Point { x = 0, y = 0 } in heaviest([
+                  Particle { pos = o, mass = 2 },
+                  Particle { pos = o, mass = 7 },
+                  Particle { pos = o, mass = 3 },
+                ], 3);
+                var p = Point { x = 1, y = 2 } in var q = p in (q = Point { x = 5, y = 0 }).x + p.x;
+                Point { x = 1, y = 2 };
+                "
+            ),
+            vec![
+                Ok(25.0),
+                Ok(7.0),
+                Ok(6.0),
+                Err("Cannot convert Point to double.".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unions() {
+        assert_eq!(
+            interpret_all(
+                "
+                type Shape = Circle(r) | Rect(w, h) | Empty
+                type Pair = Pair(a: Shape, b: Shape)
+                def area(s) match s {
+                  Circle(r) -> 3 * r * r,
+                  Rect(w, h) -> w * h,
+                  Empty -> 0,
+                }
+                def both(p) match p {
+                  Pair(Empty, Empty) -> 0,
+                  Pair(Empty, s) -> area(s),
+                  Pair(s, _) -> area(s) + 100,
+                }
+                area(Rect(2, 3)); area(Circle(1)); area(Empty);
+                both(Pair(Empty, Empty)); both(Pair(Empty, Rect(1, 2))); both(Pair(Circle(1), Empty));
+                var r = 5 in match Circle(1) { Circle(r) -> r, _ -> 0 } + r;
+                Empty;
+                type List = Nil | Cons(x, rest: List)
+                def sum(l) match l { Nil -> 0, Cons(x, rest) -> x + sum(rest) }
+                sum(Cons(1, Cons(2, Cons(3, Nil))));
+                "
+            ),
+            vec![
+                Ok(6.0),
+                Ok(3.0),
+                Ok(0.0),
+                Ok(0.0),
+                Ok(2.0),
+                Ok(103.0),
+                Ok(6.0),
+                Err("Cannot convert Shape to double.".into()),
+                Ok(6.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(
+            interpret_all(
+                "
+                def dot(a: [double] b: [double] n: int)
+                  var acc = 0 in (for i = 0, i < n - 1 in acc = acc + a[i] * b[i]) + acc;
+                def fill(a n: int v) for i = 0, i < n - 1 in a[i] = v;
+                def histogram(data: [int] counts: [int] n: int)
+                  for i = 0, i < n - 1 in counts[data[i]] = counts[data[i]] + 1;
+                dot([1, 2, 3], [4, 5, 6], 3);
+                var a = [1, 2] in fill(a, 2, 7) + a[0] + a[1];
+                var counts = [0, 0, 0] in
+                  histogram([2, 0, 2, 1, 2], counts, 5) + double(counts[2] * 10 + counts[0]);
+                [[1, 2], [3]][1][0];
+                [1, 2][2];
+                var a = [1] in
+                  a[0 - 1] = 2;
+                "
+            ),
+            vec![
+                Ok(32.0),
+                Ok(14.0),
+                Ok(31.0),
+                Ok(3.0),
+                Err("Index 2 is out of bounds for an array of length 2 at 12:17.".into()),
+                Err("Index -1 is out of bounds for an array of length 1 at 14:19.".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn typed_arithmetic() {
+        assert_eq!(
+            interpret_all(
+                "
+                def half(n: int) n / 2;
+                def pick(b: bool) if b then 1 else 2.5;
+                half(7); half(0 - 7); 7 / 0; 7.0 / 2;
+                pick(1 < 2); pick(false);
+                int(2.9) + 1; int(0.0 / 0); double(true); bool(0.5);
+                1 + true;
+                "
+            ),
+            vec![
+                Ok(3.0),
+                Ok(-3.0),
+                Ok(0.0),
+                Ok(3.5),
+                Ok(1.0),
+                Ok(2.5),
+                Ok(3.0),
+                Ok(0.0),
+                Ok(1.0),
+                Ok(1.0),
+                Err("Expected a number but found bool.".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nan_comparisons() {
+        assert_eq!(
+            interpret_all("0.0/0 < 1; 1 > 0.0/0; if 0.0/0 then 1 else 2;"),
+            vec![Ok(1.0), Ok(1.0), Ok(2.0)]
+        );
+    }
+
+    #[test]
+    fn functions_and_control_flow() {
+        assert_eq!(
+            interpret_all(
+                "
+                def fib(n) if n < 3 then 1 else fib(n - 1) + fib(n - 2);
+                def sum(n) var acc = 0 in (for i = 0, i < n in acc = acc + i) + acc;
+                def shadow(i) (for i = 0, i < 3, 0.5 in i) + i;
+                def binary : 1 (x y) y;
+                def unary - (x) 0 - x;
+                def swap(a b) var tmp = a in a = b : b = tmp : a - b;
+                fib(10);
+                sum(5);
+                shadow(7);
+                -swap(1, 3);
+                "
+            ),
+            vec![Ok(55.0), Ok(15.0), Ok(7.0), Ok(-2.0)]
+        );
+    }
+
+    #[test]
+    fn first_class_functions() {
+        assert_eq!(
+            interpret_all(
+                "
+                extern sin(x);
+                def square(x) x * x;
+                def apply(f x) f(x);
+                def integrate(f a b n: int)
+                  var sum = 0.0, h = (b - a) / double(n) in
+                    (for i = 0, i < n - 1 in sum = sum + f(a + (double(i) + 0.5) * h) * h) + sum;
+                apply(square, 3);
+                integrate(square, 0, 3, 3);
+                var g = square in var a = g(3), b = (g = sin) in a + g(0);
+                square;
+                "
+            ),
+            vec![
+                Ok(9.0),
+                Ok(8.75),
+                Ok(9.0),
+                Err("Cannot convert (double) -> double to double.".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn closures() {
+        assert_eq!(
+            interpret_all(
+                "
+                def adder(k) \\x -> x + k;
+                def compose(f: (double) -> double g: (double) -> double) \\x -> f(g(x));
+                def fold(f: (double, double) -> double acc a: [double] n: int)
+                  (for i = 0, i < n - 1 in acc = f(acc, a[i])) + acc;
+                var add2 = adder(2) in add2(1);
+                var k = 10 in fold(\\acc x -> acc + x * k, 0, [1, 2, 3], 3);
+                var f = compose(adder(1), \\x -> x * 2) in f(5);
+                var k = 1 in var f = \\x -> x + k in (k = 100) + f(0);
+                "
+            ),
+            vec![Ok(3.0), Ok(60.0), Ok(11.0), Ok(101.0)]
+        );
+    }
+
+    #[test]
+    fn gradients() {
+        assert_eq!(
+            interpret_all(
+                "
+                extern sin(x);
+                extern cos(x);
+                def cube(x) x * x * x;
+                def f(x y) sin(x) * y + cube(y) / x;
+                var d = grad(cube) in d(2);
+                var d = grad(f) in var g = d(1, 2) in g[0] + g[1];
+                var x = 0 in var d = grad(sin) in d(x);
+                def' cube;
+                def' f;
+                cube'(2) + f'(1, 2)[1];
+                def count(n) for i = 0, i < n in i;
+                grad(count);
+                "
+            ),
+            vec![
+                Ok(12.0),
+                Ok(2.0 * 1f64.cos() - 8.0 + 1f64.sin() + 12.0),
+                Ok(1.0),
+                Ok(12.0 + 1f64.sin() + 12.0),
+                Err("Cannot differentiate count: for loops are not supported.".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn redefinitions() {
+        let mut interp = Interpreter::new();
+        let mut run = |program: &str| -> Vec<Result<(), String>> {
+            Parser::new(Lexer::new(program.chars()))
+                .parse_program()
+                .nodes
+                .iter()
+                .map(|node| interp.run_item(node).map(|_| ()).map_err(|err| err.message))
+                .collect()
+        };
+
+        // g calls the latest definition of f, which must keep its types
+        assert_eq!(
+            run("def f(x) x; def g(x) f(x) + 1; def f(y) y * 10; def f(x: int) x;"),
+            vec![
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err("Function f is already declared as f(y: double): double.".into())
+            ]
+        );
+        assert_eq!(
+            interp.call("g", &[Value::Double(1.0)]),
+            Ok(Value::Double(11.0))
+        );
+    }
+
+    #[test]
+    fn externs() {
+        let mut interp = Interpreter::new();
+        let proto = |name: &str, args: &[&str]| {
+            Prototype::new(
+                name,
+                args.iter().map(|&arg| arg.into()).collect(),
+                Default::default(),
+            )
+        };
+
+        assert!(interp.declare_extern(&proto("sqrt", &["x"])).is_ok());
+        assert_eq!(
+            interp.call("sqrt", &[Value::Double(16.0)]),
+            Ok(Value::Double(4.0))
+        );
+        assert!(interp.declare_extern(&proto("sqrt", &["x", "y"])).is_err());
+        assert!(interp.declare_extern(&proto("printf", &["x"])).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            interpret_all(
+                "x; foo(1); def f(x) x; f(1, 2); !1; def binary | 5 (a b) c; 1 | 2; 1 = 2;"
+            ),
+            vec![
+                Err("Unknown variable name: x".into()),
+                Err("Unknown function: foo".into()),
+                Err("Unmatched arg number. Function f expects 1 but the input has 2.".into()),
+                Err("Unknown unary op !".into()),
+                // errors in the body of an operator are not hidden
+                Err("Unknown variable name: c".into()),
+                Err("Destination of '=' must be a variable or an array element.".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn declared_functions() {
+        let mut interp = Interpreter::new();
+        let odd = Prototype::new("odd", vec!["n".into()], Default::default());
+        interp.declare_func(&odd).unwrap();
+
+        let program = "def even(n) if n < 1 then 1 else odd(n - 1); even(3); \
+                       def odd(n) if n < 1 then 0 else even(n - 1); even(3);";
+        let results: Vec<_> = Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+            .iter()
+            .filter_map(|node| interp.run_item(node).map_err(|err| err.message).transpose())
+            .collect();
+        assert_eq!(
+            results,
+            vec![Err("Function odd is not defined.".into()), Ok(0.0)]
+        );
+    }
+
+    #[test]
+    fn errors_are_at_their_expressions() {
+        let mut interp = Interpreter::new();
+        let columns: Vec<_> = Parser::new(Lexer::new(
+            "x; foo(1); 1 = 2; def g(x) y; g(1); [1][3];".chars(),
+        ))
+        .parse_program()
+        .nodes
+        .iter()
+        .map(|node| interp.run_item(node).map_err(|err| err.span.start.column))
+        .collect();
+
+        // the call of g fails in its body, and out of bounds indices are at the top level expression
+        assert_eq!(
+            columns,
+            vec![Err(1), Err(4), Err(12), Ok(None), Err(28), Err(37)]
+        );
+    }
+}
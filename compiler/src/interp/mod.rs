@@ -0,0 +1,10 @@
+mod builtins;
+mod interpreter;
+mod value;
+
+#[cfg(test)]
+mod differential;
+
+pub use builtins::{get_builtin, resolve_extern, Builtin};
+pub use interpreter::*;
+pub use value::Value;
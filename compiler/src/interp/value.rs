@@ -0,0 +1,344 @@
+use crate::types::Type;
+use crate::util::Position;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// A value computed by the interpreter or the VM, with the same semantics as the generated code
+#[derive(PartialEq, Clone, Debug)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Double(f64),
+    /// Strings are immutable, so they are shared
+    Str(Rc<str>),
+    /// Arrays are shared by reference, as pointers to their elements in the generated code.
+    /// They have at least one element, all of the same type.
+    Array(Rc<RefCell<Vec<Value>>>),
+    /// Structs of a type created with `Type::named` are immutable, so they are shared
+    Struct(Type, Rc<[Value]>),
+    /// A variant of a tagged union, by its tag, with its fields, shared as structs are
+    Variant(Type, usize, Rc<[Value]>),
+    /// A function of a type created with `Type::function`, by name,
+    /// with the values it captured, which it takes before its args
+    Function(Type, Rc<str>, Rc<[Value]>),
+}
+
+impl Value {
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Int(_) => Type::Int,
+            Value::Bool(_) => Type::Bool,
+            Value::Double(_) => Type::Double,
+            Value::Str(_) => Type::Str,
+            Value::Array(elements) => Type::array(elements.borrow()[0].ty()),
+            Value::Struct(ty, _) | Value::Variant(ty, ..) | Value::Function(ty, ..) => ty.clone(),
+        }
+    }
+
+    /// A new array of values of the same type
+    pub fn array(elements: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(elements)))
+    }
+
+    /// The element of an array at `index`, failing if it is out of bounds of the array indexed at `pos`
+    pub fn index(&self, index: &Value, pos: Position) -> Result<Value, String> {
+        let elements = self.elements()?.borrow();
+        Ok(elements[element_index(index, elements.len(), pos)?].clone())
+    }
+
+    /// Replace the element of an array at `index`, failing as `index` does
+    pub fn store(&self, index: &Value, value: Value, pos: Position) -> Result<(), String> {
+        let mut elements = self.elements()?.borrow_mut();
+        let index = element_index(index, elements.len(), pos)?;
+        elements[index] = value;
+        Ok(())
+    }
+
+    /// The field of a struct or a variant at `index`
+    pub fn field(&self, index: usize) -> Result<Value, String> {
+        match self {
+            Value::Struct(_, fields) | Value::Variant(_, _, fields) => Ok(fields[index].clone()),
+            value => Err(format!("Expected a struct but found {}.", value.ty())),
+        }
+    }
+
+    /// The tag of a variant
+    pub fn tag(&self) -> Result<usize, String> {
+        match self {
+            Value::Variant(_, tag, _) => Ok(*tag),
+            value => Err(format!("Expected a union but found {}.", value.ty())),
+        }
+    }
+
+    /// The name of the function held by a value and the values it captured
+    pub fn function(&self) -> Result<(&str, &[Value]), String> {
+        match self {
+            Value::Function(_, name, captured) => Ok((name, captured)),
+            value => Err(format!("Expected a function but found {}.", value.ty())),
+        }
+    }
+
+    fn elements(&self) -> Result<&RefCell<Vec<Value>>, String> {
+        match self {
+            Value::Array(elements) => Ok(elements),
+            value => Err(format!("Expected an array but found {}.", value.ty())),
+        }
+    }
+
+    /// Zero and NaN are treated as false, as `icmp ne x, 0` and `fcmp one x, 0.0`.
+    /// Strings, arrays, structs, unions and functions are never converted,
+    /// but strings, arrays and functions would be true as non-null pointers.
+    pub fn is_true(&self) -> bool {
+        match *self {
+            Value::Int(value) => value != 0,
+            Value::Bool(value) => value,
+            Value::Double(value) => !value.is_nan() && value != 0.0,
+            Value::Str(_)
+            | Value::Array(_)
+            | Value::Struct(..)
+            | Value::Variant(..)
+            | Value::Function(..) => true,
+        }
+    }
+
+    /// Convert the value as a cast expression does.
+    /// A double is truncated into an int, saturating at the bounds and NaN becoming 0.
+    pub fn cast(self, ty: Type) -> Value {
+        match (self, ty) {
+            (value, Type::Bool) => Value::Bool(value.is_true()),
+            (Value::Int(value), Type::Double) => Value::Double(value as f64),
+            (Value::Bool(value), Type::Double) => Value::Double(if value { 1.0 } else { 0.0 }),
+            (Value::Double(value), Type::Int) => Value::Int(value as i64),
+            (Value::Bool(value), Type::Int) => Value::Int(value as i64),
+            (value, _) => value,
+        }
+    }
+
+    /// The value converted to a double
+    pub fn to_f64(&self) -> f64 {
+        match self.clone().cast(Type::Double) {
+            Value::Double(value) => value,
+            value => unreachable!("{:?} is not a double", value),
+        }
+    }
+
+    /// Apply one of the builtin binary operators `+ - * / < >` to two numbers of the same type.
+    /// Int arithmetic wraps around, and the comparisons of doubles are true if either is NaN.
+    pub fn binary(op: char, lhs: Value, rhs: Value) -> Result<Value, String> {
+        let value = match (op, &lhs, &rhs) {
+            ('+', Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs.wrapping_add(*rhs)),
+            ('-', Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs.wrapping_sub(*rhs)),
+            ('*', Value::Int(lhs), Value::Int(rhs)) => Value::Int(lhs.wrapping_mul(*rhs)),
+            ('/', Value::Int(lhs), Value::Int(rhs)) => Value::Int(divide(*lhs, *rhs)),
+            ('<', Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs < rhs),
+            ('>', Value::Int(lhs), Value::Int(rhs)) => Value::Bool(lhs > rhs),
+            ('+', Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs + rhs),
+            ('-', Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs - rhs),
+            ('*', Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs * rhs),
+            ('/', Value::Double(lhs), Value::Double(rhs)) => Value::Double(lhs / rhs),
+            ('<', Value::Double(lhs), Value::Double(rhs)) => {
+                Value::Bool(unordered_less_than(*lhs, *rhs))
+            }
+            ('>', Value::Double(lhs), Value::Double(rhs)) => {
+                Value::Bool(unordered_less_than(*rhs, *lhs))
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid operands of {}: {} and {}.",
+                    op,
+                    lhs.ty(),
+                    rhs.ty()
+                ))
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// The index of an element of an array of length `len` indexed at `pos`,
+/// failing as the bounds checks of the generated code
+fn element_index(index: &Value, len: usize, pos: Position) -> Result<usize, String> {
+    match *index {
+        // a negative index is a large unsigned one, as `icmp ult index, len`
+        Value::Int(index) if (index as u64) < len as u64 => Ok(index as usize),
+        Value::Int(index) => Err(format!(
+            "Index {} is out of bounds for an array of length {} at {}:{}.",
+            index, len, pos.line, pos.column
+        )),
+        ref index => Err(format!("Expected int but found {}.", index.ty())),
+    }
+}
+
+/// Unordered or less than, as `fcmp ult`
+fn unordered_less_than(lhs: f64, rhs: f64) -> bool {
+    lhs < rhs || lhs.is_nan() || rhs.is_nan()
+}
+
+/// Division defined for every input, as the generated code:
+/// dividing by 0 gives 0, and dividing the minimum by -1 wraps around
+fn divide(lhs: i64, rhs: i64) -> i64 {
+    if rhs == 0 {
+        0
+    } else {
+        lhs.wrapping_div(rhs)
+    }
+}
+
+/// Formats the value as a literal, e.g. `1` for an int and `1.0` for a double,
+/// a struct as its type followed by its fields, e.g. `Point { 1, 2.0 }`,
+/// a variant as its union and its tag followed by its fields, e.g. `Shape#1(2.0, 3.0)`,
+/// and a function by its name
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Double(value) => write!(f, "{:?}", value),
+            Value::Str(value) => write!(f, "{:?}", value),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Value::Struct(ty, fields) => {
+                write!(f, "{} {{", ty)?;
+                for (i, field) in fields.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { ", " } else { " " }, field)?;
+                }
+                write!(f, " }}")
+            }
+            Value::Variant(ty, tag, fields) => {
+                write!(f, "{}#{}", ty, tag)?;
+                for (i, field) in fields.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { ", " } else { "(" }, field)?;
+                }
+                if fields.is_empty() {
+                    Ok(())
+                } else {
+                    write!(f, ")")
+                }
+            }
+            Value::Function(_, name, _) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let int = |op, lhs, rhs| Value::binary(op, Value::Int(lhs), Value::Int(rhs));
+
+        assert_eq!(int('+', i64::MAX, 1), Ok(Value::Int(i64::MIN)));
+        assert_eq!(int('/', 7, 2), Ok(Value::Int(3)));
+        assert_eq!(int('/', -7, 2), Ok(Value::Int(-3)));
+        assert_eq!(int('/', 1, 0), Ok(Value::Int(0)));
+        assert_eq!(int('/', i64::MIN, -1), Ok(Value::Int(i64::MIN)));
+        assert_eq!(int('<', 1, 2), Ok(Value::Bool(true)));
+        assert_eq!(
+            Value::binary('>', Value::Double(f64::NAN), Value::Double(1.0)),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            Value::binary('+', Value::Int(1), Value::Double(1.0)),
+            Err("Invalid operands of +: int and double.".into())
+        );
+    }
+
+    #[test]
+    fn casts() {
+        assert_eq!(Value::Double(2.9).cast(Type::Int), Value::Int(2));
+        assert_eq!(Value::Double(-2.9).cast(Type::Int), Value::Int(-2));
+        assert_eq!(Value::Double(1e30).cast(Type::Int), Value::Int(i64::MAX));
+        assert_eq!(Value::Double(f64::NAN).cast(Type::Int), Value::Int(0));
+        assert_eq!(Value::Bool(true).cast(Type::Double), Value::Double(1.0));
+        assert_eq!(Value::Int(-3).cast(Type::Bool), Value::Bool(true));
+        assert_eq!(Value::Double(f64::NAN).cast(Type::Bool), Value::Bool(false));
+        assert_eq!(Value::Int(3).to_f64(), 3.0);
+    }
+
+    #[test]
+    fn arrays() {
+        let array = Value::array(vec![Value::Int(1), Value::Int(2)]);
+        let alias = array.clone();
+        let pos = Position {
+            offset: 12,
+            line: 2,
+            column: 5,
+        };
+
+        assert_eq!(array.ty(), Type::array(Type::Int));
+        assert_eq!(alias.store(&Value::Int(1), Value::Int(5), pos), Ok(()));
+        assert_eq!(array.index(&Value::Int(1), pos), Ok(Value::Int(5)));
+        assert_eq!(array.to_string(), "[1, 5]");
+        assert_eq!(
+            array.index(&Value::Int(2), pos),
+            Err("Index 2 is out of bounds for an array of length 2 at 2:5.".into())
+        );
+        assert_eq!(
+            array.store(&Value::Int(-1), Value::Int(0), pos),
+            Err("Index -1 is out of bounds for an array of length 2 at 2:5.".into())
+        );
+    }
+
+    #[test]
+    fn structs() {
+        let point = Value::Struct(
+            Type::named("Point"),
+            vec![Value::Int(1), Value::Double(2.0)].into(),
+        );
+
+        assert_eq!(point.ty(), Type::named("Point"));
+        assert_eq!(point.field(1), Ok(Value::Double(2.0)));
+        assert_eq!(point.to_string(), "Point { 1, 2.0 }");
+        assert_eq!(
+            Value::Int(1).field(0),
+            Err("Expected a struct but found int.".into())
+        );
+    }
+
+    #[test]
+    fn variants() {
+        let rect = Value::Variant(
+            Type::named("Shape"),
+            1,
+            vec![Value::Double(2.0), Value::Double(3.0)].into(),
+        );
+        let empty = Value::Variant(Type::named("Shape"), 2, vec![].into());
+
+        assert_eq!(rect.ty(), Type::named("Shape"));
+        assert_eq!(rect.tag(), Ok(1));
+        assert_eq!(rect.field(1), Ok(Value::Double(3.0)));
+        assert_eq!(rect.to_string(), "Shape#1(2.0, 3.0)");
+        assert_eq!(empty.to_string(), "Shape#2");
+        assert_eq!(
+            Value::Int(1).tag(),
+            Err("Expected a union but found int.".into())
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let ty = Type::function(vec![Type::Double], Type::Double);
+        let sin = Value::Function(ty.clone(), "sin".into(), Rc::new([]));
+        let closure = Value::Function(ty.clone(), "lambda.0".into(), Rc::new([Value::Int(1)]));
+
+        assert_eq!(sin.ty(), ty);
+        assert_eq!(sin.function(), Ok(("sin", &[][..])));
+        assert_eq!(closure.function(), Ok(("lambda.0", &[Value::Int(1)][..])));
+        assert_eq!(sin.to_string(), "sin");
+        assert!(sin.is_true());
+        assert_eq!(
+            Value::Int(1).function(),
+            Err("Expected a function but found int.".into())
+        );
+    }
+}
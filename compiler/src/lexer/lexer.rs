@@ -1,31 +1,142 @@
-use super::token::Token;
+use super::token::{SpannedToken, Token};
 use crate::lexer::token::Token::*;
 use crate::or_return;
+use crate::types::Type;
 use crate::util::buffer::Buffer;
+use crate::util::{Position, Span};
 
 #[derive(Debug, PartialEq)]
 pub enum LexerError {
-    NumberNotValid(String),
-    NotRecognized(char),
+    NumberNotValid(String, Span),
+    /// An integer literal does not fit in 64 bits
+    IntegerOutOfRange(String, Span),
+    NotRecognized(char, Span),
+    /// A string literal without its closing `"`
+    UnterminatedString(Span),
+    /// An unknown escape sequence in a string literal, or one escaping an invalid char
+    InvalidEscape(String, Span),
+}
+
+impl LexerError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexerError::NumberNotValid(_, span) => *span,
+            LexerError::IntegerOutOfRange(_, span) => *span,
+            LexerError::NotRecognized(_, span) => *span,
+            LexerError::UnterminatedString(span) => *span,
+            LexerError::InvalidEscape(_, span) => *span,
+        }
+    }
 }
 
 pub struct Lexer<I: Iterator<Item = char>> {
     /// The source of input
     buffer: Buffer<char, I>,
+    /// Position of the current char in the buffer
+    pos: Position,
 }
 
-pub type LexerResult = Result<Token, LexerError>;
+pub type LexerResult = Result<SpannedToken, LexerError>;
 
 impl<I: Iterator<Item = char>> Lexer<I> {
     pub fn new(char_iter: I) -> Self {
         Lexer {
             buffer: Buffer::new(char_iter),
+            pos: Position::default(),
+        }
+    }
+
+    /// Eat the current char, keeping track of the position
+    fn advance(&mut self) {
+        if let Some(c) = self.buffer.curr() {
+            self.pos = self.pos.after(*c);
+        }
+        self.buffer.advance();
+    }
+
+    /// Read the rest of a string literal after its opening `"`, replacing the escape sequences.
+    /// A NUL is rejected like its escape sequence `\u{0}`.
+    /// After an invalid escape sequence, the literal is still read to its end.
+    fn read_string(&mut self, start: Position) -> Result<String, LexerError> {
+        let mut value = String::new();
+        let mut error = None;
+        loop {
+            let c = match self.buffer.curr() {
+                Some(c) => *c,
+                None => return Err(LexerError::UnterminatedString(Span::new(start, self.pos))),
+            };
+            let escape_start = self.pos;
+            self.advance();
+
+            match c {
+                '"' => return error.map_or(Ok(value), Err),
+                '\\' => match self.read_escape(escape_start) {
+                    Ok(c) => value.push(c),
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                },
+                '\0' => {
+                    let span = Span::new(escape_start, self.pos);
+                    error.get_or_insert(LexerError::InvalidEscape("\\u{0}".into(), span));
+                }
+                c => value.push(c),
+            }
         }
     }
+
+    /// Read an escape sequence after its `\`: `\n`, `\t`, `\"`, `\\` or `\u{..}` with a hex code point.
+    /// NUL is not allowed, as strings are NUL-terminated once compiled.
+    fn read_escape(&mut self, start: Position) -> Result<char, LexerError> {
+        let mut sequence = String::from("\\");
+        let mut eat = |lexer: &mut Self| {
+            let c = lexer.buffer.curr().copied();
+            if let Some(c) = c {
+                sequence.push(c);
+                lexer.advance();
+            }
+            c
+        };
+
+        let escaped = match eat(self) {
+            Some('n') => Some('\n'),
+            Some('t') => Some('\t'),
+            Some('"') => Some('"'),
+            Some('\\') => Some('\\'),
+            // the closing `"` of the literal is never eaten here
+            Some('u') if self.buffer.curr() == Some(&'{') => {
+                eat(self);
+                let mut digits = String::new();
+                loop {
+                    match self.buffer.curr().copied() {
+                        Some('}') => {
+                            eat(self);
+                            break;
+                        }
+                        Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => {
+                            eat(self);
+                            digits.push(c);
+                        }
+                        _ => {
+                            digits.clear();
+                            break;
+                        }
+                    }
+                }
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+                    .filter(|&c| c != '\0')
+            }
+            _ => None,
+        };
+
+        escaped.ok_or_else(|| LexerError::InvalidEscape(sequence, Span::new(start, self.pos)))
+    }
 }
 
 impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
-    type Item = Result<Token, LexerError>;
+    type Item = LexerResult;
 
     fn next(&mut self) -> Option<Self::Item> {
         // Read a char
@@ -34,91 +145,146 @@ impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
 
         // Skip whitespaces
         while c.is_whitespace() {
-            self.buffer.advance();
+            self.advance();
             c = *or_return!(self.buffer.curr(), None);
         }
 
         // eat current
-        self.buffer.advance();
+        let start = self.pos;
+        self.advance();
 
         // handle comment by getting until eol
         if c == '#' {
             while {
-                self.buffer.curr().map_or(false, |x| {
+                self.buffer.curr().is_some_and(|x| {
                     c = *x;
                     c != '\n'
                 })
             } {
-                self.buffer.advance();
+                self.advance();
             }
             return self.next();
         }
 
         // handle all other case
-        Some(match c {
+        let token = match c {
             // Simple cases
-            '(' => Ok(OpeningParenthesis),
-            ')' => Ok(ClosingParenthesis),
-            ';' => Ok(Delimiter),
-            ',' => Ok(Comma),
-            '+' | '-' | '*' => Ok(BinOp(c)),
+            '(' => OpeningParenthesis,
+            ')' => ClosingParenthesis,
+            '[' => OpeningBracket,
+            ']' => ClosingBracket,
+            '{' => OpeningBrace,
+            '}' => ClosingBrace,
+            ';' => Delimiter,
+            ',' => Comma,
+            '"' => match self.read_string(start) {
+                Ok(value) => Str(value),
+                Err(err) => return Some(Err(err)),
+            },
             // Get a letter, it may be a identifier, or a keyword
             _ if c.is_alphabetic() => {
                 let mut ident = c.to_string();
                 // Collect all alphanumeric chars
                 while {
-                    self.buffer.curr().map_or(false, |x| {
+                    self.buffer.curr().is_some_and(|x| {
                         c = *x;
                         x.is_alphanumeric()
                     })
                 } {
                     ident.push(c);
-                    self.buffer.advance();
+                    self.advance();
+                }
+                // followed by primes, e.g. `f'` the derivative of `f`
+                while self.buffer.curr() == Some(&'\'') {
+                    ident.push('\'');
+                    self.advance();
                 }
-                Ok(match ident.as_ref() {
+                match ident.as_ref() {
                     "def" => Def,
+                    "def'" => DefDerivative,
                     "extern" => Extern,
+                    "if" => If,
+                    "then" => Then,
+                    "else" => Else,
+                    "for" => For,
+                    "in" => In,
+                    "binary" => Binary,
+                    "unary" => Unary,
+                    "var" => Var,
+                    "struct" => Struct,
+                    // not to be confused with the types of values
+                    "type" => Token::Type,
+                    "match" => Match,
+                    "grad" => Grad,
+                    "true" => Boolean(true),
+                    "false" => Boolean(false),
+                    "int" => TypeName(Type::Int),
+                    "bool" => TypeName(Type::Bool),
+                    "double" => TypeName(Type::Double),
+                    "string" => TypeName(Type::Str),
                     _ => Identifier(ident),
-                })
+                }
+            }
+            '-' if self.buffer.curr() == Some(&'>') => {
+                self.advance();
+                Arrow
             }
+            '\\' => Lambda,
+            // a field access, e.g. `p.x`, unless the dot starts a number
+            '.' if !self.buffer.curr().is_some_and(|x| x.is_ascii_digit()) => Dot,
             // Get a digit, it may be a digit.
             _ if c.is_ascii_digit() || c == '.' => {
                 let mut val = c.to_string();
                 // Collect all numbers and at most one dot (.).
                 while {
-                    self.buffer.curr().map_or(false, |x| {
+                    self.buffer.curr().is_some_and(|x| {
                         c = *x;
                         c == '.' || c.is_ascii_digit()
                     })
                 } {
-                    self.buffer.advance();
+                    self.advance();
                     val.push(c);
                 }
-                val.parse::<f64>()
-                    .map(|x| Number(x))
-                    .map_err(|_| LexerError::NumberNotValid(val))
+                // a number without a dot is an integer
+                let span = Span::new(start, self.pos);
+                if !val.contains('.') {
+                    match val.parse::<i64>() {
+                        Ok(x) => Integer(x),
+                        Err(_) => return Some(Err(LexerError::IntegerOutOfRange(val, span))),
+                    }
+                } else {
+                    match val.parse::<f64>() {
+                        Ok(x) => Number(x),
+                        Err(_) => return Some(Err(LexerError::NumberNotValid(val, span))),
+                    }
+                }
+            }
+            // Any other ASCII symbol is an operator, builtin or user defined
+            _ if c.is_ascii_punctuation() => BinOp(c),
+            _ => {
+                let span = Span::new(start, self.pos);
+                return Some(Err(LexerError::NotRecognized(c, span)));
             }
-            _ => Err(LexerError::NotRecognized(c)),
-        })
+        };
+
+        Some(Ok(SpannedToken {
+            token,
+            span: Span::new(start, self.pos),
+        }))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lexer::Token;
 
     macro_rules! tokens {
         ( $( $x:expr),*) => {
             tokens!($($x,)*)
         };
         ( $( $x:expr,)* ) => {
-            {
-                let mut temp_vec = Vec::new();
-                $(
-                    temp_vec.push(Ok($x));
-                )*
-                temp_vec
-            }
+            vec![$(Ok($x),)*]
         };
     }
 
@@ -130,22 +296,74 @@ mod tests {
             read_all("ident123 als"),
             tokens![Identifier("ident123".into()), Identifier("als".into())]
         );
+        assert_eq!(
+            read_all("f' x'' if'"),
+            tokens![
+                Identifier("f'".into()),
+                Identifier("x''".into()),
+                Identifier("if'".into())
+            ]
+        );
+        assert_eq!(read_all("'a"), tokens![BinOp('\''), Identifier("a".into())]);
     }
 
     #[test]
     fn keywords_and_symbols() {
         assert_eq!(
-            read_all("def extern ; ( ) , + - *"),
+            read_all(
+                "def def' extern if then else for in binary unary var struct type match grad ; ( ) [ ] { } . -> \\ , + - * / < > = | ! :"
+            ),
             tokens![
                 Def,
+                DefDerivative,
                 Extern,
+                If,
+                Then,
+                Else,
+                For,
+                In,
+                Binary,
+                Unary,
+                Var,
+                Struct,
+                Token::Type,
+                Match,
+                Grad,
                 Delimiter,
                 OpeningParenthesis,
                 ClosingParenthesis,
+                OpeningBracket,
+                ClosingBracket,
+                OpeningBrace,
+                ClosingBrace,
+                Dot,
+                Arrow,
+                Lambda,
                 Comma,
                 BinOp('+'),
                 BinOp('-'),
                 BinOp('*'),
+                BinOp('/'),
+                BinOp('<'),
+                BinOp('>'),
+                BinOp('='),
+                BinOp('|'),
+                BinOp('!'),
+                BinOp(':'),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_recognized() {
+        assert_eq!(
+            read_all("a ∂"),
+            vec![
+                Ok(Identifier("a".into())),
+                Err(LexerError::NotRecognized(
+                    '∂',
+                    Span::new(pos(2, 1, 3), pos(5, 1, 4))
+                ))
             ]
         );
     }
@@ -153,13 +371,109 @@ mod tests {
     #[test]
     fn numbers() {
         assert_eq!(
-            read_all("123 12 .4 1234. 12345.6"),
+            read_all("123 12 .4 1234. 12345.6 0"),
             tokens![
-                Number(123.0),
-                Number(12.0),
+                Integer(123),
+                Integer(12),
                 Number(0.4),
                 Number(1234.0),
                 Number(12345.6),
+                Integer(0),
+            ]
+        );
+        assert_eq!(
+            read_all("p.x a[0].5"),
+            tokens![
+                Identifier("p".into()),
+                Dot,
+                Identifier("x".into()),
+                Identifier("a".into()),
+                OpeningBracket,
+                Integer(0),
+                ClosingBracket,
+                Number(0.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn types_and_booleans() {
+        assert_eq!(
+            read_all("int bool double true false integer"),
+            tokens![
+                TypeName(Type::Int),
+                TypeName(Type::Bool),
+                TypeName(Type::Double),
+                Boolean(true),
+                Boolean(false),
+                Identifier("integer".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            read_all(r#""hello" "" "a\tb\n" "say \"hi\" \\" "\u{48}\u{1F600}" "#),
+            tokens![
+                Str("hello".into()),
+                Str("".into()),
+                Str("a\tb\n".into()),
+                Str("say \"hi\" \\".into()),
+                Str("H\u{1F600}".into()),
+            ]
+        );
+        assert_eq!(read_all("string"), tokens![TypeName(Type::Str)]);
+
+        assert_eq!(
+            read_all(r#""a\qb" 1"#),
+            vec![
+                Err(LexerError::InvalidEscape(
+                    "\\q".into(),
+                    Span::new(pos(2, 1, 3), pos(4, 1, 5))
+                )),
+                Ok(Integer(1))
+            ]
+        );
+        for escape in &[
+            r"\u{0}",
+            r"\u{}",
+            r"\u{D800}",
+            r"\u{1234567}",
+            r"\u{zz}",
+            r"\u41",
+        ] {
+            let program = format!("\"{}\" 1", escape);
+            let tokens = read_all(&program);
+            assert!(
+                matches!(tokens[0], Err(LexerError::InvalidEscape(_, _))),
+                "{}",
+                program
+            );
+            assert_eq!(tokens.last(), Some(&Ok(Integer(1))), "{}", program);
+        }
+        assert_eq!(
+            read_all("1 \"abc"),
+            vec![
+                Ok(Integer(1)),
+                Err(LexerError::UnterminatedString(Span::new(
+                    pos(2, 1, 3),
+                    pos(6, 1, 7)
+                )))
+            ]
+        );
+        assert!(matches!(
+            read_all(r#""\u{""#)[0],
+            Err(LexerError::InvalidEscape(_, _))
+        ));
+        assert_eq!(
+            read_all("\"a\0b\" 1"),
+            vec![
+                Err(LexerError::InvalidEscape(
+                    "\\u{0}".into(),
+                    Span::new(pos(2, 1, 3), pos(3, 1, 4))
+                )),
+                Ok(Integer(1))
             ]
         );
     }
@@ -189,7 +503,7 @@ mod tests {
                 ClosingParenthesis,
                 Identifier("a".into()),
                 BinOp('+'),
-                Number(4.0),
+                Integer(4),
                 BinOp('*'),
                 Identifier("b".into()),
                 BinOp('-'),
@@ -203,11 +517,24 @@ mod tests {
     fn malformed_numbers() {
         assert_eq!(
             read_all("1.4.2"),
-            vec![Err(LexerError::NumberNotValid("1.4.2".into()))]
+            vec![Err(LexerError::NumberNotValid(
+                "1.4.2".into(),
+                Span::new(pos(0, 1, 1), pos(5, 1, 6))
+            ))]
+        );
+        assert_eq!(
+            read_all(" .4.2"),
+            vec![Err(LexerError::NumberNotValid(
+                ".4.2".into(),
+                Span::new(pos(1, 1, 2), pos(5, 1, 6))
+            ))]
         );
         assert_eq!(
-            read_all(".4.2"),
-            vec![Err(LexerError::NumberNotValid(".4.2".into()))]
+            read_all("9223372036854775808"),
+            vec![Err(LexerError::IntegerOutOfRange(
+                "9223372036854775808".into(),
+                Span::new(pos(0, 1, 1), pos(19, 1, 20))
+            ))]
         );
     }
 
@@ -220,12 +547,45 @@ mod tests {
             def
             "
             ),
-            tokens![Number(123.0), Def,]
+            tokens![Integer(123), Def,]
         );
-        assert_eq!(read_all("123 #12312321ojff"), tokens![Number(123.0),]);
+        assert_eq!(read_all("123 #12312321ojff"), tokens![Integer(123),]);
     }
 
+    /// Read all tokens, dropping the spans of successfully read ones
     fn read_all(input: &str) -> Vec<Result<Token, LexerError>> {
-        Lexer::new(input.chars()).collect()
+        Lexer::new(input.chars())
+            .map(|x| x.map(|x| x.token))
+            .collect()
+    }
+
+    fn pos(offset: usize, line: usize, column: usize) -> Position {
+        Position {
+            offset,
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let spans: Vec<Span> = Lexer::new("def f(x)\n  x+12.5 # comment\n;".chars())
+            .map(|x| x.unwrap().span)
+            .collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(pos(0, 1, 1), pos(3, 1, 4)),
+                Span::new(pos(4, 1, 5), pos(5, 1, 6)),
+                Span::new(pos(5, 1, 6), pos(6, 1, 7)),
+                Span::new(pos(6, 1, 7), pos(7, 1, 8)),
+                Span::new(pos(7, 1, 8), pos(8, 1, 9)),
+                Span::new(pos(11, 2, 3), pos(12, 2, 4)),
+                Span::new(pos(12, 2, 4), pos(13, 2, 5)),
+                Span::new(pos(13, 2, 5), pos(17, 2, 9)),
+                Span::new(pos(28, 3, 1), pos(29, 3, 2)),
+            ]
+        );
     }
 }
@@ -2,4 +2,4 @@ mod lexer;
 mod token;
 
 pub use lexer::*;
-pub use token::Token;
+pub use token::{SpannedToken, Token};
@@ -1,12 +1,98 @@
+use crate::types::Type;
+use crate::util::Span;
+use std::fmt;
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum Token {
     Def,
+    /// `def'`, defining the derivative of a function
+    DefDerivative,
     Extern,
+    If,
+    Then,
+    Else,
+    For,
+    In,
+    Binary,
+    Unary,
+    Var,
+    Struct,
+    Type,
+    Match,
+    /// `grad`, differentiating a function
+    Grad,
     Delimiter, //';' character
     OpeningParenthesis,
     ClosingParenthesis,
+    OpeningBracket,
+    ClosingBracket,
+    OpeningBrace,
+    ClosingBrace,
+    /// `.` not followed by a digit
+    Dot,
+    /// `->`
+    Arrow,
+    /// `\`, starting a lambda
+    Lambda,
     Comma,
     Identifier(String),
+    /// A number with a `.`
     Number(f64),
+    /// A number without a `.`
+    Integer(i64),
+    /// `true` or `false`
+    Boolean(bool),
+    /// A string literal with its escape sequences replaced
+    Str(String),
+    /// A type name, e.g. `int`
+    TypeName(Type),
     BinOp(char),
 }
+
+/// Formats the token as it appears in the source
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Def => write!(f, "def"),
+            Token::DefDerivative => write!(f, "def'"),
+            Token::Extern => write!(f, "extern"),
+            Token::If => write!(f, "if"),
+            Token::Then => write!(f, "then"),
+            Token::Else => write!(f, "else"),
+            Token::For => write!(f, "for"),
+            Token::In => write!(f, "in"),
+            Token::Binary => write!(f, "binary"),
+            Token::Unary => write!(f, "unary"),
+            Token::Var => write!(f, "var"),
+            Token::Struct => write!(f, "struct"),
+            Token::Type => write!(f, "type"),
+            Token::Match => write!(f, "match"),
+            Token::Grad => write!(f, "grad"),
+            Token::Delimiter => write!(f, ";"),
+            Token::OpeningParenthesis => write!(f, "("),
+            Token::ClosingParenthesis => write!(f, ")"),
+            Token::OpeningBracket => write!(f, "["),
+            Token::ClosingBracket => write!(f, "]"),
+            Token::OpeningBrace => write!(f, "{{"),
+            Token::ClosingBrace => write!(f, "}}"),
+            Token::Dot => write!(f, "."),
+            Token::Arrow => write!(f, "->"),
+            Token::Lambda => write!(f, "\\"),
+            Token::Comma => write!(f, ","),
+            Token::Identifier(ident) => write!(f, "{}", ident),
+            Token::Number(num) => write!(f, "{:?}", num),
+            Token::Integer(num) => write!(f, "{}", num),
+            Token::Boolean(value) => write!(f, "{}", value),
+            Token::Str(value) => write!(f, "{:?}", value),
+            Token::TypeName(ty) => write!(f, "{}", ty),
+            Token::BinOp(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+/// A token along with where it is in the source
+#[derive(PartialEq, Clone, Debug)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
@@ -1,4 +1,13 @@
+pub mod autodiff;
+pub mod backend;
+pub mod closure;
 pub mod codegen;
+pub mod diagnostics;
+pub mod interp;
 pub mod lexer;
+pub mod matching;
 pub mod parser;
+pub mod sema;
+pub mod types;
 pub mod util;
+pub mod vm;
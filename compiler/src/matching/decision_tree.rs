@@ -0,0 +1,325 @@
+use crate::parser::nodes::{Pattern, PatternKind};
+use crate::types::{Type, TypeDefs};
+
+/// A part of the value being matched, as the indices of the fields to go through from the value,
+/// e.g. `[1, 0]` for `x` in `Rect(_, Circle(x))`
+pub type Occurrence = Vec<usize>;
+
+/// How to find the arm of a match a value goes to, testing each part of the value at most once
+#[derive(PartialEq, Clone, Debug)]
+pub enum Decision {
+    /// Evaluate an arm, with the variables of its pattern bound to parts of the value
+    Arm {
+        index: usize,
+        bindings: Vec<(String, Occurrence)>,
+    },
+    /// Go on with the case of the tag of the variant at an occurrence,
+    /// or with the default for the tags without a case
+    Switch {
+        occurrence: Occurrence,
+        /// The union of the variant
+        ty: Type,
+        cases: Vec<(usize, Decision)>,
+        default: Option<Box<Decision>>,
+    },
+}
+
+/// A row of the pattern matrix, the patterns of an arm still to be tested.
+/// `None` matches anything, as `_`.
+struct Row<'a> {
+    patterns: Vec<Option<&'a Pattern>>,
+    bindings: Vec<(String, Occurrence)>,
+    arm: usize,
+}
+
+impl<'a> Row<'a> {
+    /// The variant of the pattern in a column and the patterns of its fields, if it is one
+    fn variant(&self, column: usize) -> Option<(&'a str, &'a [Pattern])> {
+        match self.patterns[column].map(|pattern| &pattern.kind) {
+            Some(PatternKind::Variant(name, fields)) => Some((name, fields)),
+            _ => None,
+        }
+    }
+
+    /// Bind the variable of the pattern in a column, if it is one, to an occurrence
+    fn bind(&mut self, column: usize, occurrence: &[usize]) {
+        if let Some(PatternKind::Binding(name)) = self.patterns[column].map(|pattern| &pattern.kind)
+        {
+            self.bindings.push((name.clone(), occurrence.to_vec()));
+        }
+    }
+}
+
+/// Compile the patterns of the arms of a match, in order, into a decision tree,
+/// the first arm matching a value being the one it goes to.
+///
+/// The patterns must have been type checked against the unions of `defs`.
+/// If some values match no pattern, one of them is returned, written as a pattern.
+pub fn compile_match(patterns: &[&Pattern], defs: &TypeDefs) -> Result<Decision, String> {
+    let rows = patterns
+        .iter()
+        .enumerate()
+        .map(|(arm, &pattern)| Row {
+            patterns: vec![Some(pattern)],
+            bindings: vec![],
+            arm,
+        })
+        .collect();
+    compile(rows, vec![vec![]], defs).map_err(|mut missing| missing.pop().expect("one column"))
+}
+
+/// Compile a pattern matrix whose columns are tested on the parts of the value at `occurrences`,
+/// returning the values matched by no row as a pattern for each column if there are some
+fn compile(
+    mut rows: Vec<Row>,
+    occurrences: Vec<Occurrence>,
+    defs: &TypeDefs,
+) -> Result<Decision, Vec<String>> {
+    let first = match rows.first_mut() {
+        Some(first) => first,
+        None => return Err(vec!["_".into(); occurrences.len()]),
+    };
+
+    // test the first column where the first row has a variant,
+    // the first row matching anything if there is none
+    let column = match (0..occurrences.len()).find(|&column| first.variant(column).is_some()) {
+        Some(column) => column,
+        None => {
+            for (column, occurrence) in occurrences.iter().enumerate() {
+                first.bind(column, occurrence);
+            }
+            let first = rows.swap_remove(0);
+            return Ok(Decision::Arm {
+                index: first.arm,
+                bindings: first.bindings,
+            });
+        }
+    };
+
+    let (variant, _) = first.variant(column).expect("a variant");
+    let (union, _) = defs.variant(variant).expect("type checked");
+    let occurrence = &occurrences[column];
+
+    // the tags of the variants in the column, the other variants going to the default
+    let mut tags: Vec<usize> = rows
+        .iter()
+        .filter_map(|row| row.variant(column))
+        .map(|(variant, _)| defs.variant(variant).expect("type checked").1)
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+
+    let mut cases = vec![];
+    for &tag in &tags {
+        let arity = union.variants[tag].fields.len();
+
+        // the rows matching the variant, with the patterns of its fields in place of the column
+        let specialized = rows
+            .iter()
+            .filter_map(|row| {
+                let fields: Vec<Option<&Pattern>> = match row.variant(column) {
+                    Some((variant, fields))
+                        if defs.variant(variant).expect("type checked").1 == tag =>
+                    {
+                        fields.iter().map(Some).collect()
+                    }
+                    Some(_) => return None,
+                    None => vec![None; arity],
+                };
+                let mut row = Row {
+                    patterns: row.patterns.clone(),
+                    bindings: row.bindings.clone(),
+                    arm: row.arm,
+                };
+                row.bind(column, occurrence);
+                row.patterns.splice(column..=column, fields);
+                Some(row)
+            })
+            .collect();
+        let mut field_occurrences = occurrences.clone();
+        field_occurrences.splice(
+            column..=column,
+            (0..arity).map(|field| [occurrence.as_slice(), &[field]].concat()),
+        );
+
+        match compile(specialized, field_occurrences, defs) {
+            Ok(decision) => cases.push((tag, decision)),
+            Err(mut missing) => {
+                let fields: Vec<String> = missing.drain(column..column + arity).collect();
+                missing.insert(column, write_variant(&union.variants[tag].name, &fields));
+                return Err(missing);
+            }
+        }
+    }
+
+    let default = match (0..union.variants.len()).find(|tag| !tags.contains(tag)) {
+        Some(missing_tag) => {
+            // the rows matching anything in the column, without it
+            let rows = rows
+                .into_iter()
+                .filter(|row| row.variant(column).is_none())
+                .map(|mut row| {
+                    row.bind(column, occurrence);
+                    row.patterns.remove(column);
+                    row
+                })
+                .collect();
+            let mut rest = occurrences.clone();
+            rest.remove(column);
+
+            match compile(rows, rest, defs) {
+                Ok(decision) => Some(Box::new(decision)),
+                Err(mut missing) => {
+                    let variant = &union.variants[missing_tag];
+                    let fields = vec!["_".to_string(); variant.fields.len()];
+                    missing.insert(column, write_variant(&variant.name, &fields));
+                    return Err(missing);
+                }
+            }
+        }
+        None => None,
+    };
+
+    Ok(Decision::Switch {
+        occurrence: occurrence.clone(),
+        ty: Type::named(&union.name),
+        cases,
+        default,
+    })
+}
+
+/// Write a variant with patterns for its fields as in the source, e.g. `Rect(_, _)`
+fn write_variant(name: &str, fields: &[String]) -> String {
+    if fields.is_empty() {
+        name.into()
+    } else {
+        format!("{}({})", name, fields.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::{ASTNode, ExpressionKind};
+    use crate::parser::parser::Parser;
+
+    const UNIONS: &str = "type Shape = Circle(r) | Rect(w, h) | Empty
+        type Pair = Pair(a: Shape, b: Shape)";
+
+    /// Compile the arms of the match in the body of the function at the end of `program`
+    fn decide(program: &str) -> Result<Decision, String> {
+        let mut defs = TypeDefs::new();
+        let program = format!("{} {}", UNIONS, program);
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::UnionNode(def) => defs.define_union(&def).unwrap(),
+                ASTNode::FunctionNode(func) => match func.body.kind {
+                    ExpressionKind::MatchExpr { arms, .. } => {
+                        let patterns: Vec<&Pattern> =
+                            arms.iter().map(|(pattern, _)| pattern).collect();
+                        return compile_match(&patterns, &defs);
+                    }
+                    kind => panic!("unexpected expression {:?}", kind),
+                },
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+        panic!("no function")
+    }
+
+    fn arm(index: usize, bindings: &[(&str, &[usize])]) -> Decision {
+        Decision::Arm {
+            index,
+            bindings: bindings
+                .iter()
+                .map(|(name, occurrence)| (name.to_string(), occurrence.to_vec()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn switches() {
+        let shape = Type::named("Shape");
+        assert_eq!(
+            decide("def f(s) match s { Circle(r) -> r, Rect(w, _) -> w, Empty -> 0 }"),
+            Ok(Decision::Switch {
+                occurrence: vec![],
+                ty: shape.clone(),
+                cases: vec![
+                    (0, arm(0, &[("r", &[0])])),
+                    (1, arm(1, &[("w", &[0])])),
+                    (2, arm(2, &[]))
+                ],
+                default: None
+            })
+        );
+        assert_eq!(
+            decide("def f(s) match s { Empty -> 0, other -> 1 }"),
+            Ok(Decision::Switch {
+                occurrence: vec![],
+                ty: shape,
+                cases: vec![(2, arm(0, &[]))],
+                default: Some(Box::new(arm(1, &[("other", &[])])))
+            })
+        );
+        assert_eq!(
+            decide("def f(s) match s { x -> x, _ -> 0 }"),
+            Ok(arm(0, &[("x", &[])]))
+        );
+    }
+
+    #[test]
+    fn nested_patterns() {
+        // the second field is only tested when the first one is a circle
+        assert_eq!(
+            decide(
+                "def f(p) match p {
+                    Pair(Circle(r), Empty) -> r,
+                    Pair(x, _) -> 0,
+                }"
+            ),
+            Ok(Decision::Switch {
+                occurrence: vec![],
+                ty: Type::named("Pair"),
+                cases: vec![(
+                    0,
+                    Decision::Switch {
+                        occurrence: vec![0],
+                        ty: Type::named("Shape"),
+                        cases: vec![(
+                            0,
+                            Decision::Switch {
+                                occurrence: vec![1],
+                                ty: Type::named("Shape"),
+                                cases: vec![(2, arm(0, &[("r", &[0, 0])]))],
+                                default: Some(Box::new(arm(1, &[("x", &[0])])))
+                            }
+                        )],
+                        default: Some(Box::new(arm(1, &[("x", &[0])])))
+                    }
+                )],
+                default: None
+            })
+        );
+    }
+
+    #[test]
+    fn missing_values() {
+        assert_eq!(
+            decide("def f(s) match s { Circle(r) -> r, Empty -> 0 }"),
+            Err("Rect(_, _)".into())
+        );
+        assert_eq!(
+            decide("def f(p) match p { Pair(Empty, _) -> 0, Pair(_, Circle(r)) -> r }"),
+            Err("Pair(Circle(_), Rect(_, _))".into())
+        );
+        assert_eq!(
+            decide("def f(p) match p { Pair(Empty, Empty) -> 0 }"),
+            Err("Pair(Empty, Circle(_))".into())
+        );
+    }
+}
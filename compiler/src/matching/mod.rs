@@ -0,0 +1,3 @@
+mod decision_tree;
+
+pub use decision_tree::*;
@@ -1,9 +1,56 @@
+use crate::matching::Decision;
+use crate::types::Type;
+use crate::util::Span;
+use std::fmt;
+
 #[derive(Debug)]
 pub enum ASTNode {
     EOF,
     Delimiter,
     ExternNode(Prototype),
     FunctionNode(Function),
+    DerivativeNode(DerivativeDef),
+    StructNode(StructDef),
+    UnionNode(UnionDef),
+}
+
+/// structdef : Struct Identifier { [field ,?]* }
+/// field : Identifier [: Type]?
+#[derive(PartialEq, Clone, Debug)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<String>,
+    /// The type of each field, double if not annotated
+    pub field_types: Vec<Type>,
+    pub span: Span,
+}
+
+impl StructDef {
+    /// The index and the type of a field
+    pub fn field(&self, name: &str) -> Option<(usize, Type)> {
+        let index = self.fields.iter().position(|field| field == name)?;
+        Some((index, self.field_types[index].clone()))
+    }
+}
+
+/// uniondef : Type Identifier = variant [| variant]*
+#[derive(PartialEq, Clone, Debug)]
+pub struct UnionDef {
+    pub name: String,
+    pub variants: Vec<Variant>,
+    pub span: Span,
+}
+
+/// variant : Identifier [( [field ,?]* )]?
+///
+/// A value of a tagged union is one of its variants, told apart by their index as a tag
+#[derive(PartialEq, Clone, Debug)]
+pub struct Variant {
+    pub name: String,
+    pub fields: Vec<String>,
+    /// The type of each field, double if not annotated
+    pub field_types: Vec<Type>,
+    pub span: Span,
 }
 
 /// definition : Def prototype expression;
@@ -11,23 +58,259 @@ pub enum ASTNode {
 pub struct Function {
     pub prototype: Prototype,
     pub body: Expression,
+    pub span: Span,
 }
 
-/// prototype : Identifier ( [Identifier ,]* )
+/// derivativedef : DefDerivative Identifier
+/// Defines `f'`, the derivative of `f`, or the array of its partial derivatives if it takes several args
+#[derive(PartialEq, Clone, Debug)]
+pub struct DerivativeDef {
+    /// The function differentiated
+    pub name: String,
+    pub span: Span,
+}
+
+impl DerivativeDef {
+    /// The name of the function defined
+    pub fn derivative_name(&self) -> String {
+        format!("{}'", self.name)
+    }
+}
+
+/// prototype : Identifier ( [param ,?]* ) [: Type]?
+///           : Binary Op Integer? ( param ,? param ) [: Type]?
+///           : Unary Op ( param ) [: Type]?
+/// param : Identifier [: Type]?
+/// Type : TypeName
+///      : [ Type ]
+///      : Identifier
+///
+/// Operator prototypes are named with `binary` or `unary` followed by the operator, e.g. `binary|`
 #[derive(PartialEq, Clone, Debug)]
 pub struct Prototype {
     pub name: String,
     pub args: Vec<String>,
+    /// The annotated type of each arg
+    pub arg_types: Vec<Option<Type>>,
+    /// The annotated return type
+    pub ret_type: Option<Type>,
+    pub span: Span,
+}
+
+/// Top level expressions are wrapped into functions named with this prefix
+pub const ANONYMOUS_FUNCTION_PREFIX: &str = "_anonymous_";
+
+impl Prototype {
+    /// A prototype without type annotations
+    pub fn new(name: impl Into<String>, args: Vec<String>, span: Span) -> Self {
+        Prototype {
+            name: name.into(),
+            arg_types: vec![None; args.len()],
+            args,
+            ret_type: None,
+            span,
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.name.starts_with(ANONYMOUS_FUNCTION_PREFIX)
+    }
+
+    /// The type of the i-th arg, double if not annotated
+    pub fn arg_type(&self, i: usize) -> Type {
+        self.arg_types
+            .get(i)
+            .cloned()
+            .flatten()
+            .unwrap_or(Type::Double)
+    }
+
+    /// The return type, double if not annotated
+    pub fn return_type(&self) -> Type {
+        self.ret_type.clone().unwrap_or(Type::Double)
+    }
+
+    /// The type of the function as a value, e.g. `(int, double) -> double`
+    pub fn ty(&self) -> Type {
+        let params = (0..self.args.len()).map(|i| self.arg_type(i)).collect();
+        Type::function(params, self.return_type())
+    }
+
+    /// The prototype with every type written out, the unannotated ones being double
+    pub fn annotated(&self) -> Prototype {
+        Prototype {
+            arg_types: (0..self.args.len())
+                .map(|i| Some(self.arg_type(i)))
+                .collect(),
+            ret_type: Some(self.return_type()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Formats the prototype as it is written in the source, e.g. `f(n: int x): double`
+impl fmt::Display for Prototype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", arg)?;
+            if let Some(Some(ty)) = self.arg_types.get(i) {
+                write!(f, ": {}", ty)?;
+            }
+        }
+        write!(f, ")")?;
+        if let Some(ty) = &self.ret_type {
+            write!(f, ": {}", ty)?;
+        }
+        Ok(())
+    }
 }
 
-/// expression : [primaryexpr (Op primary_expr)*];
+/// expression : [unaryexpr (Op unaryexpr)*];
+/// unaryexpr : postfixexpr
+///           : Op unaryexpr
+/// postfixexpr : primaryexpr [[ expression ] | . Identifier]*
 /// primaryexpr : identifierexpr
 ///             : numberexpr
+///             : boolexpr
+///             : stringexpr
+///             : arrayexpr
+///             : structexpr
+///             : variantexpr
+///             : matchexpr
+///             : castexpr
 ///             : parenexpr
+///             : ifexpr
+///             : forexpr
+///             : varexpr
+///             : lambdaexpr
+///             : gradexpr
+#[derive(PartialEq, Clone, Debug)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        Expression { kind, span }
+    }
+}
+
+/// Creates an expression not originated from the source
+impl From<ExpressionKind> for Expression {
+    fn from(kind: ExpressionKind) -> Self {
+        Expression::new(kind, Span::default())
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
-pub enum Expression {
+pub enum ExpressionKind {
+    /// A double literal, e.g. `42.0`
     NumberExpr(f64),
+    /// An int literal, e.g. `42`
+    IntExpr(i64),
+    BoolExpr(bool),
+    /// A string literal, e.g. `"hello\n"`, with its escape sequences replaced
+    StringExpr(String),
+    /// A variable, or a function used as a value if no variable has its name
     VariableExpr(String),
+    /// arrayexpr : [ expression [, expression]* ]
+    ArrayExpr(Vec<Expression>),
+    /// An element of an array, e.g. `a[i]`, which can be assigned with `=`
+    IndexExpr(Box<Expression>, Box<Expression>),
+    /// structexpr : Identifier { [Identifier = expression ,?]* }
+    ///
+    /// The fields are given in the order they are declared
+    StructExpr(String, Vec<(String, Expression)>),
+    /// A field of a struct, e.g. `p.x`
+    FieldExpr {
+        object: Box<Expression>,
+        field: String,
+        /// The index of the field in its struct, filled in by the type checker
+        index: Option<usize>,
+    },
+    /// variantexpr : Identifier [( [expression ,]* )]?
+    ///
+    /// The identifier is the name of a variant declared before, e.g. `Circle(1)` or `None`
+    VariantExpr(String, Vec<Expression>),
+    /// matchexpr : Match expression { [pattern -> expression ,?]* }
+    ///
+    /// The first arm whose pattern matches the value is evaluated
+    MatchExpr {
+        value: Box<Expression>,
+        arms: Vec<(Pattern, Expression)>,
+        /// How to find the arm, filled in by the type checker
+        tree: Option<Box<Decision>>,
+    },
+    UnaryExpr(char, Box<Expression>),
     BinaryExpr(char, Box<Expression>, Box<Expression>),
+    /// A call to a function by name, or to the function held by a variable if one has the name
     CallExpr(String, Vec<Expression>),
+    /// ifexpr : If expression Then expression Else expression
+    IfExpr(Box<Expression>, Box<Expression>, Box<Expression>),
+    /// forexpr : For Identifier = expression , expression [, expression]? In expression
+    ForExpr {
+        var: String,
+        start: Box<Expression>,
+        end: Box<Expression>,
+        step: Option<Box<Expression>>,
+        body: Box<Expression>,
+    },
+    /// varexpr : Var Identifier [= expression]? [, Identifier [= expression]?]* In expression
+    VarExpr {
+        vars: Vec<(String, Option<Expression>)>,
+        body: Box<Expression>,
+    },
+    /// castexpr : Type ( expression )
+    CastExpr(Type, Box<Expression>),
+    /// lambdaexpr : \ [param ,?]* -> expression
+    ///
+    /// A function capturing the values the variables of the enclosing scopes used by its body have
+    /// when it is evaluated
+    LambdaExpr {
+        params: Vec<String>,
+        /// The annotated type of each param, all of them filled in by the type checker
+        param_types: Vec<Option<Type>>,
+        /// The return type, filled in by the type checker
+        ret_type: Option<Type>,
+        /// The captured variables and their types, in the order they are first used,
+        /// filled in by the type checker
+        captures: Vec<(String, Type)>,
+        body: Box<Expression>,
+    },
+    /// gradexpr : Grad ( Identifier )
+    ///
+    /// The gradient of a function of doubles returning a double, as a function taking the same args
+    /// and returning its derivative, or the array of its partial derivatives if it takes several args
+    GradExpr(String),
+    /// A function taking the values of the captured variables before its args, along with them.
+    /// Made from a lambda of type `ty` by closure conversion.
+    ClosureExpr {
+        function: String,
+        captures: Vec<String>,
+        ty: Type,
+    },
+}
+
+/// pattern : _
+///         : Identifier
+///         : Identifier ( [pattern ,?]* )
+#[derive(PartialEq, Clone, Debug)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum PatternKind {
+    /// `_`, matching any value
+    Wildcard,
+    /// A name which is not a variant, matching any value and bound to it
+    Binding(String),
+    /// A variant, with a pattern for each of its fields
+    Variant(String, Vec<Pattern>),
 }
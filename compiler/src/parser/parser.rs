@@ -2,35 +2,86 @@ use super::nodes::*;
 use crate::lexer::Token::*;
 use crate::lexer::*;
 use crate::or_return;
+use crate::types::Type;
 use crate::util::buffer::Buffer;
+use crate::util::Span;
 use phf::phf_map;
+use std::collections::{HashMap, HashSet, VecDeque};
 
+/// Precedences of builtin binary operators.
+/// User defined operators are added to the table owned by each `Parser`.
 static BINOP_PRECEDENCES: phf::Map<char, i8> = phf_map! {
+    '=' => 2,
     '<' => 10,
+    '>' => 10,
     '+' => 20,
     '-' => 20,
     '*' => 40,
+    '/' => 40,
 };
 
-fn get_binop_precedences(binop: char) -> ParseResult<i8> {
-    BINOP_PRECEDENCES
-        .get(&binop)
-        .map(|x| *x)
-        .ok_or(ParseError::new(Some(BinOp(binop)), "Unknown binop"))
-}
+/// Precedence of a user defined binary operator declared without one
+const DEFAULT_BINOP_PRECEDENCE: i8 = 30;
 
-#[derive(Debug)]
-pub struct ParseError(Option<Token>, String);
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken {
+        /// The unexpected token, or None if the input ended unexpectedly
+        token: Option<Token>,
+        message: String,
+        span: Span,
+    },
+    /// A token could not be read by the lexer
+    Lexer(LexerError),
+}
 
 impl ParseError {
-    fn new(token: Option<Token>, message: &str) -> Self {
-        ParseError(token, message.into())
+    fn new(token: Option<Token>, message: &str, span: Span) -> Self {
+        ParseError::UnexpectedToken {
+            token,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. } => *span,
+            ParseError::Lexer(err) => err.span(),
+        }
+    }
+}
+
+/// Passes tokens through, and sets lexer errors aside so that parsing can go on
+struct LexerErrorCollector<I: Iterator<Item = LexerResult>> {
+    lexer: I,
+    errors: Vec<LexerError>,
+}
+
+impl<I: Iterator<Item = LexerResult>> Iterator for LexerErrorCollector<I> {
+    type Item = SpannedToken;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.lexer.next()? {
+                Ok(token) => return Some(token),
+                Err(err) => self.errors.push(err),
+            }
+        }
     }
 }
 
+/// All top level items parsed from a source, along with all the errors found
+#[derive(Debug)]
+pub struct Program {
+    pub nodes: Vec<ASTNode>,
+    pub errors: Vec<ParseError>,
+}
+
 macro_rules! get_curr {
     ($s:expr,$err:tt) => {
-        $s.curr().ok_or(ParseError::new(None, $err))?
+        $s.curr()
+            .ok_or(ParseError::new(None, $err, $s.curr_span()))?
     };
 }
 
@@ -38,7 +89,11 @@ macro_rules! expect {
     ($s:expr,$expected:expr,$err:tt) => {{
         let token = $s.curr();
         if token != Some($expected) {
-            return Err(ParseError::new(token.map(|x| x.clone()), $err));
+            return Err(ParseError::new(
+                token.map(|x| x.clone()),
+                $err,
+                $s.curr_span(),
+            ));
         }
     }};
 }
@@ -49,107 +104,631 @@ macro_rules! extract {
         if let Some($expected(inner)) = token {
             inner
         } else {
-            return Err(ParseError::new(token.map(|x| x.clone()), $err));
+            return Err(ParseError::new(
+                token.map(|x| x.clone()),
+                $err,
+                $s.curr_span(),
+            ));
         }
     }};
 }
 
 type ParseResult<T> = Result<T, ParseError>;
 
-pub struct Parser<I: Iterator<Item = Token>> {
-    buffer: Buffer<Token, I>,
+pub struct Parser<I: Iterator<Item = LexerResult>> {
+    buffer: Buffer<SpannedToken, LexerErrorCollector<I>>,
+    /// Span of the last eaten token
+    prev_span: Span,
+    /// Errors to be reported by the following calls to `parse`
+    pending_errors: VecDeque<ParseError>,
+    anonymous_fun_count: usize,
+    binop_precedences: HashMap<char, i8>,
+    /// The variants of the unions parsed so far, which are constructed and matched by name
+    variants: HashSet<String>,
+    /// Whether an identifier followed by `{` is not a struct, as in the value of a match
+    no_struct_expr: bool,
 }
 
-impl<I: Iterator<Item = Token>> Parser<I> {
+impl<I: Iterator<Item = LexerResult>> Parser<I> {
     pub fn new(lexer: I) -> Self {
         Parser {
-            buffer: Buffer::new(lexer),
+            buffer: Buffer::new(LexerErrorCollector {
+                lexer,
+                errors: vec![],
+            }),
+            prev_span: Span::default(),
+            pending_errors: VecDeque::new(),
+            anonymous_fun_count: 0,
+            binop_precedences: BINOP_PRECEDENCES
+                .entries()
+                .map(|(op, prec)| (*op, *prec))
+                .collect(),
+            variants: HashSet::new(),
+            no_struct_expr: false,
+        }
+    }
+
+    fn get_binop_precedence(&self, binop: char) -> ParseResult<i8> {
+        self.binop_precedences
+            .get(&binop)
+            .copied()
+            .ok_or_else(|| ParseError::new(Some(BinOp(binop)), "Unknown binop", self.curr_span()))
+    }
+
+    /// Parse all the top level items until the end of input,
+    /// recovering from errors to report as many of them as possible
+    pub fn parse_program(&mut self) -> Program {
+        let mut nodes = vec![];
+        let mut errors = vec![];
+        loop {
+            match self.parse() {
+                Ok(ASTNode::EOF) => break,
+                Ok(ASTNode::Delimiter) => continue,
+                Ok(node) => nodes.push(node),
+                Err(err) => errors.push(err),
+            }
         }
+        Program { nodes, errors }
     }
 
-    /// program := []
+    /// Parse the next top level item.
+    ///
+    /// On error, tokens are skipped until the start of the next item,
+    /// so that parsing can go on with the next call.
+    /// An item containing tokens not recognized by the lexer is dropped,
+    /// and the lexer errors are returned instead.
     pub fn parse(&mut self) -> ParseResult<ASTNode> {
-        let token = or_return!(self.buffer.curr(), Ok(ASTNode::EOF));
-        let mut anonymous_fun_count = 0;
+        if let Some(err) = self.pending_errors.pop_front() {
+            return Err(err);
+        }
+
+        let result = self.parse_item();
+        if result.is_err() {
+            self.synchronize();
+        }
+
+        // Lexer errors come before any parse error they may have caused.
+        // The lookahead may have read past the item, so errors after it are left for the next one.
+        let item_end = match self.curr() {
+            Some(_) => self.prev_span.end.offset,
+            None => usize::MAX,
+        };
+        let (lexer_errors, later_errors) = std::mem::take(&mut self.buffer.iter_mut().errors)
+            .into_iter()
+            .partition(|err| err.span().start.offset < item_end);
+        self.buffer.iter_mut().errors = later_errors;
+        self.pending_errors
+            .extend(lexer_errors.into_iter().map(ParseError::Lexer));
+        let node = match result {
+            Ok(node) => node,
+            Err(err) => {
+                self.pending_errors.push_back(err);
+                ASTNode::Delimiter
+            }
+        };
+
+        match self.pending_errors.pop_front() {
+            Some(err) => Err(err),
+            None => Ok(node),
+        }
+    }
+
+    /// program := [definition | derivativedef | external | structdef | uniondef | expression | Delimiter]*
+    fn parse_item(&mut self) -> ParseResult<ASTNode> {
+        let token = or_return!(self.curr(), Ok(ASTNode::EOF));
         Ok(match token {
             Def => ASTNode::FunctionNode(self.parse_function()?),
+            DefDerivative => ASTNode::DerivativeNode(self.parse_derivative()?),
             Extern => ASTNode::ExternNode(self.parse_extern()?),
-            Delimiter => ASTNode::Delimiter,
-            _ => ASTNode::FunctionNode(Function {
-                prototype: Prototype {
-                    name: format!("_anonymous_{}", {
-                        anonymous_fun_count += 1;
-                        anonymous_fun_count
-                    }),
-                    args: vec![],
-                },
-                body: self.parse_expression()?,
-            }),
+            Struct => ASTNode::StructNode(self.parse_struct()?),
+            Token::Type => ASTNode::UnionNode(self.parse_union()?),
+            Delimiter => {
+                self.advance();
+                ASTNode::Delimiter
+            }
+            _ => ASTNode::FunctionNode(self.parse_top_level_expr()?),
         })
     }
 
+    /// Skip tokens until the end of the item, i.e. before a `;`, which is left for the next call
+    /// so that an interactive source is not read further, or before a `def`, `def'`, `extern`, `struct` or `type`
+    fn synchronize(&mut self) {
+        loop {
+            match self.curr() {
+                None | Some(Delimiter) | Some(Def) | Some(DefDerivative) | Some(Extern)
+                | Some(Struct) | Some(Token::Type) => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
     #[inline]
     fn curr(&self) -> Option<&Token> {
-        self.buffer.curr()
+        self.buffer.curr().map(|x| &x.token)
     }
 
-    #[inline]
-    fn peek(&self) -> Option<&Token> {
-        self.buffer.peek()
+    /// Span of the current token, or an empty span after the last token if the input has ended
+    fn curr_span(&self) -> Span {
+        self.buffer
+            .curr()
+            .map_or(Span::at(self.prev_span.end), |x| x.span)
+    }
+
+    /// The span from `start` to the end of the last eaten token
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.prev_span)
     }
 
     #[inline]
     fn advance(&mut self) {
+        if let Some(token) = self.buffer.curr() {
+            self.prev_span = token.span;
+        }
         self.buffer.advance()
     }
 
     fn parse_function(&mut self) -> ParseResult<Function> {
-        self.buffer.advance(); // eat def
-        let prototype = self.parse_prototype()?;
+        let start = self.curr_span();
+        self.advance(); // eat def
+        let (prototype, binop) = self.parse_prototype()?;
 
-        let body = self.parse_expression()?;
-        Ok(Function { prototype, body })
+        // install the operator so that it can be used right away, even in its own body
+        let previous = binop.map(|(op, prec)| (op, self.binop_precedences.insert(op, prec)));
+        let body = match self.parse_expression() {
+            Ok(body) => body,
+            Err(err) => {
+                // a definition which does not parse leaves the precedences as they were
+                match previous {
+                    Some((op, Some(prec))) => {
+                        self.binop_precedences.insert(op, prec);
+                    }
+                    Some((op, None)) => {
+                        self.binop_precedences.remove(&op);
+                    }
+                    None => {}
+                }
+                return Err(err);
+            }
+        };
+        Ok(Function {
+            prototype,
+            body,
+            span: self.span_from(start),
+        })
+    }
+
+    /// derivativedef : DefDerivative Identifier
+    fn parse_derivative(&mut self) -> ParseResult<DerivativeDef> {
+        let start = self.curr_span();
+
+        // eat def'
+        self.advance();
+
+        let name = extract!(self, Identifier, "expect the name of a function after def'").clone();
+        self.advance();
+
+        Ok(DerivativeDef {
+            name,
+            span: self.span_from(start),
+        })
     }
 
-    fn parse_prototype(&mut self) -> ParseResult<Prototype> {
-        // expect function name (identifier)
-        let name = extract!(self, Identifier, "expect identifier in prototype").clone();
+    /// Parse a prototype, along with the precedence of the binary operator it defines, if any.
+    ///
+    /// The precedence is only installed by the caller, once the prototype is known to be valid.
+    fn parse_prototype(&mut self) -> ParseResult<(Prototype, Option<(char, i8)>)> {
+        let start = self.curr_span();
+
+        // the number of operands an operator prototype must have
+        let (name, operands, binop) = match get_curr!(self, "expect identifier in prototype") {
+            Identifier(name) => {
+                let name = name.clone();
+
+                // eat function name
+                self.advance();
+
+                (name, None, None)
+            }
+            Unary => {
+                // eat unary
+                self.advance();
+
+                let op = *extract!(self, BinOp, "expect operator after unary");
+                self.advance();
+
+                (format!("unary{}", op), Some(1), None)
+            }
+            Binary => {
+                // eat binary
+                self.advance();
+
+                let op = *extract!(self, BinOp, "expect operator after binary");
+                // the builtin operators never call a user definition, so they cannot be redefined
+                if BINOP_PRECEDENCES.contains_key(&op) {
+                    return Err(ParseError::new(
+                        Some(BinOp(op)),
+                        "cannot redefine a builtin operator",
+                        self.curr_span(),
+                    ));
+                }
+                self.advance();
 
-        // eat function name
-        self.buffer.advance();
+                // read the optional precedence
+                let precedence = match self.curr() {
+                    Some(Integer(prec)) if (1..=100).contains(prec) => {
+                        let prec = *prec as i8;
+                        self.advance();
+                        prec
+                    }
+                    Some(token @ Integer(_)) | Some(token @ Number(_)) => {
+                        return Err(ParseError::new(
+                            Some(token.clone()),
+                            "precedence must be an integer within 1..=100",
+                            self.curr_span(),
+                        ));
+                    }
+                    _ => DEFAULT_BINOP_PRECEDENCE,
+                };
+
+                (format!("binary{}", op), Some(2), Some((op, precedence)))
+            }
+            token => {
+                return Err(ParseError::new(
+                    Some(token.clone()),
+                    "expect identifier, unary or binary in prototype",
+                    self.curr_span(),
+                ))
+            }
+        };
 
         // expect and eat (
         expect!(self, &OpeningParenthesis, "expect ( in prototype");
-        self.buffer.advance();
+        self.advance();
 
-        // read argument names
-        let mut args = Vec::<String>::new();
-        while let Identifier(arg_name) = get_curr!(self, "expect identifier or )") {
-            args.push(arg_name.to_string());
-            self.buffer.advance();
-        }
+        let (args, arg_types) = self.parse_params()?;
 
         // expect )
         expect!(self, &ClosingParenthesis, "expect identifier or )");
-        self.buffer.advance();
+        self.advance();
+
+        let ret_type = self.parse_type_annotation()?;
+
+        let span = self.span_from(start);
+
+        if operands.is_some_and(|operands| operands != args.len()) {
+            return Err(ParseError::new(
+                None,
+                "invalid number of operands for operator",
+                span,
+            ));
+        }
+
+        let prototype = Prototype {
+            name,
+            args,
+            arg_types,
+            ret_type,
+            span,
+        };
+        Ok((prototype, binop))
+    }
+
+    /// params : [Identifier type_annotation Comma?]*
+    ///
+    /// Names and optional types, optionally separated by commas
+    fn parse_params(&mut self) -> ParseResult<(Vec<String>, Vec<Option<Type>>)> {
+        let mut params = Vec::<String>::new();
+        let mut types = Vec::<Option<Type>>::new();
+        while let Some(Identifier(param)) = self.curr() {
+            params.push(param.to_string());
+            self.advance();
+
+            types.push(self.parse_type_annotation()?);
+
+            if self.curr() == Some(&Comma) {
+                self.advance();
+            }
+        }
+        Ok((params, types))
+    }
+
+    /// structdef : Struct Identifier OpeningBrace [Identifier type_annotation Comma?]* ClosingBrace
+    fn parse_struct(&mut self) -> ParseResult<StructDef> {
+        let start = self.curr_span();
+
+        // eat struct
+        self.advance();
+
+        let name = extract!(self, Identifier, "expect identifier after struct").clone();
+        self.advance();
+
+        // expect and eat {
+        expect!(self, &OpeningBrace, "expect { after the struct name");
+        self.advance();
+
+        let (fields, field_types) = self.parse_fields(&ClosingBrace, "expect identifier or }")?;
+
+        Ok(StructDef {
+            name,
+            fields,
+            field_types,
+            span: self.span_from(start),
+        })
+    }
+
+    /// uniondef : Type Identifier = variant [| variant]*
+    /// variant  : Identifier [OpeningParenthesis [Identifier type_annotation Comma?]* ClosingParenthesis]?
+    fn parse_union(&mut self) -> ParseResult<UnionDef> {
+        let start = self.curr_span();
+
+        // eat type
+        self.advance();
+
+        let name = extract!(self, Identifier, "expect identifier after type").clone();
+        self.advance();
+
+        // expect and eat =
+        expect!(self, &BinOp('='), "expect = after the type name");
+        self.advance();
+
+        let mut variants = Vec::<Variant>::new();
+        loop {
+            let variant_start = self.curr_span();
+            let variant = extract!(self, Identifier, "expect a variant").clone();
+            if variants.iter().any(|other| other.name == variant) {
+                return Err(ParseError::new(
+                    Some(Identifier(variant)),
+                    "duplicate variant",
+                    variant_start,
+                ));
+            }
+            self.advance();
+
+            // the fields are optional
+            let (fields, field_types) = if self.curr() == Some(&OpeningParenthesis) {
+                self.advance();
+                self.parse_fields(&ClosingParenthesis, "expect identifier or )")?
+            } else {
+                (vec![], vec![])
+            };
+
+            variants.push(Variant {
+                name: variant,
+                fields,
+                field_types,
+                span: self.span_from(variant_start),
+            });
+
+            if self.curr() != Some(&BinOp('|')) {
+                break;
+            }
+            self.advance();
+        }
 
-        Ok(Prototype { name, args })
+        // the variants are told apart from variables from now on
+        self.variants
+            .extend(variants.iter().map(|variant| variant.name.clone()));
+
+        Ok(UnionDef {
+            name,
+            variants,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Read field names and their optional types, optionally separated by commas,
+    /// up to and including `closing`
+    fn parse_fields(
+        &mut self,
+        closing: &Token,
+        message: &str,
+    ) -> ParseResult<(Vec<String>, Vec<Type>)> {
+        let mut fields = Vec::<String>::new();
+        let mut field_types = Vec::<Type>::new();
+        while let Identifier(field) = get_curr!(self, message) {
+            if fields.contains(field) {
+                return Err(ParseError::new(
+                    Some(Identifier(field.clone())),
+                    "duplicate field",
+                    self.curr_span(),
+                ));
+            }
+            fields.push(field.clone());
+            self.advance();
+
+            field_types.push(self.parse_type_annotation()?.unwrap_or(Type::Double));
+
+            if self.curr() == Some(&Comma) {
+                self.advance();
+            }
+        }
+
+        // expect and eat the closing token
+        expect!(self, closing, message);
+        self.advance();
+
+        Ok((fields, field_types))
+    }
+
+    /// type_annotation : [: Type]?
+    fn parse_type_annotation(&mut self) -> ParseResult<Option<Type>> {
+        if self.curr() != Some(&BinOp(':')) {
+            return Ok(None);
+        }
+
+        // eat :
+        self.advance();
+
+        self.parse_type().map(Some)
+    }
+
+    /// Type : TypeName
+    ///      : OpeningBracket Type ClosingBracket
+    ///      : OpeningParenthesis [Type Comma?]* ClosingParenthesis Arrow Type
+    ///      : Identifier
+    fn parse_type(&mut self) -> ParseResult<Type> {
+        // the name of a struct or a union
+        if let Some(Identifier(name)) = self.curr() {
+            let ty = Type::named(name);
+            self.advance();
+            return Ok(ty);
+        }
+
+        // a function, with the types of its args optionally separated by commas
+        if self.curr() == Some(&OpeningParenthesis) {
+            // eat (
+            self.advance();
+
+            let mut params = vec![];
+            while self.curr() != Some(&ClosingParenthesis) {
+                params.push(self.parse_type()?);
+                if self.curr() == Some(&Comma) {
+                    self.advance();
+                }
+            }
+            // eat )
+            self.advance();
+
+            // expect and eat ->
+            expect!(self, &Arrow, "expect -> after the types of the args");
+            self.advance();
+
+            let ret = self.parse_type()?;
+            return Ok(Type::function(params, ret));
+        }
+
+        if self.curr() != Some(&OpeningBracket) {
+            let ty = extract!(self, TypeName, "expect a type").clone();
+            self.advance();
+            return Ok(ty);
+        }
+
+        // eat [
+        self.advance();
+
+        let element = self.parse_type()?;
+
+        // expect and eat ]
+        expect!(self, &ClosingBracket, "expect ] after the element type");
+        self.advance();
+
+        Ok(Type::array(element))
+    }
+
+    /// Wraps a top level expression into an anonymous function without args
+    fn parse_top_level_expr(&mut self) -> ParseResult<Function> {
+        let body = self.parse_expression()?;
+
+        self.anonymous_fun_count += 1;
+        let prototype = Prototype::new(
+            format!("{}{}", ANONYMOUS_FUNCTION_PREFIX, self.anonymous_fun_count),
+            vec![],
+            body.span,
+        );
+
+        Ok(Function {
+            prototype,
+            span: body.span,
+            body,
+        })
     }
 
     fn parse_extern(&mut self) -> ParseResult<Prototype> {
         // eat extern
-        self.buffer.advance();
+        self.advance();
 
-        self.parse_prototype()
+        let (prototype, binop) = self.parse_prototype()?;
+        if let Some((op, prec)) = binop {
+            self.binop_precedences.insert(op, prec);
+        }
+        Ok(prototype)
     }
 
-    /// expression := primary binoprhs
+    /// expression := unary binoprhs
     fn parse_expression(&mut self) -> ParseResult<Expression> {
-        let lhs = self.parse_primary()?;
+        let lhs = self.parse_unary()?;
         self.parse_bin_op_rhs(0, lhs)
     }
 
-    /// binoprhs := ( + primary )*
+    /// Parse an expression within delimiters, where it can be a struct even in the value of a match
+    fn parse_delimited_expression(&mut self) -> ParseResult<Expression> {
+        let no_struct_expr = std::mem::replace(&mut self.no_struct_expr, false);
+        let expr = self.parse_expression();
+        self.no_struct_expr = no_struct_expr;
+        expr
+    }
+
+    /// unary := postfix
+    ///        : Op unary
+    fn parse_unary(&mut self) -> ParseResult<Expression> {
+        match self.curr() {
+            Some(BinOp(op)) => {
+                let op = *op;
+                let start = self.curr_span();
+
+                // eat op
+                self.advance();
+
+                let operand = self.parse_unary()?;
+                Ok(Expression::new(
+                    ExpressionKind::UnaryExpr(op, Box::new(operand)),
+                    self.span_from(start),
+                ))
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+
+    /// postfix := primary [OpeningBracket expression ClosingBracket | Dot Identifier]*
+    ///
+    /// Only functions named by an identifier are called, so a `(` after a postfix is an error
+    /// rather than the start of the next item, e.g. in `grad(f)(1)`.
+    fn parse_postfix(&mut self) -> ParseResult<Expression> {
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            let start = expr.span;
+            let kind = match self.curr() {
+                Some(OpeningBracket) => {
+                    // eat [
+                    self.advance();
+
+                    let index = self.parse_delimited_expression()?;
+
+                    // expect and eat ]
+                    expect!(self, &ClosingBracket, "expect ] after index");
+                    self.advance();
+
+                    ExpressionKind::IndexExpr(Box::new(expr), Box::new(index))
+                }
+                Some(Dot) => {
+                    // eat .
+                    self.advance();
+
+                    let field = extract!(self, Identifier, "expect a field after .").clone();
+                    self.advance();
+
+                    ExpressionKind::FieldExpr {
+                        object: Box::new(expr),
+                        field,
+                        index: None,
+                    }
+                }
+                Some(OpeningParenthesis) => {
+                    return Err(ParseError::new(
+                        Some(OpeningParenthesis),
+                        "only a named function can be called, bind the value with var first",
+                        self.curr_span(),
+                    ))
+                }
+                _ => return Ok(expr),
+            };
+
+            expr = Expression::new(kind, self.span_from(start));
+        }
+    }
+
+    /// binoprhs := ( Op unary )*
     fn parse_bin_op_rhs(
         &mut self,
         min_expr_prec: i8,
@@ -158,7 +737,7 @@ impl<I: Iterator<Item = Token>> Parser<I> {
         loop {
             if let Some(BinOp(binop)) = self.curr() {
                 let binop = *binop;
-                let curr_prec = get_binop_precedences(binop)?;
+                let curr_prec = self.get_binop_precedence(binop)?;
                 if curr_prec < min_expr_prec {
                     return Ok(lhs);
                 }
@@ -166,117 +745,1392 @@ impl<I: Iterator<Item = Token>> Parser<I> {
                 // eat binop
                 self.advance();
 
-                // parse next primary
-                let mut rhs = self.parse_primary()?;
+                // parse next unary
+                let mut rhs = self.parse_unary()?;
 
                 // find if the next is still binop
                 if let Some(BinOp(next_binop)) = self.curr() {
                     // if the next is still a binop, and it has higher precendence than curr
                     // than recursively call binop
-                    if get_binop_precedences(*next_binop)? > curr_prec {
+                    if self.get_binop_precedence(*next_binop)? > curr_prec {
                         rhs = self.parse_bin_op_rhs(curr_prec + 1, rhs)?;
                     }
                 }
 
-                lhs = Expression::BinaryExpr(binop, Box::new(lhs), Box::new(rhs));
+                let span = lhs.span.to(rhs.span);
+                lhs = Expression::new(
+                    ExpressionKind::BinaryExpr(binop, Box::new(lhs), Box::new(rhs)),
+                    span,
+                );
             } else {
                 return Ok(lhs);
             }
         }
     }
 
-    /// primary_expr     : [Identifier | Number | call_expr | parenthesis_expr];
+    /// primary_expr     : [Identifier | Number | Integer | Boolean | Str | array_expr | call_expr | cast_expr | parenthesis_expr | if_expr | for_expr | var_expr | lambda_expr];
     /// call_expr        : Ident OpeningParenthesis [expression Comma ?]* ClosingParenthesis;
     /// parenthesis_expr : OpeningParenthesis expression ClosingParenthesis;
     fn parse_primary(&mut self) -> ParseResult<Expression> {
         let token = get_curr!(self, "expect a primary expression");
+        let literal = match token {
+            Number(num) => Some(ExpressionKind::NumberExpr(*num)),
+            Integer(num) => Some(ExpressionKind::IntExpr(*num)),
+            Boolean(value) => Some(ExpressionKind::BoolExpr(*value)),
+            Str(value) => Some(ExpressionKind::StringExpr(value.clone())),
+            _ => None,
+        };
+        if let Some(literal) = literal {
+            let span = self.curr_span();
+            self.advance();
+            return Ok(Expression::new(literal, span));
+        }
+
         match token {
             Identifier(_) => self.parse_identifier_expr(),
-            Number(_) => self.parse_expression(),
+            TypeName(_) => self.parse_cast_expr(),
             OpeningParenthesis => self.parse_parenthesis_expr(),
+            OpeningBracket => self.parse_array_expr(),
+            If => self.parse_if_expr(),
+            For => self.parse_for_expr(),
+            Var => self.parse_var_expr(),
+            Match => self.parse_match_expr(),
+            Lambda => self.parse_lambda_expr(),
+            Grad => self.parse_grad_expr(),
             _ => Err(ParseError::new(
                 Some(token.clone()),
-                "expect identifier, number or (",
+                "expect identifier, number, type, (, [, if, for, var, match, grad or \\",
+                self.curr_span(),
             )),
         }
     }
 
-    fn parse_number_expr(&mut self) -> ParseResult<Expression> {
-        let number = extract!(self, Number, "expect a number");
-        Ok(Expression::NumberExpr(*number))
-    }
+    /// cast_expr : Type OpeningParenthesis expression ClosingParenthesis
+    fn parse_cast_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
 
-    /// parenthesis_expr : OpeningParenthesis expression ClosingParenthesis;
-    fn parse_parenthesis_expr(&mut self) -> ParseResult<Expression> {
-        // eat )
-        self.buffer.advance();
+        let ty = extract!(self, TypeName, "expect a type").clone();
+        self.advance();
 
-        // get inner expression
-        let expr = self.parse_expression()?;
+        // expect and eat (
+        expect!(self, &OpeningParenthesis, "expect ( after type");
+        self.advance();
 
-        // eat )
+        let expr = self.parse_delimited_expression()?;
+
+        // expect and eat )
         expect!(self, &ClosingParenthesis, "expect )");
-        self.buffer.advance();
+        self.advance();
 
-        Ok(expr)
+        Ok(Expression::new(
+            ExpressionKind::CastExpr(ty, Box::new(expr)),
+            self.span_from(start),
+        ))
     }
 
-    /// identifier_expr : identifier
-    ///                 : identifier ( expression* )
-    fn parse_identifier_expr(&mut self) -> ParseResult<Expression> {
-        // get identifier
-        let identifier = extract!(self, Identifier, "expect identifier").clone();
-        self.buffer.advance();
+    /// array_expr : OpeningBracket expression [Comma expression]* Comma? ClosingBracket
+    fn parse_array_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
 
-        // lookahead for whether its a call
-        if self.peek() != Some(&OpeningParenthesis) {
-            return Ok(Expression::VariableExpr(identifier));
+        // eat [
+        self.advance();
+
+        // the type of the elements is the type of the first one
+        if self.curr() == Some(&ClosingBracket) {
+            return Err(ParseError::new(
+                Some(ClosingBracket),
+                "expect an element, arrays cannot be empty",
+                self.curr_span(),
+            ));
         }
 
-        // its a call
-        // eat (
-        self.advance();
+        let mut elements = Vec::<Expression>::new();
 
-        let mut args = Vec::<Expression>::new();
+        // until a ] is reached
+        while self.curr() != Some(&ClosingBracket) {
+            elements.push(self.parse_delimited_expression()?);
 
-        // until a ) is reached
-        while self.curr() != Some(&ClosingParenthesis) {
-            let arg = self.parse_expression()?;
-            args.push(arg);
+            if self.curr() == Some(&ClosingBracket) {
+                break;
+            }
 
-            expect!(self, &Comma, "expect comma");
+            expect!(self, &Comma, "expect , or ]");
             self.advance();
         }
 
-        // eat )
+        // eat ]
         self.advance();
 
-        Ok(Expression::CallExpr(identifier, args))
+        Ok(Expression::new(
+            ExpressionKind::ArrayExpr(elements),
+            self.span_from(start),
+        ))
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// if_expr : If expression Then expression Else expression
+    fn parse_if_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
 
-    use super::*;
-    use crate::lexer::Lexer;
+        // eat if
+        self.advance();
 
-    #[test]
-    fn simple() {
-        let program = "
-         def fun1(a b)
-         a+b*e-d
-         a+b
-         extern sin(x)
-         ";
-        let lexer = Lexer::new(program.chars());
+        let cond = self.parse_expression()?;
+
+        // expect and eat then
+        expect!(self, &Then, "expect then");
+        self.advance();
 
-        let tokens = lexer.take_while(|x| x.is_ok()).map(|x| x.unwrap());
+        let then_expr = self.parse_expression()?;
 
-        let mut parser = Parser::new(tokens);
+        // expect and eat else
+        expect!(self, &Else, "expect else");
+        self.advance();
 
-        let ast = parser.parse();
+        let else_expr = self.parse_expression()?;
 
-        println!("{:#?}", ast);
+        Ok(Expression::new(
+            ExpressionKind::IfExpr(Box::new(cond), Box::new(then_expr), Box::new(else_expr)),
+            self.span_from(start),
+        ))
+    }
+
+    /// for_expr : For Identifier = expression , expression [, expression]? In expression
+    fn parse_for_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
+
+        // eat for
+        self.advance();
+
+        let var = extract!(self, Identifier, "expect identifier after for").clone();
+        self.advance();
+
+        // expect and eat =
+        expect!(self, &BinOp('='), "expect = after for");
+        self.advance();
+
+        let start_expr = self.parse_expression()?;
+
+        // expect and eat ,
+        expect!(self, &Comma, "expect , after for start value");
+        self.advance();
+
+        let end = self.parse_expression()?;
+
+        // the step value is optional
+        let step = if self.curr() == Some(&Comma) {
+            self.advance();
+            Some(Box::new(self.parse_expression()?))
+        } else {
+            None
+        };
+
+        // expect and eat in
+        expect!(self, &In, "expect in after for");
+        self.advance();
+
+        let body = self.parse_expression()?;
+
+        Ok(Expression::new(
+            ExpressionKind::ForExpr {
+                var,
+                start: Box::new(start_expr),
+                end: Box::new(end),
+                step,
+                body: Box::new(body),
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// var_expr : Var Identifier [= expression]? [, Identifier [= expression]?]* In expression
+    fn parse_var_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
+
+        // eat var
+        self.advance();
+
+        let mut vars = Vec::new();
+        loop {
+            let name = extract!(self, Identifier, "expect identifier after var").clone();
+            self.advance();
+
+            // the initializer is optional
+            let init = if self.curr() == Some(&BinOp('=')) {
+                self.advance();
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+
+            vars.push((name, init));
+
+            // end of var list
+            if self.curr() != Some(&Comma) {
+                break;
+            }
+            self.advance();
+        }
+
+        // expect and eat in
+        expect!(self, &In, "expect in after var");
+        self.advance();
+
+        let body = self.parse_expression()?;
+
+        Ok(Expression::new(
+            ExpressionKind::VarExpr {
+                vars,
+                body: Box::new(body),
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// lambda_expr : Lambda params Arrow expression
+    ///
+    /// The body goes as far as possible, as the one of a var expression
+    fn parse_lambda_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
+
+        // eat \
+        self.advance();
+
+        let (params, param_types) = self.parse_params()?;
+
+        // expect and eat ->
+        expect!(self, &Arrow, "expect -> after the params of a lambda");
+        self.advance();
+
+        let body = self.parse_expression()?;
+
+        Ok(Expression::new(
+            ExpressionKind::LambdaExpr {
+                params,
+                param_types,
+                ret_type: None,
+                captures: vec![],
+                body: Box::new(body),
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// grad_expr : Grad OpeningParenthesis Identifier ClosingParenthesis
+    fn parse_grad_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
+
+        // eat grad
+        self.advance();
+
+        // expect and eat (
+        expect!(self, &OpeningParenthesis, "expect ( after grad");
+        self.advance();
+
+        let name = extract!(self, Identifier, "expect the name of a function").clone();
+        self.advance();
+
+        // expect and eat )
+        expect!(self, &ClosingParenthesis, "expect )");
+        self.advance();
+
+        Ok(Expression::new(
+            ExpressionKind::GradExpr(name),
+            self.span_from(start),
+        ))
+    }
+
+    /// parenthesis_expr : OpeningParenthesis expression ClosingParenthesis;
+    fn parse_parenthesis_expr(&mut self) -> ParseResult<Expression> {
+        // eat )
+        self.advance();
+
+        // get inner expression
+        let expr = self.parse_delimited_expression()?;
+
+        // eat )
+        expect!(self, &ClosingParenthesis, "expect )");
+        self.advance();
+
+        Ok(expr)
+    }
+
+    /// identifier_expr : identifier
+    ///                 : identifier ( expression* )
+    ///                 : struct_expr
+    ///                 : variant_expr
+    fn parse_identifier_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
+
+        // get identifier
+        let identifier = extract!(self, Identifier, "expect identifier").clone();
+        self.advance();
+
+        if self.curr() == Some(&OpeningBrace) && !self.no_struct_expr {
+            return self.parse_struct_expr(identifier, start);
+        }
+
+        // a variant, with its fields if it has any
+        if self.variants.contains(&identifier) {
+            let args = if self.curr() == Some(&OpeningParenthesis) {
+                self.parse_args()?
+            } else {
+                vec![]
+            };
+            return Ok(Expression::new(
+                ExpressionKind::VariantExpr(identifier, args),
+                self.span_from(start),
+            ));
+        }
+
+        // lookahead for whether its a call
+        if self.curr() != Some(&OpeningParenthesis) {
+            return Ok(Expression::new(
+                ExpressionKind::VariableExpr(identifier),
+                start,
+            ));
+        }
+
+        // its a call
+        let args = self.parse_args()?;
+
+        Ok(Expression::new(
+            ExpressionKind::CallExpr(identifier, args),
+            self.span_from(start),
+        ))
+    }
+
+    /// args : OpeningParenthesis [expression Comma?]* ClosingParenthesis
+    fn parse_args(&mut self) -> ParseResult<Vec<Expression>> {
+        // eat (
+        self.advance();
+
+        let mut args = Vec::<Expression>::new();
+
+        // until a ) is reached
+        while self.curr() != Some(&ClosingParenthesis) {
+            let arg = self.parse_delimited_expression()?;
+            args.push(arg);
+
+            if self.curr() == Some(&ClosingParenthesis) {
+                break;
+            }
+
+            expect!(self, &Comma, "expect , or )");
+            self.advance();
+        }
+
+        // eat )
+        self.advance();
+
+        Ok(args)
+    }
+
+    /// match_expr : Match expression OpeningBrace [pattern Arrow expression Comma]* ClosingBrace
+    ///
+    /// The comma after the last arm is optional
+    fn parse_match_expr(&mut self) -> ParseResult<Expression> {
+        let start = self.curr_span();
+
+        // eat match
+        self.advance();
+
+        // the { after the value starts the arms
+        let no_struct_expr = std::mem::replace(&mut self.no_struct_expr, true);
+        let value = self.parse_expression();
+        self.no_struct_expr = no_struct_expr;
+        let value = value?;
+
+        // expect and eat {
+        expect!(self, &OpeningBrace, "expect { after the value to match");
+        self.advance();
+
+        if self.curr() == Some(&ClosingBrace) {
+            return Err(ParseError::new(
+                Some(ClosingBrace),
+                "expect a pattern, matches cannot be empty",
+                self.curr_span(),
+            ));
+        }
+
+        let mut arms = Vec::<(Pattern, Expression)>::new();
+        while self.curr() != Some(&ClosingBrace) {
+            let pattern = self.parse_pattern()?;
+
+            // expect and eat ->
+            expect!(self, &Arrow, "expect -> after the pattern");
+            self.advance();
+
+            arms.push((pattern, self.parse_delimited_expression()?));
+
+            if self.curr() == Some(&ClosingBrace) {
+                break;
+            }
+
+            expect!(self, &Comma, "expect , or }");
+            self.advance();
+        }
+
+        // eat }
+        self.advance();
+
+        Ok(Expression::new(
+            ExpressionKind::MatchExpr {
+                value: Box::new(value),
+                arms,
+                tree: None,
+            },
+            self.span_from(start),
+        ))
+    }
+
+    /// pattern : _
+    ///         : Identifier
+    ///         : Identifier OpeningParenthesis [pattern Comma?]* ClosingParenthesis
+    fn parse_pattern(&mut self) -> ParseResult<Pattern> {
+        let start = self.curr_span();
+
+        let kind = match get_curr!(self, "expect a pattern") {
+            BinOp('_') => {
+                self.advance();
+                PatternKind::Wildcard
+            }
+            Identifier(name) if self.variants.contains(name) => {
+                let name = name.clone();
+                self.advance();
+
+                // a pattern for each field, if the variant has any
+                let mut fields = Vec::<Pattern>::new();
+                if self.curr() == Some(&OpeningParenthesis) {
+                    self.advance();
+                    while self.curr() != Some(&ClosingParenthesis) {
+                        fields.push(self.parse_pattern()?);
+
+                        if self.curr() == Some(&ClosingParenthesis) {
+                            break;
+                        }
+
+                        expect!(self, &Comma, "expect , or )");
+                        self.advance();
+                    }
+
+                    // eat )
+                    self.advance();
+                }
+
+                PatternKind::Variant(name, fields)
+            }
+            Identifier(name) => {
+                let name = name.clone();
+                self.advance();
+
+                if self.curr() == Some(&OpeningParenthesis) {
+                    return Err(ParseError::new(
+                        Some(Identifier(name)),
+                        "expect a variant declared with type",
+                        start,
+                    ));
+                }
+                PatternKind::Binding(name)
+            }
+            token => {
+                return Err(ParseError::new(
+                    Some(token.clone()),
+                    "expect a variant, an identifier or _",
+                    self.curr_span(),
+                ))
+            }
+        };
+
+        Ok(Pattern {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    /// struct_expr : identifier OpeningBrace [identifier = expression Comma?]* ClosingBrace
+    fn parse_struct_expr(&mut self, name: String, start: Span) -> ParseResult<Expression> {
+        // eat {
+        self.advance();
+
+        let mut fields = Vec::<(String, Expression)>::new();
+        while let Identifier(field) = get_curr!(self, "expect identifier or }") {
+            let field = field.clone();
+            self.advance();
+
+            // expect and eat =
+            expect!(self, &BinOp('='), "expect = after the field");
+            self.advance();
+
+            fields.push((field, self.parse_expression()?));
+
+            if self.curr() == Some(&Comma) {
+                self.advance();
+            }
+        }
+
+        // expect and eat }
+        expect!(self, &ClosingBrace, "expect identifier or }");
+        self.advance();
+
+        Ok(Expression::new(
+            ExpressionKind::StructExpr(name, fields),
+            self.span_from(start),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::util::Position;
+    use ExpressionKind::*;
+
+    #[test]
+    fn simple() {
+        let program = "
+         def fun1(a b)
+         a+b*e-d
+         a+b
+         extern sin(x)
+         ";
+        let lexer = Lexer::new(program.chars());
+
+        let mut parser = Parser::new(lexer);
+
+        let ast = parser.parse();
+
+        println!("{:#?}", ast);
+    }
+
+    /// Reset all spans, so that only the structure of expressions is compared
+    fn strip_spans(expr: &mut Expression) {
+        expr.span = Span::default();
+        match &mut expr.kind {
+            NumberExpr(_) | IntExpr(_) | BoolExpr(_) | StringExpr(_) | VariableExpr(_) => {}
+            UnaryExpr(_, operand) | CastExpr(_, operand) => strip_spans(operand),
+            BinaryExpr(_, lhs, rhs) => {
+                strip_spans(lhs);
+                strip_spans(rhs);
+            }
+            CallExpr(_, args) | ArrayExpr(args) | VariantExpr(_, args) => {
+                args.iter_mut().for_each(strip_spans)
+            }
+            IndexExpr(array, index) => {
+                strip_spans(array);
+                strip_spans(index);
+            }
+            StructExpr(_, fields) => fields.iter_mut().for_each(|(_, value)| strip_spans(value)),
+            FieldExpr { object, .. } => strip_spans(object),
+            IfExpr(cond, then_expr, else_expr) => {
+                strip_spans(cond);
+                strip_spans(then_expr);
+                strip_spans(else_expr);
+            }
+            ForExpr {
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                strip_spans(start);
+                strip_spans(end);
+                step.iter_mut().for_each(|step| strip_spans(step));
+                strip_spans(body);
+            }
+            VarExpr { vars, body } => {
+                vars.iter_mut()
+                    .filter_map(|(_, init)| init.as_mut())
+                    .for_each(strip_spans);
+                strip_spans(body);
+            }
+            MatchExpr { value, arms, .. } => {
+                strip_spans(value);
+                for (pattern, body) in arms {
+                    strip_pattern_spans(pattern);
+                    strip_spans(body);
+                }
+            }
+            LambdaExpr { body, .. } => strip_spans(body),
+            GradExpr(_) | ClosureExpr { .. } => {}
+        }
+    }
+
+    fn strip_pattern_spans(pattern: &mut Pattern) {
+        pattern.span = Span::default();
+        if let PatternKind::Variant(_, fields) = &mut pattern.kind {
+            fields.iter_mut().for_each(strip_pattern_spans);
+        }
+    }
+
+    /// Shorthand of a pattern without span
+    fn pat(kind: PatternKind) -> Pattern {
+        Pattern {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    /// Shorthand of a boxed expression without span
+    fn b(kind: ExpressionKind) -> Box<Expression> {
+        Box::new(kind.into())
+    }
+
+    fn parse_all(program: &str) -> Vec<ASTNode> {
+        let mut parser = Parser::new(Lexer::new(program.chars()));
+
+        let mut nodes = vec![];
+        loop {
+            match parser.parse().unwrap() {
+                ASTNode::EOF => return nodes,
+                ASTNode::FunctionNode(mut func) => {
+                    strip_spans(&mut func.body);
+                    nodes.push(ASTNode::FunctionNode(func));
+                }
+                node => nodes.push(node),
+            }
+        }
+    }
+
+    fn parse_function_body(program: &str) -> Expression {
+        match parse_all(program).into_iter().next() {
+            Some(ASTNode::FunctionNode(func)) => func.body,
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    fn top_level_expressions() {
+        let nodes = parse_all("1+2*3; foo(1, x)");
+        assert_eq!(nodes.len(), 3);
+
+        match &nodes[0] {
+            ASTNode::FunctionNode(func) => {
+                assert!(func.prototype.is_anonymous());
+                assert_eq!(
+                    func.body,
+                    BinaryExpr(
+                        '+',
+                        b(IntExpr(1)),
+                        b(BinaryExpr('*', b(IntExpr(2)), b(IntExpr(3)))),
+                    )
+                    .into()
+                );
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        assert!(matches!(nodes[1], ASTNode::Delimiter));
+
+        match &nodes[2] {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(func.prototype.name, "_anonymous_2");
+                assert_eq!(
+                    func.body,
+                    CallExpr(
+                        "foo".into(),
+                        vec![IntExpr(1).into(), VariableExpr("x".into()).into()]
+                    )
+                    .into()
+                );
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    fn if_expr() {
+        assert_eq!(
+            parse_function_body("def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2)"),
+            IfExpr(
+                b(BinaryExpr('<', b(VariableExpr("x".into())), b(IntExpr(3)))),
+                b(IntExpr(1)),
+                b(BinaryExpr(
+                    '+',
+                    b(CallExpr(
+                        "fib".into(),
+                        vec![BinaryExpr('-', b(VariableExpr("x".into())), b(IntExpr(1))).into()]
+                    )),
+                    b(CallExpr(
+                        "fib".into(),
+                        vec![BinaryExpr('-', b(VariableExpr("x".into())), b(IntExpr(2))).into()]
+                    )),
+                ))
+            )
+            .into()
+        );
+    }
+
+    #[test]
+    fn for_expr() {
+        assert_eq!(
+            parse_function_body("def loop(n) for i = 1, i < n, 2 in i"),
+            ForExpr {
+                var: "i".into(),
+                start: b(IntExpr(1)),
+                end: b(BinaryExpr(
+                    '<',
+                    b(VariableExpr("i".into())),
+                    b(VariableExpr("n".into()))
+                )),
+                step: Some(b(IntExpr(2))),
+                body: b(VariableExpr("i".into())),
+            }
+            .into()
+        );
+        assert_eq!(
+            parse_function_body("def loop(n) for i = 1, n in 0"),
+            ForExpr {
+                var: "i".into(),
+                start: b(IntExpr(1)),
+                end: b(VariableExpr("n".into())),
+                step: None,
+                body: b(IntExpr(0)),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn user_defined_operators() {
+        let program = "
+        def binary| 5 (a b) a;
+        def unary!(v) v;
+        1 | 2 * !3;
+        def binary& (a b) a;
+        ";
+        let mut parser = Parser::new(Lexer::new(program.chars()));
+
+        match parser.parse().unwrap() {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(func.prototype.name, "binary|");
+                assert_eq!(func.prototype.args, vec!["a", "b"]);
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        assert!(matches!(parser.parse().unwrap(), ASTNode::Delimiter));
+        match parser.parse().unwrap() {
+            ASTNode::FunctionNode(func) => assert_eq!(func.prototype.name, "unary!"),
+            node => panic!("unexpected node {:?}", node),
+        }
+        assert!(matches!(parser.parse().unwrap(), ASTNode::Delimiter));
+        match parser.parse().unwrap() {
+            ASTNode::FunctionNode(mut func) => {
+                strip_spans(&mut func.body);
+                assert_eq!(
+                    func.body,
+                    BinaryExpr(
+                        '|',
+                        b(IntExpr(1)),
+                        b(BinaryExpr(
+                            '*',
+                            b(IntExpr(2)),
+                            b(UnaryExpr('!', b(IntExpr(3))))
+                        ))
+                    )
+                    .into()
+                )
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        assert!(matches!(parser.parse().unwrap(), ASTNode::Delimiter));
+        parser.parse().unwrap();
+        assert_eq!(parser.get_binop_precedence('&').unwrap(), 30);
+    }
+
+    #[test]
+    fn type_annotations() {
+        let nodes = parse_all(
+            "def f(n: int, x: double b): bool n; extern g(x, y); def binary : 1 (x y) y;",
+        );
+
+        match &nodes[0] {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(func.prototype.args, vec!["n", "x", "b"]);
+                assert_eq!(
+                    func.prototype.arg_types,
+                    vec![Some(Type::Int), Some(Type::Double), None]
+                );
+                assert_eq!(func.prototype.ret_type, Some(Type::Bool));
+                assert_eq!(func.prototype.to_string(), "f(n: int x: double b): bool");
+                assert_eq!(func.prototype.arg_type(2), Type::Double);
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        match &nodes[2] {
+            ASTNode::ExternNode(proto) => {
+                assert_eq!(
+                    proto,
+                    &Prototype::new("g", vec!["x".into(), "y".into()], proto.span)
+                );
+                assert_eq!(proto.return_type(), Type::Double);
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        match &nodes[4] {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(func.prototype.name, "binary:");
+                assert_eq!(func.prototype.ret_type, None);
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    #[test]
+    fn literals_and_casts() {
+        assert_eq!(
+            parse_function_body("def f(x) int(x + 1.5) < 2 + double(true)"),
+            BinaryExpr(
+                '<',
+                b(CastExpr(
+                    Type::Int,
+                    b(BinaryExpr(
+                        '+',
+                        b(VariableExpr("x".into())),
+                        b(NumberExpr(1.5))
+                    ))
+                )),
+                b(BinaryExpr(
+                    '+',
+                    b(IntExpr(2)),
+                    b(CastExpr(Type::Double, b(BoolExpr(true))))
+                )),
+            )
+            .into()
+        );
+        assert_eq!(
+            parse_function_body(r#"def f(s: string) concat(s, "!\n")"#),
+            CallExpr(
+                "concat".into(),
+                vec![
+                    VariableExpr("s".into()).into(),
+                    StringExpr("!\n".into()).into()
+                ]
+            )
+            .into()
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let a = || b(VariableExpr("a".into()));
+        assert_eq!(
+            parse_function_body("def f(a: [[int]]) a[0][1] = -a[1]"),
+            BinaryExpr(
+                '=',
+                b(IndexExpr(b(IndexExpr(a(), b(IntExpr(0)))), b(IntExpr(1)))),
+                b(UnaryExpr('-', b(IndexExpr(a(), b(IntExpr(1))))))
+            )
+            .into()
+        );
+        assert_eq!(
+            parse_function_body("def f(x) [x, [1, 2][x],]"),
+            ArrayExpr(vec![
+                VariableExpr("x".into()).into(),
+                IndexExpr(
+                    b(ArrayExpr(vec![IntExpr(1).into(), IntExpr(2).into()])),
+                    b(VariableExpr("x".into()))
+                )
+                .into()
+            ])
+            .into()
+        );
+
+        match &parse_all("def f(a: [[int]]): [double] a")[0] {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(func.prototype.to_string(), "f(a: [[int]]): [double]");
+                assert_eq!(
+                    func.prototype.arg_types,
+                    vec![Some(Type::array(Type::array(Type::Int)))]
+                );
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        for program in &[
+            "def f() []",
+            "def f(a) a[1",
+            "def f(a: [int) a",
+            "def f(a: []) a",
+        ] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn function_types() {
+        let unary = Type::function(vec![Type::Double], Type::Double);
+        match &parse_all("def f(g: (double) -> double, h: (int bool) -> () -> int) g")[0] {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(
+                    func.prototype.arg_types,
+                    vec![
+                        Some(unary),
+                        Some(Type::function(
+                            vec![Type::Int, Type::Bool],
+                            Type::function(vec![], Type::Int)
+                        ))
+                    ]
+                );
+                assert_eq!(
+                    func.prototype.to_string(),
+                    "f(g: (double) -> double h: (int, bool) -> () -> int)"
+                );
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        for program in &[
+            "def f(g: (double) double) 1",
+            "def f(g: (double -> double) 1",
+        ] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn structs() {
+        assert_eq!(
+            parse_all("struct Point { x: int, y } struct Empty {}")
+                .into_iter()
+                .map(|node| match node {
+                    ASTNode::StructNode(def) => (def.name, def.fields, def.field_types),
+                    node => panic!("unexpected node {:?}", node),
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "Point".into(),
+                    vec!["x".into(), "y".into()],
+                    vec![Type::Int, Type::Double]
+                ),
+                ("Empty".into(), vec![], vec![])
+            ]
+        );
+
+        let p = || b(VariableExpr("p".into()));
+        assert_eq!(
+            parse_function_body("def f(p: Point) Point { x = p.x + 1, y = p.y }"),
+            StructExpr(
+                "Point".into(),
+                vec![
+                    (
+                        "x".into(),
+                        BinaryExpr(
+                            '+',
+                            b(FieldExpr {
+                                object: p(),
+                                field: "x".into(),
+                                index: None
+                            }),
+                            b(IntExpr(1))
+                        )
+                        .into()
+                    ),
+                    (
+                        "y".into(),
+                        FieldExpr {
+                            object: p(),
+                            field: "y".into(),
+                            index: None
+                        }
+                        .into()
+                    )
+                ]
+            )
+            .into()
+        );
+        assert_eq!(
+            parse_function_body("def f(a) a[0].x"),
+            FieldExpr {
+                object: b(IndexExpr(b(VariableExpr("a".into())), b(IntExpr(0)))),
+                field: "x".into(),
+                index: None
+            }
+            .into()
+        );
+
+        match &parse_all("def f(p: Point): [Point] [p]")[0] {
+            ASTNode::FunctionNode(func) => {
+                assert_eq!(func.prototype.to_string(), "f(p: Point): [Point]")
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        for program in &[
+            "struct Point { x, x }",
+            "struct Point { x: 1 }",
+            "struct { x }",
+            "struct Point x",
+            "def f(p) p.",
+            "def f() Point { x 1 }",
+            "def f() Point { x = 1",
+        ] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn unions() {
+        match &parse_all("type Shape = Circle(r) | Rect(w: int, h: int) | Empty")[0] {
+            ASTNode::UnionNode(def) => {
+                assert_eq!(def.name, "Shape");
+                assert_eq!(
+                    def.variants
+                        .iter()
+                        .map(|variant| (
+                            variant.name.as_str(),
+                            variant.fields.clone(),
+                            variant.field_types.clone()
+                        ))
+                        .collect::<Vec<_>>(),
+                    vec![
+                        ("Circle", vec!["r".into()], vec![Type::Double]),
+                        (
+                            "Rect",
+                            vec!["w".into(), "h".into()],
+                            vec![Type::Int, Type::Int]
+                        ),
+                        ("Empty", vec![], vec![])
+                    ]
+                );
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        let nodes = parse_all(
+            "type Shape = Circle(r) | Empty
+            def f(s) match s { Circle(r) -> r, Empty -> 0, }
+            def g() Circle(1 + 2)",
+        );
+        let body = |node: &ASTNode| match node {
+            ASTNode::FunctionNode(func) => func.body.clone(),
+            node => panic!("unexpected node {:?}", node),
+        };
+        assert_eq!(
+            body(&nodes[1]),
+            MatchExpr {
+                value: b(VariableExpr("s".into())),
+                arms: vec![
+                    (
+                        pat(PatternKind::Variant(
+                            "Circle".into(),
+                            vec![pat(PatternKind::Binding("r".into()))]
+                        )),
+                        VariableExpr("r".into()).into()
+                    ),
+                    (
+                        pat(PatternKind::Variant("Empty".into(), vec![])),
+                        IntExpr(0).into()
+                    ),
+                ],
+                tree: None
+            }
+            .into()
+        );
+        assert_eq!(
+            body(&nodes[2]),
+            VariantExpr(
+                "Circle".into(),
+                vec![BinaryExpr('+', b(IntExpr(1)), b(IntExpr(2))).into()]
+            )
+            .into()
+        );
+
+        // the { after the value of a match starts the arms, not a struct
+        assert_eq!(
+            parse_function_body("def f(p) match p { _ -> (P { x = 1 }).x }"),
+            MatchExpr {
+                value: b(VariableExpr("p".into())),
+                arms: vec![(
+                    pat(PatternKind::Wildcard),
+                    FieldExpr {
+                        object: b(StructExpr(
+                            "P".into(),
+                            vec![("x".into(), IntExpr(1).into())]
+                        )),
+                        field: "x".into(),
+                        index: None
+                    }
+                    .into()
+                )],
+                tree: None
+            }
+            .into()
+        );
+
+        for program in &[
+            "type Shape = Circle | Circle",
+            "type Shape = ",
+            "type = Circle",
+            "type Shape Circle",
+            "def f(s) match s {}",
+            "def f(s) match s { x 1 }",
+            "def f(s) match s { x -> 1 x -> 2 }",
+            "def f(s) match s { Circle(r) -> r }",
+            "def f(s) match s { 1 -> 1 }",
+        ] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn invalid_operator_prototypes() {
+        for program in &[
+            "def binary| (a) a",
+            "def unary! (a b) a",
+            "def binary| 200 (a b) a",
+            "def binary| 2.5 (a b) a",
+            "def f(x: 1) x",
+            "def f(x): x",
+            "def f(x) int x",
+            "def binary+ 5 (a b) a",
+            "def binary= (a b) a",
+            "extern binary< (a b)",
+        ] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+
+        // a rejected definition does not install its operator
+        let mut parser = Parser::new(Lexer::new(
+            "def binary| 5 (a b c) a; def binary& 5 (a b) ); 1 | 2; 1 & 2;".chars(),
+        ));
+        assert_eq!(parser.parse_program().errors.len(), 4);
+        assert!(parser.get_binop_precedence('|').is_err());
+        assert!(parser.get_binop_precedence('&').is_err());
+
+        // nor does a rejected redefinition of a builtin operator change its precedence
+        let mut parser = Parser::new(Lexer::new("def binary+ 5 (a b) a; 1 + 2 * 3;".chars()));
+        assert_eq!(parser.parse_program().errors.len(), 1);
+        assert_eq!(parser.get_binop_precedence('+').unwrap(), 20);
+    }
+
+    #[test]
+    fn var_expr() {
+        assert_eq!(
+            parse_function_body("def f(a) var x = 1, y in y = x + a"),
+            VarExpr {
+                vars: vec![("x".into(), Some(IntExpr(1).into())), ("y".into(), None)],
+                body: b(BinaryExpr(
+                    '=',
+                    b(VariableExpr("y".into())),
+                    b(BinaryExpr(
+                        '+',
+                        b(VariableExpr("x".into())),
+                        b(VariableExpr("a".into()))
+                    ))
+                )),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn lambda_expr() {
+        let lambda = |params: &[&str], param_types, body| LambdaExpr {
+            params: params.iter().map(|&param| param.into()).collect(),
+            param_types,
+            ret_type: None,
+            captures: vec![],
+            body,
+        };
+        // the body goes as far as possible
+        assert_eq!(
+            parse_function_body("def f(k) apply(\\x, n: int -> x + k, \\ -> \\y -> y)"),
+            CallExpr(
+                "apply".into(),
+                vec![
+                    lambda(
+                        &["x", "n"],
+                        vec![None, Some(Type::Int)],
+                        b(BinaryExpr(
+                            '+',
+                            b(VariableExpr("x".into())),
+                            b(VariableExpr("k".into()))
+                        ))
+                    )
+                    .into(),
+                    lambda(
+                        &[],
+                        vec![],
+                        b(lambda(&["y"], vec![None], b(VariableExpr("y".into()))))
+                    )
+                    .into()
+                ]
+            )
+            .into()
+        );
+
+        let mut parser = Parser::new(Lexer::new("def f() \\x x".chars()));
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn grad_expr() {
+        assert_eq!(
+            parse_function_body("def f(x) apply(grad(sin), x)"),
+            CallExpr(
+                "apply".into(),
+                vec![
+                    GradExpr("sin".into()).into(),
+                    VariableExpr("x".into()).into()
+                ]
+            )
+            .into()
+        );
+
+        for program in &["def f() grad(1)", "def f() grad sin", "def f() grad(sin"] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn calls_of_expressions() {
+        for (program, expected) in &[
+            ("grad(sq)(3)", span(8, 9)),
+            ("f(1)(2)", span(4, 5)),
+            ("def f(g) (g)(1)", span(12, 13)),
+            ("(\\x -> x) (1)", span(10, 11)),
+            ("a[0](1)", span(4, 5)),
+        ] {
+            let err = Parser::new(Lexer::new(program.chars()))
+                .parse()
+                .unwrap_err();
+            assert_eq!(err.span(), *expected, "{}", program);
+        }
+
+        assert_eq!(
+            parse_function_body("def f() var g = grad(sq) in g(3)"),
+            VarExpr {
+                vars: vec![("g".into(), Some(GradExpr("sq".into()).into()))],
+                body: b(CallExpr("g".into(), vec![IntExpr(3).into()])),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn derivative_def() {
+        let nodes = parse_all("def' f f'(1)");
+        assert_eq!(nodes.len(), 2);
+        match &nodes[0] {
+            ASTNode::DerivativeNode(def) => assert_eq!(
+                *def,
+                DerivativeDef {
+                    name: "f".into(),
+                    span: span(0, 6),
+                }
+            ),
+            node => panic!("unexpected node {:?}", node),
+        }
+        match &nodes[1] {
+            ASTNode::FunctionNode(func) => assert_eq!(
+                func.body,
+                CallExpr("f'".into(), vec![IntExpr(1).into()]).into()
+            ),
+            node => panic!("unexpected node {:?}", node),
+        }
+
+        for program in &["def' 1", "def' +", "def'"] {
+            let mut parser = Parser::new(Lexer::new(program.chars()));
+            assert!(parser.parse().is_err(), "{}", program);
+        }
+    }
+
+    fn span(start: usize, end: usize) -> Span {
+        // all test programs are on a single line
+        let pos = |offset| Position {
+            offset,
+            line: 1,
+            column: offset + 1,
+        };
+        Span::new(pos(start), pos(end))
+    }
+
+    #[test]
+    fn spans() {
+        let program = "def f(a) if a then g(a, 1) else -a";
+        let func = match Parser::new(Lexer::new(program.chars())).parse().unwrap() {
+            ASTNode::FunctionNode(func) => func,
+            node => panic!("unexpected node {:?}", node),
+        };
+
+        assert_eq!(func.span, span(0, 34));
+        assert_eq!(func.prototype.span, span(4, 8));
+        assert_eq!(func.body.span, span(9, 34));
+
+        match func.body.kind {
+            IfExpr(cond, then_expr, else_expr) => {
+                assert_eq!(cond.span, span(12, 13));
+                assert_eq!(then_expr.span, span(19, 26));
+                assert_eq!(else_expr.span, span(32, 34));
+            }
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn error_spans() {
+        for (program, expected) in &[
+            ("def f(a) a +", span(12, 12)),
+            ("def f(a) if a then 1 2", span(21, 22)),
+            ("def (a) a", span(4, 5)),
+        ] {
+            let err = Parser::new(Lexer::new(program.chars()))
+                .parse()
+                .unwrap_err();
+            assert_eq!(err.span(), *expected, "{}", program);
+        }
+    }
+
+    #[test]
+    fn error_recovery() {
+        let program = "
+        def f(x) x + ;
+        def g(y) y * 2;
+        extern sin(a;
+        1.2.3 + g(1);
+        def h() ∂ 1
+        h() + 1;
+        ";
+        let program = Parser::new(Lexer::new(program.chars())).parse_program();
+
+        let names: Vec<&str> = program
+            .nodes
+            .iter()
+            .map(|node| match node {
+                ASTNode::FunctionNode(func) => func.prototype.name.as_str(),
+                ASTNode::ExternNode(proto) => proto.name.as_str(),
+                node => panic!("unexpected node {:?}", node),
+            })
+            .collect();
+        assert_eq!(names, vec!["g", "_anonymous_2"]);
+
+        let errors: Vec<(usize, bool)> = program
+            .errors
+            .iter()
+            .map(|err| (err.span().start.line, matches!(err, ParseError::Lexer(_))))
+            .collect();
+        assert_eq!(errors, vec![(2, false), (4, false), (5, true), (6, true)]);
+    }
+
+    #[test]
+    fn error_recovery_stops_at_delimiter() {
+        // reading past the line, as from a terminal waiting for more input, would panic
+        let input = "1 + ;\n"
+            .chars()
+            .chain(std::iter::from_fn(|| panic!("read past the line")));
+        let mut parser = Parser::new(Lexer::new(input));
+
+        assert!(parser.parse().is_err());
+        assert_eq!(parser.curr(), Some(&Delimiter));
     }
 }
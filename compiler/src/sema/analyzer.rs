@@ -0,0 +1,485 @@
+use super::SemaError;
+use crate::parser::nodes::{
+    ASTNode, DerivativeDef, Expression, ExpressionKind, Function, Pattern, PatternKind, Prototype,
+};
+use crate::util::Span;
+use std::collections::HashMap;
+
+/// Binary operators every backend knows without a user defined function
+const BUILTIN_BINARY_OPS: &[char] = &['+', '-', '*', '/', '<', '>'];
+
+/// Resolves every variable and call of a function before it is compiled,
+/// so that all the problems are reported at once instead of failing halfway through codegen
+#[derive(Default)]
+pub struct Analyzer {
+    /// The latest prototype of every function that can be called
+    prototypes: HashMap<String, Prototype>,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Analyzer::default()
+    }
+
+    /// Make a function callable from the functions checked afterwards
+    pub fn declare(&mut self, proto: &Prototype) {
+        self.prototypes.insert(proto.name.clone(), proto.clone());
+    }
+
+    /// Make `f'` defined by `def' f` callable, taking the same args as `f`
+    pub fn declare_derivative(&mut self, def: &DerivativeDef) {
+        if let Some(proto) = self.prototypes.get(&def.name) {
+            let proto = Prototype {
+                name: def.derivative_name(),
+                span: def.span,
+                ..proto.clone()
+            };
+            self.declare(&proto);
+        }
+    }
+
+    /// Check a function, which can call itself and every declared function
+    pub fn check_function(&self, func: &Function) -> Vec<SemaError> {
+        let mut checker = FunctionChecker {
+            analyzer: self,
+            function: &func.prototype,
+            scope: func.prototype.args.iter().map(String::as_str).collect(),
+            errors: vec![],
+        };
+        checker.check_expr(&func.body);
+        checker.errors
+    }
+
+    /// Check every function of a program.
+    /// All the prototypes are declared first, so that a function can call the ones defined after it.
+    pub fn check_program(&mut self, nodes: &[ASTNode]) -> Vec<SemaError> {
+        for node in nodes {
+            match node {
+                ASTNode::ExternNode(proto) => self.declare(proto),
+                ASTNode::FunctionNode(func) if !func.prototype.is_anonymous() => {
+                    self.declare(&func.prototype)
+                }
+                ASTNode::DerivativeNode(def) => self.declare_derivative(def),
+                _ => {}
+            }
+        }
+
+        nodes
+            .iter()
+            .flat_map(|node| match node {
+                ASTNode::FunctionNode(func) => self.check_function(func),
+                _ => vec![],
+            })
+            .collect()
+    }
+}
+
+/// Walks the body of a function, keeping track of the variables in scope
+struct FunctionChecker<'a> {
+    analyzer: &'a Analyzer,
+    function: &'a Prototype,
+    /// Variables in scope, the innermost last
+    scope: Vec<&'a str>,
+    errors: Vec<SemaError>,
+}
+
+impl<'a> FunctionChecker<'a> {
+    fn lookup(&self, name: &str) -> Option<&'a Prototype> {
+        if name == self.function.name {
+            Some(self.function)
+        } else {
+            self.analyzer.prototypes.get(name)
+        }
+    }
+
+    fn check_variable(&mut self, var: &str, span: Span) {
+        if !self.scope.contains(&var) {
+            self.errors.push(SemaError::UnknownVariable {
+                name: var.into(),
+                span,
+            });
+        }
+    }
+
+    /// A variable, or a function used as a value
+    fn check_value(&mut self, var: &str, span: Span) {
+        if !self.scope.contains(&var) && self.lookup(var).is_none() {
+            self.errors.push(SemaError::UnknownVariable {
+                name: var.into(),
+                span,
+            });
+        }
+    }
+
+    fn check_call(&mut self, name: &str, found: usize, span: Span) {
+        match self.lookup(name) {
+            Some(proto) if proto.args.len() != found => {
+                self.errors.push(SemaError::ArityMismatch {
+                    name: name.into(),
+                    expected: proto.args.len(),
+                    found,
+                    span,
+                    declared: proto.span,
+                })
+            }
+            Some(_) => {}
+            None => self.errors.push(SemaError::UnknownFunction {
+                name: name.into(),
+                span,
+            }),
+        }
+    }
+
+    /// User defined operators are functions named e.g. `binary|`, whose arity is checked by the parser
+    fn check_operator(&mut self, op: char, unary: bool, span: Span) {
+        let name = format!("{}{}", if unary { "unary" } else { "binary" }, op);
+        if self.lookup(&name).is_none() {
+            self.errors
+                .push(SemaError::UnknownOperator { op, unary, span });
+        }
+    }
+
+    /// Bring the variables of a pattern into scope
+    fn bind_pattern(&mut self, pattern: &'a Pattern) {
+        match &pattern.kind {
+            PatternKind::Wildcard => {}
+            PatternKind::Binding(name) => self.scope.push(name),
+            PatternKind::Variant(_, fields) => {
+                for field in fields {
+                    self.bind_pattern(field);
+                }
+            }
+        }
+    }
+
+    /// Check an expression, reporting problems in the order they appear in the source
+    fn check_expr(&mut self, expr: &'a Expression) {
+        match &expr.kind {
+            ExpressionKind::NumberExpr(_)
+            | ExpressionKind::IntExpr(_)
+            | ExpressionKind::BoolExpr(_)
+            | ExpressionKind::StringExpr(_) => {}
+            ExpressionKind::VariableExpr(var) => self.check_value(var, expr.span),
+            ExpressionKind::BinaryExpr('=', left, right) => {
+                match &left.kind {
+                    ExpressionKind::VariableExpr(var) => self.check_variable(var, left.span),
+                    ExpressionKind::IndexExpr(..) => self.check_expr(left),
+                    _ => self
+                        .errors
+                        .push(SemaError::InvalidAssignment { span: left.span }),
+                }
+                self.check_expr(right);
+            }
+            ExpressionKind::BinaryExpr(op, left, right) => {
+                if !BUILTIN_BINARY_OPS.contains(op) {
+                    self.check_operator(*op, false, expr.span);
+                }
+                self.check_expr(left);
+                self.check_expr(right);
+            }
+            ExpressionKind::UnaryExpr(op, operand) => {
+                self.check_operator(*op, true, expr.span);
+                self.check_expr(operand);
+            }
+            ExpressionKind::CastExpr(_, operand) => self.check_expr(operand),
+            ExpressionKind::ArrayExpr(elements) => {
+                for element in elements {
+                    self.check_expr(element);
+                }
+            }
+            ExpressionKind::IndexExpr(array, index) => {
+                self.check_expr(array);
+                self.check_expr(index);
+            }
+            // struct and field names are checked along with the types
+            ExpressionKind::StructExpr(_, fields) => {
+                for (_, value) in fields {
+                    self.check_expr(value);
+                }
+            }
+            ExpressionKind::FieldExpr { object, .. } => self.check_expr(object),
+            // variants and their fields are checked along with the types
+            ExpressionKind::VariantExpr(_, args) => {
+                for arg in args {
+                    self.check_expr(arg);
+                }
+            }
+            ExpressionKind::MatchExpr { value, arms, .. } => {
+                self.check_expr(value);
+                for (pattern, body) in arms {
+                    let depth = self.scope.len();
+                    self.bind_pattern(pattern);
+                    self.check_expr(body);
+                    self.scope.truncate(depth);
+                }
+            }
+            ExpressionKind::CallExpr(name, args) => {
+                // the arity of a call to a variable is checked along with the types
+                if !self.scope.contains(&name.as_str()) {
+                    self.check_call(name, args.len(), expr.span);
+                }
+                for arg in args {
+                    self.check_expr(arg);
+                }
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                self.check_expr(cond);
+                self.check_expr(then_expr);
+                self.check_expr(else_expr);
+            }
+            ExpressionKind::ForExpr {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                // the start value is evaluated without the variable in scope
+                self.check_expr(start);
+                self.scope.push(var);
+                self.check_expr(end);
+                if let Some(step) = step {
+                    self.check_expr(step);
+                }
+                self.check_expr(body);
+                self.scope.pop();
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                let depth = self.scope.len();
+                // an initializer is checked before its variable is in scope,
+                // but after the previous variables
+                for (var, init) in vars {
+                    if let Some(init) = init {
+                        self.check_expr(init);
+                    }
+                    self.scope.push(var);
+                }
+                self.check_expr(body);
+                self.scope.truncate(depth);
+            }
+            ExpressionKind::LambdaExpr { params, body, .. } => {
+                let depth = self.scope.len();
+                self.scope.extend(params.iter().map(String::as_str));
+                self.check_expr(body);
+                self.scope.truncate(depth);
+            }
+            ExpressionKind::ClosureExpr { captures, .. } => {
+                for var in captures {
+                    self.check_variable(var, expr.span);
+                }
+            }
+            ExpressionKind::GradExpr(name) => {
+                if self.lookup(name).is_none() {
+                    self.errors.push(SemaError::UnknownFunction {
+                        name: name.clone(),
+                        span: expr.span,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Diagnostic;
+    use crate::lexer::Lexer;
+    use crate::parser::parser::Parser;
+    use crate::util::Position;
+
+    fn check(program: &str) -> Vec<SemaError> {
+        let program = Parser::new(Lexer::new(program.chars())).parse_program();
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        Analyzer::new().check_program(&program.nodes)
+    }
+
+    /// The span from `start` to `end` on the first line, both 1-based columns
+    fn columns(start: usize, end: usize) -> Span {
+        let mut start_pos = Position::default();
+        for _ in 1..start {
+            start_pos = start_pos.after(' ');
+        }
+        let mut end_pos = start_pos;
+        for _ in start..end {
+            end_pos = end_pos.after(' ');
+        }
+        Span::new(start_pos, end_pos)
+    }
+
+    #[test]
+    fn valid_programs() {
+        let programs = [
+            "def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2); fib(10);",
+            "extern sin(x); def f(x) sin(x) * 2; f(1);",
+            "def count(n) for i = 0, i < n, i in i; count(3);",
+            "def f(x) var a = x, b = a in var x = x + b in x = a;",
+            "def unary!(v) if v then 0 else 1; def binary| 5 (l r) l; !1 | 0;",
+            "def f(a: [int]) var b = [a[0], 2] in b[1] = a[b[0]]; f([1]);",
+            "struct P { x } def f(p: P) P { x = p.x }; f(P { x = 1 }).x;",
+            "type T = A(x) | B def f(t) match t { A(x) -> x, _ -> 0 }; f(A(1));",
+            "extern sin(x); def apply(g x) g(x); var g = sin in apply(g, 1) + g(2);",
+            "def twice(g) \\x -> g(g(x)); var k = 1 in var f = twice(\\x -> x + k) in f(2);",
+            "extern sin(x); def f(x y) sin(x) * y; var d = grad(f) in d(1, 2)[0] + f(1, 2);",
+            "def f(x y) x * y; def' f; f'(1, 2)[0];",
+        ];
+        for program in &programs {
+            assert_eq!(check(program), vec![], "{}", program);
+        }
+    }
+
+    #[test]
+    fn forward_calls() {
+        assert_eq!(
+            check(
+                "def even(n) if n < 1 then 1 else odd(n - 1); \
+                 def odd(n) if n < 1 then 0 else even(n - 1); even(4);"
+            ),
+            vec![]
+        );
+        assert_eq!(check("f(1); extern f(x);"), vec![]);
+
+        // a call of a function defined later is checked against its prototype
+        assert_eq!(
+            check("def even(n) odd(n, 1); def odd(n) n;"),
+            vec![SemaError::ArityMismatch {
+                name: "odd".into(),
+                expected: 1,
+                found: 2,
+                span: columns(13, 22),
+                declared: columns(28, 34)
+            }]
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        let names = |program| -> Vec<String> {
+            check(program)
+                .into_iter()
+                .map(|err| match err {
+                    SemaError::UnknownVariable { name, .. } => name,
+                    err => panic!("unexpected error {:?}", err),
+                })
+                .collect()
+        };
+
+        // the start value does not see the loop variable, which goes out of scope after the loop
+        assert_eq!(
+            names("def f(n) (for i = i, i < n in i) + i"),
+            vec!["i", "i"]
+        );
+        // an initializer does not see its own variable, but sees the previous ones
+        assert_eq!(names("def f() var a = a, b = a in b"), vec!["a"]);
+        assert_eq!(names("def f() (var a in a) + a"), vec!["a"]);
+        // the params of a lambda are only in scope in its body
+        assert_eq!(names("def f(k) (\\x -> x + k + y) + x"), vec!["y", "x"]);
+        assert_eq!(names("def f() [a, 1][i] = b"), vec!["a", "i", "b"]);
+        assert_eq!(names("def f() P { x = a.x, y = b }.y"), vec!["a", "b"]);
+        // the variables of a pattern are only in scope in their arm
+        assert_eq!(
+            names("type T = A(x) def f(t) match t { A(x) -> x + y, _ -> x }"),
+            vec!["y", "x"]
+        );
+        // a variable can be called, and a function used as a value, but not assigned
+        assert_eq!(names("def f(g) g(1) + h + f"), vec!["h"]);
+        assert_eq!(names("def f(g) f = g"), vec!["f"]);
+    }
+
+    #[test]
+    fn all_errors_with_spans() {
+        assert_eq!(
+            check("def f(x) y + g(x) + f(x, x) + !x + (1 = x)"),
+            vec![
+                SemaError::UnknownVariable {
+                    name: "y".into(),
+                    span: columns(10, 11)
+                },
+                SemaError::UnknownFunction {
+                    name: "g".into(),
+                    span: columns(14, 18)
+                },
+                SemaError::ArityMismatch {
+                    name: "f".into(),
+                    expected: 1,
+                    found: 2,
+                    span: columns(21, 28),
+                    declared: columns(5, 9)
+                },
+                SemaError::UnknownOperator {
+                    op: '!',
+                    unary: true,
+                    span: columns(31, 33)
+                },
+                SemaError::InvalidAssignment {
+                    span: columns(37, 38)
+                },
+            ]
+        );
+        assert_eq!(
+            check("def f(x) grad(g)"),
+            vec![SemaError::UnknownFunction {
+                name: "g".into(),
+                span: columns(10, 17)
+            }]
+        );
+        assert_eq!(
+            check("def f(x) x; def' f; f'(1, 2)"),
+            vec![SemaError::ArityMismatch {
+                name: "f'".into(),
+                expected: 1,
+                found: 2,
+                span: columns(21, 29),
+                declared: columns(13, 19)
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_binary_operators() {
+        // the operator has to be defined to be parsed, so only the second function is checked
+        let nodes = Parser::new(Lexer::new("def binary~ 5 (a b) a; def f(x) x ~ 1".chars()))
+            .parse_program()
+            .nodes;
+
+        assert_eq!(
+            Analyzer::new().check_program(&nodes[1..]),
+            vec![SemaError::UnknownOperator {
+                op: '~',
+                unary: false,
+                span: columns(33, 38)
+            }]
+        );
+        assert_eq!(Analyzer::new().check_program(&nodes), vec![]);
+    }
+
+    #[test]
+    fn errors_across_functions() {
+        let errors = check("def f(x) g(x); def g(x y) x; f(1, 2);");
+        assert_eq!(errors.len(), 2);
+
+        let diagnostic = Diagnostic::from(errors[1].clone());
+        assert_eq!(
+            diagnostic.message,
+            "Unmatched arg number. Function f expects 1 but the input has 2."
+        );
+        assert_eq!(diagnostic.span, columns(30, 37));
+        assert_eq!(diagnostic.labels[0].span, columns(5, 9));
+        assert_eq!(diagnostic.labels[0].message, "declared here");
+    }
+
+    #[test]
+    fn incremental_declarations() {
+        let mut analyzer = Analyzer::new();
+        let parse = |program: &str| match Parser::new(Lexer::new(program.chars())).parse() {
+            Ok(ASTNode::FunctionNode(func)) => func,
+            node => panic!("unexpected node {:?}", node),
+        };
+
+        let g = parse("def g(x) f(x)");
+        assert_eq!(analyzer.check_function(&g).len(), 1);
+
+        analyzer.declare(&parse("def f(x) x").prototype);
+        assert_eq!(analyzer.check_function(&g), vec![]);
+    }
+}
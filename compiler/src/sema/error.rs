@@ -0,0 +1,97 @@
+use crate::diagnostics::Diagnostic;
+use crate::util::Span;
+use std::fmt;
+
+/// A name or a call that does not make sense, found before generating any code
+#[derive(PartialEq, Clone, Debug)]
+pub enum SemaError {
+    UnknownVariable {
+        name: String,
+        span: Span,
+    },
+    UnknownFunction {
+        name: String,
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+        /// The prototype of the callee
+        declared: Span,
+    },
+    UnknownOperator {
+        op: char,
+        unary: bool,
+        span: Span,
+    },
+    /// The destination of `=` is neither a variable nor an array element
+    InvalidAssignment {
+        span: Span,
+    },
+}
+
+impl SemaError {
+    pub fn span(&self) -> Span {
+        match self {
+            SemaError::UnknownVariable { span, .. }
+            | SemaError::UnknownFunction { span, .. }
+            | SemaError::ArityMismatch { span, .. }
+            | SemaError::UnknownOperator { span, .. }
+            | SemaError::InvalidAssignment { span } => *span,
+        }
+    }
+}
+
+impl fmt::Display for SemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SemaError::UnknownVariable { name, .. } => {
+                write!(f, "Unknown variable name: {}", name)
+            }
+            SemaError::UnknownFunction { name, .. } => write!(f, "Unknown function: {}", name),
+            SemaError::ArityMismatch {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "Unmatched arg number. Function {} expects {} but the input has {}.",
+                name, expected, found
+            ),
+            SemaError::UnknownOperator { op, unary, .. } => write!(
+                f,
+                "Unknown {} op {}",
+                if *unary { "unary" } else { "binary" },
+                op
+            ),
+            SemaError::InvalidAssignment { .. } => {
+                write!(
+                    f,
+                    "Destination of '=' must be a variable or an array element."
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SemaError {}
+
+impl From<SemaError> for Diagnostic {
+    fn from(err: SemaError) -> Self {
+        let diagnostic = Diagnostic::error(err.to_string(), err.span());
+        match err {
+            SemaError::ArityMismatch { declared, .. } => {
+                diagnostic.with_label(declared, "declared here")
+            }
+            SemaError::UnknownOperator { op, unary, .. } => diagnostic.with_note(format!(
+                "define it with `def {}{}`",
+                if unary { "unary" } else { "binary" },
+                op
+            )),
+            _ => diagnostic,
+        }
+    }
+}
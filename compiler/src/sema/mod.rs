@@ -0,0 +1,5 @@
+mod analyzer;
+mod error;
+
+pub use analyzer::*;
+pub use error::SemaError;
@@ -0,0 +1,1739 @@
+use super::{Type, TypeDefs, TypeError};
+use crate::matching::{compile_match, Decision};
+use crate::parser::nodes::{Expression, ExpressionKind, Function, Pattern, PatternKind, Prototype};
+use crate::util::Span;
+use std::collections::HashMap;
+
+/// A type being inferred
+#[derive(PartialEq, Clone, Debug)]
+enum Ty {
+    /// A type without arrays or functions, e.g. a struct
+    Known(Type),
+    Var(usize),
+    /// An array with elements of the type of a variable
+    Array(usize),
+    /// A function, by its index in the types of the args and return values of functions
+    Function(usize),
+}
+
+/// What a type variable can still become
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Bound {
+    Any,
+    /// The type of an int literal or of an operand of arithmetic,
+    /// which becomes a double if used as one
+    Numeric,
+}
+
+impl Bound {
+    /// The type of a variable never unified with a known type
+    fn default_type(self) -> Type {
+        match self {
+            Bound::Any => Type::Double,
+            Bound::Numeric => Type::Int,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Slot {
+    Unbound(Bound),
+    /// Bound to a type because of the expression at the span
+    Bound(Ty, Span),
+}
+
+/// Infer the type of every expression of a function with Hindley-Milner style unification,
+/// given the prototypes of the other functions it can call and the types defined so far.
+///
+/// The types of the unannotated args and of the return value are inferred from their uses,
+/// e.g. arithmetic with an int, comparisons, or calls to typed functions and externs.
+/// An arg not constrained by its uses is a double, as in untyped Kaleidoscope.
+/// Functions are not generalized, every function gets a single signature to be compiled with.
+///
+/// Returns the function with every arg and its return type annotated,
+/// with the int literals used as doubles turned into double literals
+/// and the missing steps of for loops written out as literals,
+/// so that a backend can tell the type of any expression from its operands.
+/// A top level expression is converted to a double.
+/// Strings, arrays and structs cannot be converted to or from other types, not even by conditions.
+/// Arrays are indexed with ints, and their elements all have the same type.
+/// A function used as a value has the type of its prototype,
+/// and a variable holding a function is called with as many args as the function takes.
+/// A lambda captures the variables of the enclosing scopes its body uses,
+/// which are filled in along with its types, its unconstrained params being doubles.
+/// The struct of a field access is the one of the value, or the only struct with the field
+/// if the type of the value is not known yet. The index of the field is filled in.
+/// The arms of a match must cover every value, and are compiled into a decision tree filled in.
+/// Gradients must have been expanded into the functions computing them.
+///
+/// Unknown variables and functions are left to the backends to report.
+pub fn check_function(
+    func: &Function,
+    prototypes: &HashMap<String, Prototype>,
+    defs: &TypeDefs,
+) -> Result<Function, Box<TypeError>> {
+    let proto = &func.prototype;
+    defs.check_annotations(proto)?;
+
+    let mut inference = Inference {
+        slots: vec![],
+        functions: vec![],
+        literals: vec![],
+        fields: vec![],
+        trees: vec![],
+        closures: vec![],
+        lambdas: vec![],
+        function: proto,
+        prototypes,
+        defs,
+        scope: vec![],
+        params: vec![],
+        ret: Ty::Known(Type::Double),
+        conversions: vec![],
+    };
+    for (i, arg) in proto.args.iter().enumerate() {
+        let ty = inference.annotated(proto.arg_types[i].clone(), proto.span);
+        inference.params.push(ty.clone());
+        inference.scope.push((arg.as_str(), ty));
+    }
+    inference.ret = inference.annotated(proto.ret_type.clone(), proto.span);
+
+    let body_ty = inference.infer(&func.body)?;
+    inference.unify(inference.ret.clone(), proto.span, body_ty, func.body.span)?;
+
+    // args and elements of array args not constrained by the body are doubles,
+    // and so are the literals used with them
+    for param in inference.params.clone() {
+        inference.default_to_double(param, proto.span);
+    }
+    for (params, _, _) in inference.closures.clone() {
+        for param in params {
+            inference.default_to_double(param, proto.span);
+        }
+    }
+    for (ty, to, span) in &inference.conversions {
+        inference.check_conversion(inference.resolve_type(ty.clone()), to.clone(), *span)?;
+    }
+
+    let mut body = func.body.clone();
+    elaborate(
+        &mut body,
+        &mut Inferred {
+            doubles: inference
+                .literals
+                .iter()
+                .map(|ty| inference.resolve_type(ty.clone()) == Type::Double)
+                .collect::<Vec<_>>()
+                .into_iter(),
+            fields: std::mem::take(&mut inference.fields).into_iter(),
+            trees: std::mem::take(&mut inference.trees).into_iter(),
+            closures: inference
+                .closures
+                .iter()
+                .map(|(params, ret, captures)| {
+                    (
+                        params
+                            .iter()
+                            .map(|param| inference.resolve_type(param.clone()))
+                            .collect(),
+                        inference.resolve_type(ret.clone()),
+                        captures
+                            .iter()
+                            .map(|(var, ty)| (var.to_string(), inference.resolve_type(ty.clone())))
+                            .collect(),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .into_iter(),
+        },
+    );
+
+    let mut ret_type = inference.resolve_type(inference.ret.clone());
+    if proto.is_anonymous() && ret_type != Type::Double {
+        let span = body.span;
+        inference.check_conversion(ret_type, Type::Double, span)?;
+        body = Expression::new(ExpressionKind::CastExpr(Type::Double, Box::new(body)), span);
+        ret_type = Type::Double;
+    }
+
+    Ok(Function {
+        prototype: Prototype {
+            arg_types: inference
+                .params
+                .iter()
+                .map(|param| Some(inference.resolve_type(param.clone())))
+                .collect(),
+            ret_type: Some(ret_type),
+            ..proto.clone()
+        },
+        body,
+        span: func.span,
+    })
+}
+
+/// The types of the params and of the return value of a lambda, and its captured variables
+type Closure<T, Var> = (Vec<T>, T, Vec<(Var, T)>);
+
+/// What the type checker fills in, in the order `elaborate` visits the expressions
+struct Inferred {
+    /// Whether each int literal is used as a double
+    doubles: std::vec::IntoIter<bool>,
+    /// The index of each field access in its struct
+    fields: std::vec::IntoIter<usize>,
+    /// The decision tree of each match
+    trees: std::vec::IntoIter<Decision>,
+    /// The types of the params and of the return value of each lambda, and its captured variables
+    closures: std::vec::IntoIter<Closure<Type, String>>,
+}
+
+/// Turn the int literals used as doubles into double literals,
+/// and fill in the index of every field access, the decision tree of every match
+/// and the types and captured variables of every lambda,
+/// visiting them in the order they appear in the source,
+/// a field access after its value, a match after its arms and a lambda after its body.
+/// A missing step of a for loop is a literal 1 right before the body.
+fn elaborate(expr: &mut Expression, inferred: &mut Inferred) {
+    match &mut expr.kind {
+        ExpressionKind::IntExpr(num) => {
+            if inferred
+                .doubles
+                .next()
+                .expect("every int literal is inferred")
+            {
+                expr.kind = ExpressionKind::NumberExpr(*num as f64);
+            }
+        }
+        ExpressionKind::NumberExpr(_)
+        | ExpressionKind::BoolExpr(_)
+        | ExpressionKind::StringExpr(_)
+        | ExpressionKind::VariableExpr(_) => {}
+        ExpressionKind::UnaryExpr(_, operand) | ExpressionKind::CastExpr(_, operand) => {
+            elaborate(operand, inferred)
+        }
+        ExpressionKind::BinaryExpr(_, left, right) => {
+            elaborate(left, inferred);
+            elaborate(right, inferred);
+        }
+        ExpressionKind::CallExpr(_, args)
+        | ExpressionKind::ArrayExpr(args)
+        | ExpressionKind::VariantExpr(_, args) => {
+            for arg in args {
+                elaborate(arg, inferred);
+            }
+        }
+        ExpressionKind::IndexExpr(array, index) => {
+            elaborate(array, inferred);
+            elaborate(index, inferred);
+        }
+        ExpressionKind::StructExpr(_, fields) => {
+            for (_, value) in fields {
+                elaborate(value, inferred);
+            }
+        }
+        ExpressionKind::FieldExpr { object, index, .. } => {
+            elaborate(object, inferred);
+            *index = Some(
+                inferred
+                    .fields
+                    .next()
+                    .expect("every field access is inferred"),
+            );
+        }
+        ExpressionKind::MatchExpr { value, arms, tree } => {
+            elaborate(value, inferred);
+            for (_, body) in arms {
+                elaborate(body, inferred);
+            }
+            *tree = Some(Box::new(
+                inferred.trees.next().expect("every match is inferred"),
+            ));
+        }
+        ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+            elaborate(cond, inferred);
+            elaborate(then_expr, inferred);
+            elaborate(else_expr, inferred);
+        }
+        ExpressionKind::ForExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            elaborate(start, inferred);
+            elaborate(end, inferred);
+            let step = step.get_or_insert_with(|| Box::new(ExpressionKind::IntExpr(1).into()));
+            elaborate(step, inferred);
+            elaborate(body, inferred);
+        }
+        ExpressionKind::VarExpr { vars, body } => {
+            for (_, init) in vars {
+                if let Some(init) = init {
+                    elaborate(init, inferred);
+                }
+            }
+            elaborate(body, inferred);
+        }
+        ExpressionKind::LambdaExpr {
+            param_types,
+            ret_type,
+            captures,
+            body,
+            ..
+        } => {
+            elaborate(body, inferred);
+            let (params, ret, captured) =
+                inferred.closures.next().expect("every lambda is inferred");
+            *param_types = params.into_iter().map(Some).collect();
+            *ret_type = Some(ret);
+            *captures = captured;
+        }
+        ExpressionKind::ClosureExpr { .. } => {}
+        ExpressionKind::GradExpr(_) => unreachable!("gradients are expanded before type checking"),
+    }
+}
+
+struct Inference<'a> {
+    /// Type variables, bound by unification
+    slots: Vec<Slot>,
+    /// The types of the args and of the return value of every function type
+    functions: Vec<(Vec<Ty>, Ty)>,
+    /// The type of every int literal, in the order they appear in the source
+    literals: Vec<Ty>,
+    /// The index of every field access in its struct, in the order they appear in the source
+    fields: Vec<usize>,
+    /// The decision tree of every match, in the order they end in the source
+    trees: Vec<Decision>,
+    /// The types of the params and of the return value of every lambda and the variables it captures,
+    /// in the order they end in the source
+    closures: Vec<Closure<Ty, &'a str>>,
+    /// The lambdas the expression being inferred is in, the innermost last,
+    /// with the length of the scope outside of them and the variables they capture so far
+    lambdas: Vec<(usize, Vec<(&'a str, Ty)>)>,
+    function: &'a Prototype,
+    prototypes: &'a HashMap<String, Prototype>,
+    defs: &'a TypeDefs,
+    /// Variables in scope, the innermost last
+    scope: Vec<(&'a str, Ty)>,
+    /// The types of the args of the function, shared with its recursive calls
+    params: Vec<Ty>,
+    /// The return type of the function, known before its body to type recursive calls
+    ret: Ty,
+    /// Values converted to a type by a cast or a condition, checked once every type is inferred
+    conversions: Vec<(Ty, Type, Span)>,
+}
+
+impl<'a> Inference<'a> {
+    fn fresh(&mut self, bound: Bound) -> Ty {
+        self.slots.push(Slot::Unbound(bound));
+        Ty::Var(self.slots.len() - 1)
+    }
+
+    /// An array type with a fresh variable as the type of its elements, returned first
+    fn fresh_array(&mut self) -> (Ty, Ty) {
+        self.slots.push(Slot::Unbound(Bound::Any));
+        let element = self.slots.len() - 1;
+        (Ty::Var(element), Ty::Array(element))
+    }
+
+    fn fresh_function(&mut self, params: Vec<Ty>, ret: Ty) -> Ty {
+        self.functions.push((params, ret));
+        Ty::Function(self.functions.len() - 1)
+    }
+
+    /// The type being inferred of a known type, written at `span`
+    fn known(&mut self, ty: Type, span: Span) -> Ty {
+        match ty {
+            Type::Function(function) => {
+                let params = function
+                    .params
+                    .iter()
+                    .map(|param| self.known(param.clone(), span))
+                    .collect();
+                let ret = self.known(function.ret.clone(), span);
+                self.fresh_function(params, ret)
+            }
+            Type::Array(element) => {
+                let (element_ty, array) = self.fresh_array();
+                let element = self.known((*element).clone(), span);
+                self.unify(element_ty, span, element, span)
+                    .expect("a fresh variable is bound to anything");
+                array
+            }
+            ty => Ty::Known(ty),
+        }
+    }
+
+    /// A type variable for an arg or return value, bound if it is annotated in the prototype at `span`
+    fn annotated(&mut self, annotation: Option<Type>, span: Span) -> Ty {
+        let ty = self.fresh(Bound::Any);
+        if let (Some(annotation), Ty::Var(var)) = (annotation, &ty) {
+            let var = *var;
+            self.slots[var] = Slot::Bound(self.known(annotation, span), span);
+        }
+        ty
+    }
+
+    /// Follow the bindings of a type variable,
+    /// returning the type with the span of the expression it comes from, `span` if it is not bound
+    fn resolve_at(&self, ty: Ty, span: Span) -> (Ty, Span) {
+        match ty {
+            Ty::Var(var) => match &self.slots[var] {
+                Slot::Bound(ty, origin) => self.resolve_at(ty.clone(), *origin),
+                Slot::Unbound(_) => (ty, span),
+            },
+            ty => (ty, span),
+        }
+    }
+
+    fn resolve(&self, ty: Ty) -> Ty {
+        self.resolve_at(ty, Span::default()).0
+    }
+
+    /// The type a type variable ends up as
+    fn resolve_type(&self, ty: Ty) -> Type {
+        match self.resolve(ty) {
+            Ty::Known(ty) => ty,
+            Ty::Var(var) => self.bound(var).default_type(),
+            Ty::Array(element) => Type::array(self.resolve_type(Ty::Var(element))),
+            Ty::Function(function) => {
+                let (params, ret) = &self.functions[function];
+                let params = params
+                    .iter()
+                    .map(|param| self.resolve_type(param.clone()))
+                    .collect();
+                Type::function(params, self.resolve_type(ret.clone()))
+            }
+        }
+    }
+
+    /// Bind the variables of a type still unbound to double, e.g. an unconstrained arg at `span`
+    fn default_to_double(&mut self, ty: Ty, span: Span) {
+        match self.resolve(ty) {
+            Ty::Var(var) => self.slots[var] = Slot::Bound(Ty::Known(Type::Double), span),
+            Ty::Array(element) => self.default_to_double(Ty::Var(element), span),
+            Ty::Function(function) => {
+                let (params, ret) = self.functions[function].clone();
+                for ty in params.into_iter().chain(Some(ret)) {
+                    self.default_to_double(ty, span);
+                }
+            }
+            Ty::Known(_) => {}
+        }
+    }
+
+    /// Whether a type contains a variable, which could not be bound to it without being infinite
+    fn occurs(&self, var: usize, ty: Ty) -> bool {
+        match self.resolve(ty) {
+            Ty::Var(other) => other == var,
+            Ty::Array(element) => self.occurs(var, Ty::Var(element)),
+            Ty::Function(function) => {
+                let (params, ret) = &self.functions[function];
+                params
+                    .iter()
+                    .chain(Some(ret))
+                    .any(|ty| self.occurs(var, ty.clone()))
+            }
+            Ty::Known(_) => false,
+        }
+    }
+
+    fn bound(&self, var: usize) -> Bound {
+        match self.slots[var] {
+            Slot::Unbound(bound) => bound,
+            Slot::Bound(..) => unreachable!("resolved"),
+        }
+    }
+
+    /// Make two types the same, the value of `found` at `found_span` being used where `expected` is.
+    /// `expected_span` is where `expected` comes from, e.g. the other operand.
+    fn unify(
+        &mut self,
+        expected: Ty,
+        expected_span: Span,
+        found: Ty,
+        found_span: Span,
+    ) -> Result<Ty, Box<TypeError>> {
+        let (expected, expected_origin) = self.resolve_at(expected, expected_span);
+        let (found, found_origin) = self.resolve_at(found, found_span);
+        let mismatch = |expected, found| {
+            Box::new(TypeError::Mismatch {
+                expected,
+                found,
+                span: found_span,
+                expected_origin,
+                found_origin,
+            })
+        };
+
+        match (&expected, &found) {
+            (Ty::Known(expected), Ty::Known(found)) if expected == found => {
+                Ok(Ty::Known(found.clone()))
+            }
+            (Ty::Known(expected), Ty::Known(found)) => {
+                Err(mismatch(expected.clone(), found.clone()))
+            }
+            (Ty::Array(expected_element), Ty::Array(found_element)) => {
+                // report the whole arrays rather than their elements
+                match self.unify(
+                    Ty::Var(*expected_element),
+                    expected_origin,
+                    Ty::Var(*found_element),
+                    found_origin,
+                ) {
+                    Ok(_) => Ok(found),
+                    Err(_) => Err(mismatch(
+                        self.resolve_type(expected),
+                        self.resolve_type(found),
+                    )),
+                }
+            }
+            (Ty::Function(expected_function), Ty::Function(found_function)) => {
+                let (expected_params, expected_ret) = self.functions[*expected_function].clone();
+                let (found_params, found_ret) = self.functions[*found_function].clone();
+
+                // report the whole functions rather than their args or return values
+                let mut unified = expected_params.len() == found_params.len();
+                for (expected_part, found_part) in expected_params
+                    .into_iter()
+                    .zip(found_params)
+                    .chain(Some((expected_ret, found_ret)))
+                {
+                    unified = unified
+                        && self
+                            .unify(expected_part, expected_origin, found_part, found_origin)
+                            .is_ok();
+                }
+                if unified {
+                    Ok(found)
+                } else {
+                    Err(mismatch(
+                        self.resolve_type(expected),
+                        self.resolve_type(found),
+                    ))
+                }
+            }
+            (Ty::Known(_), Ty::Array(_))
+            | (Ty::Array(_), Ty::Known(_))
+            | (Ty::Known(_), Ty::Function(_))
+            | (Ty::Function(_), Ty::Known(_))
+            | (Ty::Array(_), Ty::Function(_))
+            | (Ty::Function(_), Ty::Array(_)) => Err(mismatch(
+                self.resolve_type(expected),
+                self.resolve_type(found),
+            )),
+            (Ty::Var(var), Ty::Array(_)) | (Ty::Var(var), Ty::Function(_)) => {
+                let var = *var;
+                if self.bound(var) == Bound::Numeric {
+                    return Err(Box::new(TypeError::NotNumeric {
+                        found: self.resolve_type(found),
+                        span: found_span,
+                        origin: found_origin,
+                    }));
+                }
+                if self.occurs(var, found.clone()) {
+                    return Err(mismatch(
+                        self.resolve_type(expected),
+                        self.resolve_type(found),
+                    ));
+                }
+                self.slots[var] = Slot::Bound(found.clone(), found_origin);
+                Ok(found)
+            }
+            (Ty::Array(_), Ty::Var(var)) | (Ty::Function(_), Ty::Var(var)) => {
+                let var = *var;
+                if self.bound(var) == Bound::Numeric || self.occurs(var, expected.clone()) {
+                    let found = self.resolve_type(found);
+                    return Err(mismatch(self.resolve_type(expected), found));
+                }
+                self.slots[var] = Slot::Bound(expected.clone(), expected_origin);
+                Ok(expected)
+            }
+            // only an int literal or an operand of arithmetic can be numeric without being known
+            (Ty::Var(var), Ty::Known(known)) => {
+                if self.bound(*var) == Bound::Numeric && !known.is_numeric() {
+                    return Err(Box::new(TypeError::NotNumeric {
+                        found: known.clone(),
+                        span: found_span,
+                        origin: found_origin,
+                    }));
+                }
+                self.slots[*var] = Slot::Bound(found.clone(), found_origin);
+                Ok(found)
+            }
+            (Ty::Known(known), Ty::Var(var)) => {
+                if self.bound(*var) == Bound::Numeric && !known.is_numeric() {
+                    return Err(mismatch(known.clone(), Type::Int));
+                }
+                self.slots[*var] = Slot::Bound(expected.clone(), expected_origin);
+                Ok(expected)
+            }
+            (Ty::Var(expected), Ty::Var(found)) if expected == found => Ok(Ty::Var(*found)),
+            (Ty::Var(expected), Ty::Var(found)) => {
+                let (expected, found) = (*expected, *found);
+                if self.bound(expected) == Bound::Numeric {
+                    self.slots[found] = Slot::Unbound(Bound::Numeric);
+                }
+                self.slots[expected] = Slot::Bound(Ty::Var(found), found_origin);
+                Ok(Ty::Var(found))
+            }
+        }
+    }
+
+    /// Make sure the type of the expression at `span` is int or double
+    fn require_numeric(&mut self, ty: Ty, span: Span) -> Result<Ty, Box<TypeError>> {
+        match self.resolve_at(ty.clone(), span) {
+            (Ty::Var(var), _) => {
+                self.slots[var] = Slot::Unbound(Bound::Numeric);
+                Ok(ty)
+            }
+            (resolved, origin) => {
+                let found = self.resolve_type(resolved);
+                if found.is_numeric() {
+                    // keep the variable to keep track of where the type comes from
+                    Ok(ty)
+                } else {
+                    Err(Box::new(TypeError::NotNumeric {
+                        found,
+                        span,
+                        origin,
+                    }))
+                }
+            }
+        }
+    }
+
+    /// Make sure the type of the expression at `span` is an array, returning the type of its elements
+    fn require_array(&mut self, ty: Ty, span: Span) -> Result<Ty, Box<TypeError>> {
+        let (resolved, origin) = self.resolve_at(ty.clone(), span);
+        let is_array = match resolved {
+            Ty::Array(_) => true,
+            Ty::Var(var) => self.bound(var) == Bound::Any,
+            Ty::Known(_) | Ty::Function(_) => false,
+        };
+        if !is_array {
+            return Err(Box::new(TypeError::NotAnArray {
+                found: self.resolve_type(resolved),
+                span,
+                origin,
+            }));
+        }
+
+        let (element, array) = self.fresh_array();
+        self.unify(array, span, ty, span)?;
+        Ok(element)
+    }
+
+    /// Make sure the type of the expression at `span` is a function taking `args` args,
+    /// returning the types of its args and of its return value
+    fn require_function(
+        &mut self,
+        ty: Ty,
+        span: Span,
+        args: usize,
+    ) -> Result<(Vec<Ty>, Ty), Box<TypeError>> {
+        let (resolved, origin) = self.resolve_at(ty.clone(), span);
+        let is_function = match resolved {
+            Ty::Function(function) => self.functions[function].0.len() == args,
+            Ty::Var(var) => self.bound(var) == Bound::Any,
+            Ty::Known(_) | Ty::Array(_) => false,
+        };
+        if !is_function {
+            return Err(Box::new(TypeError::NotAFunction {
+                args,
+                found: self.resolve_type(resolved),
+                span,
+                origin,
+            }));
+        }
+
+        let params: Vec<Ty> = (0..args).map(|_| self.fresh(Bound::Any)).collect();
+        let ret = self.fresh(Bound::Any);
+        let function = self.fresh_function(params.clone(), ret.clone());
+        self.unify(function, span, ty, span)?;
+        Ok((params, ret))
+    }
+
+    /// Make sure the type of the expression at `span` is a struct with a field,
+    /// returning the index and the type of the field
+    fn require_field(
+        &mut self,
+        ty: Ty,
+        span: Span,
+        field: &str,
+    ) -> Result<(usize, Ty), Box<TypeError>> {
+        let defs = self.defs;
+        let def = match self.resolve_at(ty.clone(), span) {
+            (Ty::Known(Type::Named(name)), origin) if defs.get_union(&name).is_some() => {
+                return Err(Box::new(TypeError::NotAStruct {
+                    found: Type::Named(name),
+                    span,
+                    origin,
+                }))
+            }
+            (Ty::Known(Type::Named(name)), _) => {
+                defs.get_struct(&name)
+                    .ok_or_else(|| TypeError::UnknownStruct {
+                        name: name.to_string(),
+                        span,
+                    })?
+            }
+            // the only struct with the field, as the value is not known to be anything else
+            (Ty::Var(var), _) if self.bound(var) == Bound::Any => {
+                let mut candidates = defs.structs().filter(|def| def.field(field).is_some());
+                match (candidates.next(), candidates.next()) {
+                    (Some(def), None) => {
+                        let found = Ty::Known(Type::named(&def.name));
+                        self.unify(ty, span, found, span)?;
+                        def
+                    }
+                    (Some(_), Some(_)) => {
+                        return Err(Box::new(TypeError::AmbiguousField {
+                            field: field.into(),
+                            span,
+                        }))
+                    }
+                    (None, _) => {
+                        return Err(Box::new(TypeError::UnknownField {
+                            field: field.into(),
+                            ty: None,
+                            span,
+                        }))
+                    }
+                }
+            }
+            (resolved, origin) => {
+                return Err(Box::new(TypeError::NotAStruct {
+                    found: self.resolve_type(resolved),
+                    span,
+                    origin,
+                }))
+            }
+        };
+
+        match def.field(field) {
+            Some((index, field_ty)) => Ok((index, self.known(field_ty, def.span))),
+            None => Err(Box::new(TypeError::UnknownField {
+                field: field.into(),
+                ty: Some(Type::named(&def.name)),
+                span,
+            })),
+        }
+    }
+
+    fn check_conversion(&self, from: Type, to: Type, span: Span) -> Result<(), Box<TypeError>> {
+        if from.converts_to(&to) {
+            Ok(())
+        } else {
+            Err(Box::new(TypeError::InvalidConversion { from, to, span }))
+        }
+    }
+
+    /// The type of a variable in scope,
+    /// which is captured by the lambdas it is used in if it is defined outside of them
+    fn scope_lookup(&mut self, name: &str) -> Option<Ty> {
+        let index = self.scope.iter().rposition(|(var, _)| *var == name)?;
+        let (var, ty) = self.scope[index].clone();
+        for (depth, captures) in &mut self.lambdas {
+            if index < *depth && captures.iter().all(|(captured, _)| *captured != var) {
+                captures.push((var, ty.clone()));
+            }
+        }
+        Some(ty)
+    }
+
+    fn lookup_variable(&mut self, name: &str) -> Ty {
+        match self.scope_lookup(name) {
+            Some(ty) => ty,
+            // reported by the backend
+            None => self.fresh(Bound::Any),
+        }
+    }
+
+    /// The type of a variable, or of a function used as a value if no variable has the name
+    fn lookup_value(&mut self, name: &str) -> Ty {
+        if let Some(ty) = self.scope_lookup(name) {
+            return ty;
+        }
+        match self.lookup_function(name) {
+            Some((params, ret)) => self.fresh_function(params, ret),
+            // reported by the backend
+            None => self.fresh(Bound::Any),
+        }
+    }
+
+    /// The arg types and return type of a function
+    fn lookup_function(&mut self, name: &str) -> Option<(Vec<Ty>, Ty)> {
+        if name == self.function.name {
+            return Some((self.params.clone(), self.ret.clone()));
+        }
+        let prototypes = self.prototypes;
+        let proto = prototypes.get(name)?;
+        let args = (0..proto.args.len())
+            .map(|i| self.known(proto.arg_type(i), proto.span))
+            .collect();
+        Some((args, self.known(proto.return_type(), proto.span)))
+    }
+
+    /// Infer the type of a call at `span`,
+    /// to the function held by the variable named `name` if there is one
+    fn infer_call(
+        &mut self,
+        name: &str,
+        args: &'a [Expression],
+        span: Span,
+    ) -> Result<Ty, Box<TypeError>> {
+        let mut arg_tys = Vec::with_capacity(args.len());
+        for arg in args {
+            arg_tys.push(self.infer(arg)?);
+        }
+
+        if let Some(callee) = self.scope_lookup(name) {
+            let (params, ret) = self.require_function(callee, span, args.len())?;
+            for ((param, arg_ty), arg) in params.into_iter().zip(arg_tys).zip(args) {
+                self.unify(param, arg.span, arg_ty, arg.span)?;
+            }
+            return Ok(ret);
+        }
+
+        match self.lookup_function(name) {
+            Some((params, ret)) => {
+                // a wrong number of args is reported by the backend
+                if params.len() == args.len() {
+                    for ((param, arg_ty), arg) in params.into_iter().zip(arg_tys).zip(args) {
+                        self.unify(param, arg.span, arg_ty, arg.span)?;
+                    }
+                }
+                Ok(ret)
+            }
+            None => Ok(self.fresh(Bound::Any)),
+        }
+    }
+
+    /// Infer the type of an expression.
+    /// Int literals and field accesses are visited in the order they appear in the source,
+    /// as in `elaborate`.
+    fn infer(&mut self, expr: &'a Expression) -> Result<Ty, Box<TypeError>> {
+        match &expr.kind {
+            ExpressionKind::NumberExpr(_) => Ok(Ty::Known(Type::Double)),
+            ExpressionKind::BoolExpr(_) => Ok(Ty::Known(Type::Bool)),
+            ExpressionKind::StringExpr(_) => Ok(Ty::Known(Type::Str)),
+            ExpressionKind::IntExpr(_) => {
+                let ty = self.fresh(Bound::Numeric);
+                self.literals.push(ty.clone());
+                Ok(ty)
+            }
+            ExpressionKind::VariableExpr(var) => Ok(self.lookup_value(var)),
+            ExpressionKind::BinaryExpr('=', left, right) => {
+                let var_ty = match &left.kind {
+                    ExpressionKind::VariableExpr(var) => self.lookup_variable(var),
+                    // reported by the backend
+                    _ => self.infer(left)?,
+                };
+                let value_ty = self.infer(right)?;
+                self.unify(var_ty, left.span, value_ty, right.span)
+            }
+            ExpressionKind::BinaryExpr(op @ '+', left, right)
+            | ExpressionKind::BinaryExpr(op @ '-', left, right)
+            | ExpressionKind::BinaryExpr(op @ '*', left, right)
+            | ExpressionKind::BinaryExpr(op @ '/', left, right)
+            | ExpressionKind::BinaryExpr(op @ '<', left, right)
+            | ExpressionKind::BinaryExpr(op @ '>', left, right) => {
+                let left_ty = self.infer(left)?;
+                let left_ty = self.require_numeric(left_ty, left.span)?;
+                let right_ty = self.infer(right)?;
+                let ty = self.unify(left_ty, left.span, right_ty, right.span)?;
+
+                if *op == '<' || *op == '>' {
+                    Ok(Ty::Known(Type::Bool))
+                } else {
+                    Ok(ty)
+                }
+            }
+            ExpressionKind::BinaryExpr(op, left, right) => {
+                let name = format!("binary{}", op);
+                let left_ty = self.infer(left)?;
+                let right_ty = self.infer(right)?;
+                self.infer_operator(&name, &[(left_ty, left.span), (right_ty, right.span)])
+            }
+            ExpressionKind::UnaryExpr(op, operand) => {
+                let name = format!("unary{}", op);
+                let operand_ty = self.infer(operand)?;
+                self.infer_operator(&name, &[(operand_ty, operand.span)])
+            }
+            ExpressionKind::CallExpr(name, args) => self.infer_call(name, args, expr.span),
+            ExpressionKind::ArrayExpr(elements) => {
+                // every element has the type of the first one
+                let (element_ty, array) = self.fresh_array();
+                let mut first = None;
+                for element in elements {
+                    let ty = self.infer(element)?;
+                    let first = *first.get_or_insert(element.span);
+                    self.unify(element_ty.clone(), first, ty, element.span)?;
+                }
+                Ok(array)
+            }
+            ExpressionKind::IndexExpr(array, index) => {
+                let array_ty = self.infer(array)?;
+                let element_ty = self.require_array(array_ty, array.span)?;
+                let index_ty = self.infer(index)?;
+                self.unify(Ty::Known(Type::Int), index.span, index_ty, index.span)?;
+                Ok(element_ty)
+            }
+            ExpressionKind::StructExpr(name, fields) => {
+                let def = self
+                    .defs
+                    .get_struct(name)
+                    .ok_or_else(|| TypeError::UnknownStruct {
+                        name: name.clone(),
+                        span: expr.span,
+                    })?;
+                let ty = Type::named(name);
+
+                if !fields.iter().map(|(field, _)| field).eq(&def.fields) {
+                    return Err(Box::new(TypeError::FieldMismatch {
+                        ty,
+                        fields: def.fields.clone(),
+                        span: expr.span,
+                    }));
+                }
+                for ((_, value), field_ty) in fields.iter().zip(&def.field_types) {
+                    let value_ty = self.infer(value)?;
+                    let field_ty = self.known(field_ty.clone(), def.span);
+                    self.unify(field_ty, def.span, value_ty, value.span)?;
+                }
+                Ok(Ty::Known(ty))
+            }
+            ExpressionKind::FieldExpr { object, field, .. } => {
+                let object_ty = self.infer(object)?;
+                let (index, field_ty) = self.require_field(object_ty, object.span, field)?;
+                self.fields.push(index);
+                Ok(field_ty)
+            }
+            ExpressionKind::VariantExpr(name, args) => {
+                let (union, def, field_types) = self.lookup_variant(name, args.len(), expr.span)?;
+                for (arg, field_ty) in args.iter().zip(field_types) {
+                    let arg_ty = self.infer(arg)?;
+                    self.unify(field_ty, def, arg_ty, arg.span)?;
+                }
+                Ok(Ty::Known(union))
+            }
+            ExpressionKind::MatchExpr { value, arms, .. } => {
+                let value_ty = self.infer(value)?;
+
+                // every arm has the type of the first one
+                let mut arm_ty: Option<(Ty, Span)> = None;
+                for (pattern, body) in arms {
+                    let depth = self.scope.len();
+                    self.infer_pattern(pattern, value_ty.clone(), value.span)?;
+                    let body_ty = self.infer(body)?;
+                    self.scope.truncate(depth);
+
+                    let (ty, span) = arm_ty.get_or_insert((body_ty.clone(), body.span)).clone();
+                    self.unify(ty, span, body_ty, body.span)?;
+                }
+
+                let patterns: Vec<&Pattern> = arms.iter().map(|(pattern, _)| pattern).collect();
+                let tree = compile_match(&patterns, self.defs).map_err(|missing| {
+                    TypeError::NonExhaustive {
+                        missing,
+                        span: expr.span,
+                    }
+                })?;
+                self.trees.push(tree);
+                Ok(arm_ty.expect("a match has arms").0)
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                // any value but a string can be a condition
+                let cond_ty = self.infer(cond)?;
+                self.conversions.push((cond_ty, Type::Bool, cond.span));
+                let then_ty = self.infer(then_expr)?;
+                let else_ty = self.infer(else_expr)?;
+                self.unify(then_ty, then_expr.span, else_ty, else_expr.span)
+            }
+            ExpressionKind::ForExpr {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let var_ty = self.infer(start)?;
+                let var_ty = self.require_numeric(var_ty, start.span)?;
+
+                self.scope.push((var.as_str(), var_ty.clone()));
+                let end_ty = self.infer(end)?;
+                self.conversions.push((end_ty, Type::Bool, end.span));
+                match step {
+                    Some(step) => {
+                        let step_ty = self.infer(step)?;
+                        self.unify(var_ty, start.span, step_ty, step.span)?;
+                    }
+                    // the literal filled in by `elaborate`
+                    None => self.literals.push(var_ty),
+                }
+                self.infer(body)?;
+                self.scope.pop();
+
+                // for expression always evaluates to 0.0
+                Ok(Ty::Known(Type::Double))
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                let depth = self.scope.len();
+                for (var, init) in vars {
+                    let ty = match init {
+                        Some(init) => self.infer(init)?,
+                        None => Ty::Known(Type::Double),
+                    };
+                    self.scope.push((var.as_str(), ty));
+                }
+                let ty = self.infer(body)?;
+                self.scope.truncate(depth);
+                Ok(ty)
+            }
+            // only a string can be cast into a string
+            ExpressionKind::CastExpr(Type::Str, operand) => {
+                let operand_ty = self.infer(operand)?;
+                self.unify(Ty::Known(Type::Str), expr.span, operand_ty, operand.span)
+            }
+            ExpressionKind::CastExpr(ty, operand) => {
+                let operand_ty = self.infer(operand)?;
+                self.conversions
+                    .push((operand_ty, ty.clone(), operand.span));
+                Ok(Ty::Known(ty.clone()))
+            }
+            ExpressionKind::LambdaExpr {
+                params,
+                param_types,
+                body,
+                ..
+            } => {
+                let depth = self.scope.len();
+                let mut param_tys = Vec::with_capacity(params.len());
+                for (param, annotation) in params.iter().zip(param_types) {
+                    if let Some(annotation) = annotation {
+                        self.defs.check_defined(annotation, expr.span)?;
+                    }
+                    let ty = self.annotated(annotation.clone(), expr.span);
+                    param_tys.push(ty.clone());
+                    self.scope.push((param.as_str(), ty));
+                }
+
+                self.lambdas.push((depth, vec![]));
+                let ret = self.infer(body)?;
+                let (_, captures) = self.lambdas.pop().expect("pushed before the body");
+                self.scope.truncate(depth);
+
+                self.closures
+                    .push((param_tys.clone(), ret.clone(), captures));
+                Ok(self.fresh_function(param_tys, ret))
+            }
+            ExpressionKind::ClosureExpr { ty, .. } => Ok(self.known(ty.clone(), expr.span)),
+            ExpressionKind::GradExpr(_) => {
+                unreachable!("gradients are expanded before type checking")
+            }
+        }
+    }
+
+    /// The union of a variant given `found` fields at `span`,
+    /// where the variant is defined and the types of its fields
+    fn lookup_variant(
+        &mut self,
+        name: &str,
+        found: usize,
+        span: Span,
+    ) -> Result<(Type, Span, Vec<Ty>), Box<TypeError>> {
+        let defs = self.defs;
+        let (def, tag) = defs
+            .variant(name)
+            .ok_or_else(|| TypeError::UnknownVariant {
+                name: name.into(),
+                span,
+            })?;
+        let variant = &def.variants[tag];
+        if variant.fields.len() != found {
+            return Err(Box::new(TypeError::VariantArity {
+                name: name.into(),
+                expected: variant.fields.len(),
+                found,
+                span,
+            }));
+        }
+        let field_types = variant
+            .field_types
+            .iter()
+            .map(|ty| self.known(ty.clone(), variant.span))
+            .collect();
+        Ok((Type::named(&def.name), variant.span, field_types))
+    }
+
+    /// Check that a pattern fits values of type `ty` from the expression at `span`,
+    /// adding its variables to the scope
+    fn infer_pattern(
+        &mut self,
+        pattern: &'a Pattern,
+        ty: Ty,
+        span: Span,
+    ) -> Result<(), Box<TypeError>> {
+        match &pattern.kind {
+            PatternKind::Wildcard => Ok(()),
+            PatternKind::Binding(name) => {
+                self.scope.push((name.as_str(), ty));
+                Ok(())
+            }
+            PatternKind::Variant(name, fields) => {
+                let (union, def, field_types) =
+                    self.lookup_variant(name, fields.len(), pattern.span)?;
+                self.unify(ty, span, Ty::Known(union), pattern.span)?;
+                for (field, field_ty) in fields.iter().zip(field_types) {
+                    self.infer_pattern(field, field_ty, def)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Infer the type of a user defined operator applied to already inferred operands
+    fn infer_operator(
+        &mut self,
+        name: &str,
+        operands: &[(Ty, Span)],
+    ) -> Result<Ty, Box<TypeError>> {
+        match self.lookup_function(name) {
+            Some((params, ret)) if params.len() == operands.len() => {
+                for (param, (operand_ty, span)) in params.into_iter().zip(operands) {
+                    self.unify(param, *span, operand_ty.clone(), *span)?;
+                }
+                Ok(ret)
+            }
+            // reported by the backend
+            _ => Ok(self.fresh(Bound::Any)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Diagnostic;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+    use ExpressionKind::*;
+
+    /// Check every function of a program, in order, returning the last one
+    fn check(program: &str) -> Result<Function, Box<TypeError>> {
+        let mut prototypes = HashMap::new();
+        let mut defs = TypeDefs::new();
+        let mut last = None;
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::ExternNode(proto) => {
+                    prototypes.insert(proto.name.clone(), proto);
+                }
+                ASTNode::StructNode(def) => defs.define_struct(&def)?,
+                ASTNode::UnionNode(def) => defs.define_union(&def)?,
+                ASTNode::FunctionNode(func) => {
+                    let func = check_function(&func, &prototypes, &defs)?;
+                    prototypes.insert(func.prototype.name.clone(), func.prototype.clone());
+                    last = Some(func);
+                }
+                _ => {}
+            }
+        }
+        Ok(last.expect("a function"))
+    }
+
+    fn signature(program: &str) -> String {
+        check(program).unwrap().prototype.to_string()
+    }
+
+    fn body(program: &str) -> ExpressionKind {
+        check(program).unwrap().body.kind
+    }
+
+    #[test]
+    fn signatures() {
+        assert_eq!(signature("def f(x) x"), "f(x: double): double");
+        assert_eq!(signature("def f(n: int) n * 2"), "f(n: int): int");
+        assert_eq!(
+            signature("def f(n: int, x) n < 1"),
+            "f(n: int x: double): bool"
+        );
+        assert_eq!(signature("def f(): double 1"), "f(): double");
+        assert_eq!(signature("def f() 1"), "f(): int");
+        assert_eq!(
+            signature("def f(b: bool) if b then 1 else 2.5"),
+            "f(b: bool): double"
+        );
+        assert_eq!(signature("def f(x) int(x)"), "f(x: double): int");
+        // the return type of a recursive call is inferred from the rest of the body
+        assert_eq!(
+            signature("def fib(n: int) if n < 3 then 1 else fib(n - 1) + fib(n - 2)"),
+            "fib(n: int): int"
+        );
+        assert_eq!(signature("def f(x) f(x)"), "f(x: double): double");
+        assert_eq!(
+            signature("extern g(n: int): bool; def f() g(1)"),
+            "f(): bool"
+        );
+    }
+
+    #[test]
+    fn inferred_signatures() {
+        assert_eq!(signature("def f(x, n: int) x * n"), "f(x: int n: int): int");
+        assert_eq!(
+            signature("extern g(n: int): bool; def f(x) g(x)"),
+            "f(x: int): bool"
+        );
+        assert_eq!(
+            signature("def f(x y) if x < y then y else 1"),
+            "f(x: double y: double): double"
+        );
+        assert_eq!(
+            signature("def f(b x) if b then x else false"),
+            "f(b: double x: bool): bool"
+        );
+        assert_eq!(signature("def f(x): int x"), "f(x: int): int");
+        // recursive calls share the signature being inferred
+        assert_eq!(
+            signature("def f(x n) if n < 1 then x else f(x, n - 1)"),
+            "f(x: double n: double): double"
+        );
+        assert_eq!(
+            signature("def f(x n: int) if n < 1 then 0 else f(x, n - 1) + x"),
+            "f(x: double n: int): double"
+        );
+        assert_eq!(
+            signature("def f(b x) if b then x else f(x < 1, 2)"),
+            "f(b: bool x: double): double"
+        );
+        // the inferred signature is used by the callers
+        assert_eq!(
+            signature("extern g(n: int): int; def h(x) g(x); def f(x) h(x) + 1"),
+            "f(x: int): int"
+        );
+    }
+
+    #[test]
+    fn int_literals_used_as_doubles() {
+        let one = || Box::new(Expression::from(NumberExpr(1.0)));
+        let x = || Box::new(Expression::from(VariableExpr("x".into())));
+
+        let strip = |kind: ExpressionKind| match kind {
+            BinaryExpr(op, left, right) => {
+                BinaryExpr(op, Box::new(left.kind.into()), Box::new(right.kind.into()))
+            }
+            kind => kind,
+        };
+        assert_eq!(strip(body("def f(x) x + 1")), BinaryExpr('+', x(), one()));
+        assert_eq!(strip(body("def f(x) 1 + x")), BinaryExpr('+', one(), x()));
+        assert_eq!(
+            strip(body("def f(n: int) n + 1")),
+            BinaryExpr(
+                '+',
+                Box::new(VariableExpr("n".into()).into()),
+                Box::new(IntExpr(1).into())
+            )
+        );
+
+        // the loop variable, and so its start value, is a double because of n
+        match body("def f(x) for i = 0, i < x in i") {
+            ForExpr { start, .. } => assert_eq!(start.kind, NumberExpr(0.0)),
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+        match body("def f() for i = 0, i < 3, 0.5 in i") {
+            ForExpr { start, end, .. } => {
+                assert_eq!(start.kind, NumberExpr(0.0));
+                match end.kind {
+                    BinaryExpr(_, _, right) => assert_eq!(right.kind, NumberExpr(3.0)),
+                    kind => panic!("unexpected expression {:?}", kind),
+                }
+            }
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+        match body("def f(x) for i = 0, i < x in i") {
+            ForExpr { step, .. } => assert_eq!(step.unwrap().kind, NumberExpr(1.0)),
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+        match body("def f() for i = 0, i < 3 in i") {
+            ForExpr { step, .. } => assert_eq!(step.unwrap().kind, IntExpr(1)),
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+        match body("def f() var a = 1 in a = a + 0.5") {
+            VarExpr { vars, .. } => assert_eq!(vars[0].1.as_ref().unwrap().kind, NumberExpr(1.0)),
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn top_level_expressions_are_doubles() {
+        let func = check("1 + 2").unwrap();
+        assert_eq!(func.prototype.ret_type, Some(Type::Double));
+        assert!(matches!(func.body.kind, CastExpr(Type::Double, _)));
+
+        let func = check("1.5 + 2").unwrap();
+        assert!(matches!(func.body.kind, BinaryExpr('+', _, _)));
+    }
+
+    #[test]
+    fn user_defined_operators() {
+        assert_eq!(
+            signature("def binary| 5 (a: bool b: bool) if a then a else b; def f(x) x < 1 | x > 2"),
+            "f(x: double): bool"
+        );
+        assert_eq!(
+            signature("def unary-(v) 0 - v; def f(n: int) -double(n)"),
+            "f(n: int): double"
+        );
+        assert_eq!(
+            check("def unary-(v) 0 - v; def f(n: int) -n")
+                .unwrap_err()
+                .to_string(),
+            "Expected double but found int."
+        );
+    }
+
+    #[test]
+    fn type_errors() {
+        let error = |program: &str| check(program).unwrap_err();
+        let column = |err: Box<TypeError>| err.span().start.column;
+
+        let err = error("def f(n: int) n + 1.5");
+        assert_eq!(err.to_string(), "Expected int but found double.");
+        assert_eq!(column(err), 19);
+
+        assert_eq!(
+            error("def f(x) x + true").to_string(),
+            "Expected a number but found bool."
+        );
+        assert_eq!(
+            error("def f(b: bool) b + 1").to_string(),
+            "Expected a number but found bool."
+        );
+        assert_eq!(
+            error("def f(b: bool) 1 + b").to_string(),
+            "Expected a number but found bool."
+        );
+        assert_eq!(
+            error("def f(): bool 1").to_string(),
+            "Expected bool but found int."
+        );
+        assert_eq!(
+            error("def f(b: bool) if b then b else 1").to_string(),
+            "Expected bool but found int."
+        );
+        assert_eq!(
+            error("def f(n: int) n; f(1.5)").to_string(),
+            "Expected int but found double."
+        );
+        assert_eq!(
+            error("def f() for i = true, i in 0").to_string(),
+            "Expected a number but found bool."
+        );
+        assert_eq!(
+            error("def f(n: int) var x = n in x = 2.5").to_string(),
+            "Expected int but found double."
+        );
+        assert_eq!(
+            error("extern g(n: int): int; def f(x) if x then g(x) else 0.5").to_string(),
+            "Expected int but found double."
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let runtime = "extern concat(a: string b: string): string; extern strlen(s: string): int;";
+        let signature = |program: &str| signature(&format!("{} {}", runtime, program));
+        let error = |program: &str| {
+            check(&format!("{} {}", runtime, program))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            signature(r#"def f(s) concat(s, "!")"#),
+            "f(s: string): string"
+        );
+        assert_eq!(
+            signature(r#"def f(x) if x then "a" else "b""#),
+            "f(x: double): string"
+        );
+        assert_eq!(signature("def f(s) strlen(s) + 1"), "f(s: string): int");
+        assert_eq!(signature("def f(x) string(x)"), "f(x: string): string");
+
+        assert_eq!(
+            error(r#"def f() "a" + 1"#),
+            "Expected a number but found string."
+        );
+        assert_eq!(error("def f() string(1)"), "Expected string but found int.");
+        assert_eq!(
+            error(r#"def f() int("1")"#),
+            "Cannot convert string to int."
+        );
+        assert_eq!(
+            error("def f(s: string) if s then 1 else 2"),
+            "Cannot convert string to bool."
+        );
+        assert_eq!(
+            error("def f(s: string) for i = 0, s in 0"),
+            "Cannot convert string to bool."
+        );
+        // the type of x is only known after the cast
+        assert_eq!(
+            error("def f(x) int(x) + strlen(x)"),
+            "Cannot convert string to int."
+        );
+        assert_eq!(error(r#""hello""#), "Cannot convert string to double.");
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(signature("def f(a) a[0]"), "f(a: [double]): double");
+        assert_eq!(
+            signature("def f(a, i) a[i] + 1"),
+            "f(a: [double] i: int): double"
+        );
+        assert_eq!(signature("def f() [1, 2]"), "f(): [int]");
+        assert_eq!(signature("def f(x) [1, x]"), "f(x: double): [double]");
+        assert_eq!(signature("def f(a: [[int]]) a[0]"), "f(a: [[int]]): [int]");
+        assert_eq!(
+            signature("def f(a v) a[0] = v < 1"),
+            "f(a: [bool] v: double): bool"
+        );
+        assert_eq!(
+            signature("def f(a: [int]) a; def g() f([1])[0]"),
+            "g(): int"
+        );
+        match body("def f(a: [double]) a[0] = 1") {
+            BinaryExpr('=', _, value) => assert_eq!(value.kind, NumberExpr(1.0)),
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+
+        let error = |program: &str| check(program).unwrap_err().to_string();
+        assert_eq!(
+            error("def f(x: int) x[0]"),
+            "Expected an array but found int."
+        );
+        assert_eq!(error("def f(a) a[1.5]"), "Expected int but found double.");
+        assert_eq!(error("def f() [true, 1]"), "Expected bool but found int.");
+        assert_eq!(
+            error("def f(a: [int]) a + 1"),
+            "Expected a number but found [int]."
+        );
+        assert_eq!(
+            error("def f(a: [int]): [double] a"),
+            "Expected [double] but found [int]."
+        );
+        // an array cannot contain itself
+        assert_eq!(
+            error("def f(a) a[0] = a"),
+            "Expected double but found [double]."
+        );
+        assert_eq!(
+            error("def f(a: [int]) if a then 1 else 2"),
+            "Cannot convert [int] to bool."
+        );
+        assert_eq!(error("[1]"), "Cannot convert [int] to double.");
+    }
+
+    #[test]
+    fn structs() {
+        let point = "struct Point { x: int, y } struct Circle { center: Point, r } ";
+        let signature = |program: &str| signature(&format!("{}{}", point, program));
+        assert_eq!(signature("def f(p: Point) p.x"), "f(p: Point): int");
+        // the only struct with a field r
+        assert_eq!(
+            signature("def f(c) c.r * c.center.y"),
+            "f(c: Circle): double"
+        );
+        assert_eq!(
+            signature("def f(x) Point { x = 1, y = x }"),
+            "f(x: double): Point"
+        );
+        assert_eq!(
+            signature("def f(a) [Point { x = 1, y = 2 }, a][1].y"),
+            "f(a: Point): double"
+        );
+        match body(&format!("{}def f(c: Circle) c.center.y", point)) {
+            FieldExpr { object, index, .. } => {
+                assert_eq!(index, Some(1));
+                match object.kind {
+                    FieldExpr { index, .. } => assert_eq!(index, Some(0)),
+                    kind => panic!("unexpected expression {:?}", kind),
+                }
+            }
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+
+        let error = |program: &str| {
+            check(&format!("{}{}", point, program))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("def f(p: Point) p.z"), "Struct Point has no field z.");
+        assert_eq!(error("def f(p) p.z"), "No struct has a field z.");
+        assert_eq!(
+            error("struct Vector { x, y } def f(p) p.x"),
+            "Several structs have a field x."
+        );
+        assert_eq!(
+            error("def f(x: int) x.y"),
+            "Expected a struct but found int."
+        );
+        assert_eq!(
+            error("def f() Point { y = 1, x = 2 }"),
+            "Struct Point is constructed with the fields x, y in this order."
+        );
+        assert_eq!(
+            error("def f() Point { x = 1.5, y = 2 }"),
+            "Expected int but found double."
+        );
+        assert_eq!(error("def f() Line { a = 1 }"), "Unknown struct: Line");
+        assert_eq!(error("def f(l: Line) 1"), "Unknown type: Line");
+        assert_eq!(error("struct Line { a: Line }"), "Unknown type: Line");
+        assert_eq!(
+            error("struct Point { z }"),
+            "Type Point is already defined."
+        );
+        assert_eq!(
+            error("def f(p: Point) if p then 1 else 2"),
+            "Cannot convert Point to bool."
+        );
+        assert_eq!(
+            error("def f(p: Point, c: Circle) p = c"),
+            "Expected Point but found Circle."
+        );
+    }
+
+    #[test]
+    fn unions() {
+        let shape = "type Shape = Circle(r) | Rect(w: int, h: int) | Empty ";
+        let signature = |program: &str| signature(&format!("{}{}", shape, program));
+        assert_eq!(signature("def f(x) Circle(x)"), "f(x: double): Shape");
+        assert_eq!(signature("def f(n) Rect(n, 2)"), "f(n: int): Shape");
+        assert_eq!(
+            signature(
+                "def f(s) match s { Circle(r) -> r, Rect(w, h) -> double(w * h), Empty -> 0 }"
+            ),
+            "f(s: Shape): double"
+        );
+        assert_eq!(
+            signature("def f(s, x) match s { Rect(w, _) -> w, _ -> x }"),
+            "f(s: Shape x: int): int"
+        );
+        // a variable in a pattern is only bound in its arm
+        assert_eq!(
+            signature("def f(s, r: bool) match s { Circle(r) -> r < 1, _ -> r }"),
+            "f(s: Shape r: bool): bool"
+        );
+        // a union may hold itself
+        assert_eq!(
+            signature(
+                "type List = Nil | Cons(x: int, rest: List) \
+                 def len(l) match l { Nil -> 0, Cons(_, rest) -> 1 + len(rest) }"
+            ),
+            "len(l: List): int"
+        );
+        match body(&format!(
+            "{}def f(s) match s {{ Empty -> 0, _ -> 1 }}",
+            shape
+        )) {
+            MatchExpr { tree, .. } => assert!(tree.is_some()),
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+
+        let error = |program: &str| {
+            check(&format!("{}{}", shape, program))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("def f(s) match s { Circle(r) -> r, Empty -> 0 }"),
+            "Match is not exhaustive, Rect(_, _) is not covered."
+        );
+        assert_eq!(
+            error("def f() Rect(1)"),
+            "Variant Rect has 2 fields but 1 are given."
+        );
+        assert_eq!(
+            error("def f(s) match s { Circle -> 0, _ -> 1 }"),
+            "Variant Circle has 1 fields but 0 are given."
+        );
+        assert_eq!(
+            error("def f() Circle(true)"),
+            "Expected double but found bool."
+        );
+        assert_eq!(
+            error("type Color = Red | Green def f(s: Shape) match s { Red -> 0, _ -> 1 }"),
+            "Expected Shape but found Color."
+        );
+        assert_eq!(
+            error("def f(s) match s { Circle(r) -> r, _ -> true }"),
+            "Expected double but found bool."
+        );
+        assert_eq!(
+            error("def f(s: Shape) s.r"),
+            "Expected a struct but found Shape."
+        );
+        assert_eq!(
+            error("type Other = Empty"),
+            "Variant Empty is already defined."
+        );
+        assert_eq!(
+            error("struct Shape { x }"),
+            "Type Shape is already defined."
+        );
+        assert_eq!(error("type Line = Line(a: Path)"), "Unknown type: Path");
+        assert_eq!(
+            error("def f(s: Shape) if s then 1 else 2"),
+            "Cannot convert Shape to bool."
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let runtime = "extern sin(x); extern strlen(s: string): int; ";
+        let signature = |program: &str| signature(&format!("{}{}", runtime, program));
+        assert_eq!(signature("def f() sin"), "f(): (double) -> double");
+        assert_eq!(signature(r#"def f() var g = strlen in g("a")"#), "f(): int");
+        assert_eq!(
+            signature("def apply(g x) g(x)"),
+            "apply(g: (double) -> double x: double): double"
+        );
+        assert_eq!(
+            signature("def f(g, n: int) g(n) < 1"),
+            "f(g: (int) -> double n: int): bool"
+        );
+        assert_eq!(
+            signature("def twice(g: (int) -> int, x) g(g(x))"),
+            "twice(g: (int) -> int x: int): int"
+        );
+        assert_eq!(
+            signature(r#"def apply(g: (string) -> int, s) g(s); def f() apply(strlen, "a")"#),
+            "f(): int"
+        );
+        // a variable shadows the function with its name
+        assert_eq!(
+            signature("def f(sin: (int) -> int) sin(1)"),
+            "f(sin: (int) -> int): int"
+        );
+
+        let error = |program: &str| {
+            check(&format!("{}{}", runtime, program))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("def f(x: int) x(1)"),
+            "Expected a function of 1 args but found int."
+        );
+        assert_eq!(
+            error("def f() var g = sin in g(1, 2)"),
+            "Expected a function of 2 args but found (double) -> double."
+        );
+        assert_eq!(
+            error("def f() var g = sin in g = strlen"),
+            "Expected (double) -> double but found (string) -> int."
+        );
+        assert_eq!(
+            error("def apply(g: (int) -> int) g(1); def f() apply(sin)"),
+            "Expected (int) -> int but found (double) -> double."
+        );
+        assert_eq!(error("sin"), "Cannot convert (double) -> double to double.");
+    }
+
+    #[test]
+    fn lambdas() {
+        assert_eq!(
+            signature("def f(k: int) \\x -> x + k"),
+            "f(k: int): (int) -> int"
+        );
+        // unconstrained params are doubles, and so are the literals used with them
+        assert_eq!(signature("def f() \\x -> x + 1"), "f(): (double) -> double");
+        assert_eq!(
+            signature("def apply(g: (int, int) -> bool) g(1, 2); def f() apply(\\x y -> x < y)"),
+            "f(): bool"
+        );
+
+        // variables are captured by every lambda they are used in, in the order they are used
+        match body("def f(a b) var c = 1 in \\x -> \\y -> x + a + c + y") {
+            VarExpr { body, .. } => match body.kind {
+                LambdaExpr {
+                    param_types,
+                    ret_type,
+                    captures,
+                    body,
+                    ..
+                } => {
+                    let unary = Type::function(vec![Type::Double], Type::Double);
+                    assert_eq!(param_types, vec![Some(Type::Double)]);
+                    assert_eq!(ret_type, Some(unary));
+                    assert_eq!(
+                        captures,
+                        vec![("a".into(), Type::Double), ("c".into(), Type::Double)]
+                    );
+                    match body.kind {
+                        LambdaExpr { captures, .. } => assert_eq!(
+                            captures,
+                            vec![
+                                ("x".into(), Type::Double),
+                                ("a".into(), Type::Double),
+                                ("c".into(), Type::Double)
+                            ]
+                        ),
+                        kind => panic!("unexpected expression {:?}", kind),
+                    }
+                }
+                kind => panic!("unexpected expression {:?}", kind),
+            },
+            kind => panic!("unexpected expression {:?}", kind),
+        }
+
+        assert_eq!(
+            check("def apply(g: (double) -> double) g(1); def f() apply(\\x y -> x)")
+                .unwrap_err()
+                .to_string(),
+            "Expected (double) -> double but found (double, double) -> double."
+        );
+        assert_eq!(
+            check("def f() \\s: Shape -> 1").unwrap_err().to_string(),
+            "Unknown type: Shape"
+        );
+    }
+
+    #[test]
+    fn conflicting_sites() {
+        let program = "extern g(n: int): int; def f(x) g(x) + x * 0.5";
+        match *check(program).unwrap_err() {
+            TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Double,
+                span,
+                expected_origin,
+                found_origin,
+            } => {
+                assert_eq!(span.start.column, 44);
+                // x is an int because of the call to g
+                assert_eq!(expected_origin.start.column, 35);
+                assert_eq!(found_origin, span);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let diagnostic = Diagnostic::from(check(program).unwrap_err());
+        assert_eq!(diagnostic.labels.len(), 1);
+        assert_eq!(diagnostic.labels[0].message, "this is int");
+
+        // the origin of a type is where it is annotated
+        match *check("def f(n: int) n + 1.5").unwrap_err() {
+            TypeError::Mismatch {
+                expected_origin, ..
+            } => assert_eq!(expected_origin.start.column, 5),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+}
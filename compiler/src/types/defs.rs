@@ -0,0 +1,166 @@
+use super::{Type, TypeError};
+use crate::parser::nodes::{Prototype, StructDef, UnionDef};
+use crate::util::Span;
+use std::collections::HashMap;
+
+/// The structs and the tagged unions defined so far, which types can name
+#[derive(Clone, Debug, Default)]
+pub struct TypeDefs {
+    structs: HashMap<String, StructDef>,
+    unions: HashMap<String, UnionDef>,
+    /// The union of every variant and its tag
+    variants: HashMap<String, (String, usize)>,
+}
+
+impl TypeDefs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check that the types of the fields of a struct are defined,
+    /// and that no type has its name yet, before adding it
+    pub fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<TypeError>> {
+        self.check_new(&def.name, def.span)?;
+        for ty in &def.field_types {
+            self.check_defined(ty, def.span)?;
+        }
+        self.structs.insert(def.name.clone(), def.clone());
+        Ok(())
+    }
+
+    /// Check that the types of the fields of the variants of a union are defined,
+    /// and that no type or variant has their names yet, before adding it.
+    /// A variant may contain its own union, e.g. the subtrees of a node of a tree.
+    pub fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<TypeError>> {
+        self.check_new(&def.name, def.span)?;
+        for variant in &def.variants {
+            if let Some((union, tag)) = self.variants.get(&variant.name) {
+                return Err(Box::new(TypeError::VariantRedefinition {
+                    name: variant.name.clone(),
+                    span: variant.span,
+                    previous: self.unions[union].variants[*tag].span,
+                }));
+            }
+            for ty in &variant.field_types {
+                self.check_defined_in(ty, variant.span, Some(&def.name))?;
+            }
+        }
+
+        for (tag, variant) in def.variants.iter().enumerate() {
+            self.variants
+                .insert(variant.name.clone(), (def.name.clone(), tag));
+        }
+        self.unions.insert(def.name.clone(), def.clone());
+        Ok(())
+    }
+
+    pub fn get_struct(&self, name: &str) -> Option<&StructDef> {
+        self.structs.get(name)
+    }
+
+    pub fn get_union(&self, name: &str) -> Option<&UnionDef> {
+        self.unions.get(name)
+    }
+
+    pub fn structs(&self) -> impl Iterator<Item = &StructDef> {
+        self.structs.values()
+    }
+
+    /// The union of a variant and its tag
+    pub fn variant(&self, name: &str) -> Option<(&UnionDef, usize)> {
+        let (union, tag) = self.variants.get(name)?;
+        Some((&self.unions[union], *tag))
+    }
+
+    /// Check that the types in the annotations of a prototype are defined
+    pub fn check_annotations(&self, proto: &Prototype) -> Result<(), Box<TypeError>> {
+        for ty in proto.arg_types.iter().chain([&proto.ret_type]).flatten() {
+            self.check_defined(ty, proto.span)?;
+        }
+        Ok(())
+    }
+
+    /// Check the annotations of an extern, whose structs and unions are passed by value as in C.
+    /// They are only passed as in the x86-64 System V ABI,
+    /// and structs with bools cannot be passed, as a bool takes 1 byte in C
+    /// while small structs are passed in registers holding 8 bytes each.
+    pub fn check_extern(&self, proto: &Prototype) -> Result<(), Box<TypeError>> {
+        self.check_annotations(proto)?;
+        for ty in proto.arg_types.iter().chain([&proto.ret_type]).flatten() {
+            let reason = match ty {
+                Type::Named(_) if !cfg!(all(target_arch = "x86_64", unix)) => {
+                    "only the x86-64 System V ABI is supported"
+                }
+                Type::Named(_) if self.has_bool(ty) => "a bool field is not passed as in C",
+                _ => continue,
+            };
+            return Err(Box::new(TypeError::NotCCompatible {
+                ty: ty.clone(),
+                reason,
+                span: proto.span,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Whether a value of a type holds a bool, including in the fields of nested structs
+    fn has_bool(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Bool => true,
+            Type::Named(name) => self
+                .structs
+                .get(&**name)
+                .is_some_and(|def| def.field_types.iter().any(|field| self.has_bool(field))),
+            _ => false,
+        }
+    }
+
+    /// Check that no struct or union is named `name` yet
+    fn check_new(&self, name: &str, span: Span) -> Result<(), Box<TypeError>> {
+        let previous = match (self.structs.get(name), self.unions.get(name)) {
+            (Some(def), _) => def.span,
+            (_, Some(def)) => def.span,
+            (None, None) => return Ok(()),
+        };
+        Err(Box::new(TypeError::TypeRedefinition {
+            name: name.into(),
+            span,
+            previous,
+        }))
+    }
+
+    /// Check that the structs and unions in a type written at `span` are defined,
+    /// including the ones in arrays and functions
+    pub fn check_defined(&self, ty: &Type, span: Span) -> Result<(), Box<TypeError>> {
+        self.check_defined_in(ty, span, None)
+    }
+
+    /// Check that the structs and unions in a type are defined, or are the union being defined
+    fn check_defined_in(
+        &self,
+        ty: &Type,
+        span: Span,
+        union: Option<&str>,
+    ) -> Result<(), Box<TypeError>> {
+        match ty {
+            Type::Array(element) => self.check_defined_in(element, span, union),
+            Type::Function(function) => {
+                for ty in function.params.iter().chain([&function.ret]) {
+                    self.check_defined_in(ty, span, union)?;
+                }
+                Ok(())
+            }
+            Type::Named(name)
+                if Some(&**name) != union
+                    && !self.structs.contains_key(&**name)
+                    && !self.unions.contains_key(&**name) =>
+            {
+                Err(Box::new(TypeError::UnknownType {
+                    name: name.to_string(),
+                    span,
+                }))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use super::Type;
+use crate::diagnostics::Diagnostic;
+use crate::util::Span;
+use std::fmt;
+
+/// An expression whose type does not fit where it is used.
+/// The origins are the expressions or annotations the conflicting types were inferred from.
+#[derive(PartialEq, Clone, Debug)]
+pub enum TypeError {
+    Mismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+        expected_origin: Span,
+        found_origin: Span,
+    },
+    /// A bool used in arithmetic, a comparison or as a loop variable
+    NotNumeric {
+        found: Type,
+        span: Span,
+        origin: Span,
+    },
+    /// A value indexed with `[]` which is not an array
+    NotAnArray {
+        found: Type,
+        span: Span,
+        origin: Span,
+    },
+    /// A variable called which does not hold a function taking as many args as it is given
+    NotAFunction {
+        args: usize,
+        found: Type,
+        span: Span,
+        origin: Span,
+    },
+    /// A value whose field is accessed with `.` which is not a struct
+    NotAStruct {
+        found: Type,
+        span: Span,
+        origin: Span,
+    },
+    /// A cast or a condition converting a string, an array or a struct to another type,
+    /// or the other way around
+    InvalidConversion { from: Type, to: Type, span: Span },
+    /// A struct constructed without being defined
+    UnknownStruct { name: String, span: Span },
+    /// A struct or a union written in an annotation without being defined
+    UnknownType { name: String, span: Span },
+    /// A struct or a union with the name of a type already defined
+    TypeRedefinition {
+        name: String,
+        span: Span,
+        previous: Span,
+    },
+    /// A variant with the name of a variant of another union
+    VariantRedefinition {
+        name: String,
+        span: Span,
+        previous: Span,
+    },
+    /// A variant constructed or matched whose union is not defined
+    UnknownVariant { name: String, span: Span },
+    /// A variant constructed or matched with a wrong number of fields
+    VariantArity {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// A match whose arms do not cover every value, `missing` being one of them written as a pattern
+    NonExhaustive { missing: String, span: Span },
+    /// A field of a struct which does not have it,
+    /// or of a value whose struct is unknown if no struct has it
+    UnknownField {
+        field: String,
+        ty: Option<Type>,
+        span: Span,
+    },
+    /// A field of a value whose struct is unknown, which several structs have
+    AmbiguousField { field: String, span: Span },
+    /// A struct constructed without its fields in the order they are declared
+    FieldMismatch {
+        ty: Type,
+        fields: Vec<String>,
+        span: Span,
+    },
+    /// A struct or a union passed to or returned from an extern, which C would not pass the same way
+    NotCCompatible {
+        ty: Type,
+        reason: &'static str,
+        span: Span,
+    },
+    /// A function whose gradient is taken, whose derivative cannot be generated
+    NotDifferentiable {
+        name: String,
+        reason: String,
+        span: Span,
+    },
+}
+
+impl TypeError {
+    pub fn span(&self) -> Span {
+        match self {
+            TypeError::Mismatch { span, .. }
+            | TypeError::NotNumeric { span, .. }
+            | TypeError::NotAnArray { span, .. }
+            | TypeError::NotAFunction { span, .. }
+            | TypeError::NotAStruct { span, .. }
+            | TypeError::InvalidConversion { span, .. }
+            | TypeError::UnknownStruct { span, .. }
+            | TypeError::UnknownType { span, .. }
+            | TypeError::TypeRedefinition { span, .. }
+            | TypeError::VariantRedefinition { span, .. }
+            | TypeError::UnknownVariant { span, .. }
+            | TypeError::VariantArity { span, .. }
+            | TypeError::NonExhaustive { span, .. }
+            | TypeError::UnknownField { span, .. }
+            | TypeError::AmbiguousField { span, .. }
+            | TypeError::FieldMismatch { span, .. }
+            | TypeError::NotCCompatible { span, .. }
+            | TypeError::NotDifferentiable { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::Mismatch {
+                expected, found, ..
+            } => write!(f, "Expected {} but found {}.", expected, found),
+            TypeError::NotNumeric { found, .. } => {
+                write!(f, "Expected a number but found {}.", found)
+            }
+            TypeError::NotAnArray { found, .. } => {
+                write!(f, "Expected an array but found {}.", found)
+            }
+            TypeError::NotAFunction { args, found, .. } => {
+                write!(
+                    f,
+                    "Expected a function of {} args but found {}.",
+                    args, found
+                )
+            }
+            TypeError::NotAStruct { found, .. } => {
+                write!(f, "Expected a struct but found {}.", found)
+            }
+            TypeError::InvalidConversion { from, to, .. } => {
+                write!(f, "Cannot convert {} to {}.", from, to)
+            }
+            TypeError::UnknownStruct { name, .. } => write!(f, "Unknown struct: {}", name),
+            TypeError::UnknownType { name, .. } => write!(f, "Unknown type: {}", name),
+            TypeError::TypeRedefinition { name, .. } => {
+                write!(f, "Type {} is already defined.", name)
+            }
+            TypeError::VariantRedefinition { name, .. } => {
+                write!(f, "Variant {} is already defined.", name)
+            }
+            TypeError::UnknownVariant { name, .. } => write!(f, "Unknown variant: {}", name),
+            TypeError::VariantArity {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "Variant {} has {} fields but {} are given.",
+                name, expected, found
+            ),
+            TypeError::NonExhaustive { missing, .. } => {
+                write!(f, "Match is not exhaustive, {} is not covered.", missing)
+            }
+            TypeError::UnknownField {
+                field,
+                ty: Some(ty),
+                ..
+            } => write!(f, "Struct {} has no field {}.", ty, field),
+            TypeError::UnknownField {
+                field, ty: None, ..
+            } => {
+                write!(f, "No struct has a field {}.", field)
+            }
+            TypeError::AmbiguousField { field, .. } => {
+                write!(f, "Several structs have a field {}.", field)
+            }
+            TypeError::FieldMismatch { ty, fields, .. } => write!(
+                f,
+                "Struct {} is constructed with the fields {} in this order.",
+                ty,
+                fields.join(", ")
+            ),
+            TypeError::NotCCompatible { ty, reason, .. } => {
+                write!(f, "Cannot pass {} to or from an extern: {}.", ty, reason)
+            }
+            TypeError::NotDifferentiable { name, reason, .. } => {
+                write!(f, "Cannot differentiate {}: {}.", name, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+impl From<Box<TypeError>> for Diagnostic {
+    fn from(err: Box<TypeError>) -> Self {
+        Diagnostic::from(*err)
+    }
+}
+
+impl From<Box<TypeError>> for Box<Diagnostic> {
+    fn from(err: Box<TypeError>) -> Self {
+        Box::new(Diagnostic::from(*err))
+    }
+}
+
+impl From<TypeError> for Diagnostic {
+    fn from(err: TypeError) -> Self {
+        let span = err.span();
+        let diagnostic = Diagnostic::error(err.to_string(), span);
+        // point at where the types come from when it is not the expression itself
+        let label = |diagnostic: Diagnostic, origin: Span, ty: Type| {
+            if origin == span || origin == Span::default() {
+                diagnostic
+            } else {
+                diagnostic.with_label(origin, format!("this is {}", ty))
+            }
+        };
+        match err {
+            TypeError::Mismatch {
+                expected,
+                found,
+                expected_origin,
+                found_origin,
+                ..
+            } => {
+                let diagnostic = label(
+                    label(diagnostic, expected_origin, expected.clone()),
+                    found_origin,
+                    found,
+                );
+                // functions cannot be converted
+                match expected {
+                    Type::Function(_) => diagnostic,
+                    _ => diagnostic.with_note(format!("use `{}(...)` to convert it", expected)),
+                }
+            }
+            TypeError::NotNumeric { found, origin, .. }
+            | TypeError::NotAnArray { found, origin, .. }
+            | TypeError::NotAFunction { found, origin, .. }
+            | TypeError::NotAStruct { found, origin, .. } => label(diagnostic, origin, found),
+            TypeError::TypeRedefinition { previous, .. }
+            | TypeError::VariantRedefinition { previous, .. } => {
+                diagnostic.with_label(previous, "previously defined here")
+            }
+            TypeError::NonExhaustive { .. } => {
+                diagnostic.with_note("add an arm for it, or one with `_` for every other value")
+            }
+            TypeError::AmbiguousField { .. } => {
+                diagnostic.with_note("annotate the type of the value")
+            }
+            _ => diagnostic,
+        }
+    }
+}
@@ -0,0 +1,9 @@
+mod checker;
+mod defs;
+mod error;
+mod ty;
+
+pub use checker::*;
+pub use defs::TypeDefs;
+pub use error::TypeError;
+pub use ty::{FunctionType, Type};
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// Type of a value
+#[derive(PartialEq, Eq, Clone, Debug, Hash)]
+pub enum Type {
+    /// 64-bit signed integer
+    Int,
+    Bool,
+    /// 64-bit float, the type of everything not annotated
+    Double,
+    /// An immutable, NUL-terminated UTF-8 string, a pointer to its bytes
+    Str,
+    /// A fixed-size array of elements of a type, shared by reference. Created with `Type::array`.
+    Array(Rc<Type>),
+    /// A struct declared with `struct` or a tagged union declared with `type`, by name.
+    /// Created with `Type::named`.
+    Named(Rc<str>),
+    /// A function taking args of some types and returning a value of a type,
+    /// e.g. `(double, int) -> bool`. Created with `Type::function`.
+    Function(Rc<FunctionType>),
+}
+
+/// The types of the args and of the return value of functions
+#[derive(PartialEq, Eq, Debug, Hash)]
+pub struct FunctionType {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
+impl Type {
+    /// The type of arrays of `element`
+    pub fn array(element: Type) -> Type {
+        Type::Array(Rc::new(element))
+    }
+
+    /// The type of the structs or the tagged unions named `name`
+    pub fn named(name: &str) -> Type {
+        Type::Named(name.into())
+    }
+
+    /// The type of functions taking args of types `params` and returning a value of type `ret`
+    pub fn function(params: Vec<Type>, ret: Type) -> Type {
+        Type::Function(Rc::new(FunctionType { params, ret }))
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        *self == Type::Int || *self == Type::Double
+    }
+
+    /// Whether the type is an int, a bool or a double
+    pub fn is_scalar(&self) -> bool {
+        self.is_numeric() || *self == Type::Bool
+    }
+
+    /// Whether a value of the type can be converted into `ty` by a cast or a condition,
+    /// strings, arrays, structs, unions and functions being only convertible to themselves
+    pub fn converts_to(&self, ty: &Type) -> bool {
+        self == ty || (self.is_scalar() && ty.is_scalar())
+    }
+}
+
+/// Formats the type as it is written in the source
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Double => write!(f, "double"),
+            Type::Str => write!(f, "string"),
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::Named(name) => write!(f, "{}", name),
+            Type::Function(function) => {
+                write!(f, "(")?;
+                for (i, param) in function.params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ") -> {}", function.ret)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrays() {
+        let matrix = Type::array(Type::array(Type::Double));
+        assert_eq!(matrix, Type::array(Type::array(Type::Double)));
+        assert_ne!(matrix, Type::array(Type::Double));
+        assert_eq!(matrix.to_string(), "[[double]]");
+
+        assert!(Type::Int.converts_to(&Type::Bool));
+        assert!(matrix.converts_to(&matrix));
+        assert!(!matrix.converts_to(&Type::Bool));
+        assert!(!Type::Str.converts_to(&Type::array(Type::Str)));
+    }
+
+    #[test]
+    fn structs() {
+        let point = Type::named("Point");
+        assert_eq!(point, Type::named(&String::from("Point")));
+        assert_ne!(point, Type::named("Vector"));
+        assert_eq!(Type::array(point.clone()).to_string(), "[Point]");
+        assert!(!point.converts_to(&Type::Bool));
+    }
+
+    #[test]
+    fn functions() {
+        let unary = Type::function(vec![Type::Double], Type::Double);
+        assert_eq!(unary, Type::function(vec![Type::Double], Type::Double));
+        assert_ne!(unary, Type::function(vec![Type::Int], Type::Double));
+        assert_eq!(unary.to_string(), "(double) -> double");
+        assert_eq!(
+            Type::function(
+                vec![unary.clone(), Type::Int],
+                Type::function(vec![], Type::Bool)
+            )
+            .to_string(),
+            "((double) -> double, int) -> () -> bool"
+        );
+        assert!(!unary.converts_to(&Type::Double));
+    }
+}
@@ -1,37 +1,50 @@
 pub struct Buffer<I, T: Iterator<Item = I>> {
     iter: T,
     curr: Option<I>,
-    next: Option<I>,
+    /// The lookahead is only pulled from `iter` when `peek` is called,
+    /// so interactive sources (e.g. stdin) are never read further than needed.
+    next: Option<Option<I>>,
 }
 
 impl<I, T: Iterator<Item = I>> Buffer<I, T> {
     pub fn new(mut iter: T) -> Self {
         let curr = iter.next();
-        let next = iter.next();
-        Buffer { iter, curr, next }
+        Buffer {
+            iter,
+            curr,
+            next: None,
+        }
     }
 
     pub fn curr(&self) -> Option<&I> {
         self.curr.as_ref()
     }
 
-    pub fn peek(&self) -> Option<&I> {
-        self.next.as_ref()
+    pub fn peek(&mut self) -> Option<&I> {
+        let iter = &mut self.iter;
+        self.next.get_or_insert_with(|| iter.next()).as_ref()
     }
 
     pub fn advance(&mut self) {
-        self.curr = self.next.take();
-        self.next = self.iter.next();
+        self.curr = match self.next.take() {
+            Some(next) => next,
+            None => self.iter.next(),
+        };
     }
 
     pub fn iter(&mut self) -> &T {
         &self.iter
     }
+
+    pub fn iter_mut(&mut self) -> &mut T {
+        &mut self.iter
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
 
     #[test]
     fn string_test() {
@@ -67,4 +80,20 @@ mod tests {
         assert_eq!(buffer.curr(), None);
         assert_eq!(buffer.peek(), None);
     }
+
+    #[test]
+    fn lazy_lookahead() {
+        let pulled = Cell::new(0);
+        let mut buffer = Buffer::new("123".chars().inspect(|_| pulled.set(pulled.get() + 1)));
+
+        assert_eq!(pulled.get(), 1);
+        buffer.advance();
+        assert_eq!(buffer.curr(), Some(&'2'));
+        assert_eq!(pulled.get(), 2);
+        assert_eq!(buffer.peek(), Some(&'3'));
+        assert_eq!(pulled.get(), 3);
+        buffer.advance();
+        assert_eq!(buffer.curr(), Some(&'3'));
+        assert_eq!(pulled.get(), 3);
+    }
 }
@@ -1,4 +1,6 @@
 pub mod buffer;
 pub mod or;
+pub mod span;
 
 pub use buffer::Buffer;
+pub use span::{Position, Span};
@@ -0,0 +1,103 @@
+/// A location in the source
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Position {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number, counted in chars
+    pub column: usize,
+}
+
+impl Default for Position {
+    /// The start of the source
+    fn default() -> Self {
+        Position {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+impl Position {
+    /// The position right after `c`, if `c` is at this position
+    pub fn after(self, c: char) -> Self {
+        if c == '\n' {
+            Position {
+                offset: self.offset + c.len_utf8(),
+                line: self.line + 1,
+                column: 1,
+            }
+        } else {
+            Position {
+                offset: self.offset + c.len_utf8(),
+                line: self.line,
+                column: self.column + 1,
+            }
+        }
+    }
+}
+
+/// A range in the source, from `start` (inclusive) to `end` (exclusive)
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Span { start, end }
+    }
+
+    /// An empty span at `pos`
+    pub fn at(pos: Position) -> Self {
+        Span::new(pos, pos)
+    }
+
+    /// The span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Self {
+        let start = if self.start.offset <= other.start.offset {
+            self.start
+        } else {
+            other.start
+        };
+        let end = if self.end.offset >= other.end.offset {
+            self.end
+        } else {
+            other.end
+        };
+        Span::new(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let pos = Position::default().after('a').after('\n').after('∂');
+
+        assert_eq!(
+            pos,
+            Position {
+                offset: 5,
+                line: 2,
+                column: 2
+            }
+        );
+    }
+
+    #[test]
+    fn merge_spans() {
+        let a = Position::default();
+        let b = a.after('a');
+        let c = b.after('b');
+
+        assert_eq!(Span::new(a, b).to(Span::new(b, c)), Span::new(a, c));
+        assert_eq!(Span::new(b, c).to(Span::new(a, b)), Span::new(a, c));
+        assert_eq!(Span::new(a, c).to(Span::at(b)), Span::new(a, c));
+    }
+}
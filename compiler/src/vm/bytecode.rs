@@ -0,0 +1,210 @@
+use crate::interp::Value;
+use crate::types::Type;
+use crate::util::Position;
+use std::fmt;
+
+/// An instruction of the stack machine.
+/// Operands are indices into the constant pool, the local slots of the frame,
+/// the function table of the machine, the code of the chunk or its positions.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Op {
+    /// Push a constant
+    Constant(u16),
+    /// Push the value of a local slot
+    Load(u16),
+    /// Store the top of the stack into a local slot, without popping it
+    Store(u16),
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Pop rhs and lhs, push true if lhs < rhs or either is NaN
+    LessThan,
+    /// Pop rhs and lhs, push true if lhs > rhs or either is NaN
+    GreaterThan,
+    /// Pop a value, push it converted to a type
+    Cast(Type),
+    /// Pop a number of values, push an array of them in the order they were pushed
+    Array(u16),
+    /// Pop an index and an array, push the element at the index.
+    /// Fails at a position of the chunk if the index is out of bounds.
+    Index(u16),
+    /// Pop an index and an array, store the top of the stack into the element without popping it.
+    /// Fails at a position of the chunk if the index is out of bounds.
+    StoreIndex(u16),
+    /// Pop a number of values, push a struct of the type made of them
+    Struct(Type, u16),
+    /// Pop a struct or a variant, push its field at an index
+    Field(u16),
+    /// Pop a number of values, push a variant of the union with the tag made of them
+    Variant(Type, u16, u16),
+    Jump(u16),
+    /// Pop a value, jump if it is false, zero or NaN
+    JumpIfFalse(u16),
+    /// Pop a variant, jump to the second operand if its tag is not the first one
+    JumpIfNotTag(u16, u16),
+    /// Call a function with its arguments on top of the stack, replacing them with the result
+    Call(u16),
+    /// Pop a function, call it with as many arguments on top of the stack as the operand,
+    /// after the values it captured, replacing them with the result
+    CallValue(u16),
+    /// Pop a number of values and a function, push the function capturing them
+    Closure(u16),
+    /// Return the top of the stack to the caller
+    Return,
+}
+
+/// The compiled code of a function
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Chunk {
+    pub name: String,
+    pub code: Vec<Op>,
+    pub constants: Vec<Value>,
+    /// Number of arguments, which are in the first local slots
+    pub arity: usize,
+    /// Number of local slots, including the arguments
+    pub locals: usize,
+    /// Where arrays are indexed in the source, to report indices out of bounds
+    pub positions: Vec<Position>,
+}
+
+impl Chunk {
+    /// Add a constant to the pool, reusing an identical one if any
+    pub fn add_constant(&mut self, value: Value) -> Result<u16, String> {
+        let index = match self
+            .constants
+            .iter()
+            .position(|constant| match (constant, &value) {
+                // tell 0.0 from -0.0, and reuse NaN
+                (Value::Double(constant), Value::Double(value)) => {
+                    constant.to_bits() == value.to_bits()
+                }
+                (constant, value) => constant == value,
+            }) {
+            Some(index) => index,
+            None => {
+                self.constants.push(value);
+                self.constants.len() - 1
+            }
+        };
+        to_operand(index, "constants")
+    }
+
+    /// Add a position in the source, returning its index
+    pub fn add_position(&mut self, pos: Position) -> Result<u16, String> {
+        self.positions.push(pos);
+        to_operand(self.positions.len() - 1, "array accesses")
+    }
+
+    /// Append an op, returning its index
+    pub fn push(&mut self, op: Op) -> Result<u16, String> {
+        self.code.push(op);
+        to_operand(self.code.len() - 1, "ops")
+    }
+
+    /// The index of the next op to be pushed, e.g. as the target of a jump
+    pub fn next_index(&self) -> Result<u16, String> {
+        to_operand(self.code.len(), "ops")
+    }
+}
+
+/// Convert an index into an operand, failing if the chunk has grown too big
+pub fn to_operand(index: usize, what: &str) -> Result<u16, String> {
+    if index > u16::MAX as usize {
+        Err(format!(
+            "Too many {}, at most {} are supported.",
+            what,
+            u16::MAX as usize + 1
+        ))
+    } else {
+        Ok(index as u16)
+    }
+}
+
+/// Disassembles the chunk, one op per line
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "chunk {} (arity {}, locals {})",
+            self.name, self.arity, self.locals
+        )?;
+        for (index, op) in self.code.iter().enumerate() {
+            match op {
+                Op::Constant(constant) => writeln!(
+                    f,
+                    "{:04} Constant {} ({})",
+                    index, constant, self.constants[*constant as usize]
+                )?,
+                Op::Index(position) | Op::StoreIndex(position) => {
+                    let pos = self.positions[*position as usize];
+                    writeln!(f, "{:04} {:?} ({}:{})", index, op, pos.line, pos.column)?
+                }
+                Op::Struct(ty, count) => writeln!(f, "{:04} Struct({}, {})", index, ty, count)?,
+                Op::Variant(ty, tag, count) => {
+                    writeln!(f, "{:04} Variant({}, {}, {})", index, ty, tag, count)?
+                }
+                op => writeln!(f, "{:04} {:?}", index, op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constants_are_reused() {
+        let mut chunk = Chunk::default();
+
+        assert_eq!(chunk.add_constant(Value::Double(1.0)), Ok(0));
+        assert_eq!(chunk.add_constant(Value::Double(2.0)), Ok(1));
+        assert_eq!(chunk.add_constant(Value::Double(1.0)), Ok(0));
+        assert_eq!(chunk.add_constant(Value::Double(-0.0)), Ok(2));
+        assert_eq!(chunk.add_constant(Value::Double(0.0)), Ok(3));
+        assert_eq!(chunk.add_constant(Value::Int(1)), Ok(4));
+        assert_eq!(chunk.add_constant(Value::Double(f64::NAN)), Ok(5));
+        assert_eq!(chunk.add_constant(Value::Double(f64::NAN)), Ok(5));
+        assert_eq!(
+            &chunk.constants[..5],
+            &[
+                Value::Double(1.0),
+                Value::Double(2.0),
+                Value::Double(-0.0),
+                Value::Double(0.0),
+                Value::Int(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn disassemble() {
+        let mut chunk = Chunk {
+            name: "inc".into(),
+            arity: 1,
+            locals: 1,
+            ..Default::default()
+        };
+        let one = chunk.add_constant(Value::Int(1)).unwrap();
+        chunk.push(Op::Load(0)).unwrap();
+        chunk.push(Op::Constant(one)).unwrap();
+        chunk.push(Op::Add).unwrap();
+        chunk.push(Op::Cast(Type::Double)).unwrap();
+        chunk.push(Op::Return).unwrap();
+
+        assert_eq!(
+            chunk.to_string(),
+            "\
+chunk inc (arity 1, locals 1)
+0000 Load(0)
+0001 Constant 0 (1)
+0002 Add
+0003 Cast(Double)
+0004 Return
+"
+        );
+    }
+}
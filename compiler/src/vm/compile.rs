@@ -0,0 +1,775 @@
+use super::bytecode::{to_operand, Chunk, Op};
+use crate::diagnostics::SpannedError;
+use crate::interp::Value;
+use crate::matching::Decision;
+use crate::parser::nodes::{Expression, ExpressionKind, Function, Pattern};
+use crate::types::{Type, TypeDefs};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Where a function is in the function table of the machine, how many arguments it takes
+/// and its type as a value
+#[derive(PartialEq, Clone, Debug)]
+pub struct Signature {
+    pub index: u16,
+    pub arity: usize,
+    pub ty: Type,
+}
+
+/// Compiles a function into a chunk,
+/// resolving calls against the functions known so far
+struct ChunkCompiler<'a> {
+    signatures: &'a HashMap<String, Signature>,
+    /// The errors of the functions that could not be defined, reported again when they are used
+    failed: &'a HashMap<String, SpannedError>,
+    type_defs: &'a TypeDefs,
+    chunk: Chunk,
+    /// Slots of the variables in scope
+    slots: HashMap<String, u16>,
+}
+
+/// Compile a function, which may call any function in `signatures`, including itself,
+/// and use the variants of the unions in `type_defs`.
+/// The function must be type checked, so that the operands of every op have the same type.
+/// Using a function of `failed` fails with the error of its definition.
+pub fn compile_function(
+    func: &Function,
+    signatures: &HashMap<String, Signature>,
+    failed: &HashMap<String, SpannedError>,
+    type_defs: &TypeDefs,
+) -> Result<Chunk, SpannedError> {
+    let mut compiler = ChunkCompiler {
+        signatures,
+        failed,
+        type_defs,
+        chunk: Chunk {
+            name: func.prototype.name.clone(),
+            arity: func.prototype.args.len(),
+            ..Default::default()
+        },
+        slots: HashMap::new(),
+    };
+
+    // the arguments are in the first slots
+    for arg in &func.prototype.args {
+        let slot = compiler.new_slot()?;
+        compiler.slots.insert(arg.clone(), slot);
+    }
+
+    compiler.compile_expr(&func.body)?;
+    compiler.chunk.push(Op::Return)?;
+
+    Ok(compiler.chunk)
+}
+
+impl<'a> ChunkCompiler<'a> {
+    /// Allocate a local slot, which is never reused in the function
+    fn new_slot(&mut self) -> Result<u16, String> {
+        self.chunk.locals += 1;
+        to_operand(self.chunk.locals - 1, "locals")
+    }
+
+    /// Pop the top of the stack into a new slot
+    fn store_into_new_slot(&mut self) -> Result<u16, String> {
+        let slot = self.new_slot()?;
+        self.chunk.push(Op::Store(slot))?;
+        self.chunk.push(Op::Pop)?;
+        Ok(slot)
+    }
+
+    fn push_constant(&mut self, value: Value) -> Result<(), String> {
+        let constant = self.chunk.add_constant(value)?;
+        self.chunk.push(Op::Constant(constant))?;
+        Ok(())
+    }
+
+    fn get_slot(&self, var: &str) -> Result<u16, String> {
+        self.slots
+            .get(var)
+            .copied()
+            .ok_or(format!("Unknown variable name: {}", var))
+    }
+
+    /// Point a previously pushed jump at `target`
+    fn patch_jump(&mut self, jump: u16, target: u16) {
+        match &mut self.chunk.code[jump as usize] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::JumpIfNotTag(_, to) => *to = target,
+            op => unreachable!("{:?} is not a jump", op),
+        }
+    }
+
+    /// Push the function call, with the arguments already on the stack
+    fn push_call(&mut self, name: &str) -> Result<(), String> {
+        let signature = &self.signatures[name];
+        self.chunk.push(Op::Call(signature.index))?;
+        Ok(())
+    }
+
+    /// The error of a function not found, which is the error of its definition if it failed
+    fn unknown(&self, name: &str, message: String) -> SpannedError {
+        self.failed
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| message.into())
+    }
+
+    /// Generate code leaving the value of the expression on top of the stack.
+    /// An error is at the expression, unless a part of it causes it.
+    fn compile_expr(&mut self, expr: &Expression) -> Result<(), SpannedError> {
+        self.compile_expr_kind(expr)
+            .map_err(|err| err.or_at(expr.span))
+    }
+
+    fn compile_expr_kind(&mut self, expr: &Expression) -> Result<(), SpannedError> {
+        match &expr.kind {
+            ExpressionKind::NumberExpr(num) => self.push_constant(Value::Double(*num))?,
+            ExpressionKind::IntExpr(num) => self.push_constant(Value::Int(*num))?,
+            ExpressionKind::BoolExpr(value) => self.push_constant(Value::Bool(*value))?,
+            ExpressionKind::StringExpr(value) => {
+                self.push_constant(Value::Str(value.as_str().into()))?
+            }
+            ExpressionKind::VariableExpr(var) => match self.slots.get(var) {
+                Some(&slot) => {
+                    self.chunk.push(Op::Load(slot))?;
+                }
+                // a function used as a value
+                None => match self.signatures.get(var) {
+                    Some(signature) => self.push_constant(Value::Function(
+                        signature.ty.clone(),
+                        var.as_str().into(),
+                        Rc::new([]),
+                    ))?,
+                    None => {
+                        return Err(self.unknown(var, format!("Unknown variable name: {}", var)))
+                    }
+                },
+            },
+            ExpressionKind::BinaryExpr('=', left, right) => {
+                // the destination must be a variable, which should not be evaluated, or an array element
+                let var = match &left.kind {
+                    ExpressionKind::VariableExpr(var) => var,
+                    ExpressionKind::IndexExpr(array, index) => {
+                        // the value is evaluated first, as for a variable
+                        self.compile_expr(right)?;
+                        self.compile_expr(array)?;
+                        self.compile_expr(index)?;
+                        let position = self.chunk.add_position(left.span.start)?;
+                        self.chunk.push(Op::StoreIndex(position))?;
+                        return Ok(());
+                    }
+                    _ => {
+                        return Err(SpannedError::new(
+                            "Destination of '=' must be a variable or an array element.",
+                            left.span,
+                        ))
+                    }
+                };
+
+                self.compile_expr(right)?;
+                let slot = self
+                    .get_slot(var)
+                    .map_err(|err| SpannedError::new(err, left.span))?;
+                self.chunk.push(Op::Store(slot))?;
+            }
+            ExpressionKind::BinaryExpr(op, left, right) => {
+                self.compile_expr(left)?;
+                self.compile_expr(right)?;
+                let op = match op {
+                    '+' => Op::Add,
+                    '-' => Op::Sub,
+                    '*' => Op::Mul,
+                    '/' => Op::Div,
+                    '<' => Op::LessThan,
+                    '>' => Op::GreaterThan,
+                    // fall back to user defined operators
+                    _ => {
+                        let name = format!("binary{}", op);
+                        if !self.signatures.contains_key(&name) {
+                            return Err(format!("Unknown binary op {}", op).into());
+                        }
+                        return Ok(self.push_call(&name)?);
+                    }
+                };
+                self.chunk.push(op)?;
+            }
+            ExpressionKind::UnaryExpr(op, operand) => {
+                self.compile_expr(operand)?;
+                let name = format!("unary{}", op);
+                if !self.signatures.contains_key(&name) {
+                    return Err(format!("Unknown unary op {}", op).into());
+                }
+                self.push_call(&name)?;
+            }
+            ExpressionKind::CastExpr(ty, operand) => {
+                self.compile_expr(operand)?;
+                self.chunk.push(Op::Cast(ty.clone()))?;
+            }
+            ExpressionKind::ArrayExpr(elements) => {
+                for element in elements {
+                    self.compile_expr(element)?;
+                }
+                let count = to_operand(elements.len(), "array elements")?;
+                self.chunk.push(Op::Array(count))?;
+            }
+            ExpressionKind::IndexExpr(array, index) => {
+                self.compile_expr(array)?;
+                self.compile_expr(index)?;
+                let position = self.chunk.add_position(expr.span.start)?;
+                self.chunk.push(Op::Index(position))?;
+            }
+            ExpressionKind::StructExpr(name, fields) => {
+                for (_, value) in fields {
+                    self.compile_expr(value)?;
+                }
+                let count = to_operand(fields.len(), "fields")?;
+                self.chunk.push(Op::Struct(Type::named(name), count))?;
+            }
+            ExpressionKind::FieldExpr {
+                object,
+                field,
+                index,
+            } => {
+                self.compile_expr(object)?;
+                let index = index.ok_or(format!("Unknown field: {}", field))?;
+                self.chunk.push(Op::Field(to_operand(index, "fields")?))?;
+            }
+            ExpressionKind::VariantExpr(name, args) => {
+                let (def, tag) = self
+                    .type_defs
+                    .variant(name)
+                    .ok_or(format!("Unknown variant: {}", name))?;
+                for arg in args {
+                    self.compile_expr(arg)?;
+                }
+                let count = to_operand(args.len(), "fields")?;
+                self.chunk.push(Op::Variant(
+                    Type::named(&def.name),
+                    to_operand(tag, "variants")?,
+                    count,
+                ))?;
+            }
+            ExpressionKind::MatchExpr { value, arms, tree } => {
+                let tree = tree.as_ref().ok_or("Match is not type checked.")?;
+
+                // keep the value aside while testing its parts
+                self.compile_expr(value)?;
+                let slot = self.store_into_new_slot()?;
+
+                let mut jumps_to_end = vec![];
+                self.compile_decision(tree, slot, arms, &mut jumps_to_end)?;
+
+                let end = self.chunk.next_index()?;
+                for jump in jumps_to_end {
+                    self.patch_jump(jump, end);
+                }
+            }
+            ExpressionKind::LambdaExpr { .. } => {
+                unreachable!("lambdas are converted into closures")
+            }
+            ExpressionKind::GradExpr(_) => {
+                unreachable!("gradients are expanded before type checking")
+            }
+            ExpressionKind::ClosureExpr {
+                function,
+                captures,
+                ty,
+            } => {
+                self.push_constant(Value::Function(
+                    ty.clone(),
+                    function.as_str().into(),
+                    Rc::new([]),
+                ))?;
+                for var in captures {
+                    self.chunk.push(Op::Load(self.get_slot(var)?))?;
+                }
+                self.chunk
+                    .push(Op::Closure(to_operand(captures.len(), "captures")?))?;
+            }
+            ExpressionKind::CallExpr(name, args) if self.slots.contains_key(name) => {
+                // the function held by a variable, whose arity is checked when it is called
+                for arg in args {
+                    self.compile_expr(arg)?;
+                }
+                self.chunk.push(Op::Load(self.slots[name]))?;
+                self.chunk
+                    .push(Op::CallValue(to_operand(args.len(), "arguments")?))?;
+            }
+            ExpressionKind::CallExpr(name, args) => {
+                let signature = self
+                    .signatures
+                    .get(name)
+                    .ok_or_else(|| self.unknown(name, format!("Unknown function: {}", name)))?;
+
+                if args.len() != signature.arity {
+                    return Err(format!(
+                        "Unmatched arg number. Function {} expects {} but the input has {}.",
+                        name,
+                        signature.arity,
+                        args.len()
+                    )
+                    .into());
+                }
+
+                for arg in args {
+                    self.compile_expr(arg)?;
+                }
+                self.push_call(name)?;
+            }
+            ExpressionKind::IfExpr(cond, then_expr, else_expr) => {
+                self.compile_expr(cond)?;
+                let jump_to_else = self.chunk.push(Op::JumpIfFalse(0))?;
+
+                self.compile_expr(then_expr)?;
+                let jump_to_end = self.chunk.push(Op::Jump(0))?;
+
+                let else_start = self.chunk.next_index()?;
+                self.patch_jump(jump_to_else, else_start);
+                self.compile_expr(else_expr)?;
+
+                let end = self.chunk.next_index()?;
+                self.patch_jump(jump_to_end, end);
+            }
+            ExpressionKind::ForExpr {
+                var,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let slot = self.new_slot()?;
+                let end_cond_slot = self.new_slot()?;
+
+                // emit the start value without the variable in scope
+                self.compile_expr(start)?;
+                self.chunk.push(Op::Store(slot))?;
+                self.chunk.push(Op::Pop)?;
+
+                let old_slot = self.slots.insert(var.clone(), slot);
+                let result = self.compile_for_loop(slot, end_cond_slot, end, step.as_deref(), body);
+                self.restore_slots(vec![(var.clone(), old_slot)]);
+                result?;
+
+                // for expression always evaluates to 0.0
+                self.push_constant(Value::Double(0.0))?;
+            }
+            ExpressionKind::VarExpr { vars, body } => {
+                let mut old_slots = Vec::with_capacity(vars.len());
+                let mut result = Ok(());
+                for (var, init) in vars {
+                    // the initializer is evaluated before the variable is in scope
+                    let init = match init {
+                        Some(init) => self.compile_expr(init),
+                        None => self
+                            .push_constant(Value::Double(0.0))
+                            .map_err(SpannedError::from),
+                    };
+
+                    let slot = match init.and_then(|_| Ok(self.store_into_new_slot()?)) {
+                        Ok(slot) => slot,
+                        Err(err) => {
+                            result = Err(err);
+                            break;
+                        }
+                    };
+
+                    old_slots.push((var.clone(), self.slots.insert(var.clone(), slot)));
+                }
+
+                let body = result.and_then(|_| self.compile_expr(body));
+                self.restore_slots(old_slots);
+                body?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate the body, step and end condition of a for loop,
+    /// with the induction variable already bound to `slot`.
+    /// As with LLVM, the body runs at least once,
+    /// and the end condition is evaluated before the variable is incremented.
+    fn compile_for_loop(
+        &mut self,
+        slot: u16,
+        end_cond_slot: u16,
+        end: &Expression,
+        step: Option<&Expression>,
+        body: &Expression,
+    ) -> Result<(), SpannedError> {
+        let loop_start = self.chunk.next_index()?;
+
+        // the value of body is ignored
+        self.compile_expr(body)?;
+        self.chunk.push(Op::Pop)?;
+
+        // the type checker writes out a missing step with the type of the variable
+        match step {
+            Some(step) => self.compile_expr(step)?,
+            None => self.push_constant(Value::Double(1.0))?,
+        };
+
+        // keep the end condition aside while incrementing the variable
+        self.compile_expr(end)?;
+        self.chunk.push(Op::Store(end_cond_slot))?;
+        self.chunk.push(Op::Pop)?;
+
+        self.chunk.push(Op::Load(slot))?;
+        self.chunk.push(Op::Add)?;
+        self.chunk.push(Op::Store(slot))?;
+        self.chunk.push(Op::Pop)?;
+
+        self.chunk.push(Op::Load(end_cond_slot))?;
+        let jump_to_end = self.chunk.push(Op::JumpIfFalse(0))?;
+        self.chunk.push(Op::Jump(loop_start))?;
+
+        let end = self.chunk.next_index()?;
+        self.patch_jump(jump_to_end, end);
+        Ok(())
+    }
+
+    /// Generate the tests of a decision tree on the value in `slot`,
+    /// each leaf evaluating its arm and jumping to the end of the match.
+    /// The jumps to be patched are added to `jumps_to_end`.
+    /// An arm reached from several leaves is generated at each of them.
+    fn compile_decision(
+        &mut self,
+        decision: &Decision,
+        slot: u16,
+        arms: &[(Pattern, Expression)],
+        jumps_to_end: &mut Vec<u16>,
+    ) -> Result<(), SpannedError> {
+        match decision {
+            Decision::Arm { index, bindings } => {
+                let mut old_slots = Vec::with_capacity(bindings.len());
+                for (var, occurrence) in bindings {
+                    self.load_occurrence(slot, occurrence)?;
+                    let var_slot = self.store_into_new_slot()?;
+                    old_slots.push((var.clone(), self.slots.insert(var.clone(), var_slot)));
+                }
+
+                let body = self.compile_expr(&arms[*index].1);
+                self.restore_slots(old_slots);
+                body?;
+                jumps_to_end.push(self.chunk.push(Op::Jump(0))?);
+            }
+            Decision::Switch {
+                occurrence,
+                cases,
+                default,
+                ..
+            } => {
+                for (i, (tag, case)) in cases.iter().enumerate() {
+                    // without a default, the last case is the only tag left
+                    if i + 1 == cases.len() && default.is_none() {
+                        return self.compile_decision(case, slot, arms, jumps_to_end);
+                    }
+
+                    self.load_occurrence(slot, occurrence)?;
+                    let tag = to_operand(*tag, "variants")?;
+                    let jump_to_next = self.chunk.push(Op::JumpIfNotTag(tag, 0))?;
+                    self.compile_decision(case, slot, arms, jumps_to_end)?;
+
+                    let next = self.chunk.next_index()?;
+                    self.patch_jump(jump_to_next, next);
+                }
+                if let Some(default) = default {
+                    self.compile_decision(default, slot, arms, jumps_to_end)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Push the part of the value in `slot` at an occurrence
+    fn load_occurrence(&mut self, slot: u16, occurrence: &[usize]) -> Result<(), String> {
+        self.chunk.push(Op::Load(slot))?;
+        for &field in occurrence {
+            self.chunk.push(Op::Field(to_operand(field, "fields")?))?;
+        }
+        Ok(())
+    }
+
+    /// Restore bindings shadowed by a scope, in reverse order of shadowing
+    fn restore_slots(&mut self, old_slots: Vec<(String, Option<u16>)>) {
+        for (var, old_slot) in old_slots.into_iter().rev() {
+            match old_slot {
+                Some(old_slot) => self.slots.insert(var, old_slot),
+                None => self.slots.remove(&var),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::closure::ClosureConverter;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+    use crate::types::check_function;
+    use crate::util::{Position, Span};
+
+    /// Compile the first function of a program, after the unions defined before it
+    fn compile(
+        program: &str,
+        signatures: &HashMap<String, Signature>,
+    ) -> Result<Chunk, SpannedError> {
+        let mut type_defs = TypeDefs::new();
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::UnionNode(def) => type_defs.define_union(&def).unwrap(),
+                ASTNode::FunctionNode(func) => {
+                    let func = check_function(&func, &HashMap::new(), &type_defs).unwrap();
+                    let (func, _) = ClosureConverter::new().convert(&func);
+                    return compile_function(&func, signatures, &HashMap::new(), &type_defs);
+                }
+                node => panic!("unexpected node {:?}", node),
+            }
+        }
+        panic!("no function")
+    }
+
+    #[test]
+    fn compile_expressions() {
+        let chunk = compile("def f(x y) x * (y + 1)", &HashMap::new()).unwrap();
+
+        assert_eq!(chunk.arity, 2);
+        assert_eq!(chunk.locals, 2);
+        assert_eq!(chunk.constants, vec![Value::Double(1.0)]);
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Load(0),
+                Op::Load(1),
+                Op::Constant(0),
+                Op::Add,
+                Op::Mul,
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_if_and_calls() {
+        let mut signatures = HashMap::new();
+        signatures.insert(
+            "g".to_string(),
+            Signature {
+                index: 3,
+                arity: 1,
+                ty: Type::function(vec![Type::Double], Type::Double),
+            },
+        );
+
+        let chunk = compile("def f(x) if x then g(x) else 2", &signatures).unwrap();
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Load(0),
+                Op::JumpIfFalse(5),
+                Op::Load(0),
+                Op::Call(3),
+                Op::Jump(6),
+                Op::Constant(0),
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_function_values() {
+        let mut signatures = HashMap::new();
+        let ty = Type::function(vec![Type::Double], Type::Double);
+        signatures.insert(
+            "g".to_string(),
+            Signature {
+                index: 3,
+                arity: 1,
+                ty: ty.clone(),
+            },
+        );
+
+        let chunk = compile("def f(h) h(g, 1)", &signatures).unwrap();
+
+        assert_eq!(
+            chunk.constants,
+            vec![
+                Value::Function(ty, "g".into(), Rc::new([])),
+                Value::Double(1.0)
+            ]
+        );
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Constant(0),
+                Op::Constant(1),
+                Op::Load(0),
+                Op::CallValue(2),
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_closures() {
+        let chunk = compile("def f(k) \\x -> x + k", &HashMap::new()).unwrap();
+
+        assert_eq!(
+            chunk.constants,
+            vec![Value::Function(
+                Type::function(vec![Type::Double], Type::Double),
+                "lambda.0".into(),
+                Rc::new([])
+            )]
+        );
+        assert_eq!(
+            chunk.code,
+            vec![Op::Constant(0), Op::Load(0), Op::Closure(1), Op::Return]
+        );
+    }
+
+    #[test]
+    fn scopes_get_new_slots() {
+        let chunk = compile(
+            "def f(x) (var x = x, y in x + y) + (for i = 0, i < 1 in x)",
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        // x, the inner x and y, then i and the end condition of the loop
+        assert_eq!(chunk.locals, 5);
+    }
+
+    #[test]
+    fn compile_typed_expressions() {
+        let chunk = compile(
+            "def f(n: int) if n < 2 then double(n) else 2",
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(chunk.constants, vec![Value::Int(2), Value::Double(2.0)]);
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Load(0),
+                Op::Constant(0),
+                Op::LessThan,
+                Op::JumpIfFalse(7),
+                Op::Load(0),
+                Op::Cast(Type::Double),
+                Op::Jump(8),
+                Op::Constant(1),
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_arrays() {
+        let chunk = compile("def f(a: [int]) a[1] = [2][0]", &HashMap::new()).unwrap();
+        assert_eq!(
+            chunk.to_string(),
+            "\
+chunk f (arity 1, locals 1)
+0000 Constant 0 (2)
+0001 Array(1)
+0002 Constant 1 (0)
+0003 Index(0) (1:24)
+0004 Load(0)
+0005 Constant 2 (1)
+0006 StoreIndex(1) (1:17)
+0007 Return
+"
+        );
+    }
+
+    #[test]
+    fn compile_match() {
+        let chunk = compile(
+            "type Shape = Circle(r) | Empty def f(s) match s { Circle(r) -> r, Empty -> 0 }",
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        // the last tag is not tested
+        assert_eq!(
+            chunk.code,
+            vec![
+                Op::Load(0),
+                Op::Store(1),
+                Op::Pop,
+                Op::Load(1),
+                Op::JumpIfNotTag(0, 11),
+                Op::Load(1),
+                Op::Field(0),
+                Op::Store(2),
+                Op::Pop,
+                Op::Load(2),
+                Op::Jump(13),
+                Op::Constant(0),
+                Op::Jump(13),
+                Op::Return
+            ]
+        );
+        assert_eq!(chunk.constants, vec![Value::Double(0.0)]);
+    }
+
+    /// The span of columns `start..end` of the first line
+    fn columns(start: usize, end: usize) -> Span {
+        let mut start_pos = Position::default();
+        for _ in 1..start {
+            start_pos = start_pos.after(' ');
+        }
+        let mut end_pos = start_pos;
+        for _ in start..end {
+            end_pos = end_pos.after(' ');
+        }
+        Span::new(start_pos, end_pos)
+    }
+
+    #[test]
+    fn errors() {
+        let mut signatures = HashMap::new();
+        signatures.insert(
+            "g".to_string(),
+            Signature {
+                index: 0,
+                arity: 1,
+                ty: Type::function(vec![Type::Double], Type::Double),
+            },
+        );
+
+        // every error is at the expression causing it
+        for (program, message, span) in &[
+            ("def f(x) y", "Unknown variable name: y", columns(10, 11)),
+            ("def f(x) 1 + h(x)", "Unknown function: h", columns(14, 18)),
+            (
+                "def f(x) g(x, x)",
+                "Unmatched arg number. Function g expects 1 but the input has 2.",
+                columns(10, 17),
+            ),
+            ("def f(x) 2 * !x", "Unknown unary op !", columns(14, 16)),
+            (
+                "def f(x) (var y = 1 in y) + y",
+                "Unknown variable name: y",
+                columns(29, 30),
+            ),
+            (
+                "def f(x) z = x",
+                "Unknown variable name: z",
+                columns(10, 11),
+            ),
+        ] {
+            assert_eq!(
+                compile(program, &signatures).map(|_| ()),
+                Err(SpannedError::new(*message, *span)),
+                "{}",
+                program
+            );
+        }
+    }
+}
@@ -0,0 +1,786 @@
+use super::bytecode::{to_operand, Chunk, Op};
+use super::compile::{compile_function, Signature};
+use crate::backend::{Backend, Lower, Lowering};
+use crate::diagnostics::{Diagnostic, SpannedError};
+use crate::interp::{resolve_extern, Builtin, Value};
+use crate::parser::nodes::{DerivativeDef, Function, Prototype, StructDef, UnionDef};
+use crate::types::{check_function, TypeDefs, TypeError};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Calls nested deeper than this fail instead of exhausting the memory
+const MAX_FRAMES: usize = 10_000;
+
+/// A function in the function table of the machine
+#[derive(Clone)]
+enum Callable {
+    Defined(Rc<Chunk>),
+    Builtin(Builtin),
+    /// A function being compiled, so that it can call itself,
+    /// or declared to be defined later, so that the functions before its definition can call it
+    Compiling,
+}
+
+/// The state of a function call
+struct Frame {
+    chunk: Rc<Chunk>,
+    /// Index of the next op
+    ip: usize,
+    /// Index of the first local slot in the stack
+    base: usize,
+}
+
+/// A stack machine running functions compiled into bytecode,
+/// as a lightweight alternative to the JIT.
+#[derive(Default)]
+pub struct VirtualMachine {
+    /// Every function defined or declared so far, called by index
+    functions: Vec<Callable>,
+    signatures: HashMap<String, Signature>,
+    /// The errors of the new functions that could not be defined,
+    /// reported again by the functions using them rather than an unknown function
+    failed: HashMap<String, SpannedError>,
+    /// The typed prototype of every function in the table, to type check the functions calling them
+    prototypes: HashMap<String, Prototype>,
+    type_defs: TypeDefs,
+    /// Lowers every function into functions without gradients or lambdas
+    lowering: Lowering,
+}
+
+impl VirtualMachine {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Find the slot of a function in the function table, adding one if it is new.
+    /// `proto` must have every type annotated.
+    fn declare(&mut self, proto: &Prototype) -> Result<Signature, String> {
+        if let Some(signature) = self.signatures.get(&proto.name) {
+            // calls compiled against the old definition push as many arguments as it takes,
+            // of the types it takes
+            if signature.arity != proto.args.len() {
+                return Err(format!(
+                    "Function {} is already declared with {} args.",
+                    proto.name, signature.arity
+                ));
+            }
+            let previous = &self.prototypes[&proto.name];
+            if previous.arg_types != proto.arg_types || previous.ret_type != proto.ret_type {
+                return Err(format!(
+                    "Function {} is already declared as {}.",
+                    proto.name, previous
+                ));
+            }
+            return Ok(signature.clone());
+        }
+
+        let signature = Signature {
+            index: to_operand(self.functions.len(), "functions")?,
+            arity: proto.args.len(),
+            ty: proto.ty(),
+        };
+        self.functions.push(Callable::Compiling);
+        self.signatures
+            .insert(proto.name.clone(), signature.clone());
+        self.prototypes.insert(proto.name.clone(), proto.clone());
+        Ok(signature)
+    }
+
+    /// Declare an extern, which must be one of the builtins
+    pub fn declare_extern(&mut self, proto: &Prototype) -> Result<(), String> {
+        let builtin = resolve_extern(proto)?;
+        let signature = self.declare(&proto.annotated())?;
+        self.functions[signature.index as usize] = Callable::Builtin(builtin);
+        Ok(())
+    }
+
+    /// Declare a function defined later, so that the functions before its definition can call it
+    pub fn declare_func(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        let proto = &proto.annotated();
+        self.type_defs.check_annotations(proto)?;
+        self.declare(proto)
+            .map(|_| ())
+            .map_err(|err| Box::new(Diagnostic::error(err, proto.span)))
+    }
+
+    /// Define a struct, so that it can be used by later functions
+    pub fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<TypeError>> {
+        self.type_defs.define_struct(def)
+    }
+
+    /// Define a tagged union, so that its variants can be used by later functions
+    pub fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<TypeError>> {
+        self.type_defs.define_union(def)
+    }
+
+    /// Type check a function against the functions in the table and the types defined so far
+    pub fn check(&self, func: &Function) -> Result<Function, Box<TypeError>> {
+        check_function(func, &self.prototypes, &self.type_defs)
+    }
+
+    /// Type check and compile a function into bytecode, so that it can be called from later expressions
+    pub fn define_func(&mut self, func: &Function) -> Result<Rc<Chunk>, Box<Diagnostic>> {
+        let result = self.lower(func);
+        self.record_failure(&func.prototype.name, &result);
+        result
+    }
+
+    /// Type check and compile `f'` for `def' f`, so that it can be called from later expressions
+    pub fn define_derivative(&mut self, def: &DerivativeDef) -> Result<Rc<Chunk>, Box<Diagnostic>> {
+        let result = self.lower_derivative(def);
+        self.record_failure(&def.derivative_name(), &result);
+        result
+    }
+
+    /// Keep the error of a new function that could not be defined,
+    /// and forget it once the function is defined
+    fn record_failure<T>(&mut self, name: &str, result: &Result<T, Box<Diagnostic>>) {
+        match result {
+            Ok(_) => {
+                self.failed.remove(name);
+            }
+            Err(err) if !self.signatures.contains_key(name) => {
+                self.failed.insert(
+                    name.to_string(),
+                    SpannedError::new(err.message.clone(), err.span),
+                );
+            }
+            // a failed redefinition keeps the previous definition
+            Err(_) => {}
+        }
+    }
+
+    /// Type check, compile and run an anonymous function wrapping a top level expression
+    pub fn evaluate(&mut self, func: &Function) -> Result<f64, Box<Diagnostic>> {
+        self.lower_and_run(func, |vm, func| {
+            let chunk = compile_function(func, &vm.signatures, &vm.failed, &vm.type_defs)
+                .map_err(|err| err.into_diagnostic(func.span))?;
+            let value = vm
+                .run(Rc::new(chunk), vec![])
+                .map_err(|err| Diagnostic::error(err, func.span))?;
+            Ok(value.to_f64())
+        })
+    }
+
+    /// Call a function by name
+    pub fn call(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        let signature = self.signature(name, args.len())?;
+        match &self.functions[signature.index as usize] {
+            Callable::Defined(chunk) => self.run(chunk.clone(), args.to_vec()),
+            Callable::Builtin(builtin) => Ok(builtin.call(args)),
+            Callable::Compiling => Err(format!("Function {} is not defined.", name)),
+        }
+    }
+
+    /// Find the signature of a function called with `args` arguments
+    fn signature(&self, name: &str, args: usize) -> Result<Signature, String> {
+        let signature = self
+            .signatures
+            .get(name)
+            .ok_or(format!("Unknown function: {}", name))?;
+
+        if args != signature.arity {
+            return Err(format!(
+                "Unmatched arg number. Function {} expects {} but the input has {}.",
+                name, signature.arity, args
+            ));
+        }
+        Ok(signature.clone())
+    }
+
+    /// Run a chunk until it returns, with its arguments as the initial stack
+    fn run(&self, chunk: Rc<Chunk>, mut stack: Vec<Value>) -> Result<Value, String> {
+        stack.resize(chunk.locals, Value::Double(0.0));
+        let mut frames = vec![Frame {
+            chunk,
+            ip: 0,
+            base: 0,
+        }];
+
+        loop {
+            let frame = frames.last_mut().unwrap();
+            let op = frame.chunk.code[frame.ip].clone();
+            frame.ip += 1;
+
+            match op {
+                Op::Constant(constant) => {
+                    stack.push(frame.chunk.constants[constant as usize].clone())
+                }
+                Op::Load(slot) => stack.push(stack[frame.base + slot as usize].clone()),
+                Op::Store(slot) => {
+                    stack[frame.base + slot as usize] = stack.last().unwrap().clone()
+                }
+                Op::Pop => {
+                    stack.pop();
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::LessThan | Op::GreaterThan => {
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+                    let op = match op {
+                        Op::Add => '+',
+                        Op::Sub => '-',
+                        Op::Mul => '*',
+                        Op::Div => '/',
+                        Op::LessThan => '<',
+                        _ => '>',
+                    };
+                    stack.push(Value::binary(op, lhs, rhs)?);
+                }
+                Op::Cast(ty) => {
+                    let value = stack.pop().unwrap();
+                    stack.push(value.cast(ty));
+                }
+                Op::Array(count) => {
+                    let elements = stack.split_off(stack.len() - count as usize);
+                    stack.push(Value::array(elements));
+                }
+                Op::Index(position) | Op::StoreIndex(position) => {
+                    let pos = frame.chunk.positions[position as usize];
+                    let index = stack.pop().unwrap();
+                    let array = stack.pop().unwrap();
+                    match op {
+                        Op::Index(_) => stack.push(array.index(&index, pos)?),
+                        _ => array.store(&index, stack.last().unwrap().clone(), pos)?,
+                    }
+                }
+                Op::Struct(ty, count) => {
+                    let fields = stack.split_off(stack.len() - count as usize);
+                    stack.push(Value::Struct(ty, fields.into()));
+                }
+                Op::Field(index) => {
+                    let value = stack.pop().unwrap();
+                    stack.push(value.field(index as usize)?);
+                }
+                Op::Variant(ty, tag, count) => {
+                    let fields = stack.split_off(stack.len() - count as usize);
+                    stack.push(Value::Variant(ty, tag as usize, fields.into()));
+                }
+                Op::Jump(target) => frame.ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if !stack.pop().unwrap().is_true() {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::JumpIfNotTag(tag, target) => {
+                    if stack.pop().unwrap().tag()? != tag as usize {
+                        frame.ip = target as usize;
+                    }
+                }
+                Op::Call(index) => self.push_call(index, &mut stack, &mut frames)?,
+                Op::CallValue(count) => {
+                    // the function is only known now, so its arity is checked against the arguments
+                    let callee = stack.pop().unwrap();
+                    let (name, captured) = callee.function()?;
+                    let signature = self.signature(name, captured.len() + count as usize)?;
+                    let args = stack.len() - count as usize;
+                    stack.splice(args..args, captured.iter().cloned());
+                    self.push_call(signature.index, &mut stack, &mut frames)?;
+                }
+                Op::Closure(count) => {
+                    let captured = stack.split_off(stack.len() - count as usize);
+                    let (ty, name) = match stack.pop().unwrap() {
+                        Value::Function(ty, name, _) => (ty, name),
+                        value => unreachable!("closure of {}", value),
+                    };
+                    stack.push(Value::Function(ty, name, captured.into()));
+                }
+                Op::Return => {
+                    let result = stack.pop().unwrap();
+                    let frame = frames.pop().unwrap();
+                    if frames.is_empty() {
+                        return Ok(result);
+                    }
+                    stack.truncate(frame.base);
+                    stack.push(result);
+                }
+            }
+        }
+    }
+
+    /// Call the function at an index of the function table, with its arguments on top of the stack.
+    /// A defined function gets a new frame, a builtin replaces its arguments with the result.
+    fn push_call(
+        &self,
+        index: u16,
+        stack: &mut Vec<Value>,
+        frames: &mut Vec<Frame>,
+    ) -> Result<(), String> {
+        match &self.functions[index as usize] {
+            Callable::Defined(chunk) => {
+                if frames.len() == MAX_FRAMES {
+                    return Err("Stack overflow.".into());
+                }
+
+                // the arguments on the stack become the first local slots
+                let base = stack.len() - chunk.arity;
+                stack.resize(base + chunk.locals, Value::Double(0.0));
+                frames.push(Frame {
+                    chunk: chunk.clone(),
+                    ip: 0,
+                    base,
+                });
+            }
+            Callable::Builtin(builtin) => {
+                let base = stack.len() - builtin.arity();
+                let result = builtin.call(&stack[base..]);
+                stack.truncate(base);
+                stack.push(result);
+            }
+            // a function being compiled cannot run, so the function is only declared
+            Callable::Compiling => {
+                let name = self
+                    .signatures
+                    .iter()
+                    .find(|(_, signature)| signature.index == index)
+                    .map(|(name, _)| name);
+                return Err(format!("Function {} is not defined.", name.unwrap()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Lower for VirtualMachine {
+    type Output = Rc<Chunk>;
+    type Error = Box<Diagnostic>;
+
+    fn front_end(&mut self) -> (&mut Lowering, &HashMap<String, Prototype>, &TypeDefs) {
+        (&mut self.lowering, &self.prototypes, &self.type_defs)
+    }
+
+    fn declare_lowered(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        self.declare(proto)
+            .map(|_| ())
+            .map_err(|err| Box::new(Diagnostic::error(err, proto.span)))
+    }
+
+    fn define_lowered(&mut self, func: &Function) -> Result<Rc<Chunk>, Box<Diagnostic>> {
+        let error = |err| Box::new(Diagnostic::error(err, func.span));
+        let is_new = !self.signatures.contains_key(&func.prototype.name);
+        let signature = self.declare(&func.prototype).map_err(error)?;
+        let index = signature.index as usize;
+
+        // keep the previous definition if any, for it to be restored on failure
+        let previous = std::mem::replace(&mut self.functions[index], Callable::Compiling);
+
+        match compile_function(func, &self.signatures, &self.failed, &self.type_defs) {
+            Ok(chunk) => {
+                let chunk = Rc::new(chunk);
+                self.functions[index] = Callable::Defined(chunk.clone());
+                Ok(chunk)
+            }
+            Err(err) => {
+                if is_new {
+                    self.forget_lowered(&func.prototype.name);
+                } else {
+                    self.functions[index] = previous;
+                }
+                Err(Box::new(err.into_diagnostic(func.span)))
+            }
+        }
+    }
+
+    fn forget_lowered(&mut self, name: &str) {
+        if let Some(signature) = self.signatures.remove(name) {
+            self.prototypes.remove(name);
+            // the functions forgotten are the latest ones, forgotten in reverse order
+            if signature.index as usize + 1 == self.functions.len() {
+                self.functions.pop();
+            }
+        }
+    }
+}
+
+impl Backend for VirtualMachine {
+    fn declare_extern(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        VirtualMachine::declare_extern(self, proto)
+            .map_err(|err| Box::new(Diagnostic::error(err, proto.span)))
+    }
+
+    fn declare_function(&mut self, proto: &Prototype) -> Result<(), Box<Diagnostic>> {
+        self.declare_func(proto)
+    }
+
+    fn define_function(&mut self, func: &Function) -> Result<(), Box<Diagnostic>> {
+        self.define_func(func).map(|_| ())
+    }
+
+    fn define_derivative(&mut self, def: &DerivativeDef) -> Result<(), Box<Diagnostic>> {
+        VirtualMachine::define_derivative(self, def).map(|_| ())
+    }
+
+    fn define_struct(&mut self, def: &StructDef) -> Result<(), Box<Diagnostic>> {
+        VirtualMachine::define_struct(self, def).map_err(|err| Box::new(err.into()))
+    }
+
+    fn define_union(&mut self, def: &UnionDef) -> Result<(), Box<Diagnostic>> {
+        VirtualMachine::define_union(self, def).map_err(|err| Box::new(err.into()))
+    }
+
+    fn evaluate(&mut self, func: &Function) -> Result<f64, Box<Diagnostic>> {
+        VirtualMachine::evaluate(self, func)
+    }
+
+    /// The disassembled bytecode of the function
+    fn dump(&self, name: &str) -> Option<String> {
+        let signature = self.signatures.get(name)?;
+        match &self.functions[signature.index as usize] {
+            Callable::Defined(chunk) => Some(chunk.to_string()),
+            Callable::Builtin(_) => Some(format!("builtin {}", name)),
+            Callable::Compiling => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::nodes::ASTNode;
+    use crate::parser::parser::Parser;
+
+    /// Run a program, returning the results of its top level expressions and failed definitions
+    fn run_all(program: &str) -> Vec<Result<f64, String>> {
+        run_all_in(&mut VirtualMachine::new(), program)
+    }
+
+    fn run_all_in(vm: &mut VirtualMachine, program: &str) -> Vec<Result<f64, String>> {
+        let mut results = vec![];
+
+        for node in Parser::new(Lexer::new(program.chars()))
+            .parse_program()
+            .nodes
+        {
+            match node {
+                ASTNode::ExternNode(proto) => vm.declare_extern(&proto).unwrap(),
+                ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                    results.push(vm.evaluate(&func).map_err(|err| err.message))
+                }
+                ASTNode::UnionNode(def) => vm.define_union(&def).unwrap(),
+                ASTNode::FunctionNode(func) => {
+                    if let Err(err) = vm.define_func(&func) {
+                        results.push(Err(err.message));
+                    }
+                }
+                ASTNode::StructNode(def) => vm.define_struct(&def).unwrap(),
+                ASTNode::DerivativeNode(def) => {
+                    if let Err(err) = vm.define_derivative(&def) {
+                        results.push(Err(err.message));
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        results
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        assert_eq!(
+            run_all("1+2*3; 4-2; 1/4.0; 1 < 2; 2 > 1; 0.0/0 < 1; if 0.0/0 then 1 else 2;"),
+            vec![
+                Ok(7.0),
+                Ok(2.0),
+                Ok(0.25),
+                Ok(1.0),
+                Ok(1.0),
+                Ok(1.0),
+                Ok(2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn call_functions() {
+        assert_eq!(
+            run_all(
+                "
+                extern sqrt(x);
+                def fib(n) if n < 3 then 1 else fib(n - 1) + fib(n - 2);
+                def sum(n) var acc = 0 in (for i = 0, i < n in acc = acc + i) + acc;
+                def shadow(i) (for i = 0, i < 3, 0.5 in i) + i;
+                def binary : 1 (x y) y;
+                def unary - (x) 0 - x;
+                def swap(a b) var tmp = a in a = b : b = tmp : a - b;
+                fib(20);
+                sum(5);
+                shadow(7);
+                -swap(1, 3);
+                sqrt(double(fib(4)) + 13);
+                "
+            ),
+            vec![Ok(6765.0), Ok(15.0), Ok(7.0), Ok(-2.0), Ok(4.0)]
+        );
+        assert_eq!(
+            VirtualMachine::new().call("f", &[]),
+            Err("Unknown function: f".into())
+        );
+    }
+
+    #[test]
+    fn redefinitions() {
+        assert_eq!(
+            run_all(
+                "
+                def f(x) x;
+                def g(x) f(x) + 1;
+                g(1);
+                def f(x) x * 10;
+                g(1);
+                def f(x) y;
+                g(1);
+                def f(x y) x;
+                g(1);
+                "
+            ),
+            vec![
+                Ok(2.0),
+                Ok(11.0),
+                Err("Unknown variable name: y".into()),
+                Ok(11.0),
+                Err("Function f is already declared with 1 args.".into()),
+                Ok(11.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_definitions_are_removed() {
+        let mut vm = VirtualMachine::new();
+
+        // using the function fails with the error of its definition, not as an unknown function
+        assert_eq!(
+            run_all_in(&mut vm, "def f(x) f(x) + y; f(1);"),
+            vec![
+                Err("Unknown variable name: y".into()),
+                Err("Unknown variable name: y".into())
+            ]
+        );
+        assert!(vm.call("f", &[Value::Double(1.0)]).is_err());
+        assert!(vm.functions.is_empty());
+        assert!(vm.prototypes.is_empty());
+
+        assert_eq!(run_all_in(&mut vm, "def f(x) x + 1; f(1);"), vec![Ok(2.0)]);
+        assert!(vm.failed.is_empty());
+    }
+
+    #[test]
+    fn declared_functions() {
+        let mut vm = VirtualMachine::new();
+        let odd = Prototype::new("odd", vec!["n".into()], Default::default());
+        vm.declare_func(&odd).unwrap();
+
+        // even can call odd before it is defined, but not run until then
+        assert_eq!(
+            run_all_in(
+                &mut vm,
+                "def even(n) if n < 1 then 1 else odd(n - 1); even(3); \
+                 def odd(n) if n < 1 then 0 else even(n - 1); even(3);"
+            ),
+            vec![Err("Function odd is not defined.".into()), Ok(0.0)]
+        );
+    }
+
+    #[test]
+    fn errors_are_at_their_expressions() {
+        let mut vm = VirtualMachine::new();
+        let spans: Vec<_> = Parser::new(Lexer::new("def g(x) y; g(1); 1 + z;".chars()))
+            .parse_program()
+            .nodes
+            .iter()
+            .map(|node| vm.run_item(node).map_err(|err| err.span.start.column))
+            .collect();
+
+        // the call fails at the unknown variable of the function, which could not be defined
+        assert_eq!(spans, vec![Err(10), Err(10), Err(23)]);
+    }
+
+    #[test]
+    fn typed_functions() {
+        assert_eq!(
+            run_all(
+                "
+                def half(n: int) n / 2;
+                def pick(b: bool) if b then 1 else 2.5;
+                def count(n: int) var c = 0 in (for i = 0, i < n in c = c + double(i)) + c;
+                half(7); half(7 / 0); pick(half(1) < 1); count(4);
+                def half(x) x / 2;
+                def half(n: int) n + 1.5;
+                half(3);
+                "
+            ),
+            vec![
+                Ok(3.0),
+                Ok(0.0),
+                Ok(1.0),
+                Ok(10.0),
+                Err("Function half is already declared as half(n: int): int.".into()),
+                Err("Expected int but found double.".into()),
+                Ok(1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            run_all(
+                r#"
+                extern strlen(s: string): int;
+                extern concat(a: string b: string): string;
+                def greet(name) concat("hello, ", name);
+                strlen(greet("world")); strlen("\u{e9}\n"); strlen(concat("", ""));
+                def pick(b: bool) if b then "yes" else "no";
+                strlen(pick(1 < 2)); strlen(pick(false));
+                int("1");
+                "#
+            ),
+            vec![
+                Ok(12.0),
+                Ok(3.0),
+                Ok(0.0),
+                Ok(3.0),
+                Ok(2.0),
+                Err("Cannot convert string to int.".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(
+            run_all(
+                "
+                def sum(a: [double] n: int) var s = 0 in (for i = 0, i < n - 1 in s = s + a[i]) + s;
+                def set(a: [[int]] i j v) a[i][j] = v;
+                def get(a: [[int]] i j) a[i][j];
+                sum([1, 2.5, 3], 3);
+                var m = [[1, 2], [3, 4]] in set(m, 1, 0, 7) + get(m, 1, 0);
+                var a = [1, 2], b = a in (b[0] = 5) + a[0];
+                sum([1], 2);
+                "
+            ),
+            vec![
+                Ok(6.5),
+                Ok(14.0),
+                Ok(10.0),
+                Err("Index 1 is out of bounds for an array of length 1 at 2:91.".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn structs() {
+        assert_eq!(
+            run_all(
+                "
+                struct Point { x, y: int }
+                struct Segment { from: Point, to: Point }
+                def length2(s: Segment) var dx = s.to.x - s.from.x, dy = double(s.to.y - s.from.y) in dx * dx + dy * dy;
+                def origin() Point { x = 0, y = 0 };
+                length2(Segment { from = origin(), to = Point { x = 3, y = 4 } });
+                var s = Segment { from = origin(), to = origin() } in (s = Segment { from = s.to, to = Point { x = 1, y = 2 } }).to.y;
+                "
+            ),
+            vec![Ok(25.0), Ok(2.0)]
+        );
+    }
+
+    #[test]
+    fn unions() {
+        assert_eq!(
+            run_all(
+                "
+                type Shape = Circle(r) | Rect(w, h) | Empty
+                type Option = Some(s: Shape) | None
+                def area(s) match s { Circle(r) -> 3 * r * r, Rect(w, h) -> w * h, Empty -> 0 };
+                def fallback(o, default) match o { Some(Empty) -> default, Some(s) -> area(s), None -> default };
+                area(Rect(2, 3)) + area(Circle(1));
+                fallback(Some(Rect(1, 5)), 1) + fallback(Some(Empty), 10) + fallback(None, 100);
+                "
+            ),
+            vec![Ok(9.0), Ok(115.0)]
+        );
+    }
+
+    #[test]
+    fn first_class_functions() {
+        assert_eq!(
+            run_all(
+                "
+                extern sin(x);
+                def square(x) x * x;
+                def apply(f x) f(x);
+                def integrate(f a b n: int)
+                  var sum = 0.0, h = (b - a) / double(n) in
+                    (for i = 0, i < n - 1 in sum = sum + f(a + (double(i) + 0.5) * h) * h) + sum;
+                apply(square, 3);
+                integrate(square, 0, 3, 3);
+                var g = square in var a = g(3), b = (g = sin) in a + g(0);
+                "
+            ),
+            vec![Ok(9.0), Ok(8.75), Ok(9.0)]
+        );
+    }
+
+    #[test]
+    fn closures() {
+        assert_eq!(
+            run_all(
+                "
+                def adder(k) \\x -> x + k;
+                def curry(f: (double, double) -> double) \\x -> \\y -> f(x, y);
+                var add2 = adder(2) in add2(1);
+                var k = 1 in var f = \\x -> x + k in (k = 100) + f(0);
+                var g = curry(\\x y -> x - y) in var h = g(10) in h(3);
+                var f = adder(1) in f(1, 2);
+                def count(n) if n < 1 then 0 else var g = \\x -> count(x) in 1 + g(n - 1);
+                count(3);
+                "
+            ),
+            vec![
+                Ok(3.0),
+                Ok(101.0),
+                Ok(7.0),
+                Err("Expected a function of 2 args but found (double) -> double.".into()),
+                Ok(3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn gradients() {
+        assert_eq!(
+            run_all(
+                "
+                extern exp(x);
+                def sigmoid(x) 1 / (1 + exp(0 - x));
+                def f(x y) var s = sigmoid(x) in s * y;
+                var d = grad(sigmoid) in d(0);
+                var d = grad(f) in d(0, 4)[0];
+                def f(x y) x + y;
+                var d = grad(f) in d(0, 4)[0];
+                def' sigmoid;
+                sigmoid'(0);
+                def' g;
+                "
+            ),
+            vec![
+                Ok(0.25),
+                Ok(1.0),
+                Ok(1.0),
+                Ok(0.25),
+                Err("Cannot differentiate g: it is not defined.".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        assert_eq!(
+            run_all("def f(x) f(x); f(1);"),
+            vec![Err("Stack overflow.".into())]
+        );
+    }
+}
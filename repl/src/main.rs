@@ -1,18 +1,37 @@
 use compiler;
-use compiler::{codegen::codegen_context::CodegenContext, parser::parser::ParseError};
+use compiler::backend::{create_backend, BackendKind};
+use compiler::codegen::passes::{parse_opt_level, OptimizationLevel};
+use compiler::diagnostics::Diagnostic;
+use compiler::lexer::Lexer;
+use compiler::parser::nodes::{ASTNode, Function};
+use compiler::parser::parser::Parser;
+use compiler::sema::Analyzer;
 use std::{
+    cell::RefCell,
     error::Error,
     io::{stdout, Read, Stdin, Write},
+    rc::Rc,
 };
 
-struct StdinIterator(Stdin);
+/// Name of the source shown in diagnostics
+const SOURCE_NAME: &str = "<stdin>";
+
+/// Reads stdin char by char, keeping everything read so far to render diagnostics
+struct StdinIterator {
+    stdin: Stdin,
+    source: Rc<RefCell<String>>,
+}
 
 impl Iterator for StdinIterator {
     type Item = char;
     fn next(&mut self) -> Option<Self::Item> {
         let mut character = [0];
-        match self.0.read(&mut character) {
-            Ok(x) if x > 0 => Some(character[0] as char),
+        match self.stdin.read(&mut character) {
+            Ok(x) if x > 0 => {
+                let c = character[0] as char;
+                self.source.borrow_mut().push(c);
+                Some(c)
+            }
             _ => None,
         }
     }
@@ -24,41 +43,124 @@ fn print_prompt() -> std::io::Result<()> {
     Ok(())
 }
 
+fn report(diagnostic: &Diagnostic, source: &RefCell<String>) {
+    eprint!("{}", diagnostic.render(SOURCE_NAME, &source.borrow()));
+}
+
+/// Report the problems found in a function before running it, returning whether there are none
+fn analyze(analyzer: &Analyzer, func: &Function, source: &RefCell<String>) -> bool {
+    let errors = analyzer.check_function(func);
+    let ok = errors.is_empty();
+    for err in errors {
+        report(&err.into(), source);
+    }
+    ok
+}
+
+/// Command line arguments
+struct Args {
+    backend: BackendKind,
+    opt_level: OptimizationLevel,
+}
+
+/// Parse the command line arguments
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        backend: BackendKind::Jit,
+        opt_level: OptimizationLevel::None,
+    };
+    for arg in std::env::args().skip(1) {
+        if let Some(backend) = arg.strip_prefix("--backend=") {
+            args.backend = backend.parse()?;
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            args.opt_level = parse_opt_level(level)?;
+        } else {
+            return Err(format!(
+                "Unknown argument {}. Usage: repl [-O<level>] [--backend=jit|vm|interp]",
+                arg
+            ));
+        }
+    }
+    Ok(args)
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    print_prompt()?;
-    let stdin_wrapper = StdinIterator(std::io::stdin());
-    let lexer = compiler::lexer::Lexer::new(stdin_wrapper);
-    let tokens = lexer.take_while(|x| x.is_ok()).map(|x| x.unwrap());
-    let mut parser = compiler::parser::parser::Parser::new(tokens);
+    let args = parse_args()?;
 
     let context = compiler::codegen::codegen_context::create_inkwell_context();
-    let mut cc = CodegenContext::new(&context, "test");
+    let mut backend = create_backend(args.backend, &context, args.opt_level)?;
+
+    print_prompt()?;
+    let source = Rc::new(RefCell::new(String::new()));
+    let stdin_wrapper = StdinIterator {
+        stdin: std::io::stdin(),
+        source: source.clone(),
+    };
+    let mut parser = Parser::new(Lexer::new(stdin_wrapper));
+    // knows the functions accepted by the backend so far
+    let mut analyzer = Analyzer::new();
 
     loop {
         match parser.parse() {
-            Ok(node) => match node {
-                compiler::parser::nodes::ASTNode::ExternNode(proto) => {
-                    match cc.compile_proto(&proto) {
-                        Ok(fun_value) => {
-                            println!("Read extern: ");
-                            fun_value.print_to_stderr();
-                        }
-                        Err(err) => println!("Err parsing extern: {}", err),
+            Ok(node) => match &node {
+                ASTNode::ExternNode(proto) => match backend.declare_extern(proto) {
+                    Ok(()) => {
+                        analyzer.declare(proto);
+                        println!("Read extern: ");
+                        eprintln!(
+                            "{}",
+                            backend.dump(&proto.name).unwrap_or_default().trim_end()
+                        );
                     }
-                }
-                compiler::parser::nodes::ASTNode::FunctionNode(func) => {
-                    match cc.compile_func(&func) {
-                        Ok(fun_value) => {
-                            println!("Read function: ");
-                            fun_value.print_to_stderr();
-                        }
-                        Err(err) => println!("Err parsing function: {}", err),
+                    Err(err) => report(&err, &source),
+                },
+                ASTNode::FunctionNode(func) if !analyze(&analyzer, func, &source) => {}
+                ASTNode::FunctionNode(func) if func.prototype.is_anonymous() => {
+                    match backend.evaluate(func) {
+                        Ok(value) => println!("Evaluated to {}", value),
+                        Err(err) => report(&err, &source),
                     }
                 }
-                compiler::parser::nodes::ASTNode::EOF => break,
-                compiler::parser::nodes::ASTNode::Delimiter => continue,
+                ASTNode::FunctionNode(func) => match backend.define_function(func) {
+                    Ok(()) => {
+                        analyzer.declare(&func.prototype);
+                        println!("Read function: ");
+                        eprintln!(
+                            "{}",
+                            backend
+                                .dump(&func.prototype.name)
+                                .unwrap_or_default()
+                                .trim_end()
+                        );
+                    }
+                    Err(err) => report(&err, &source),
+                },
+                ASTNode::DerivativeNode(def) => match backend.define_derivative(def) {
+                    Ok(()) => {
+                        analyzer.declare_derivative(def);
+                        println!("Read derivative: ");
+                        eprintln!(
+                            "{}",
+                            backend
+                                .dump(&def.derivative_name())
+                                .unwrap_or_default()
+                                .trim_end()
+                        );
+                    }
+                    Err(err) => report(&err, &source),
+                },
+                ASTNode::StructNode(def) => match backend.define_struct(def) {
+                    Ok(()) => println!("Read struct: {}", def.name),
+                    Err(err) => report(&err, &source),
+                },
+                ASTNode::UnionNode(def) => match backend.define_union(def) {
+                    Ok(()) => println!("Read type: {}", def.name),
+                    Err(err) => report(&err, &source),
+                },
+                ASTNode::EOF => break,
+                ASTNode::Delimiter => continue,
             },
-            Err(err) => println!("Err parsing node: {:?}", err),
+            Err(err) => report(&err.into(), &source),
         }
         print_prompt()?;
     }